@@ -9,17 +9,21 @@ with open('rustls/src/generated/named_group_to_kex.rs', 'w') as fh:
         fh.write(f"""
         NamedGroup::{oqsalg} => {{
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::{oqsalg}).ok()?;
             Some(KexAlgorithm::KEM(kem))
         }},
 """)
     fh.write("_ => None,\n}")
 
+# This is the NamedGroups extension we send, so its order must be stable:
+# KEMs in code point order (the order of `kems`), then hybrid and classical
+# groups.  suites::test checks the encoding.
 with open('rustls/src/generated/supported_kex_groups.rs', 'w') as fh:
     fh.write("&[\n")
     for alg, oqsalg in kems:
         fh.write(f"""NamedGroup::{oqsalg},\n""")
-    fh.write("""    NamedGroup::X25519,
+    fh.write("""    NamedGroup::X25519Kyber768,
+    NamedGroup::X25519,
     NamedGroup::secp384r1,
     NamedGroup::secp256r1,
 ]""")
@@ -44,6 +48,7 @@ enum_builder! {
         FFDHE4096 => 0x0102,
         FFDHE6144 => 0x0103,
         FFDHE8192 => 0x0104,
+        X25519Kyber768 => 0x6399,
 """)
     for id, (alg, oqsalg) in enumerate(kems, start=0x01fc):
         fh.write(f"        {oqsalg} => 0x{id:04x},\n")
@@ -118,7 +123,7 @@ with open("rustls/src/generated/supported_sign_tls13.rs", "w") as fh:
     fh.write("]")
 
 with open("rustls/src/generated/pq_sigscheme_to_sigalg.rs", "w") as fh:
-    fh.write("match self.scheme {\n")
+    fh.write("match scheme {\n")
     for alg, oqsalg in signs:
         fh.write(f"    SignatureScheme::{alg.upper()} => SignatureAlgorithm::{alg.upper()},\n")
     fh.write("    _ => unreachable!(),")
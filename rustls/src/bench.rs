@@ -0,0 +1,167 @@
+use crate::client::{ClientConfig, ClientSession};
+use crate::error::TLSError;
+use crate::msgs::enums::HandshakeType;
+use crate::server::{ServerConfig, ServerSession};
+use crate::session::Session;
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use webpki;
+
+/// A handshake message sent during `HandshakeBench::run`.
+#[derive(Clone, Debug)]
+pub struct TimedMessage {
+    /// True if the client sent this message, false if the server did.
+    pub from_client: bool,
+
+    /// The handshake message type.
+    pub typ: HandshakeType,
+
+    /// The encoded length of the message, including its header.
+    pub len: usize,
+
+    /// When the message was sent, relative to the start of the handshake.
+    pub at: Duration,
+}
+
+/// The outcome of `HandshakeBench::run`.
+#[derive(Clone, Debug)]
+pub struct HandshakeTimings {
+    /// Every handshake message sent, in order.  This includes
+    /// encrypted messages and any NewSessionTickets.
+    pub messages: Vec<TimedMessage>,
+
+    /// How long until neither side had anything more to send.
+    pub total: Duration,
+}
+
+/// The messages of the handshake being run, and when it started.
+struct Recording {
+    start: Instant,
+    messages: Vec<TimedMessage>,
+}
+
+type Record = Arc<Mutex<Recording>>;
+
+fn observer(record: &Record, from_client: bool)
+            -> Arc<dyn Fn(HandshakeType, &[u8]) + Send + Sync> {
+    let record = record.clone();
+    Arc::new(move |typ, bytes: &[u8]| {
+        let mut record = record.lock().unwrap();
+        let at = record.start.elapsed();
+        record.messages
+            .push(TimedMessage { from_client, typ, len: bytes.len(), at });
+    })
+}
+
+/// Move everything `from` wants to write into `to`, processing it as we go.
+/// Returns whether anything was moved.
+fn pump(from: &mut dyn Session, to: &mut dyn Session, buf: &mut Vec<u8>) -> Result<bool, TLSError> {
+    let mut moved = false;
+
+    while from.wants_write() {
+        buf.clear();
+        from.write_tls(buf)
+            .map_err(|err| TLSError::General(format!("write_tls failed: {}", err)))?;
+        if buf.is_empty() {
+            break;
+        }
+
+        let mut offs = 0;
+        while offs < buf.len() {
+            offs += to.read_tls(&mut &buf[offs..])
+                .map_err(|err| TLSError::General(format!("read_tls failed: {}", err)))?;
+            to.process_new_packets()?;
+        }
+        moved = true;
+    }
+
+    Ok(moved)
+}
+
+/// Runs handshakes between a client and server configuration,
+/// recording the messages of each.
+///
+/// Building one of these copies the client configuration to install
+/// its observer, so do that outside any timed code and call `run` as
+/// often as needed.  The `on_outgoing_handshake` callback of the
+/// client configuration is not called.
+pub struct HandshakeBench {
+    client_config: Arc<ClientConfig>,
+    server_config: Arc<ServerConfig>,
+    record: Record,
+}
+
+impl HandshakeBench {
+    /// Make a `HandshakeBench` for `client_config` and `server_config`.
+    pub fn new(client_config: &Arc<ClientConfig>,
+               server_config: &Arc<ServerConfig>) -> HandshakeBench {
+        let record = Arc::new(Mutex::new(Recording {
+            start: Instant::now(),
+            messages: Vec::new(),
+        }));
+
+        let mut client_config = ClientConfig::clone(client_config);
+        client_config.on_outgoing_handshake = Some(observer(&record, true));
+        client_config.on_outgoing_handshake_encrypted = true;
+
+        HandshakeBench {
+            client_config: Arc::new(client_config),
+            server_config: server_config.clone(),
+            record,
+        }
+    }
+
+    /// Run a full handshake between a new client and server session,
+    /// pumping bytes between them through memory until neither has
+    /// anything left to send.
+    ///
+    /// Errors from either side are returned as-is; a handshake that
+    /// stops making progress is an error too.
+    pub fn run(&self, hostname: webpki::DNSNameRef)
+               -> Result<HandshakeTimings, TLSError> {
+        let start = Instant::now();
+        {
+            let mut record = self.record.lock().unwrap();
+            record.messages.clear();
+            record.start = start;
+        }
+
+        let mut client = ClientSession::new(&self.client_config, hostname);
+        let mut server = ServerSession::new(&self.server_config);
+        server.imp.common.handshake_observer = Some(observer(&self.record, false));
+        server.imp.common.observe_encrypted_handshake = true;
+
+        let mut buf = Vec::new();
+        loop {
+            let client_moved = pump(&mut client, &mut server, &mut buf)?;
+            let server_moved = pump(&mut server, &mut client, &mut buf)?;
+            if !client_moved && !server_moved {
+                break;
+            }
+        }
+        let total = start.elapsed();
+
+        if client.is_handshaking() || server.is_handshaking() {
+            return Err(TLSError::General("handshake stalled".to_string()));
+        }
+
+        let messages = self.record.lock()
+            .unwrap()
+            .messages
+            .clone();
+        Ok(HandshakeTimings { messages, total })
+    }
+}
+
+/// Run one handshake between `client_config` and `server_config`.
+/// See `HandshakeBench::run`; use a `HandshakeBench` directly to run
+/// many.
+pub fn run_handshake(client_config: &Arc<ClientConfig>,
+                     server_config: &Arc<ServerConfig>,
+                     hostname: webpki::DNSNameRef)
+                     -> Result<HandshakeTimings, TLSError> {
+    HandshakeBench::new(client_config, server_config)
+        .run(hostname)
+}
@@ -6,7 +6,7 @@ use crate::msgs::handshake::ServerExtension;
 use crate::msgs::handshake::ClientExtension;
 use crate::msgs::persist;
 use crate::msgs::enums::ExtensionType;
-use crate::msgs::enums::NamedGroup;
+use crate::msgs::enums::{CipherSuite, NamedGroup, SignatureScheme};
 use crate::session::SessionRandoms;
 use crate::hash_hs;
 use crate::sign;
@@ -15,7 +15,9 @@ use crate::suites;
 use crate::log::trace;
 use webpki;
 
-use std::{mem, time::Instant};
+use std::mem;
+#[cfg(feature = "print_runtime")]
+use std::time::Instant;
 
 pub struct ServerCertDetails {
     pub cert_chain: CertificatePayload,
@@ -35,6 +37,10 @@ impl ServerCertDetails {
     pub fn take_chain(&mut self) -> CertificatePayload {
         mem::replace(&mut self.cert_chain, Vec::new())
     }
+
+    pub fn take_ocsp_response(&mut self) -> Vec<u8> {
+        mem::replace(&mut self.ocsp_response, Vec::new())
+    }
 }
 
 pub struct ServerKXDetails {
@@ -61,6 +67,8 @@ pub struct HandshakeDetails {
     pub sent_tls13_fake_ccs: bool,
     pub dns_name: webpki::DNSName,
     pub extra_exts: Vec<ClientExtension>,
+    pub kx_group: Option<NamedGroup>,
+    #[cfg(feature = "print_runtime")]
     start_time: Instant,
 }
 
@@ -76,19 +84,35 @@ impl HandshakeDetails {
             sent_tls13_fake_ccs: false,
             dns_name: host_name,
             extra_exts,
+            kx_group: None,
+            #[cfg(feature = "print_runtime")]
             start_time: Instant::now(),
         }
     }
 
+    /// Print `label` and the time elapsed since the handshake started.
+    /// This does nothing unless the `print_runtime` feature is enabled.
     #[inline]
+    #[cfg_attr(not(feature = "print_runtime"), allow(unused_variables))]
     pub(crate) fn print_runtime(&self, label: &str) {
+        #[cfg(feature = "print_runtime")]
         println!("{}: {} ns", label, self.start_time.elapsed().as_nanos())
     }
 }
 
+/// The GREASE values (RFC 8701) we offer.  These are kept so a
+/// retried ClientHello offers the same ones.
+pub struct Grease {
+    pub cipher_suite: CipherSuite,
+    pub group: NamedGroup,
+    pub sig_scheme: SignatureScheme,
+}
+
 pub struct ClientHelloDetails {
     pub sent_extensions: Vec<ExtensionType>,
     pub offered_key_shares: Vec<suites::KeyExchange>,
+    pub grease: Option<Grease>,
+    pub sent_session_id: SessionID,
 }
 
 impl ClientHelloDetails {
@@ -96,6 +120,8 @@ impl ClientHelloDetails {
         ClientHelloDetails {
             sent_extensions: Vec::new(),
             offered_key_shares: Vec::new(),
+            grease: None,
+            sent_session_id: SessionID::empty(),
         }
     }
 
@@ -162,6 +188,7 @@ pub struct ClientAuthDetails {
     pub signer: Option<Box<dyn sign::Signer>>,
     pub auth_context: Option<Vec<u8>>,
     pub private_key: Option<Vec<u8>>,
+    pub kem_ciphertext_len: Option<usize>,
 }
 
 impl ClientAuthDetails {
@@ -171,6 +198,7 @@ impl ClientAuthDetails {
             signer: None,
             auth_context: None,
             private_key: None,
+            kem_ciphertext_len: None,
         }
     }
 }
@@ -2,6 +2,7 @@ use crate::msgs::enums::SignatureScheme;
 use crate::sign;
 use crate::key;
 use crate::client;
+use crate::x509;
 use crate::error::TLSError;
 
 use std::collections;
@@ -18,6 +19,10 @@ impl client::StoresClientSessions for NoClientSessionStorage {
     fn get(&self, _key: &[u8]) -> Option<Vec<u8>> {
         None
     }
+
+    fn remove(&self, _key: &[u8]) -> bool {
+        true
+    }
 }
 
 /// An implementor of `StoresClientSessions` that stores everything
@@ -62,6 +67,13 @@ impl client::StoresClientSessions for ClientSessionMemoryCache {
             .unwrap()
             .get(key).cloned()
     }
+
+    fn remove(&self, key: &[u8]) -> bool {
+        self.cache.lock()
+            .unwrap()
+            .remove(key);
+        true
+    }
 }
 
 pub struct FailResolveClientCert {}
@@ -103,6 +115,100 @@ impl client::ResolvesClientCert for AlwaysResolvesClientCert {
     }
 }
 
+/// Resolves a single KEM certificate and private key, for KEMTLS
+/// client authentication.
+pub struct AlwaysResolvesClientKemCert(sign::CertifiedKey);
+
+impl AlwaysResolvesClientKemCert {
+    pub fn new(chain: Vec<key::Certificate>,
+               priv_key: &key::PrivateKey) -> Result<AlwaysResolvesClientKemCert, TLSError> {
+        let key = sign::any_kem_type(priv_key)
+            .map_err(|_| TLSError::General("invalid KEM private key".into()))?;
+        Ok(AlwaysResolvesClientKemCert(sign::CertifiedKey::new(chain, Arc::new(key))))
+    }
+}
+
+impl client::ResolvesClientCert for AlwaysResolvesClientKemCert {
+    fn resolve(&self,
+               _acceptable_issuers: &[&[u8]],
+               _sigschemes: &[SignatureScheme])
+               -> Option<sign::CertifiedKey> {
+        None
+    }
+
+    fn resolve_kem(&self,
+                   _acceptable_issuers: &[&[u8]])
+                   -> Option<sign::CertifiedKey> {
+        Some(self.0.clone())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+/// Resolves one of several KEM certificates for KEMTLS client
+/// authentication, choosing the first whose chain was issued by
+/// one of the server's acceptable issuers.
+///
+/// If the server doesn't name any issuers, the first certificate
+/// is used.  If none match, no client certificate is sent.
+pub struct ResolvesClientKemCertUsingIssuers {
+    certs: Vec<sign::CertifiedKey>,
+}
+
+impl ResolvesClientKemCertUsingIssuers {
+    /// Create a new and empty (ie, knows no certificates) resolver.
+    pub fn new() -> ResolvesClientKemCertUsingIssuers {
+        ResolvesClientKemCertUsingIssuers { certs: Vec::new() }
+    }
+
+    /// Add a new KEM certificate chain and private key, in order
+    /// of preference.
+    ///
+    /// `chain` is a vector of DER-encoded certificates, `priv_key`
+    /// is a DER-encoded PKCS#8 KEM private key.
+    pub fn add(&mut self,
+               chain: Vec<key::Certificate>,
+               priv_key: &key::PrivateKey) -> Result<(), TLSError> {
+        let key = sign::any_kem_type(priv_key)
+            .map_err(|_| TLSError::General("invalid KEM private key".into()))?;
+        self.certs.push(sign::CertifiedKey::new(chain, Arc::new(key)));
+        Ok(())
+    }
+
+    fn issued_by_any(certkey: &sign::CertifiedKey,
+                     acceptable_issuers: &[&[u8]]) -> bool {
+        certkey.cert
+            .iter()
+            .filter_map(|cert| x509::certificate_issuer(&cert.0))
+            .any(|issuer| acceptable_issuers.iter().any(|ai| *ai == issuer))
+    }
+}
+
+impl client::ResolvesClientCert for ResolvesClientKemCertUsingIssuers {
+    fn resolve(&self,
+               _acceptable_issuers: &[&[u8]],
+               _sigschemes: &[SignatureScheme])
+               -> Option<sign::CertifiedKey> {
+        None
+    }
+
+    fn resolve_kem(&self,
+                   acceptable_issuers: &[&[u8]])
+                   -> Option<sign::CertifiedKey> {
+        self.certs
+            .iter()
+            .find(|ck| acceptable_issuers.is_empty() ||
+                  Self::issued_by_any(ck, acceptable_issuers))
+            .cloned()
+    }
+
+    fn has_certs(&self) -> bool {
+        !self.certs.is_empty()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -145,6 +251,15 @@ mod test {
         assert_eq!(c.get(&[0x01]), Some(vec![0x04]));
     }
 
+    #[test]
+    fn test_clientsessionmemorycache_removes() {
+        let c = ClientSessionMemoryCache::new(4);
+        assert_eq!(c.put(vec![0x01], vec![0x02]), true);
+        assert_eq!(c.remove(&[0x01]), true);
+        assert_eq!(c.get(&[0x01]), None);
+        assert_eq!(c.remove(&[0x01]), true);
+    }
+
     #[test]
     fn test_clientsessionmemorycache_drops_to_maintain_size_invariant() {
         let c = ClientSessionMemoryCache::new(4);
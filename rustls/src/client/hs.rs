@@ -1,10 +1,12 @@
 use crate::{ALL_CIPHERSUITES, msgs::enums::{ContentType, HandshakeType, ExtensionType}};
 use crate::msgs::enums::{Compression, ProtocolVersion, AlertDescription};
+use crate::msgs::enums::{CipherSuite, NamedGroup, SignatureScheme};
 use crate::msgs::message::{Message, MessagePayload};
 use crate::msgs::base::Payload;
+use crate::msgs::fragmenter;
 use crate::msgs::handshake::{HandshakePayload, HandshakeMessagePayload, ClientHelloPayload};
 use crate::msgs::handshake::{SessionID, Random};
-use crate::msgs::handshake::{ClientExtension, HasServerExtensions};
+use crate::msgs::handshake::{ClientExtension, HasServerExtensions, UnknownExtension};
 use crate::msgs::handshake::{ECPointFormatList, SupportedPointFormats};
 use crate::msgs::handshake::{ProtocolNameList, ConvertProtocolNameList};
 use crate::msgs::handshake::HelloRetryRequest;
@@ -12,25 +14,27 @@ use crate::msgs::handshake::{CertificateStatusRequest, SCTList};
 use crate::msgs::enums::{PSKKeyExchangeMode, ECPointFormat, SignatureAlgorithm};
 use crate::msgs::codec::{Codec, Reader};
 use crate::msgs::persist;
-use crate::client::ClientSessionImpl;
+use crate::client::{ClientSessionImpl, CompatCCSPlacement};
 use crate::session::SessionSecrets;
 use crate::key_schedule::{KeyScheduleEarly, KeyScheduleHandshake};
 use crate::cipher;
 use crate::suites;
+use crate::sign;
 use crate::verify;
 use crate::rand;
+use crate::key;
 use crate::ticketer;
 #[cfg(feature = "logging")]
 use crate::bs_debug;
 #[cfg(feature = "logging")]
 use crate::log::{debug, trace};
 use crate::check::check_message;
-use crate::error::TLSError;
+use crate::error::{TLSError, HandshakeState};
 #[cfg(feature = "quic")]
 use crate::msgs::base::PayloadU16;
 
 use crate::client::common::{ServerCertDetails, HandshakeDetails};
-use crate::client::common::{ClientHelloDetails, ReceivedTicketDetails};
+use crate::client::common::{ClientHelloDetails, ReceivedTicketDetails, Grease};
 use crate::client::{tls12, tls13};
 
 use log::warn;
@@ -55,6 +59,19 @@ pub trait State {
 
     fn perhaps_write_key_update(&mut self, _sess: &mut ClientSessionImpl) {
     }
+
+    fn send_key_update(&mut self,
+                       _sess: &mut ClientSessionImpl,
+                       _update_requested: bool) -> Result<(), TLSError> {
+        Err(TLSError::HandshakeNotComplete)
+    }
+
+    fn transcript_hash(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Which state this is, for `get_failed_state`.
+    fn state(&self) -> HandshakeState;
 }
 
 pub fn illegal_param(sess: &mut ClientSessionImpl, why: &str) -> TLSError {
@@ -62,6 +79,48 @@ pub fn illegal_param(sess: &mut ClientSessionImpl, why: &str) -> TLSError {
     TLSError::PeerMisbehavedError(why.to_string())
 }
 
+/// Where we end up once the peer has sent close_notify.  Plaintext
+/// received before the alert stays readable; nothing may follow it.
+pub struct ExpectClosed {
+    traffic: NextState,
+}
+
+impl ExpectClosed {
+    pub fn new(traffic: NextState) -> NextState {
+        Box::new(ExpectClosed { traffic })
+    }
+}
+
+impl State for ExpectClosed {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::Closed
+    }
+
+    fn handle(self: Box<Self>, _sess: &mut ClientSessionImpl, m: Message) -> NextStateOrError {
+        Err(TLSError::InappropriateMessage {
+            expect_types: vec![],
+            got_type: m.typ,
+        })
+    }
+
+    fn export_keying_material(&self,
+                              output: &mut [u8],
+                              label: &[u8],
+                              context: Option<&[u8]>) -> Result<(), TLSError> {
+        self.traffic.export_keying_material(output, label, context)
+    }
+
+    fn send_key_update(&mut self,
+                       _sess: &mut ClientSessionImpl,
+                       _update_requested: bool) -> Result<(), TLSError> {
+        Err(TLSError::PeerClosed)
+    }
+
+    fn transcript_hash(&self) -> Option<Vec<u8>> {
+        self.traffic.transcript_hash()
+    }
+}
+
 pub fn check_aligned_handshake(sess: &mut ClientSessionImpl) -> Result<(), TLSError> {
     if !sess.common.handshake_joiner.is_empty() {
         sess.common.send_fatal_alert(AlertDescription::UnexpectedMessage);
@@ -86,7 +145,19 @@ fn find_session(sess: &mut ClientSessionImpl, dns_name: webpki::DNSNameRef)
     let value = maybe_value.unwrap();
     let mut reader = Reader::init(&value[..]);
     if let Some(result) = persist::ClientSessionValue::read(&mut reader) {
-        if result.has_expired(ticketer::timebase()) {
+        let now = ticketer::timebase();
+        if result.has_expired(now) {
+            None
+        } else if result.is_from_future(now) {
+            debug!("Cached session for {:?} was issued in the future", dns_name);
+            None
+        } else if !sess.config.supports_version(result.version) {
+            debug!("Cached session for {:?} is for {:?}, which we no longer support",
+                   dns_name, result.version);
+            None
+        } else if !cached_kem_cert_is_current(sess, &result) {
+            debug!("Cached session for {:?} authenticated a KEM certificate we no longer expect",
+                   dns_name);
             None
         } else {
             #[cfg(feature = "quic")] {
@@ -102,6 +173,29 @@ fn find_session(sess: &mut ClientSessionImpl, dns_name: webpki::DNSNameRef)
     }
 }
 
+/// Resuming a KEMTLS session reuses the secret we encapsulated to the
+/// server's certificate, rather than encapsulating again.  That's only
+/// appropriate while the server still has that certificate: if we've
+/// been told which certificates to expect, and the cached one isn't
+/// among them, we do a full KEMTLS handshake instead.
+fn cached_kem_cert_is_current(sess: &ClientSessionImpl,
+                              value: &persist::ClientSessionValue) -> bool {
+    let cached = match value.server_cert_chain.first() {
+        Some(cert) => cert,
+        None => return true,
+    };
+
+    if sess.config.known_certificates.is_empty() {
+        return true;
+    }
+
+    let is_kem_cert = webpki::EndEntityCert::from(&cached.0)
+        .map(|cert| cert.is_kem_cert())
+        .unwrap_or(false);
+
+    !is_kem_cert || sess.config.known_certificates.contains(cached)
+}
+
 fn random_sessionid() -> SessionID {
     let mut random_id = [0u8; 32];
     rand::fill_random(&mut random_id);
@@ -127,10 +221,18 @@ impl InitialState {
         }
     }
 
-    fn emit_initial_client_hello(mut self, sess: &mut ClientSessionImpl) -> NextState {
+    fn emit_initial_client_hello(mut self, sess: &mut ClientSessionImpl) -> NextStateOrError {
         if sess.config.client_auth_cert_resolver.has_certs() {
             self.handshake.transcript.set_client_auth_enabled();
         }
+        #[cfg(feature = "dangerous_configuration")] {
+            if let Some(random) = sess.config.fixed_client_random {
+                self.handshake.randoms.client = random;
+                if !sess.common.is_quic() {
+                    self.handshake.session_id = SessionID::new(&random);
+                }
+            }
+        }
         let hello_details = ClientHelloDetails::new();
         self.handshake.print_runtime("START");
         emit_client_hello_for_retry(sess, self.handshake, hello_details, None)
@@ -139,7 +241,7 @@ impl InitialState {
 
 
 pub fn start_handshake(sess: &mut ClientSessionImpl, host_name: webpki::DNSName,
-                       extra_exts: Vec<ClientExtension>) -> NextState {
+                       extra_exts: Vec<ClientExtension>) -> NextStateOrError {
     InitialState::new(host_name, extra_exts)
         .emit_initial_client_hello(sess)
 }
@@ -170,10 +272,84 @@ pub fn compatible_suite(sess: &ClientSessionImpl,
     }
 }
 
+/// Pick random GREASE values.  These have the form 0x?a?a, with the
+/// same nibble in both bytes.
+fn choose_grease() -> Grease {
+    let mut nibbles = [0u8; 3];
+    rand::fill_random(&mut nibbles);
+    let grease = |nibble: u8| {
+        let nibble = u16::from(nibble & 0x0f);
+        0x0a0a | (nibble << 12) | (nibble << 4)
+    };
+
+    Grease {
+        cipher_suite: CipherSuite::Unknown(grease(nibbles[0])),
+        group: NamedGroup::Unknown(grease(nibbles[1])),
+        sig_scheme: SignatureScheme::Unknown(grease(nibbles[2])),
+    }
+}
+
+/// The length a handshake message must have to take exactly `target`
+/// bytes on the wire, once split into records carrying at most
+/// `max_frag` bytes each.  None if no length does.
+fn handshake_len_for_wire_len(target: usize, max_frag: usize) -> Option<usize> {
+    let record_len = max_frag + fragmenter::PACKET_OVERHEAD;
+    let full_records = target / record_len;
+    match target % record_len {
+        0 => Some(full_records * max_frag),
+        rest if rest > fragmenter::PACKET_OVERHEAD => {
+            Some(full_records * max_frag + rest - fragmenter::PACKET_OVERHEAD)
+        }
+        _ => None,
+    }
+}
+
+/// Add a padding extension to `chp` so its records, of at most
+/// `max_frag` bytes each, are `target` bytes in total.
+/// The padding goes before any PSK extension, which must stay last;
+/// the PSK binder is filled in afterwards and doesn't change the size.
+fn pad_client_hello(chp: &mut HandshakeMessagePayload, target: usize,
+                    max_frag: usize) -> Result<(), TLSError> {
+    const PADDING_EXTENSION_OVERHEAD: usize = 2 + 2;
+
+    let padded = handshake_len_for_wire_len(target, max_frag)
+        .ok_or_else(|| TLSError::General(format!("no ClientHello is exactly {} bytes in records of {} bytes",
+                                                 target, max_frag)))?;
+    let unpadded = chp.get_encoding().len() + PADDING_EXTENSION_OVERHEAD;
+    if unpadded > padded {
+        return Err(TLSError::General(format!("ClientHello of {} bytes cannot be padded to {} bytes",
+                                             unpadded, target)));
+    }
+
+    if let HandshakePayload::ClientHello(ref mut ch) = chp.payload {
+        // The extensions, padding included, must fit their u16 length.
+        let exts_len = ch.extensions.iter()
+            .map(|ext| ext.get_encoding().len())
+            .sum::<usize>();
+        if exts_len + PADDING_EXTENSION_OVERHEAD + padded - unpadded > 0xffff {
+            return Err(TLSError::General(format!("ClientHello of {} bytes cannot be padded to {} bytes",
+                                                 unpadded, target)));
+        }
+
+        let padding = ClientExtension::Unknown(UnknownExtension {
+            typ: ExtensionType::Padding,
+            payload: Payload::new(vec![0u8; padded - unpadded]),
+        });
+
+        let at = match ch.extensions.last() {
+            Some(ClientExtension::PresharedKey(_)) => ch.extensions.len() - 1,
+            _ => ch.extensions.len(),
+        };
+        ch.extensions.insert(at, padding);
+    }
+
+    Ok(())
+}
+
 fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
                                mut handshake: HandshakeDetails,
                                mut hello: ClientHelloDetails,
-                               retryreq: Option<&HelloRetryRequest>) -> NextState {
+                               retryreq: Option<&HelloRetryRequest>) -> NextStateOrError {
     // Do we have a SessionID or ticket cached for this host?
     handshake.resuming_session = find_session(sess, handshake.dns_name.as_ref());
     let (session_id, ticket, resume_version) = if handshake.resuming_session.is_some() {
@@ -182,7 +358,18 @@ fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
             random_sessionid_for_ticket(resuming);
         }
         debug!("Resuming session");
-        (resuming.session_id, resuming.ticket.0.clone(), resuming.version)
+
+        // Keep the same compatibility session id across a HelloRetryRequest.
+        let session_id = if resuming.session_id.is_empty() &&
+            sess.config.always_send_compat_session_id && !sess.common.is_quic() {
+            if handshake.session_id.is_empty() {
+                handshake.session_id = random_sessionid();
+            }
+            handshake.session_id
+        } else {
+            resuming.session_id
+        };
+        (session_id, resuming.ticket.0.clone(), resuming.version)
     } else {
         debug!("Not resuming any session");
         if handshake.session_id.is_empty() && !sess.common.is_quic() {
@@ -190,6 +377,7 @@ fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
         }
         (handshake.session_id, Vec::new(), ProtocolVersion::Unknown(0))
     };
+    hello.sent_session_id = session_id;
 
     let support_tls12 = sess.config.supports_version(ProtocolVersion::TLSv1_2);
     let support_tls13 = sess.config.supports_version(ProtocolVersion::TLSv1_3);
@@ -210,11 +398,53 @@ fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
     if sess.config.enable_sni {
         exts.push(ClientExtension::make_sni(handshake.dns_name.as_ref()));
     }
-    exts.push(ClientExtension::ECPointFormats(ECPointFormatList::supported()));
-    exts.push(ClientExtension::NamedGroups(suites::KeyExchange::supported_groups().to_vec()));
-    exts.push(ClientExtension::SignatureAlgorithms(sess.config.get_verifier().supported_verify_schemes()));
+    // Offer the group the server chose last time first, if we know it.
+    let kx_hint = if support_tls13 {
+        tls13::find_kx_hint(sess, handshake.dns_name.as_ref())
+    } else {
+        None
+    };
+    let mut offered_groups = sess.config.offered_groups();
+    if let Some(pos) = kx_hint.and_then(|hint| offered_groups.iter().position(|g| *g == hint)) {
+        let hinted = offered_groups.remove(pos);
+        offered_groups.insert(0, hinted);
+    }
+
+    // ECPointFormats is only meaningful if we offer an elliptic curve group.
+    if offered_groups.iter().any(|group| suites::KeyExchange::is_ecdh_group(*group)) {
+        exts.push(ClientExtension::ECPointFormats(ECPointFormatList::supported()));
+    }
+    let mut cipher_suites = sess.get_cipher_suites();
+    let mut sig_schemes = sess.config.get_verify_schemes();
+    if sess.config.strict_pq_only {
+        if offered_groups.is_empty() {
+            return Err(TLSError::NoUsableKexGroup);
+        }
+        if sig_schemes.is_empty() {
+            return Err(TLSError::General("strict_pq_only leaves no signature schemes to offer"
+                                         .to_string()));
+        }
+    }
+    if sess.config.enable_grease {
+        let grease = hello.grease.get_or_insert_with(choose_grease);
+        cipher_suites.insert(0, grease.cipher_suite);
+        offered_groups.insert(0, grease.group);
+        sig_schemes.insert(0, grease.sig_scheme);
+    }
+
+    exts.push(ClientExtension::NamedGroups(offered_groups));
+    exts.push(ClientExtension::SignatureAlgorithms(sig_schemes));
+    let cert_schemes = sess.config.cert_signature_schemes.iter()
+        .cloned()
+        .filter(|scheme| sess.config.allows_scheme(*scheme))
+        .collect::<Vec<_>>();
+    if !cert_schemes.is_empty() {
+        exts.push(ClientExtension::SignatureAlgorithmsCert(cert_schemes));
+    }
     exts.push(ClientExtension::ExtendedMasterSecretRequest);
-    exts.push(ClientExtension::CertificateStatusRequest(CertificateStatusRequest::build_ocsp()));
+    if sess.config.request_ocsp_response {
+        exts.push(ClientExtension::CertificateStatusRequest(CertificateStatusRequest::build_ocsp()));
+    }
 
     let mut proactive_static_shared_secret = None;
     if !sess.config.known_certificates.is_empty() {
@@ -235,7 +465,7 @@ fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
     }
 
     if support_tls13 {
-        tls13::choose_kx_groups(sess, &mut exts, &mut hello, &mut handshake, retryreq);
+        tls13::choose_kx_groups(sess, &mut exts, &mut hello, &mut handshake, kx_hint, retryreq)?;
     }
 
     if let Some(cookie) = retryreq.and_then(HelloRetryRequest::get_cookie) {
@@ -249,6 +479,11 @@ fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
         exts.push(ClientExtension::PresharedKeyModes(psk_modes));
     }
 
+    // QUIC doesn't allow post-handshake authentication.
+    if support_tls13 && sess.config.enable_post_handshake_auth && !sess.common.is_quic() {
+        exts.push(ClientExtension::PostHandshakeAuth);
+    }
+
     if !sess.config.alpn_protocols.is_empty() {
         exts.push(ClientExtension::Protocols(ProtocolNameList::from_slices(&sess.config
             .alpn_protocols
@@ -264,8 +499,13 @@ fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
     let fill_in_binder = if support_tls13 && sess.config.enable_tickets &&
                             resume_version == ProtocolVersion::TLSv1_3 &&
                             !ticket.is_empty() {
-        tls13::prepare_resumption(sess, ticket, &handshake, &mut exts,
-                                  retryreq.is_some())
+        let resuming = tls13::prepare_resumption(sess, ticket, &handshake, &mut exts,
+                                                 retryreq.is_some())?;
+        if !resuming {
+            // Fall back to a full handshake.
+            handshake.resuming_session = None;
+        }
+        resuming
     } else if sess.config.enable_tickets {
         // If we have a ticket, include it.  Otherwise, request one.
         if ticket.is_empty() {
@@ -278,6 +518,11 @@ fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
         false
     };
 
+    if retryreq.is_none() {
+        sess.config.session_loaded(handshake.dns_name.as_ref(),
+                                   handshake.resuming_session.is_some());
+    }
+
     // indicate KEMTLS-PDK client auth is coming
     if sess.config.client_auth_cert_resolver.has_certs() && proactive_static_shared_secret.is_some() {
         exts.push(ClientExtension::ProactiveClientAuth);
@@ -294,16 +539,25 @@ fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
             client_version: ProtocolVersion::TLSv1_2,
             random: Random::from_slice(&handshake.randoms.client),
             session_id,
-            cipher_suites: sess.get_cipher_suites(),
+            cipher_suites,
             compression_methods: vec![Compression::Null],
             extensions: exts,
         }),
     };
 
+    if let Some(target) = sess.config.clienthello_pad_to {
+        let max_frag = sess.config.mtu.unwrap_or(fragmenter::MAX_FRAGMENT_LEN);
+        pad_client_hello(&mut chp, target, max_frag)?;
+    }
+
     let early_key_schedule = if fill_in_binder {
-        Some(tls13::fill_in_psk_binder(sess, &mut handshake, &mut chp))
+        Some(tls13::fill_in_psk_binder(sess, &mut handshake, &mut chp)?)
     } else if let Some(ss) = &proactive_static_shared_secret {
-        Some(KeyScheduleEarly::new(ALL_CIPHERSUITES[0].hkdf_algorithm, ss.as_ref()))
+        #[allow(unused_mut)]
+        let mut key_schedule = KeyScheduleEarly::new(ALL_CIPHERSUITES[0].hkdf_algorithm, ss.as_ref());
+        #[cfg(feature = "dangerous_configuration")]
+        key_schedule.set_trace(sess.config.key_schedule_trace.clone());
+        Some(key_schedule)
     } else {
         None
     };
@@ -335,16 +589,19 @@ fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
     handshake.transcript.add_message(&ch);
     sess.common.send_msg(ch, false);
 
+    if support_tls13 && sess.config.compat_ccs_placement == CompatCCSPlacement::AfterClientHello {
+        tls13::emit_fake_ccs(&mut handshake, sess);
+    }
+
     let mut maybe_client_auth = None;
     // Calculate the hash of ClientHello and use it to derive EarlyTrafficSecret
     if sess.early_data.is_enabled() {
         // For middlebox compatibility
         tls13::emit_fake_ccs(&mut handshake, sess);
 
-        // It is safe to call unwrap() because fill_in_binder is true.
-        let resuming_suite = handshake.resuming_session
-            .as_ref()
-            .and_then(|resume| sess.find_cipher_suite(resume.cipher_suite)).unwrap();
+        // Early data is only enabled once we've chosen a suite to resume.
+        let resuming_suite = sess.resumption_ciphersuite
+            .ok_or_else(|| TLSError::General("early data without a resumption suite".to_string()))?;
 
         let client_hello_hash = handshake.transcript.get_hash_given(resuming_suite.get_hash(), &[]);
         let client_early_traffic_secret = early_key_schedule
@@ -371,9 +628,10 @@ fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
             let crt = webpki::EndEntityCert::from(&c.0).unwrap();
             crt.subject().to_vec()
         }).collect::<Vec<_>>();
-        use crate::msgs::enums::SignatureScheme;
         let refissuers = issuers.iter().map(|c| c.as_ref()).collect::<Vec<_>>();
-        let maybe_certkey = sess.config.client_auth_cert_resolver.resolve(&refissuers, include!("../generated/pq_kemschemes.rs"));
+        let resolver = &sess.config.client_auth_cert_resolver;
+        let maybe_certkey = resolver.resolve(&refissuers, sign::supported_kem_schemes())
+            .or_else(|| resolver.resolve_kem(&refissuers));
         if let Some(mut certkey) = maybe_certkey {
             if certkey.key.algorithm() == SignatureAlgorithm::KEMTLS {
                 tls13::emit_fake_ccs(&mut handshake, sess);
@@ -388,6 +646,7 @@ fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
                 debug!("Attempting pdk client auth");
                 let mut client_auth = ClientAuthDetails::new();
                 client_auth.private_key = Some(certkey.key.get_bytes().to_vec());
+                client_auth.kem_ciphertext_len = certkey.key.kem_ciphertext_len();
                 client_auth.cert = Some(certkey.take_cert());
                 client_auth.auth_context = None;
                 tls13::emit_certificate_tls13(&mut handshake, &mut client_auth, sess);
@@ -406,10 +665,12 @@ fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
         client_auth: maybe_client_auth,
     };
 
+    // Only one HelloRetryRequest is allowed: after retrying, anything but
+    // a ServerHello is an unexpected message.
     if support_tls13 && retryreq.is_none() {
-        Box::new(ExpectServerHelloOrHelloRetryRequest(next))
+        Ok(Box::new(ExpectServerHelloOrHelloRetryRequest(next)))
     } else {
-        Box::new(next)
+        Ok(Box::new(next))
     }
 }
 
@@ -485,6 +746,10 @@ impl ExpectServerHello {
 }
 
 impl State for ExpectServerHello {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::ServerHello
+    }
+
     fn handle(mut self: Box<Self>, sess: &mut ClientSessionImpl, m: Message) -> NextStateOrError {
         let server_hello = require_handshake_msg!(m, HandshakeType::ServerHello, HandshakePayload::ServerHello)?;
         trace!("We got ServerHello {:#?}", server_hello);
@@ -533,9 +798,8 @@ impl State for ExpectServerHello {
             return Err(TLSError::PeerMisbehavedError("server sent duplicate extensions".to_string()));
         }
 
-        let allowed_unsolicited = [ ExtensionType::RenegotiationInfo ];
         if self.hello.server_sent_unsolicited_extensions(&server_hello.extensions,
-                                                         &allowed_unsolicited) {
+                                                         &sess.config.allowed_unsolicited_extensions) {
             sess.common.send_fatal_alert(AlertDescription::UnsupportedExtension);
             return Err(TLSError::PeerMisbehavedError("server sent unsolicited extension".to_string()));
         }
@@ -546,8 +810,11 @@ impl State for ExpectServerHello {
         }
 
         // If ECPointFormats extension is supplied by the server, it must contain
-        // Uncompressed.  But it's allowed to be omitted.
-        if let Some(point_fmts) = server_hello.get_ecpoints_extension() {
+        // Uncompressed.  But it's allowed to be omitted.  If we didn't offer any
+        // elliptic curve groups we didn't send it, and an echo has already been
+        // rejected as unsolicited.
+        let sent_ecpoints = self.hello.sent_extensions.contains(&ExtensionType::ECPointFormats);
+        if let Some(point_fmts) = server_hello.get_ecpoints_extension().filter(|_| sent_ecpoints) {
             if !point_fmts.contains(&ECPointFormat::Uncompressed) {
                 sess.common.send_fatal_alert(AlertDescription::HandshakeFailure);
                 return Err(TLSError::PeerMisbehavedError("server does not support uncompressed points"
@@ -582,6 +849,19 @@ impl State for ExpectServerHello {
         // handshake_traffic_secret.
         if sess.common.is_tls13() {
             tls13::validate_server_hello(sess, &server_hello)?;
+
+            // A server choosing TLS1.3 has no business setting the
+            // TLS1.2 downgrade marker.
+            server_hello.random.write_slice(&mut self.handshake.randoms.server);
+            if self.handshake.randoms.has_tls12_downgrade_marker() {
+                return Err(illegal_param(sess, "server chose TLS1.3 with a TLS1.2 downgrade marker"));
+            }
+
+            // TLS1.3 servers echo our legacy_session_id verbatim.
+            if server_hello.session_id != self.hello.sent_session_id {
+                return Err(illegal_param(sess, "server did not echo our session id"));
+            }
+
             let key_schedule = tls13::start_handshake_traffic(sess,
                                                               self.early_key_schedule.take(),
                                                               &server_hello,
@@ -608,6 +888,10 @@ impl State for ExpectServerHello {
         // Doing EMS?
         if server_hello.ems_support_acked() {
             self.handshake.using_ems = true;
+        } else if sess.config.require_ems {
+            sess.common.send_fatal_alert(AlertDescription::HandshakeFailure);
+            return Err(TLSError::PeerIncompatibleError("server does not support extended master secret"
+                                                       .to_string()));
         }
 
         // Might the server send a ticket?
@@ -701,9 +985,15 @@ impl ExpectServerHelloOrHelloRetryRequest {
             return Err(illegal_param(sess, "server requested hrr with our group"));
         }
 
-        // Or asks for us to retry on an unsupported group.
+        // Or asks for us to retry on a group we didn't offer.  We list
+        // some groups (eg. Classic McEliece) that we can't key-share.
         if let Some(group) = req_group {
-            if !suites::KeyExchange::supported_groups().contains(&group) {
+            if !sess.config.allows_group(group) {
+                sess.common.send_fatal_alert(AlertDescription::InsufficientSecurity);
+                return Err(TLSError::PeerIncompatibleError(
+                    format!("server requested hrr with classical group {:?}", group)));
+            }
+            if !sess.config.offered_groups().contains(&group) {
                 return Err(illegal_param(sess, "server requested hrr with bad group"));
             }
         }
@@ -762,14 +1052,18 @@ impl ExpectServerHelloOrHelloRetryRequest {
             sess.early_data.rejected();
         }
 
-        Ok(emit_client_hello_for_retry(sess,
-                                       self.0.handshake,
-                                       self.0.hello,
-                                       Some(&hrr)))
+        emit_client_hello_for_retry(sess,
+                                    self.0.handshake,
+                                    self.0.hello,
+                                    Some(&hrr))
     }
 }
 
 impl State for ExpectServerHelloOrHelloRetryRequest {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::ServerHelloOrHelloRetryRequest
+    }
+
     fn handle(self: Box<Self>, sess: &mut ClientSessionImpl, m: Message) -> NextStateOrError {
         check_message(&m,
                       &[ContentType::Handshake],
@@ -782,6 +1076,55 @@ impl State for ExpectServerHelloOrHelloRetryRequest {
     }
 }
 
+/// Reject `chain` if it's bigger than `max_peer_cert_chain_bytes`.
+pub fn check_cert_chain_size(sess: &mut ClientSessionImpl,
+                             chain: &[key::Certificate]) -> Result<(), TLSError> {
+    let total = chain.iter()
+        .fold(0usize, |total, cert| total.saturating_add(cert.0.len()));
+    if total > sess.config.max_peer_cert_chain_bytes {
+        sess.common.send_fatal_alert(AlertDescription::DecodeError);
+        return Err(TLSError::PeerMisbehavedError("server certificate chain too large".to_string()));
+    }
+
+    Ok(())
+}
+
+/// Reject `chain` if it has a key of an algorithm not allowed by
+/// `cert_chain_key_algorithms`.
+pub fn check_cert_chain_key_algorithms(sess: &mut ClientSessionImpl,
+                                       chain: &[key::Certificate]) -> Result<(), TLSError> {
+    let disallowed = sess.config.cert_chain_key_algorithms
+        .as_ref()
+        .and_then(|policy| policy.first_disallowed(chain));
+
+    if let Some((idx, alg)) = disallowed {
+        sess.common.send_fatal_alert(AlertDescription::UnsupportedCertificate);
+        let alg = match alg {
+            Some(alg) => format!("{:?}", alg),
+            None => "unknown".to_string(),
+        };
+        return Err(TLSError::PeerIncompatibleError(
+            format!("server certificate {} has {} key, which is not allowed", idx, alg)));
+    }
+
+    Ok(())
+}
+
+pub fn send_sct_error_alert(sess: &mut ClientSessionImpl, err: TLSError) -> TLSError {
+    if let TLSError::InvalidSCT(ref sct_err) = err {
+        let desc = match *sct_err {
+            sct::Error::MalformedSCT => AlertDescription::DecodeError,
+            sct::Error::InvalidSignature => AlertDescription::DecryptError,
+            sct::Error::TimestampInFuture => AlertDescription::BadCertificateStatusResponse,
+            sct::Error::UnsupportedSCTVersion => AlertDescription::UnsupportedCertificate,
+            sct::Error::UnknownLog => AlertDescription::UnknownCA,
+        };
+        sess.common.send_fatal_alert(desc);
+    }
+
+    err
+}
+
 pub fn send_cert_error_alert(sess: &mut ClientSessionImpl, err: TLSError) -> TLSError {
     match err {
         TLSError::WebPKIError(webpki::Error::BadDER) => {
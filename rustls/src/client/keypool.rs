@@ -0,0 +1,200 @@
+use crate::msgs::enums::NamedGroup;
+use crate::suites::KeyExchange;
+use crate::log::warn;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+/// How many times the background thread tries to generate a key pair
+/// for a group before giving up on that group.
+const KEYGEN_ATTEMPTS: u32 = 3;
+
+/// A pool of ephemeral key pairs, generated ahead of time on a
+/// background thread, so that key generation for slow KEMs is not
+/// on the critical path of a handshake.
+///
+/// Set `ClientConfig::ephemeral_key_pool` to use one.  When the pool
+/// has a key pair ready for the group a ClientHello offers, it is
+/// removed from the pool and used for that handshake only: a key pair
+/// is never handed out twice.  When none is ready, one is generated
+/// as usual.
+///
+/// Forward secrecy: every handshake still uses its own ephemeral key,
+/// but the private keys sit in memory from when they are generated
+/// until they are used.  An attacker able to read this process'
+/// memory can therefore learn the keys of handshakes that have not
+/// happened yet, as well as those in progress.  Keep `per_group`
+/// small so keys don't wait long.
+pub struct EphemeralKeyPool {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    groups: Vec<NamedGroup>,
+    per_group: usize,
+    state: Mutex<PoolState>,
+    changed: Condvar,
+}
+
+struct PoolState {
+    ready: HashMap<NamedGroup, Vec<KeyExchange>>,
+    failed: Vec<NamedGroup>,
+    closed: bool,
+}
+
+impl EphemeralKeyPool {
+    /// Start a pool which keeps up to `per_group` key pairs ready for
+    /// each of `groups`.  Groups we can't generate keys for, or whose
+    /// public keys don't fit in a key share, are ignored.
+    pub fn new(groups: &[NamedGroup], per_group: usize) -> Arc<EphemeralKeyPool> {
+        let groups = groups.iter()
+            .cloned()
+            .filter(|group| KeyExchange::named_group_to_ecdh_alg(*group).is_some())
+            .filter(|group| KeyExchange::fits_key_share(*group))
+            .collect();
+
+        let shared = Arc::new(Shared {
+            groups,
+            per_group,
+            state: Mutex::new(PoolState {
+                ready: HashMap::new(),
+                failed: Vec::new(),
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        });
+
+        let worker = shared.clone();
+        thread::spawn(move || worker.fill());
+
+        Arc::new(EphemeralKeyPool { shared })
+    }
+
+    /// How many key pairs are ready for `group`.
+    pub fn available(&self, group: NamedGroup) -> usize {
+        self.shared.state.lock()
+            .unwrap()
+            .ready
+            .get(&group)
+            .map_or(0, Vec::len)
+    }
+
+    /// Remove a key pair for `group` from the pool, if one is ready.
+    pub(crate) fn take(&self, group: NamedGroup) -> Option<KeyExchange> {
+        let key = self.shared.state.lock()
+            .unwrap()
+            .ready
+            .get_mut(&group)
+            .and_then(Vec::pop);
+
+        if key.is_some() {
+            self.shared.changed.notify_one();
+        }
+        key
+    }
+}
+
+impl Drop for EphemeralKeyPool {
+    fn drop(&mut self) {
+        self.shared.state.lock()
+            .unwrap()
+            .closed = true;
+        self.shared.changed.notify_one();
+    }
+}
+
+impl Shared {
+    /// The group most in need of another key pair, if any.  Groups
+    /// we've given up on are skipped.
+    fn next_group(&self, state: &PoolState) -> Option<NamedGroup> {
+        self.groups.iter()
+            .cloned()
+            .filter(|group| !state.failed.contains(group))
+            .min_by_key(|group| state.ready.get(group).map_or(0, Vec::len))
+            .filter(|group| state.ready.get(group).map_or(0, Vec::len) < self.per_group)
+    }
+
+    fn fill(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            if state.closed {
+                return;
+            }
+
+            let group = match self.next_group(&state) {
+                Some(group) => group,
+                None => {
+                    state = self.changed.wait(state).unwrap();
+                    continue;
+                }
+            };
+
+            // Don't hold the lock while generating.
+            drop(state);
+            let key = KeyExchange::start_kex_with_retries(group, KEYGEN_ATTEMPTS - 1);
+            state = self.state.lock().unwrap();
+
+            // Keep filling the other groups if this one keeps failing.
+            match key {
+                Some(key) => state.ready.entry(group).or_insert_with(Vec::new).push(key),
+                None => {
+                    warn!("giving up on pre-generating {:?} key pairs", group);
+                    state.failed.push(group);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn wait_for(pool: &EphemeralKeyPool, group: NamedGroup, count: usize) {
+        while pool.available(group) < count {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn pool_fills_to_limit() {
+        let pool = EphemeralKeyPool::new(&[NamedGroup::X25519, NamedGroup::Kyber512], 2);
+        wait_for(&pool, NamedGroup::X25519, 2);
+        wait_for(&pool, NamedGroup::Kyber512, 2);
+        assert_eq!(pool.available(NamedGroup::secp256r1), 0);
+    }
+
+    #[test]
+    fn pool_never_hands_out_a_key_twice() {
+        let group = NamedGroup::Kyber512;
+        let pool = EphemeralKeyPool::new(&[group], 2);
+
+        let mut seen = Vec::new();
+        for _ in 0..8 {
+            wait_for(&pool, group, 1);
+            let key = pool.take(group).unwrap();
+            let pubkey = key.pubkey.as_ref().to_vec();
+            assert!(!seen.contains(&pubkey));
+            seen.push(pubkey);
+        }
+    }
+
+    #[test]
+    fn pool_ignores_oversized_groups() {
+        let oversized = NamedGroup::ClassicMcEliece348864;
+        let pool = EphemeralKeyPool::new(&[oversized, NamedGroup::Kyber512], 2);
+        wait_for(&pool, NamedGroup::Kyber512, 2);
+        assert_eq!(pool.available(oversized), 0);
+        assert_eq!(pool.shared.groups, vec![ NamedGroup::Kyber512 ]);
+    }
+
+    #[test]
+    fn pool_ignores_unsupported_groups() {
+        let pool = EphemeralKeyPool::new(&[NamedGroup::FFDHE2048], 1);
+        assert!(pool.take(NamedGroup::FFDHE2048).is_none());
+        assert!(pool.shared.groups.is_empty());
+    }
+}
@@ -1,20 +1,30 @@
-use crate::msgs::enums::CipherSuite;
-use crate::msgs::enums::{AlertDescription, HandshakeType};
-use crate::session::{Session, SessionCommon, MiddleboxCCS};
+use crate::msgs::enums::{CipherSuite, HashAlgorithm};
+use crate::msgs::enums::{AlertDescription, ExtensionType, HandshakeType};
+use crate::session::{Session, SessionCommon, MiddleboxCCS, ParsedChain};
 use crate::keylog::{KeyLog, NoKeyLog};
+#[cfg(feature = "dangerous_configuration")]
+use crate::keylog::KeyScheduleTrace;
+use crate::msgs::enums::NamedGroup;
+use crate::suites;
 use crate::suites::{SupportedCipherSuite, ALL_CIPHERSUITES};
 use crate::msgs::handshake::CertificatePayload;
-use crate::msgs::enums::SignatureScheme;
+use crate::msgs::handshake::DecomposedSignatureScheme;
+use crate::msgs::enums::{SignatureAlgorithm, SignatureScheme};
 use crate::msgs::enums::{ContentType, ProtocolVersion};
-use crate::msgs::handshake::ClientExtension;
+use crate::msgs::handshake::{ClientExtension, UnknownExtension};
+use crate::msgs::base::Payload;
+use crate::msgs::codec::{Codec, Reader};
+use crate::msgs::persist;
 use crate::msgs::message::Message;
 use crate::verify;
+use crate::verify::KemSecurityLevel;
 use crate::anchors;
+use crate::ticketer;
 use crate::sign;
-use crate::error::TLSError;
+use crate::error::{TLSError, HandshakeState};
 use crate::key;
 #[cfg(feature = "logging")]
-use crate::log::trace;
+use crate::log::{debug, trace};
 
 use std::sync::Arc;
 use std::io::{self, IoSlice};
@@ -31,6 +41,9 @@ mod tls13;
 mod common;
 pub mod handy;
 mod default_group;
+mod keypool;
+
+pub use crate::client::keypool::EphemeralKeyPool;
 
 /// A trait for the ability to store client session data.
 /// The keys and values are opaque.
@@ -51,6 +64,15 @@ pub trait StoresClientSessions : Send + Sync {
     /// Returns the latest value for `key`.  Returns `None`
     /// if there's no such value.
     fn get(&self, key: &[u8]) -> Option<Vec<u8>>;
+
+    /// Removes any value stored for `key`.  Returns `true`
+    /// if the value is gone afterwards.
+    ///
+    /// The default implementation overwrites the value with an
+    /// empty one, which rustls treats as absent.
+    fn remove(&self, key: &[u8]) -> bool {
+        self.put(key.to_vec(), Vec::new())
+    }
 }
 
 /// A trait for the ability to choose a certificate chain and
@@ -72,6 +94,19 @@ pub trait ResolvesClientCert : Send + Sync {
                sigschemes: &[SignatureScheme])
                -> Option<sign::CertifiedKey>;
 
+    /// With the server-supplied acceptable issuers in `acceptable_issuers`,
+    /// return a KEM certificate chain and KEM private key to authenticate
+    /// with KEMTLS.  The key is used to decapsulate the server's ciphertext
+    /// rather than to sign.
+    ///
+    /// This is consulted when `resolve` returns None and the server
+    /// accepts KEM authentication.  The default returns None.
+    fn resolve_kem(&self,
+                   _acceptable_issuers: &[&[u8]])
+                   -> Option<sign::CertifiedKey> {
+        None
+    }
+
     /// Return true if any certificates at all are available.
     fn has_certs(&self) -> bool;
 }
@@ -109,6 +144,16 @@ pub struct ClientConfig {
     /// The default is true.
     pub enable_tickets: bool,
 
+    /// Whether to send a random 32-byte legacy_session_id when
+    /// resuming a TLS1.3 ticket, as we do in every full handshake,
+    /// so middleboxes see the same shape of ClientHello either way
+    /// (RFC8446 appendix D.4).  Sessions saved from TLS1.3 have no
+    /// session id of their own, so otherwise we send an empty one.
+    /// QUIC never sends a session id.
+    ///
+    /// The default is false.
+    pub always_send_compat_session_id: bool,
+
     /// Supported versions, in no particular order.  The default
     /// is all supported versions.
     pub versions: Vec<ProtocolVersion>,
@@ -118,12 +163,40 @@ pub struct ClientConfig {
     /// checking is disabled.
     pub ct_logs: Option<&'static [&'static sct::Log<'static>]>,
 
+    /// Whether to ask the server to staple an OCSP response to its
+    /// certificate.  Without a request, a stapled response is
+    /// rejected with an unsupported_extension alert, unless
+    /// `allowed_unsolicited_extensions` has `ExtensionType::StatusRequest`,
+    /// in which case it is ignored.
+    ///
+    /// The default is true.
+    pub request_ocsp_response: bool,
+
     /// Whether to send the Server Name Indication (SNI) extension
     /// during the client handshake.
     ///
     /// The default is true.
     pub enable_sni: bool,
 
+    /// Whether to check the server's certificate is valid for the
+    /// name given to `ClientSession::new`.  Turning this off is for
+    /// servers without a DNS name, such as those addressed by IP:
+    /// the name is then only used to look up cached sessions (and for
+    /// SNI, unless `enable_sni` is false), and the certificate chain is
+    /// still verified.  Use `kem_spki_pins` to check which server it
+    /// is instead.
+    ///
+    /// The default is true.
+    pub verify_server_name: bool,
+
+    /// SHA-256 hashes of KEM SubjectPublicKeyInfos, as made by
+    /// `kem_spki_pin`.  If not empty, the server's certificate must
+    /// hold one of these KEM public keys, as well as passing the usual
+    /// verification.
+    ///
+    /// The default is empty.
+    pub kem_spki_pins: Vec<[u8; 32]>,
+
     /// How to verify the server certificate chain.
     verifier: Arc<dyn verify::ServerCertVerifier>,
 
@@ -139,6 +212,210 @@ pub struct ClientConfig {
 
     /// RFC 7924
     pub known_certificates: Vec<key::Certificate>,
+
+    /// Whether to remember which key exchange group each server chose,
+    /// and offer that group first (and as our key share) next time.
+    /// This avoids a HelloRetryRequest on later connections.
+    ///
+    /// The default is true.
+    pub enable_kx_hints: bool,
+
+    /// How the server may authenticate: with a KEM certificate
+    /// (KEMTLS), or with a signature.  A server authenticating in a
+    /// way this rules out is rejected.
+    ///
+    /// The default is `AuthModePreference::PreferSigned`.
+    pub auth_mode: AuthModePreference,
+
+    /// The lowest security level we accept for the KEM of a server's
+    /// KEMTLS certificate.  A server presenting a weaker one (or one
+    /// whose level we don't know) is rejected with an
+    /// `insufficient_security` alert.  This has no bearing on the
+    /// key exchange group.
+    ///
+    /// The default is `KemSecurityLevel::Level1`, which accepts any.
+    pub min_auth_kem_level: KemSecurityLevel,
+
+    /// An approximate cap on the expensive work a server can make us do.
+    /// Verifying each certificate in the server's chain, verifying a
+    /// signature, performing a key exchange or KEM operation, and
+    /// processing a KeyUpdate each cost one unit.  If the total exceeds
+    /// this limit, the connection fails with `PeerMisbehavedError`.
+    ///
+    /// The default is None, meaning no limit.
+    pub max_work_units: Option<usize>,
+
+    /// The most certificate data, in bytes, we accept from a server.
+    /// This counts the DER encoding of each certificate in its chain.
+    /// Larger chains are rejected with a decode_error alert.
+    ///
+    /// Chains of post-quantum certificates can be very large, so the
+    /// default is a generous 8MB.
+    pub max_peer_cert_chain_bytes: usize,
+
+    /// Extensions we tolerate in a ServerHello or EncryptedExtensions
+    /// even though we didn't offer them.  Any other unsolicited
+    /// extension is rejected with an unsupported_extension alert.
+    ///
+    /// This is useful with servers that send GREASE or experimental
+    /// extensions.  The default is just RenegotiationInfo.
+    pub allowed_unsolicited_extensions: Vec<ExtensionType>,
+
+    /// Where to take pre-generated key pairs for our key shares from.
+    /// See `EphemeralKeyPool` for what this means for forward secrecy.
+    ///
+    /// The default is None: each key pair is generated when needed.
+    pub ephemeral_key_pool: Option<Arc<EphemeralKeyPool>>,
+
+    /// Whether to offer post-handshake client authentication, so a
+    /// TLS1.3 or KEMTLS server may ask for our certificate once the
+    /// handshake is complete.  We reply using
+    /// `client_auth_cert_resolver`: with a signature, or for a KEM
+    /// certificate by proving we can decapsulate the server's
+    /// ciphertext.
+    ///
+    /// The default is false.
+    pub enable_post_handshake_auth: bool,
+
+    /// Whether to add a random GREASE value (RFC 8701) to the cipher
+    /// suites, supported groups and signature algorithms we offer, so
+    /// servers that choke on unknown values are found early.  A server
+    /// selecting one of these values is rejected.
+    ///
+    /// The default is false.
+    pub enable_grease: bool,
+
+    /// Never offer a classical key exchange group or signature scheme.
+    /// Only post-quantum KEMs and the hybrid groups are offered for key
+    /// exchange, and only post-quantum signature schemes and KEMTLS
+    /// authentication are advertised.  TLS1.2 is not offered at all, and
+    /// a server asking us to retry with a classical group is refused.
+    ///
+    /// The certificate chain itself is not constrained by this; see
+    /// `cert_signature_schemes` for that.
+    ///
+    /// The default is false.
+    pub strict_pq_only: bool,
+
+    /// How many more times to try generating a KEM key share if the
+    /// KEM backend fails, before giving up on that group.  Some
+    /// hardware or FFI backends fail occasionally.
+    ///
+    /// The default is 0: a failed key generation isn't retried.
+    pub kem_keygen_retries: u32,
+
+    /// Pad our ClientHello with a padding extension (RFC 7685) so that
+    /// it is exactly this many bytes long on the wire, including the
+    /// 5 byte header of each record it is sent in.  This hides which
+    /// (and how large a) key share we offer.  A ClientHello longer than
+    /// the maximum fragment size (see `set_mtu`) is split across records,
+    /// each with its own header.
+    ///
+    /// A ClientHello which can't be padded to this size fails the
+    /// handshake.  That is so if it is already too long even without
+    /// padding, if the padding would make its extensions too long, or
+    /// if the size would leave a record with only a header.
+    ///
+    /// The default is None, meaning no padding.
+    pub clienthello_pad_to: Option<usize>,
+
+    /// The largest server signature we're prepared to receive, in bytes.
+    /// Some post-quantum signatures are very large; a server sending a
+    /// larger signature than this in its CertificateVerify is rejected
+    /// without verifying it.
+    ///
+    /// The default is None, meaning no limit.
+    pub max_signature_size: Option<usize>,
+
+    /// Where we send the dummy ChangeCipherSpec used for middlebox
+    /// compatibility in TLS1.3 and KEMTLS.  Some research implementations
+    /// of KEMTLS expect a particular placement.
+    ///
+    /// The default is `CompatCCSPlacement::BeforeSecondFlight`.
+    pub compat_ccs_placement: CompatCCSPlacement,
+
+    /// Whether to refuse servers which don't support the Extended Master
+    /// Secret extension (RFC7627).  We always offer it.
+    ///
+    /// This only affects TLS1.2: TLS1.3 and KEMTLS always bind the
+    /// master secret to the handshake transcript.
+    ///
+    /// The default is false.
+    pub require_ems: bool,
+
+    /// How many TLS1.3 session tickets we store from each connection.
+    /// Further tickets the server sends are ignored, so a server can't
+    /// flood the `session_persistence` store.
+    ///
+    /// The default is 8.
+    pub max_tickets_stored: usize,
+
+    /// Called with each handshake message we send, as the handshake
+    /// message type and its encoding (including the four byte handshake
+    /// header, but not the record header).  This is for inspecting the
+    /// wire format of extensions; it has no effect on the handshake.
+    ///
+    /// By default this sees only messages sent in plaintext, such as
+    /// ClientHello and (in TLS1.2) ClientKeyExchange.
+    ///
+    /// The default is None.
+    pub on_outgoing_handshake: Option<Arc<dyn Fn(HandshakeType, &[u8]) + Send + Sync>>,
+
+    /// Whether `on_outgoing_handshake` is also called for handshake
+    /// messages which are sent encrypted.  These include client
+    /// certificates and KEMTLS ciphertexts, so take care with where
+    /// they are recorded.
+    ///
+    /// The default is false.
+    pub on_outgoing_handshake_encrypted: bool,
+
+    /// Called with the server's name whenever we store a session (or
+    /// TLS1.3 ticket) for it in `session_persistence`.
+    ///
+    /// The default is None.
+    pub on_session_saved: Option<Arc<dyn Fn(&str) + Send + Sync>>,
+
+    /// Called once per handshake, when we send our first ClientHello,
+    /// with the server's name and whether we found a usable cached
+    /// session for it and are offering to resume it.  Together with
+    /// `on_session_saved`, this is enough to track cache hit rates.
+    ///
+    /// The default is None.
+    pub on_session_loaded: Option<Arc<dyn Fn(&str, bool) + Send + Sync>>,
+
+    /// The signature schemes we advertise, if not all those our verifier
+    /// supports.  See `set_signature_schemes`.
+    verify_schemes: Option<Vec<SignatureScheme>>,
+
+    /// Signature schemes we accept in the server's certificate chain,
+    /// sent in a signature_algorithms_cert extension.  This lets us
+    /// accept (say) Falcon-signed certificates while only accepting
+    /// Dilithium in CertificateVerify.
+    ///
+    /// If this is empty, no extension is sent and certificates are
+    /// checked as usual.  The default is empty.
+    pub cert_signature_schemes: Vec<SignatureScheme>,
+
+    /// Public key algorithms allowed in the server's certificate chain,
+    /// checked for every certificate the server sends in a TLS1.3 or
+    /// KEMTLS handshake.  A chain with a key of any other algorithm is
+    /// refused, before it is verified.
+    ///
+    /// The default is None, meaning any algorithm we can verify.
+    pub cert_chain_key_algorithms: Option<CertChainKeyAlgorithms>,
+
+    /// Where to send a trace of key schedule derivations, if anywhere.
+    #[cfg(feature = "dangerous_configuration")]
+    key_schedule_trace: Option<Arc<dyn KeyScheduleTrace>>,
+
+    /// Key pairs to offer instead of fresh ones: group, public key,
+    /// private key.
+    #[cfg(feature = "dangerous_configuration")]
+    fixed_key_shares: Vec<(NamedGroup, Vec<u8>, Vec<u8>)>,
+
+    /// Client random to use instead of a fresh one.
+    #[cfg(feature = "dangerous_configuration")]
+    fixed_client_random: Option<[u8; 32]>,
 }
 
 impl Default for ClientConfig {
@@ -169,13 +446,46 @@ impl ClientConfig {
             mtu: None,
             client_auth_cert_resolver: Arc::new(handy::FailResolveClientCert {}),
             enable_tickets: true,
+            always_send_compat_session_id: false,
             versions: vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
             ct_logs: None,
+            request_ocsp_response: true,
             enable_sni: true,
+            verify_server_name: true,
+            kem_spki_pins: Vec::new(),
             verifier: Arc::new(verify::WebPKIVerifier::new()),
             key_log: Arc::new(NoKeyLog {}),
             enable_early_data: false,
             known_certificates: Vec::new(),
+            enable_kx_hints: true,
+            auth_mode: AuthModePreference::PreferSigned,
+            min_auth_kem_level: KemSecurityLevel::Level1,
+            max_work_units: None,
+            max_peer_cert_chain_bytes: 8 * 1024 * 1024,
+            allowed_unsolicited_extensions: vec![ExtensionType::RenegotiationInfo],
+            ephemeral_key_pool: None,
+            enable_post_handshake_auth: false,
+            enable_grease: false,
+            strict_pq_only: false,
+            kem_keygen_retries: 0,
+            clienthello_pad_to: None,
+            max_signature_size: None,
+            compat_ccs_placement: CompatCCSPlacement::BeforeSecondFlight,
+            require_ems: false,
+            max_tickets_stored: 8,
+            on_outgoing_handshake: None,
+            on_outgoing_handshake_encrypted: false,
+            on_session_saved: None,
+            on_session_loaded: None,
+            verify_schemes: None,
+            cert_signature_schemes: Vec::new(),
+            cert_chain_key_algorithms: None,
+            #[cfg(feature = "dangerous_configuration")]
+            key_schedule_trace: None,
+            #[cfg(feature = "dangerous_configuration")]
+            fixed_key_shares: Vec::new(),
+            #[cfg(feature = "dangerous_configuration")]
+            fixed_client_random: None,
         }
     }
 
@@ -184,14 +494,126 @@ impl ClientConfig {
     /// versions *and* at least one ciphersuite for this version is
     /// also configured.
     pub fn supports_version(&self, v: ProtocolVersion) -> bool {
+        if self.strict_pq_only && v != ProtocolVersion::TLSv1_3 {
+            return false;
+        }
+
         self.versions.contains(&v) && self.ciphersuites.iter().any(|cs| cs.usable_for_version(v))
     }
 
+    /// May we offer or accept `group`?  See `strict_pq_only`.
+    pub(crate) fn allows_group(&self, group: NamedGroup) -> bool {
+        !self.strict_pq_only || !suites::KeyExchange::is_ecdh_group(group)
+    }
+
+    /// May we offer or accept `scheme`?  See `strict_pq_only`.
+    pub(crate) fn allows_scheme(&self, scheme: SignatureScheme) -> bool {
+        if !self.strict_pq_only {
+            return true;
+        }
+
+        match scheme.sign() {
+            SignatureAlgorithm::RSA | SignatureAlgorithm::ECDSA |
+                SignatureAlgorithm::ED25519 | SignatureAlgorithm::ED448 => false,
+            _ => true,
+        }
+    }
+
+    /// The key exchange groups we offer, in preference order.  Groups
+    /// whose public keys are too big for a key share are left out:
+    /// we could never reply to a HelloRetryRequest for them.
+    pub(crate) fn offered_groups(&self) -> Vec<NamedGroup> {
+        suites::KeyExchange::supported_groups()
+            .iter()
+            .cloned()
+            .filter(|group| self.allows_group(*group))
+            .filter(|group| suites::KeyExchange::fits_key_share(*group))
+            .collect()
+    }
+
     #[doc(hidden)]
     pub fn get_verifier(&self) -> &dyn verify::ServerCertVerifier {
         self.verifier.as_ref()
     }
 
+    /// Advertise only `schemes` in the signature_algorithms extension,
+    /// in this order, rather than every scheme our verifier supports.
+    /// Servers signing with any other scheme are rejected.
+    ///
+    /// The post-quantum scheme list is long, so this can make the
+    /// ClientHello considerably smaller.  Every scheme must be supported
+    /// by the verifier; otherwise an error is returned and the setting
+    /// is unchanged.
+    pub fn set_signature_schemes(&mut self, schemes: &[SignatureScheme]) -> Result<(), TLSError> {
+        if schemes.is_empty() {
+            return Err(TLSError::General("no signature schemes given".to_string()));
+        }
+
+        let supported = self.get_verifier().supported_verify_schemes();
+        if let Some(scheme) = schemes.iter().find(|scheme| !supported.contains(scheme)) {
+            return Err(TLSError::General(format!("unsupported signature scheme {:?}", scheme)));
+        }
+
+        self.verify_schemes = Some(schemes.to_vec());
+        Ok(())
+    }
+
+    /// Verify the server's certificate chain, honouring
+    /// `cert_signature_schemes`, `verify_server_name` and
+    /// `kem_spki_pins`.
+    pub(crate) fn verify_server_cert(&self,
+                                     presented_certs: &[key::Certificate],
+                                     dns_name: webpki::DNSNameRef,
+                                     ocsp_response: &[u8])
+                                     -> Result<verify::ServerCertVerified, TLSError> {
+        let verified = if !self.verify_server_name {
+            self.get_verifier()
+                .verify_server_cert_without_name(&self.root_store, presented_certs, ocsp_response,
+                                                 &self.cert_signature_schemes)
+        } else if self.cert_signature_schemes.is_empty() {
+            self.get_verifier()
+                .verify_server_cert(&self.root_store, presented_certs, dns_name, ocsp_response)
+        } else {
+            self.get_verifier()
+                .verify_server_cert_signed_with(&self.root_store, presented_certs, dns_name,
+                                                ocsp_response, &self.cert_signature_schemes)
+        }?;
+
+        if !self.kem_spki_pins.is_empty() {
+            let pin = presented_certs.first().and_then(verify::kem_spki_pin);
+            if !pin.map_or(false, |pin| self.kem_spki_pins.contains(&pin)) {
+                return Err(TLSError::General("server certificate key is not pinned".to_string()));
+            }
+        }
+
+        Ok(verified)
+    }
+
+    /// The signature schemes we advertise, and accept from servers.
+    pub(crate) fn get_verify_schemes(&self) -> Vec<SignatureScheme> {
+        let supported = self.get_verifier().supported_verify_schemes();
+        let schemes: Vec<SignatureScheme> = match self.verify_schemes {
+            Some(ref schemes) => schemes.iter()
+                .filter(|scheme| supported.contains(scheme))
+                .cloned()
+                .collect(),
+            None => supported,
+        };
+        let schemes: Vec<SignatureScheme> = schemes.into_iter()
+            .filter(|scheme| self.allows_scheme(*scheme))
+            .collect();
+
+        // List the schemes for the kind of authentication we prefer first.
+        let kem_schemes = sign::supported_kem_schemes();
+        let (kem, signed): (Vec<_>, Vec<_>) = schemes.into_iter()
+            .partition(|scheme| kem_schemes.contains(scheme));
+        if self.auth_mode.prefers_kem() {
+            kem.into_iter().chain(signed).collect()
+        } else {
+            signed.into_iter().chain(kem).collect()
+        }
+    }
+
     /// Set the ALPN protocol list to the given protocol names.
     /// Overwrites any existing configured protocols.
     /// The first element in the `protocols` list is the most
@@ -201,11 +623,114 @@ impl ClientConfig {
         self.alpn_protocols.extend_from_slice(protocols);
     }
 
+    /// Restricts the ciphersuites we offer to `suites`, in exactly
+    /// this order.  This order is the one sent in the ClientHello.
+    ///
+    /// Each suite must be one rustls supports.  An error is returned,
+    /// and the config left unchanged, if `suites` is empty, names an
+    /// unsupported suite, or names a suite twice.
+    pub fn set_ciphersuites(&mut self, suites: &[CipherSuite]) -> Result<(), TLSError> {
+        if suites.is_empty() {
+            return Err(TLSError::General("no ciphersuites given".to_string()));
+        }
+
+        let mut ciphersuites = Vec::new();
+        for suite in suites {
+            let scs = ALL_CIPHERSUITES.iter()
+                .find(|scs| scs.suite == *suite)
+                .ok_or_else(|| TLSError::General(format!("ciphersuite {:?} is not supported", suite)))?;
+
+            if ciphersuites.iter().any(|cs: &&SupportedCipherSuite| cs.suite == *suite) {
+                return Err(TLSError::General(format!("ciphersuite {:?} given twice", suite)));
+            }
+
+            ciphersuites.push(*scs);
+        }
+
+        self.ciphersuites = ciphersuites;
+        Ok(())
+    }
+
+    /// Restricts the ciphersuites we offer to those whose hash is
+    /// `hash`, keeping their order.  This fixes the hash used by the key
+    /// schedule; see `ClientSession::negotiated_suite_hash`.
+    ///
+    /// An error is returned, and the config left unchanged, if no
+    /// configured suite uses `hash`.
+    pub fn restrict_suite_hash(&mut self, hash: HashAlgorithm) -> Result<(), TLSError> {
+        if !self.ciphersuites.iter().any(|cs| cs.hash == hash) {
+            return Err(TLSError::General(format!("no ciphersuite uses {:?}", hash)));
+        }
+
+        self.ciphersuites.retain(|cs| cs.hash == hash);
+        Ok(())
+    }
+
     /// Sets persistence layer to `persist`.
     pub fn set_persistence(&mut self, persist: Arc<dyn StoresClientSessions>) {
         self.session_persistence = persist;
     }
 
+    /// How many bytes of early data the server for `dns_name` said it
+    /// would accept, according to our cached session for it.
+    ///
+    /// This only consults the persistence layer; no handshake is started.
+    /// Returns None if there is no usable cached session for `dns_name`.
+    /// A cached session whose ticket does not allow early data gives
+    /// `Some(0)`.
+    pub fn max_early_data_size_for(&self, dns_name: &str) -> Option<u32> {
+        let dns_name = webpki::DNSNameRef::try_from_ascii_str(dns_name).ok()?;
+        let key = persist::ClientSessionKey::session_for_dns_name(dns_name);
+        let value = self.session_persistence.get(&key.get_encoding())?;
+        let value = persist::ClientSessionValue::read(&mut Reader::init(&value))?;
+
+        let now = ticketer::timebase();
+        if value.has_expired(now) || value.is_from_future(now) ||
+            !self.supports_version(value.version) {
+            return None;
+        }
+
+        Some(value.max_early_data_size)
+    }
+
+    /// The server KEM public key stored for `dns_name` by
+    /// `ClientSession::cache_server_kem_pubkey`, as a DER-encoded
+    /// SubjectPublicKeyInfo.
+    ///
+    /// This only consults the persistence layer.  Returns None if
+    /// nothing is stored for `dns_name`.
+    pub fn cached_server_kem_pubkey(&self, dns_name: &str) -> Option<Vec<u8>> {
+        let dns_name = webpki::DNSNameRef::try_from_ascii_str(dns_name).ok()?;
+        let key = persist::ClientSessionKey::kem_pubkey_for_dns_name(dns_name);
+        self.session_persistence.get(&key.get_encoding())
+            .filter(|spki| !spki.is_empty())
+    }
+
+    /// Forgets the key exchange hint cached for `dns_name`, so the next
+    /// handshake with it starts from the default group again.
+    ///
+    /// Returns `true` if the persistence layer dropped the hint.
+    pub fn forget_kx_hint(&self, dns_name: &str) -> bool {
+        let dns_name = match webpki::DNSNameRef::try_from_ascii_str(dns_name) {
+            Ok(dns_name) => dns_name,
+            Err(_) => return false,
+        };
+        let key = persist::ClientSessionKey::hint_for_dns_name(dns_name);
+        self.session_persistence.remove(&key.get_encoding())
+    }
+
+    pub(crate) fn session_saved(&self, dns_name: webpki::DNSNameRef) {
+        if let Some(callback) = &self.on_session_saved {
+            callback(dns_name.into());
+        }
+    }
+
+    pub(crate) fn session_loaded(&self, dns_name: webpki::DNSNameRef, resuming: bool) {
+        if let Some(callback) = &self.on_session_loaded {
+            callback(dns_name.into(), resuming);
+        }
+    }
+
     /// Sets MTU to `mtu`.  If None, the default is used.
     /// If Some(x) then x must be greater than 5 bytes.
     pub fn set_mtu(&mut self, mtu: &Option<usize>) {
@@ -236,6 +761,20 @@ impl ClientConfig {
         Ok(())
     }
 
+    /// Sets a single KEM certificate and private key for KEMTLS client
+    /// authentication.  This is blindly used for all servers that ask
+    /// for client auth.
+    ///
+    /// `cert_chain` is a vector of DER-encoded certificates,
+    /// `key_der` is a DER-encoded PKCS#8 KEM private key.
+    pub fn set_single_client_kem_cert(&mut self,
+                                      cert_chain: Vec<key::Certificate>,
+                                      key_der: key::PrivateKey) -> Result<(), TLSError> {
+        let resolver = handy::AlwaysResolvesClientKemCert::new(cert_chain, &key_der)?;
+        self.client_auth_cert_resolver = Arc::new(resolver);
+        Ok(())
+    }
+
     /// Access configuration options whose use is dangerous and requires
     /// extra care.
     #[cfg(feature = "dangerous_configuration")]
@@ -251,6 +790,10 @@ pub mod danger {
 
     use super::ClientConfig;
     use super::verify::ServerCertVerifier;
+    use super::KeyScheduleTrace;
+    use crate::error::TLSError;
+    use crate::msgs::enums::NamedGroup;
+    use crate::suites::KeyExchange;
 
     /// Accessor for dangerous configuration options.
     pub struct DangerousClientConfig<'a> {
@@ -264,9 +807,168 @@ pub mod danger {
                                         verifier: Arc<dyn ServerCertVerifier>) {
             self.cfg.verifier = verifier;
         }
+
+        /// Sends every secret derived by the TLS1.3 key schedule, along
+        /// with its label and transcript hash, to `trace`.
+        pub fn set_key_schedule_trace(&mut self,
+                                      trace: Arc<dyn KeyScheduleTrace>) {
+            self.cfg.key_schedule_trace = Some(trace);
+        }
+
+        /// Offer this key pair whenever we send a key share for
+        /// `group`, instead of generating a fresh one.
+        ///
+        /// This is for testing against servers with fixed key shares.
+        /// It gives up forward secrecy entirely: every connection
+        /// making use of it can be decrypted by anyone who learns
+        /// `private_key`.  Only KEM groups are supported.
+        pub fn set_fixed_key_share(&mut self,
+                                   group: NamedGroup,
+                                   public_key: &[u8],
+                                   private_key: &[u8]) -> Result<(), TLSError> {
+            if KeyExchange::from_kem_keypair(group, public_key, private_key).is_none() {
+                return Err(TLSError::General(format!("invalid fixed key share for {:?}", group)));
+            }
+
+            self.cfg.fixed_key_shares.retain(|(g, _, _)| *g != group);
+            self.cfg.fixed_key_shares.push((group, public_key.to_vec(), private_key.to_vec()));
+            Ok(())
+        }
+
+        /// Use `random` as the client random of every handshake, instead
+        /// of fresh randomness.  The legacy session ID we would otherwise
+        /// randomise for middlebox compatibility is set to it too, so that
+        /// together with `set_fixed_key_share` the ClientHello is
+        /// reproducible byte for byte.
+        ///
+        /// This is for testing only, such as reproducing known-answer
+        /// handshakes.  It must never be used in production: a repeated
+        /// random undermines the freshness of every handshake.
+        pub fn set_fixed_client_random(&mut self, random: [u8; 32]) {
+            self.cfg.fixed_client_random = Some(random);
+        }
+    }
+}
+
+/// Where the client sends its dummy ChangeCipherSpec message,
+/// as allowed by RFC8446 appendix D.4.
+///
+/// In both cases the client's final flight is, in order:
+///
+/// - TLS1.3: [EndOfEarlyData], [Certificate, CertificateVerify], Finished.
+/// - KEMTLS: ClientKemCiphertext, [Certificate], then once the server's
+///   ciphertext arrives, Finished.
+///
+/// where bracketed messages are only sent when needed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompatCCSPlacement {
+    /// Immediately before the client's second flight.
+    BeforeSecondFlight,
+
+    /// Immediately after the first ClientHello.
+    AfterClientHello,
+}
+
+/// Which public key algorithms the server's certificates may use, by
+/// position in the chain.  KEM keys of any kind are `KEMTLS`.
+///
+/// For example, to allow classical intermediates while requiring a
+/// Dilithium end-entity certificate:
+///
+/// ```
+/// # use rustls::CertChainKeyAlgorithms;
+/// # use rustls::internal::msgs::enums::SignatureAlgorithm;
+/// let policy = CertChainKeyAlgorithms {
+///     end_entity: vec![ SignatureAlgorithm::DILITHIUM2 ],
+///     intermediates: vec![ SignatureAlgorithm::RSA, SignatureAlgorithm::DILITHIUM2 ],
+/// };
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct CertChainKeyAlgorithms {
+    /// Algorithms allowed for the end-entity certificate.
+    pub end_entity: Vec<SignatureAlgorithm>,
+
+    /// Algorithms allowed for every other certificate the server
+    /// sends, including a root if it sends one.
+    pub intermediates: Vec<SignatureAlgorithm>,
+}
+
+impl CertChainKeyAlgorithms {
+    /// The first certificate in `chain` whose key algorithm isn't
+    /// allowed, with its position and algorithm (None if unknown).
+    pub(crate) fn first_disallowed(&self, chain: &[key::Certificate])
+                                   -> Option<(usize, Option<SignatureAlgorithm>)> {
+        chain.iter()
+            .enumerate()
+            .map(|(idx, cert)| (idx, verify::cert_key_algorithm(cert)))
+            .find(|&(idx, alg)| {
+                let allowed = if idx == 0 { &self.end_entity } else { &self.intermediates };
+                alg.map_or(true, |alg| !allowed.contains(&alg))
+            })
     }
 }
 
+/// Which ways of authenticating the server a client accepts, and
+/// which it prefers.
+///
+/// The server chooses how to authenticate.  A preference only orders
+/// the client's signature_algorithms extension, which the server
+/// may consult; a requirement also aborts the handshake if the
+/// server authenticates the other way.
+///
+/// KEMTLS needs TLS1.3, so `RequireKem` rejects all TLS1.2 servers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthModePreference {
+    /// Accept either, listing KEM schemes first.
+    PreferKem,
+
+    /// Accept either, listing signature schemes first.
+    PreferSigned,
+
+    /// Only accept a server presenting a KEM certificate.
+    RequireKem,
+
+    /// Only accept a server authenticating with a signature.
+    RequireSigned,
+}
+
+impl AuthModePreference {
+    fn prefers_kem(self) -> bool {
+        match self {
+            AuthModePreference::PreferKem | AuthModePreference::RequireKem => true,
+            AuthModePreference::PreferSigned | AuthModePreference::RequireSigned => false,
+        }
+    }
+
+    /// Whether to proceed with a server presenting a KEM certificate
+    /// if `kem` is true, or one authenticating with a signature if not.
+    pub fn accepts(self, kem: bool) -> bool {
+        match self {
+            AuthModePreference::PreferKem | AuthModePreference::PreferSigned => true,
+            AuthModePreference::RequireKem => kem,
+            AuthModePreference::RequireSigned => !kem,
+        }
+    }
+}
+
+/// Whether a stored key exchange hint influenced this handshake.
+///
+/// See `ClientConfig::enable_kx_hints`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KxHintStatus {
+    /// No hint was stored for this server.
+    None,
+
+    /// A hint was stored for this server, but was not used: kx hints
+    /// are disabled, we don't offer its group, or we couldn't make a
+    /// key share for its group.
+    Available,
+
+    /// A hint was stored for this server and was used to choose
+    /// the offered key share.
+    Used,
+}
+
 #[derive(Debug, PartialEq)]
 enum EarlyDataState {
     Disabled,
@@ -303,6 +1005,14 @@ impl EarlyData {
         }
     }
 
+    fn outcome(&self) -> Option<bool> {
+        match self.state {
+            EarlyDataState::Disabled | EarlyDataState::Ready => None,
+            EarlyDataState::Accepted | EarlyDataState::AcceptedFinished => Some(true),
+            EarlyDataState::Rejected => Some(false),
+        }
+    }
+
     fn enable(&mut self, max_data: usize) {
         assert_eq!(self.state, EarlyDataState::Disabled);
         self.state = EarlyDataState::Ready;
@@ -387,8 +1097,18 @@ pub struct ClientSessionImpl {
     pub error: Option<TLSError>,
     pub state: Option<hs::NextState>,
     pub server_cert_chain: CertificatePayload,
+    pub server_ocsp_response: Vec<u8>,
+    pub resumed: bool,
+    pub selected_psk_index: Option<u16>,
     pub early_data: EarlyData,
     pub resumption_ciphersuite: Option<&'static SupportedCipherSuite>,
+    pub kx_hint_status: KxHintStatus,
+    pub work_units: usize,
+    pub failed_state: Option<HandshakeState>,
+    pub server_name: Option<webpki::DNSName>,
+    pub peer_signature_scheme: Option<SignatureScheme>,
+    pub kx_group: Option<NamedGroup>,
+    pub server_auth_kem: Option<SignatureScheme>,
 }
 
 impl fmt::Debug for ClientSessionImpl {
@@ -399,20 +1119,39 @@ impl fmt::Debug for ClientSessionImpl {
 
 impl ClientSessionImpl {
     pub fn new(config: &Arc<ClientConfig>) -> ClientSessionImpl {
+        let mut common = SessionCommon::new(config.mtu, true);
+        common.handshake_observer = config.on_outgoing_handshake.clone();
+        common.observe_encrypted_handshake = config.on_outgoing_handshake_encrypted;
+
         ClientSessionImpl {
             config: config.clone(),
             alpn_protocol: None,
-            common: SessionCommon::new(config.mtu, true),
+            common,
             error: None,
             state: None,
             server_cert_chain: Vec::new(),
+            server_ocsp_response: Vec::new(),
+            resumed: false,
+            selected_psk_index: None,
             early_data: EarlyData::new(),
             resumption_ciphersuite: None,
+            kx_hint_status: KxHintStatus::None,
+            work_units: 0,
+            failed_state: None,
+            server_name: None,
+            peer_signature_scheme: None,
+            kx_group: None,
+            server_auth_kem: None,
         }
     }
 
     pub fn start_handshake(&mut self, hostname: webpki::DNSName, extra_exts: Vec<ClientExtension>) {
-        self.state = Some(hs::start_handshake(self, hostname, extra_exts));
+        self.server_name = Some(hostname.clone());
+        match hs::start_handshake(self, hostname, extra_exts) {
+            Ok(state) => self.state = Some(state),
+            // Reported by the next call to process_new_packets.
+            Err(err) => self.error = Some(err),
+        }
     }
 
     pub fn get_cipher_suites(&self) -> Vec<CipherSuite> {
@@ -428,6 +1167,20 @@ impl ClientSessionImpl {
         ret
     }
 
+    /// Account for `units` of expensive work done on behalf of the peer,
+    /// failing if this takes us over `ClientConfig::max_work_units`.
+    pub fn charge_work(&mut self, units: usize) -> Result<(), TLSError> {
+        self.work_units = self.work_units.saturating_add(units);
+
+        match self.config.max_work_units {
+            Some(limit) if self.work_units > limit => {
+                self.common.send_fatal_alert(AlertDescription::HandshakeFailure);
+                Err(TLSError::PeerMisbehavedError("handshake work budget exceeded".to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
     pub fn find_cipher_suite(&self, suite: CipherSuite) -> Option<&'static SupportedCipherSuite> {
         for scs in &self.config.ciphersuites {
             if scs.suite == suite {
@@ -445,7 +1198,7 @@ impl ClientSessionImpl {
         //
         // This also covers the handshake case, because we don't have
         // readable plaintext before handshake has completed.
-        !self.common.has_readable_plaintext()
+        !self.common.has_readable_plaintext() && !self.common.is_read_blocked()
     }
 
     pub fn wants_write(&self) -> bool {
@@ -493,7 +1246,13 @@ impl ClientSessionImpl {
 
         // For alerts, we have separate logic.
         if msg.is_content_type(ContentType::Alert) {
-            return self.common.process_alert(msg);
+            let was_closed = self.common.peer_has_closed();
+            self.common.process_alert(msg)?;
+            if !was_closed && self.common.peer_has_closed() && self.common.traffic {
+                let traffic = self.state.take().unwrap();
+                self.state = Some(hs::ExpectClosed::new(traffic));
+            }
+            return Ok(());
         }
 
         self.process_main_protocol(msg)
@@ -540,13 +1299,41 @@ impl ClientSessionImpl {
         }
 
         let state = self.state.take().unwrap();
-        let maybe_next_state = state.handle(self, msg);
+        let failed_state = state.state();
+        let maybe_next_state = state.handle(self, msg)
+            .map_err(|err| {
+                debug!("handshake failed in {:?}: {:?}", failed_state, err);
+                self.failed_state = Some(failed_state);
+                err
+            });
         let next_state = self.maybe_send_unexpected_alert(maybe_next_state)?;
         self.state = Some(next_state);
 
         Ok(())
     }
 
+    pub fn abort_with_alert(&mut self, desc: AlertDescription) {
+        if self.error.is_some() {
+            return;
+        }
+
+        self.common.send_fatal_alert(desc);
+        self.state = None;
+        self.error = Some(TLSError::AbortedWithAlert(desc));
+    }
+
+    /// Plaintext reads and writes fail once the session is aborted with
+    /// `abort_with_alert`.  Other errors leave them alone: buffered
+    /// plaintext can still be read after (eg) a decrypt error.
+    fn check_not_aborted(&self) -> io::Result<()> {
+        match self.error {
+            Some(ref err @ TLSError::AbortedWithAlert(_)) => {
+                Err(io::Error::new(io::ErrorKind::ConnectionAborted, err.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+
     pub fn process_new_packets(&mut self) -> Result<(), TLSError> {
         if let Some(ref err) = self.error {
             return Err(err.clone());
@@ -556,7 +1343,12 @@ impl ClientSessionImpl {
             return Err(TLSError::CorruptMessage);
         }
 
-        while let Some(msg) = self.common.message_deframer.frames.pop_front() {
+        while !self.common.is_read_blocked() {
+            let msg = match self.common.message_deframer.frames.pop_front() {
+                Some(msg) => msg,
+                None => break,
+            };
+
             match self.process_msg(msg) {
                 Ok(_) => {}
                 Err(err) => {
@@ -569,6 +1361,24 @@ impl ClientSessionImpl {
         Ok(())
     }
 
+    pub fn cache_server_kem_pubkey(&self) -> bool {
+        if self.is_handshaking() || self.error.is_some() || self.resumed {
+            return false;
+        }
+
+        let spki = match self.server_cert_chain.first().and_then(verify::kem_cert_spki) {
+            Some(spki) => spki,
+            None => return false,
+        };
+        let dns_name = match self.server_name.as_ref() {
+            Some(dns_name) => dns_name.as_ref(),
+            None => return false,
+        };
+
+        let key = persist::ClientSessionKey::kem_pubkey_for_dns_name(dns_name);
+        self.config.session_persistence.put(key.get_encoding(), spki)
+    }
+
     pub fn get_peer_certificates(&self) -> Option<Vec<key::Certificate>> {
         if self.server_cert_chain.is_empty() {
             return None;
@@ -598,6 +1408,15 @@ impl ClientSessionImpl {
             })
     }
 
+    pub fn request_key_update(&mut self, update_requested: bool) -> Result<(), TLSError> {
+        let mut st = self.state.take();
+        let result = st.as_mut()
+            .ok_or(TLSError::HandshakeNotComplete)
+            .and_then(|st| st.send_key_update(self, update_requested));
+        self.state = st;
+        result
+    }
+
     fn export_keying_material(&self,
                               output: &mut [u8],
                               label: &[u8],
@@ -608,6 +1427,12 @@ impl ClientSessionImpl {
             .and_then(|st| st.export_keying_material(output, label, context))
     }
 
+    fn transcript_hash(&self) -> Option<Vec<u8>> {
+        self.state
+            .as_ref()
+            .and_then(|st| st.transcript_hash())
+    }
+
     fn send_some_plaintext(&mut self, buf: &[u8]) -> usize {
         let mut st = self.state.take();
         st.as_mut()
@@ -635,6 +1460,42 @@ impl ClientSession {
         ClientSession { imp }
     }
 
+    /// Make a new ClientSession which also sends `extra_extensions`
+    /// in its ClientHello.  Each is given as an extension type and
+    /// the extension's body, and is sent exactly as given, in this
+    /// order -- including in the second ClientHello sent after a
+    /// HelloRetryRequest.  This is useful for GREASE and other
+    /// extensions rustls doesn't otherwise support.
+    ///
+    /// An error is returned if an extension type is repeated, or is
+    /// one rustls may send itself.
+    pub fn new_with_extensions(config: &Arc<ClientConfig>,
+                               hostname: webpki::DNSNameRef,
+                               extra_extensions: Vec<(u16, Vec<u8>)>)
+                               -> Result<ClientSession, TLSError> {
+        let mut exts: Vec<ClientExtension> = Vec::new();
+        for (typ, body) in extra_extensions {
+            let typ = ExtensionType::read_bytes(&typ.to_be_bytes()).unwrap();
+            match typ {
+                ExtensionType::Unknown(_) => {}
+                _ => return Err(TLSError::General(format!("extension {:?} is sent by rustls", typ))),
+            }
+
+            if exts.iter().any(|ext| ext.get_type() == typ) {
+                return Err(TLSError::General(format!("extension {:?} given twice", typ)));
+            }
+
+            exts.push(ClientExtension::Unknown(UnknownExtension {
+                typ,
+                payload: Payload::new(body),
+            }));
+        }
+
+        let mut imp = ClientSessionImpl::new(config);
+        imp.start_handshake(hostname.into(), exts);
+        Ok(ClientSession { imp })
+    }
+
     /// Returns an `io::Write` implementor you can write bytes to
     /// to send TLS1.3 early data (a.k.a. "0-RTT data") to the server.
     ///
@@ -661,6 +1522,70 @@ impl ClientSession {
         }
     }
 
+    /// Reports what happened to early data in this handshake.
+    ///
+    /// Returns None if early data was not attempted (or the server
+    /// has not yet decided), Some(true) if the server accepted it, and
+    /// Some(false) if it was rejected.  Rejected early data is lost and
+    /// may need to be sent again.
+    pub fn early_data_accepted(&self) -> Option<bool> {
+        self.imp.early_data.outcome()
+    }
+
+    /// The signature scheme the server used to sign the handshake,
+    /// in its TLS1.3 CertificateVerify or TLS1.2 ServerKeyExchange.
+    /// It is only recorded once the signature has been verified.
+    ///
+    /// Returns None until then, and always for KEMTLS and resumed
+    /// sessions, where the server authenticates without a signature.
+    pub fn peer_signature_scheme(&self) -> Option<SignatureScheme> {
+        self.imp.peer_signature_scheme
+    }
+
+    /// The group of the ephemeral TLS1.3 key exchange, once the
+    /// server has chosen it in its ServerHello.
+    ///
+    /// In KEMTLS this is independent of `server_auth_kem`: the server
+    /// may pick any group we offered, whatever KEM its certificate has.
+    pub fn negotiated_kx_group(&self) -> Option<NamedGroup> {
+        self.imp.kx_group
+    }
+
+    /// The KEM of the server's KEMTLS certificate, which we
+    /// encapsulated to in order to authenticate the server.
+    ///
+    /// Returns None until then, and always for signature-authenticated,
+    /// KEMTLS-PDK and resumed sessions.
+    pub fn server_auth_kem(&self) -> Option<SignatureScheme> {
+        self.imp.server_auth_kem
+    }
+
+    /// Stores the KEM public key of the server's certificate in the
+    /// session persistence layer, keyed by the server's name.  Read it
+    /// back with `ClientConfig::cached_server_kem_pubkey`.
+    ///
+    /// The key is kept so a later connection can start with KEMTLS-PDK
+    /// (pre-distributed keys) rather than a full KEMTLS handshake.
+    /// Returns `true` if a key was stored, which needs a completed,
+    /// non-resumed handshake in which the server authenticated with a
+    /// KEM certificate.
+    pub fn cache_server_kem_pubkey(&self) -> bool {
+        self.imp.cache_server_kem_pubkey()
+    }
+
+    /// Sends a KeyUpdate message to the server and switches to the next
+    /// client application traffic secret for everything we send afterwards.
+    /// If `update_requested` is true the server is asked to update its
+    /// sending keys too.
+    ///
+    /// This is only possible once a TLS1.3 (or KEMTLS) handshake has
+    /// completed, and not for QUIC.  Otherwise an error is returned and
+    /// nothing is sent.  Once the server has sent close_notify, the
+    /// error is `TLSError::PeerClosed`.
+    pub fn request_key_update(&mut self, update_requested: bool) -> Result<(), TLSError> {
+        self.imp.request_key_update(update_requested)
+    }
+
     /// Returns True if the server signalled it will process early data.
     ///
     /// If you sent early data and this returns false at the end of the
@@ -669,6 +1594,121 @@ impl ClientSession {
     pub fn is_early_data_accepted(&self) -> bool {
         self.imp.early_data.is_accepted()
     }
+
+    /// Returns the hash of the handshake transcript, computed with
+    /// the hash function of the negotiated ciphersuite.
+    ///
+    /// This is intended for channel binding by application protocols.
+    /// It is only available once a TLS1.3 handshake has completed;
+    /// before that (or for TLS1.2) this returns None.
+    pub fn transcript_hash(&self) -> Option<Vec<u8>> {
+        self.imp.transcript_hash()
+    }
+
+    /// Reports whether a key exchange hint was stored for this server,
+    /// and if so whether it was used for this handshake.
+    ///
+    /// This is useful for diagnosing repeated HelloRetryRequests.
+    pub fn kx_hint_status(&self) -> KxHintStatus {
+        self.imp.kx_hint_status
+    }
+
+    /// Returns the handshake state which failed, if processing a
+    /// message has failed.
+    pub fn get_failed_state(&self) -> Option<HandshakeState> {
+        self.imp.failed_state
+    }
+
+    /// Returns the OCSP response the server stapled to its certificate
+    /// during the handshake, if any.
+    ///
+    /// This is None until the server's certificate has been verified,
+    /// if the server sent no staple, and for resumed sessions.
+    pub fn get_peer_ocsp_response(&self) -> Option<&[u8]> {
+        if self.imp.server_ocsp_response.is_empty() {
+            None
+        } else {
+            Some(&self.imp.server_ocsp_response)
+        }
+    }
+
+    /// Returns true if the server accepted the session we offered
+    /// to resume, via a TLS1.3 or KEMTLS PSK.
+    ///
+    /// This is false until the server's hello has been processed.
+    pub fn did_resume(&self) -> bool {
+        self.imp.resumed
+    }
+
+    /// Returns the index, into the PSK identities we offered, of the
+    /// one the server selected to resume with.  We offer at most one
+    /// identity, so this is `Some(0)` whenever `did_resume` is true.
+    ///
+    /// This is None until the server's hello has been processed, and
+    /// for full handshakes.
+    pub fn selected_psk_index(&self) -> Option<u16> {
+        self.imp.selected_psk_index
+    }
+
+    /// Queues the fatal alert `desc` to be sent in the next `write_tls`
+    /// call, and aborts the session.  Use this to reject a peer for
+    /// application-level reasons, including mid-handshake.
+    ///
+    /// Afterwards `process_new_packets`, reads and writes fail with
+    /// `TLSError::AbortedWithAlert`.  This does nothing if the session
+    /// has already failed.
+    pub fn abort_with_alert(&mut self, desc: AlertDescription) {
+        self.imp.abort_with_alert(desc)
+    }
+
+    /// Sets a soft limit on the buffer of received plaintext which
+    /// has not yet been `read`.
+    ///
+    /// Once the limit is reached, `process_new_packets` stops
+    /// decrypting records, `wants_read` returns false, and `read_tls`
+    /// fails rather than buffer more records.  Call
+    /// `process_new_packets` again after reading to continue.  The
+    /// buffer may exceed the limit by up to one record.
+    ///
+    /// By default, there is no limit.  This has no effect during the
+    /// handshake.
+    pub fn set_received_plaintext_limit(&mut self, limit: Option<usize>) {
+        self.imp.common.set_received_plaintext_limit(limit)
+    }
+
+    /// Pauses or resumes processing of received records, for callers
+    /// not ready to `read` more plaintext.
+    ///
+    /// While paused, this behaves as if `set_received_plaintext_limit`
+    /// had been reached.  Call `process_new_packets` after resuming to
+    /// process records received meanwhile.  This has no effect during
+    /// the handshake.
+    pub fn set_read_paused(&mut self, paused: bool) {
+        self.imp.common.set_read_paused(paused)
+    }
+
+    /// Like `get_peer_certificates`, but with the peer's own certificate
+    /// parsed, for inspecting (eg) which KEM or signature algorithm it
+    /// uses.  This borrows the chain rather than copying it, and parses
+    /// it on every call.
+    ///
+    /// Fails with `TLSError::NoCertificatesPresented` until there is a
+    /// chain, and with `TLSError::WebPKIError` if its first certificate
+    /// doesn't parse.
+    pub fn peer_cert_chain_parsed(&self) -> Result<ParsedChain<'_>, TLSError> {
+        ParsedChain::parse(&self.imp.server_cert_chain)
+    }
+
+    /// Retrieves the hash algorithm of the ciphersuite agreed with the
+    /// peer.  This is the hash used throughout the key schedule.
+    ///
+    /// This returns None until the ciphersuite is agreed: until the
+    /// ServerHello is processed.
+    pub fn negotiated_suite_hash(&self) -> Option<HashAlgorithm> {
+        // Unlike get_negotiated_ciphersuite, don't guess from the
+        // session we're trying to resume.
+        self.imp.get_negotiated_ciphersuite().map(|suite| suite.hash)
+    }
 }
 
 impl Session for ClientSession {
@@ -744,6 +1784,7 @@ impl io::Read for ClientSession {
     /// This means applications using rustls must both handle ErrorKind::ConnectionAborted
     /// from this function, *and* unexpected closure of the underlying TCP connection.
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.imp.check_not_aborted()?;
         self.imp.common.read(buf)
     }
 }
@@ -760,10 +1801,12 @@ impl io::Write for ClientSession {
     /// writing much data before it can be sent will
     /// cause excess memory usage.
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.imp.check_not_aborted()?;
         Ok(self.imp.send_some_plaintext(buf))
     }
 
     fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.imp.check_not_aborted()?;
         let mut sz = 0;
         for buf in bufs {
             sz += self.imp.send_some_plaintext(buf);
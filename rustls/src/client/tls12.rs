@@ -16,7 +16,7 @@ use crate::verify;
 use crate::ticketer;
 #[cfg(feature = "logging")]
 use crate::log::{debug, trace};
-use crate::error::TLSError;
+use crate::error::{TLSError, HandshakeState};
 use crate::check::check_message;
 
 use crate::client::common::{ServerCertDetails, ServerKXDetails, HandshakeDetails};
@@ -52,10 +52,22 @@ impl ExpectCertificate {
 }
 
 impl hs::State for ExpectCertificate {
-    fn handle(mut self: Box<Self>, _sess: &mut ClientSessionImpl, m: Message) -> hs::NextStateOrError {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::Certificate
+    }
+
+    fn handle(mut self: Box<Self>, sess: &mut ClientSessionImpl, m: Message) -> hs::NextStateOrError {
         let cert_chain = require_handshake_msg!(m, HandshakeType::Certificate, HandshakePayload::Certificate)?;
         self.handshake.transcript.add_message(&m);
 
+        // TLS1.2 servers always authenticate with a signature.
+        if !sess.config.auth_mode.accepts(false) {
+            sess.common.send_fatal_alert(AlertDescription::UnsupportedCertificate);
+            return Err(TLSError::PeerIncompatibleError(
+                "server sent signing certificate but KEMTLS authentication is required".to_string()));
+        }
+
+        hs::check_cert_chain_size(sess, cert_chain)?;
         self.server_cert.cert_chain = cert_chain.clone();
 
         if self.may_send_cert_status {
@@ -83,6 +95,10 @@ impl ExpectCertificateStatus {
 }
 
 impl hs::State for ExpectCertificateStatus {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::CertificateStatus
+    }
+
     fn handle(mut self: Box<Self>, _sess: &mut ClientSessionImpl, m: Message) -> hs::NextStateOrError {
         self.handshake.transcript.add_message(&m);
         let mut status = require_handshake_msg_mut!(m, HandshakeType::CertificateStatus, HandshakePayload::CertificateStatus)?;
@@ -118,6 +134,10 @@ impl ExpectCertificateStatusOrServerKX {
 }
 
 impl hs::State for ExpectCertificateStatusOrServerKX {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::CertificateStatusOrServerKeyExchange
+    }
+
     fn handle(self: Box<Self>, sess: &mut ClientSessionImpl, m: Message) -> hs::NextStateOrError {
         check_message(&m,
                       &[ContentType::Handshake],
@@ -148,6 +168,10 @@ impl ExpectServerKX {
 }
 
 impl hs::State for ExpectServerKX {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::ServerKeyExchange
+    }
+
     fn handle(mut self: Box<Self>, sess: &mut ClientSessionImpl, m: Message) -> hs::NextStateOrError {
         let opaque_kx = require_handshake_msg!(m, HandshakeType::ServerKeyExchange, HandshakePayload::ServerKeyExchange)?;
         let maybe_decoded_kx = opaque_kx.unwrap_given_kxa(&sess.common.get_suite_assert().kx);
@@ -197,7 +221,7 @@ fn emit_certificate(handshake: &mut HandshakeDetails,
 fn emit_clientkx(handshake: &mut HandshakeDetails,
                  sess: &mut ClientSessionImpl,
                  kxd: &suites::KeyExchangeResult) {
-    let mut buf = Vec::new();
+    let mut buf = Vec::with_capacity(1 + kxd.ciphertext.len());
     let ecpoint = PayloadU8::new(kxd.ciphertext.clone());
     ecpoint.encode(&mut buf);
     let pubkey = Payload::new(buf);
@@ -297,6 +321,10 @@ impl ExpectCertificateRequest {
 }
 
 impl hs::State for ExpectCertificateRequest {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::CertificateRequest
+    }
+
     fn handle(mut self: Box<Self>, sess: &mut ClientSessionImpl, m: Message) -> hs::NextStateOrError {
         let certreq = require_handshake_msg!(m, HandshakeType::CertificateRequest, HandshakePayload::CertificateRequest)?;
         self.handshake.transcript.add_message(&m);
@@ -362,6 +390,10 @@ impl ExpectServerDoneOrCertReq {
 }
 
 impl hs::State for ExpectServerDoneOrCertReq {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::ServerHelloDoneOrCertificateRequest
+    }
+
     fn handle(mut self: Box<Self>, sess: &mut ClientSessionImpl, m: Message) -> hs::NextStateOrError {
         if require_handshake_msg!(m, HandshakeType::CertificateRequest, HandshakePayload::CertificateRequest).is_ok() {
             self.into_expect_certificate_req().handle(sess, m)
@@ -411,6 +443,10 @@ impl ExpectServerDone {
 }
 
 impl hs::State for ExpectServerDone {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::ServerHelloDone
+    }
+
     fn handle(self: Box<Self>, sess: &mut ClientSessionImpl, m: Message) -> hs::NextStateOrError {
         let mut st = *self;
         check_message(&m, &[ContentType::Handshake], &[HandshakeType::ServerHelloDone])?;
@@ -438,10 +474,10 @@ impl hs::State for ExpectServerDone {
             return Err(TLSError::NoCertificatesPresented);
         }
 
+        sess.charge_work(st.server_cert.cert_chain.len() + 1)?;
+
         let certv = sess.config
-            .get_verifier()
-            .verify_server_cert(&sess.config.root_store,
-                                &st.server_cert.cert_chain,
+            .verify_server_cert(&st.server_cert.cert_chain,
                                 st.handshake.dns_name.as_ref(),
                                 &st.server_cert.ocsp_response)
             .map_err(|err| hs::send_cert_error_alert(sess, err))?;
@@ -451,7 +487,8 @@ impl hs::State for ExpectServerDone {
             (Some(scts), Some(logs)) => {
                 verify::verify_scts(&st.server_cert.cert_chain[0],
                                     scts,
-                                    logs)?;
+                                    logs)
+                    .map_err(|err| hs::send_sct_error_alert(sess, err))?;
             }
             (_, _) => {}
         }
@@ -475,6 +512,10 @@ impl hs::State for ExpectServerDone {
                 return Err(TLSError::PeerMisbehavedError(error_message));
             }
 
+            if !sess.config.get_verify_schemes().contains(&sig.scheme) {
+                return Err(hs::illegal_param(sess, "server used signature scheme we did not offer"));
+            }
+
             sess.config
                 .get_verifier()
                 .verify_tls12_signature(&message,
@@ -482,7 +523,9 @@ impl hs::State for ExpectServerDone {
                                         sig)
                 .map_err(|err| hs::send_cert_error_alert(sess, err))?
         };
+        sess.peer_signature_scheme = Some(st.server_kx.kx_sig.scheme);
         sess.server_cert_chain = st.server_cert.take_chain();
+        sess.server_ocsp_response = st.server_cert.take_ocsp_response();
 
         // 4.
         if st.client_auth.is_some() {
@@ -492,9 +535,13 @@ impl hs::State for ExpectServerDone {
         }
 
         // 5a.
+        sess.charge_work(1)?;
         let kxd = sess.common.get_suite_assert()
             .do_client_kx(&st.server_kx.kx_params)
-            .ok_or_else(|| TLSError::PeerMisbehavedError("key exchange failed".to_string()))?;
+            .ok_or_else(|| {
+                sess.common.send_fatal_alert(AlertDescription::IllegalParameter);
+                TLSError::PeerMisbehavedError("key exchange failed".to_string())
+            })?;
 
         // 5b.
         emit_clientkx(&mut st.handshake, sess, &kxd);
@@ -566,6 +613,10 @@ impl ExpectCCS {
 }
 
 impl hs::State for ExpectCCS {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::ChangeCipherSpec
+    }
+
     fn handle(self: Box<Self>, sess: &mut ClientSessionImpl, m: Message) -> hs::NextStateOrError {
         check_message(&m, &[ContentType::ChangeCipherSpec], &[])?;
         // CCS should not be received interleaved with fragmented handshake-level
@@ -603,6 +654,10 @@ impl ExpectNewTicket {
 }
 
 impl hs::State for ExpectNewTicket {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::NewSessionTicket
+    }
+
     fn handle(mut self: Box<Self>, _sess: &mut ClientSessionImpl, m: Message) -> hs::NextStateOrError {
         self.handshake.transcript.add_message(&m);
 
@@ -652,6 +707,7 @@ fn save_session(secrets: &SessionSecrets,
 
     if worked {
         debug!("Session saved");
+        sess.config.session_saved(handshake.dns_name.as_ref());
     } else {
         debug!("Session not saved");
     }
@@ -678,6 +734,10 @@ impl ExpectFinished {
 }
 
 impl hs::State for ExpectFinished {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::Finished
+    }
+
     fn handle(self: Box<Self>, sess: &mut ClientSessionImpl, m: Message) -> hs::NextStateOrError {
         let mut st = *self;
         let finished = require_handshake_msg!(m, HandshakeType::Finished, HandshakePayload::Finished)?;
@@ -728,6 +788,10 @@ struct ExpectTraffic {
 }
 
 impl hs::State for ExpectTraffic {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::Traffic
+    }
+
     fn handle(self: Box<Self>, sess: &mut ClientSessionImpl, mut m: Message) -> hs::NextStateOrError {
         check_message(&m, &[ContentType::ApplicationData], &[])?;
         sess.common.take_received_plaintext(m.take_opaque_payload().unwrap());
@@ -741,4 +805,9 @@ impl hs::State for ExpectTraffic {
         self.secrets.export_keying_material(output, label, context);
         Ok(())
     }
+    fn send_key_update(&mut self,
+                       _sess: &mut ClientSessionImpl,
+                       _update_requested: bool) -> Result<(), TLSError> {
+        Err(TLSError::General("KeyUpdate requires TLS1.3".to_string()))
+    }
 }
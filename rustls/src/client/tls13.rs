@@ -8,13 +8,15 @@ use crate::msgs::handshake::{SessionID, ServerHelloPayload, HasServerExtensions}
 use crate::msgs::handshake::{ClientExtension, HelloRetryRequest, KeyShareEntry};
 use crate::msgs::handshake::EncryptedExtensions;
 use crate::msgs::handshake::{CertificatePayloadTLS13, CertificateEntry};
+use crate::msgs::handshake::KEM_CERT_ENTRY_EXTENSIONS;
+use crate::msgs::handshake::CertificateRequestPayloadTLS13;
 use crate::msgs::handshake::{PresharedKeyIdentity, PresharedKeyOffer};
 use crate::msgs::handshake::DigitallySignedStruct;
 use crate::msgs::handshake::NewSessionTicketPayloadTLS13;
 use crate::msgs::ccs::ChangeCipherSpecPayload;
 use crate::msgs::codec::Codec;
 use crate::msgs::persist;
-use crate::client::ClientSessionImpl;
+use crate::client::{ClientSessionImpl, KxHintStatus};
 use crate::key_schedule::{
     KeyScheduleEarly,
     KeyScheduleNonSecret,
@@ -28,7 +30,7 @@ use crate::suites;
 use crate::ticketer;
 #[cfg(feature = "logging")]
 use crate::log::{debug, warn, trace};
-use crate::error::TLSError;
+use crate::error::{TLSError, KemtlsStep, HandshakeState};
 use crate::check::check_message;
 #[cfg(feature = "quic")]
 use crate::{
@@ -40,10 +42,12 @@ use crate::{
 use crate::client::common::{ServerCertDetails, HandshakeDetails};
 use crate::client::common::{ClientHelloDetails, ClientAuthDetails};
 use crate::client::hs;
+use crate::hash_hs::HandshakeHash;
 
 use crate::client::default_group::DEFAULT_GROUP;
 
 use ring::constant_time;
+use std::mem;
 use webpki;
 
 // Extensions we expect in plaintext in the ServerHello.
@@ -66,51 +70,112 @@ static DISALLOWED_TLS13_EXTS: &[ExtensionType] = &[
 pub fn validate_server_hello(sess: &mut ClientSessionImpl,
                              server_hello: &ServerHelloPayload) -> Result<(), TLSError> {
     for ext in &server_hello.extensions {
-        if !ALLOWED_PLAINTEXT_EXTS.contains(&ext.get_type()) {
+        let ext_type = ext.get_type();
+        if !ALLOWED_PLAINTEXT_EXTS.contains(&ext_type) &&
+            !sess.config.allowed_unsolicited_extensions.contains(&ext_type) {
             sess.common.send_fatal_alert(AlertDescription::UnsupportedExtension);
             return Err(TLSError::PeerMisbehavedError("server sent unexpected cleartext ext"
                                                      .to_string()));
         }
     }
 
+    // We only get here having negotiated TLS1.3, so any plaintext
+    // supported_versions must say so too.
+    if let Some(version) = server_hello.get_supported_versions() {
+        if version != ProtocolVersion::TLSv1_3 {
+            return Err(hs::illegal_param(sess, "server sent mismatched supported_versions"));
+        }
+    }
+
     Ok(())
 }
 
-fn find_kx_hint(sess: &mut ClientSessionImpl, dns_name: webpki::DNSNameRef) -> Option<NamedGroup> {
+pub fn find_kx_hint(sess: &mut ClientSessionImpl, dns_name: webpki::DNSNameRef) -> Option<NamedGroup> {
     let key = persist::ClientSessionKey::hint_for_dns_name(dns_name);
     let key_buf = key.get_encoding();
 
     let maybe_value = sess.config.session_persistence.get(&key_buf);
-    maybe_value.and_then(|enc| NamedGroup::read_bytes(&enc))
+    let hint = maybe_value.and_then(|enc| NamedGroup::read_bytes(&enc));
+
+    let usable_hint = hint.filter(|_| sess.config.enable_kx_hints)
+        .filter(|group| sess.config.offered_groups().contains(group));
+
+    sess.kx_hint_status = match (hint, usable_hint) {
+        (None, _) => KxHintStatus::None,
+        (Some(_), None) => KxHintStatus::Available,
+        (Some(_), Some(_)) => KxHintStatus::Used,
+    };
+
+    usable_hint
 }
 
 fn save_kx_hint(sess: &mut ClientSessionImpl, dns_name: webpki::DNSNameRef, group: NamedGroup) {
+    if !sess.config.enable_kx_hints {
+        return;
+    }
+
     let key = persist::ClientSessionKey::hint_for_dns_name(dns_name);
 
     sess.config.session_persistence.put(key.get_encoding(), group.get_encoding());
 }
 
+/// The key pair configured with `DangerousClientConfig::set_fixed_key_share`
+/// for `group`, if any.
+#[cfg(feature = "dangerous_configuration")]
+fn fixed_key_share(sess: &ClientSessionImpl, group: NamedGroup) -> Option<suites::KeyExchange> {
+    sess.config.fixed_key_shares
+        .iter()
+        .find(|(g, _, _)| *g == group)
+        .and_then(|(_, public_key, private_key)| {
+            warn!("Offering fixed key share for {:?}", group);
+            suites::KeyExchange::from_kem_keypair(group, public_key, private_key)
+        })
+}
+
+#[cfg(not(feature = "dangerous_configuration"))]
+fn fixed_key_share(_sess: &ClientSessionImpl, _group: NamedGroup) -> Option<suites::KeyExchange> {
+    None
+}
+
+/// A key share for `group`: a fixed one if configured, else one from
+/// the ephemeral key pool, else a fresh one.
+fn new_key_share(sess: &ClientSessionImpl, group: NamedGroup) -> Option<suites::KeyExchange> {
+    fixed_key_share(sess, group)
+        .or_else(|| sess.config.ephemeral_key_pool
+            .as_ref()
+            .and_then(|pool| pool.take(group)))
+        .or_else(|| suites::KeyExchange::start_kex_with_retries(group,
+                                                                 sess.config.kem_keygen_retries))
+}
+
 pub fn choose_kx_groups(sess: &mut ClientSessionImpl,
                         exts: &mut Vec<ClientExtension>,
                         hello: &mut ClientHelloDetails,
                         handshake: &mut HandshakeDetails,
-                        retryreq: Option<&HelloRetryRequest>) {
+                        kx_hint: Option<NamedGroup>,
+                        retryreq: Option<&HelloRetryRequest>) -> Result<(), TLSError> {
     // Choose our groups:
     // - if we've been asked via HelloRetryRequest for a specific
     //   one, do that.
     // - if not, we might have a hint of what the server supports
-    // - if not, send just X25519.
+    // - if not, send just X25519 (or, in strict PQ mode, our most
+    //   preferred KEM).
     //
+    let default_group = if sess.config.allows_group(DEFAULT_GROUP) {
+        DEFAULT_GROUP
+    } else {
+        sess.config.offered_groups()[0]
+    };
     let groups = retryreq.and_then(HelloRetryRequest::get_requested_key_share_group)
-        .or_else(|| find_kx_hint(sess, handshake.dns_name.as_ref()))
-        .or_else(|| Some(DEFAULT_GROUP))
+        .or(kx_hint)
+        .or_else(|| Some(default_group))
         .map(|grp| vec![ grp ])
         .unwrap();
 
-    let mut key_shares = vec![];
+    let mut key_shares = Vec::with_capacity(groups.len());
 
     handshake.print_runtime("CREATING KEYSHARES");
-    for group in groups {
+    for &group in &groups {
         // in reply to HelloRetryRequest, we must not alter any existing key
         // shares
         if let Some(already_offered_share) = hello.find_key_share(group) {
@@ -119,24 +184,47 @@ pub fn choose_kx_groups(sess: &mut ClientSessionImpl,
             continue;
         }
 
-        if let Some(key_share) = suites::KeyExchange::start_kex(group) {
+        if let Some(key_share) = new_key_share(sess, group) {
             key_shares.push(KeyShareEntry::new(group, key_share.pubkey.as_ref()));
             hello.offered_key_shares.push(key_share);
         }
     }
+
+    // If we can't make a share for the hinted group after all, offer
+    // the group we'd have chosen without the hint.
+    if key_shares.is_empty() && retryreq.is_none() && kx_hint.is_some() {
+        sess.kx_hint_status = KxHintStatus::Available;
+        if let Some(key_share) = new_key_share(sess, default_group) {
+            key_shares.push(KeyShareEntry::new(default_group, key_share.pubkey.as_ref()));
+            hello.offered_key_shares.push(key_share);
+        }
+    }
     handshake.print_runtime("CREATED KEYSHARES");
 
+    // After a HelloRetryRequest, shares for groups the server didn't
+    // choose are of no further use.
+    if retryreq.is_some() {
+        hello.offered_key_shares.retain(|share| groups.contains(&share.group));
+    }
+
+    // Sending an empty key share would only fail later, less clearly.
+    if key_shares.is_empty() {
+        return Err(TLSError::NoUsableKexGroup);
+    }
+
     exts.push(ClientExtension::KeyShare(key_shares));
+    Ok(())
 }
 
 /// This implements the horrifying TLS1.3 hack where PSK binders have a
 /// data dependency on the message they are contained within.
 pub fn fill_in_psk_binder(sess: &mut ClientSessionImpl,
                           handshake: &mut HandshakeDetails,
-                          hmp: &mut HandshakeMessagePayload) -> KeyScheduleEarly {
+                          hmp: &mut HandshakeMessagePayload) -> Result<KeyScheduleEarly, TLSError> {
     // We need to know the hash function of the suite we're trying to resume into.
     let resuming = handshake.resuming_session.as_ref().unwrap();
-    let suite = sess.find_cipher_suite(resuming.cipher_suite).unwrap();
+    let suite = sess.find_cipher_suite(resuming.cipher_suite)
+        .ok_or_else(|| TLSError::General("resuming suite is not supported".to_string()))?;
     let hkdf_alg = suite.hkdf_algorithm;
     let suite_hash = suite.get_hash();
 
@@ -148,14 +236,17 @@ pub fn fill_in_psk_binder(sess: &mut ClientSessionImpl,
 
     // Run a fake key_schedule to simulate what the server will do if it choses
     // to resume.
-    let key_schedule = KeyScheduleEarly::new(hkdf_alg, &resuming.master_secret.0);
+    #[allow(unused_mut)]
+    let mut key_schedule = KeyScheduleEarly::new(hkdf_alg, &resuming.master_secret.0);
+    #[cfg(feature = "dangerous_configuration")]
+    key_schedule.set_trace(sess.config.key_schedule_trace.clone());
     let real_binder = key_schedule.resumption_psk_binder_key_and_sign_verify_data(&handshake_hash);
 
     if let HandshakePayload::ClientHello(ref mut ch) = hmp.payload {
         ch.set_psk_binder(real_binder);
     };
 
-    key_schedule
+    Ok(key_schedule)
 }
 
 pub fn start_handshake_traffic(sess: &mut ClientSessionImpl,
@@ -167,23 +258,88 @@ pub fn start_handshake_traffic(sess: &mut ClientSessionImpl,
                            -> Result<KeyScheduleHandshake, TLSError> {
     let suite = sess.common.get_suite_assert();
 
+    if server_hello.get_key_share().is_none() &&
+        server_hello.find_extension(ExtensionType::KeyShare).is_some() {
+        return Err(hs::illegal_param(sess, "server sent more than one key share"));
+    }
+
+    // We only ever offer psk_dhe_ke, so a server resuming without a key
+    // share is attempting psk_ke resumption.
+    if server_hello.get_key_share().is_none() && server_hello.get_psk_index().is_some() {
+        sess.common.send_fatal_alert(AlertDescription::MissingExtension);
+        return Err(TLSError::PeerMisbehavedError("server resumed with psk_ke, which we did not offer"
+                                                 .to_string()));
+    }
+
     let their_key_share = server_hello.get_key_share()
         .ok_or_else(|| {
             sess.common.send_fatal_alert(AlertDescription::MissingExtension);
             TLSError::PeerMisbehavedError("missing key share".to_string())
             })?;
 
-    let our_key_share = hello.find_key_share_and_discard_others(their_key_share.group)
-        .ok_or_else(|| hs::illegal_param(sess, "wrong group for key share"))?;
+    // We may advertise more groups than we send key shares for.  A
+    // server wanting one of those must ask for it with a
+    // HelloRetryRequest, not just choose it here.
+    let our_key_share = match hello.find_key_share_and_discard_others(their_key_share.group) {
+        Some(share) => share,
+        None if sess.config.offered_groups().contains(&their_key_share.group) => {
+            return Err(hs::illegal_param(sess, &format!(
+                "server chose {:?} without a HelloRetryRequest, though we sent no key share for it",
+                their_key_share.group)));
+        }
+        None => {
+            return Err(hs::illegal_param(sess, &format!(
+                "server chose {:?}, which we did not offer",
+                their_key_share.group)));
+        }
+    };
+
+    let expected_len = suites::kex_sizes(our_key_share.group)
+        .map(|sizes| sizes.ciphertext_len);
+    if expected_len != Some(their_key_share.payload.0.len()) {
+        return Err(hs::illegal_param(sess, "server key share has wrong length"));
+    }
+
+    // Resuming a session that was established post-quantum with a
+    // classical key exchange would leave the resumed session's forward
+    // secrecy to ECDH alone.
+    if server_hello.get_psk_index().is_some() {
+        let original_group = handshake.resuming_session
+            .as_ref()
+            .and_then(|resuming| resuming.kx_group);
+        if let Some(original_group) = original_group {
+            if !suites::KeyExchange::is_ecdh_group(original_group) &&
+                suites::KeyExchange::is_ecdh_group(our_key_share.group) {
+                return Err(hs::illegal_param(sess, &format!(
+                    "server resumed {:?} session with {:?}",
+                    original_group,
+                    our_key_share.group)));
+            }
+        }
+    }
+
+    sess.charge_work(1)?;
     handshake.print_runtime("DECAPSULATING EPHEMERAL");
+    let group = our_key_share.group;
     let shared = our_key_share.decapsulate(&their_key_share.payload.0)
-        .ok_or_else(|| TLSError::PeerMisbehavedError("key exchange failed"
-                                                     .to_string()))?;
+        .ok_or_else(|| {
+            if suites::KeyExchange::is_ecdh_group(group) {
+                TLSError::PeerMisbehavedError("key exchange failed".to_string())
+            } else {
+                sess.common.send_fatal_alert(AlertDescription::IllegalParameter);
+                TLSError::KemDecapsulationFailed(group)
+            }
+        })?;
     handshake.print_runtime("DECAPSULATED EPHEMERAL");
+    handshake.kx_group = Some(group);
+    sess.kx_group = Some(group);
 
     let mut key_schedule = if let Some(selected_psk) = server_hello.get_psk_index() {
         if let Some(ref resuming) = handshake.resuming_session {
-            let resume_from_suite = sess.find_cipher_suite(resuming.cipher_suite).unwrap();
+            let resume_from_suite = match sess.resumption_ciphersuite {
+                Some(resume_from_suite) => resume_from_suite,
+                None => return Err(hs::illegal_param(sess, "server resumed a session we did not offer")),
+            };
             if !resume_from_suite.can_resume_to(suite) {
                 return Err(hs::illegal_param(sess, "server resuming incompatible suite"));
             }
@@ -199,6 +355,8 @@ pub fn start_handshake_traffic(sess: &mut ClientSessionImpl,
             }
 
             debug!("Resuming using PSK");
+            sess.resumed = true;
+            sess.selected_psk_index = Some(selected_psk);
             // The key schedule has been initialized and set in fill_in_psk_binder()
         } else {
             return Err(TLSError::PeerMisbehavedError("server selected unoffered psk".to_string()));
@@ -215,8 +373,11 @@ pub fn start_handshake_traffic(sess: &mut ClientSessionImpl,
         sess.early_data.rejected();
         sess.common.early_traffic = false;
         handshake.resuming_session.take();
-        KeyScheduleNonSecret::new(suite.hkdf_algorithm)
-            .into_handshake(&shared)
+        #[allow(unused_mut)]
+        let mut key_schedule = KeyScheduleNonSecret::new(suite.hkdf_algorithm);
+        #[cfg(feature = "dangerous_configuration")]
+        key_schedule.set_trace(sess.config.key_schedule_trace.clone());
+        key_schedule.into_handshake(&shared)
     };
 
     // Remember what KX group the server liked for next time.
@@ -276,12 +437,29 @@ pub fn prepare_resumption(sess: &mut ClientSessionImpl,
                           ticket: Vec<u8>,
                           handshake: &HandshakeDetails,
                           exts: &mut Vec<ClientExtension>,
-                          doing_retry: bool) -> bool {
+                          doing_retry: bool) -> Result<bool, TLSError> {
     let resuming_suite = handshake.resuming_session
         .as_ref()
         .and_then(|resume| sess.find_cipher_suite(resume.cipher_suite));
 
     if hs::compatible_suite(sess, resuming_suite) {
+        // compatible_suite is only true for a suite we support.
+        let binder_len = resuming_suite.unwrap().get_hash().output_len;
+        if binder_len == 0 {
+            return Err(TLSError::General("resuming suite has no hash for PSK binder".to_string()));
+        }
+
+        // The resumption secret comes from our cache, which could be
+        // corrupt; it must be exactly one hash output long.
+        let secret_len = handshake.resuming_session
+            .as_ref()
+            .map_or(0, |resume| resume.master_secret.0.len());
+        if secret_len != binder_len {
+            debug!("Not resuming: cached session's secret is {} bytes, expected {}",
+                   secret_len, binder_len);
+            return Ok(false);
+        }
+
         sess.resumption_ciphersuite = resuming_suite;
         // The EarlyData extension MUST be supplied together with the
         // PreSharedKey extension.
@@ -299,22 +477,20 @@ pub fn prepare_resumption(sess: &mut ClientSessionImpl,
         //
         // Include an empty binder. It gets filled in below because it depends on
         // the message it's contained in (!!!).
-        let (obfuscated_ticket_age, suite) = {
-            let resuming = handshake.resuming_session
-                .as_ref()
-                .unwrap();
-            (resuming.get_obfuscated_ticket_age(ticketer::timebase()), resuming.cipher_suite)
-        };
+        let obfuscated_ticket_age = handshake.resuming_session
+            .as_ref()
+            .unwrap()
+            .get_obfuscated_ticket_age(ticketer::timebase());
 
-        let binder_len = sess.find_cipher_suite(suite).unwrap().get_hash().output_len;
         let binder = vec![0u8; binder_len];
 
         let psk_identity = PresharedKeyIdentity::new(ticket, obfuscated_ticket_age);
         let psk_ext = PresharedKeyOffer::new(psk_identity, binder);
         exts.push(ClientExtension::PresharedKey(psk_ext));
-        true
+        Ok(true)
     } else {
-        false
+        debug!("Not resuming: cached session's suite is unavailable or incompatible");
+        Ok(false)
     }
 }
 
@@ -343,19 +519,25 @@ fn validate_encrypted_extensions(sess: &mut ClientSessionImpl,
                                                  .to_string()));
     }
 
-    if hello.server_sent_unsolicited_extensions(exts, &[]) {
+    if hello.server_sent_unsolicited_extensions(exts, &sess.config.allowed_unsolicited_extensions) {
         sess.common.send_fatal_alert(AlertDescription::UnsupportedExtension);
         let msg = "server sent unsolicited encrypted extension".to_string();
         return Err(TLSError::PeerMisbehavedError(msg));
     }
 
     for ext in exts {
-        if ALLOWED_PLAINTEXT_EXTS.contains(&ext.get_type()) ||
-           DISALLOWED_TLS13_EXTS.contains(&ext.get_type()) {
-            sess.common.send_fatal_alert(AlertDescription::UnsupportedExtension);
-            let msg = "server sent inappropriate encrypted extension".to_string();
-            return Err(TLSError::PeerMisbehavedError(msg));
-        }
+        let ext_type = ext.get_type();
+        let msg = if ALLOWED_PLAINTEXT_EXTS.contains(&ext_type) {
+            format!("server sent {:?} extension in EncryptedExtensions, not ServerHello",
+                    ext_type)
+        } else if DISALLOWED_TLS13_EXTS.contains(&ext_type) {
+            format!("server sent {:?} extension, which is not allowed in TLS1.3", ext_type)
+        } else {
+            continue;
+        };
+
+        sess.common.send_fatal_alert(AlertDescription::UnsupportedExtension);
+        return Err(TLSError::PeerMisbehavedError(msg));
     }
 
     Ok(())
@@ -404,7 +586,19 @@ impl ExpectEncryptedExtensions {
 }
 
 impl hs::State for ExpectEncryptedExtensions {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::EncryptedExtensions
+    }
+
     fn handle(mut self: Box<Self>, sess: &mut ClientSessionImpl, m: Message) -> hs::NextStateOrError {
+        // A server that sends its Certificate first has skipped
+        // EncryptedExtensions.  Say so, as the generic error is easy to
+        // mistake for a certificate problem.
+        if m.is_handshake_type(HandshakeType::Certificate) {
+            sess.common.send_fatal_alert(AlertDescription::UnexpectedMessage);
+            return Err(TLSError::PeerMisbehavedError(
+                "server sent Certificate before EncryptedExtensions".to_string()));
+        }
         let exts = require_handshake_msg!(m, HandshakeType::EncryptedExtensions, HandshakePayload::EncryptedExtensions)?;
         debug!("TLS1.3 encrypted extensions: {:?}", exts);
         self.handshake.transcript.add_message(&m);
@@ -483,12 +677,51 @@ impl ExpectCertificate {
         })
     }
 
+    /// Verify the server's KEM certificate chain, and encapsulate to it.
+    ///
+    /// We encapsulate whatever the outcome of verification, and only act
+    /// on it afterwards, so an invalid certificate and a valid one take
+    /// the same path until we would send our ciphertext.  This is not
+    /// fully timing-neutral: webpki's path building returns as soon as it
+    /// finds a problem, so verification itself takes longer for a good
+    /// chain than a bad one; and the peer sees an alert rather than our
+    /// ciphertext either way.  What we avoid is skipping the (often much
+    /// more expensive) encapsulation for bad certificates.
+    ///
+    /// Our ciphertext moves us to new handshake keys, so nothing of the
+    /// server's flight may still be pending.
     fn emit_ciphertext(&mut self, sess: &mut ClientSessionImpl, certificate: webpki::EndEntityCert) -> Result<(), TLSError> {
-        emit_fake_ccs(&mut self.handshake, sess);
+        hs::check_aligned_handshake(sess)?;
+        sess.charge_work(self.server_cert.cert_chain.len() + 1)?;
+        let certv = if self.server_cert.cert_chain.is_empty() {
+            Err(TLSError::NoCertificatesPresented)
+        } else {
+            sess.config.verify_server_cert(&self.server_cert.cert_chain,
+                                           self.handshake.dns_name.as_ref(),
+                                           &self.server_cert.ocsp_response)
+        };
 
         self.handshake.print_runtime("ENCAPSULATING TO CERT");
-        let (ct, ss) = certificate.encapsulate().map_err(TLSError::WebPKIError)?;
+        suites::count_cert_kem_op(suites::KemOp::Encapsulate);
+        let encapsulated = if self.server_cert.cert_chain.first()
+            .map_or(false, verify::kem_cert_has_valid_key_len) {
+            certificate.encapsulate()
+                .map_err(|err| debug!("encapsulation to server certificate failed: {:?}", err))
+                .ok()
+        } else {
+            debug!("server certificate's KEM public key has the wrong length");
+            None
+        };
         self.handshake.print_runtime("ENCAPSULATED TO CERT");
+
+        certv.map_err(|err| send_cert_error_alert(sess, err))?;
+        let (ct, ss) = encapsulated
+            .ok_or_else(|| {
+                sess.common.send_fatal_alert(AlertDescription::BadCertificate);
+                TLSError::KemtlsFlightFailed(KemtlsStep::Encapsulate)
+            })?;
+
+        emit_fake_ccs(&mut self.handshake, sess);
         let m = Message {
             typ: ContentType::Handshake,
             version: ProtocolVersion::TLSv1_3,
@@ -501,10 +734,13 @@ impl ExpectCertificate {
         sess.common.send_msg(m, true);
         self.handshake.print_runtime("SUBMITTED CKEX TO SERVER");
 
-        // update keys
-        hs::check_aligned_handshake(sess)?;
+        self.derive_authenticated_handshake_keys(sess, ss.as_ref());
+        Ok(())
+    }
+
+    fn derive_authenticated_handshake_keys(&mut self, sess: &mut ClientSessionImpl, ss: &[u8]) {
         let suite = sess.common.get_suite_assert();
-        self.key_schedule.authenticate_handshake(ss.as_ref());
+        self.key_schedule.authenticate_handshake(ss);
         let hs_hash = self.handshake.transcript.get_current_hash();
 
         let write_key = self.key_schedule
@@ -516,9 +752,6 @@ impl ExpectCertificate {
         sess.common.record_layer.set_message_decrypter(cipher::new_tls13_read(suite, &read_key));
 
         self.handshake.print_runtime("DERIVED AHS");
-
-        // done
-        Ok(())
     }
 
     fn into_expect_ciphertext(self) -> hs::NextState {
@@ -530,17 +763,19 @@ impl ExpectCertificate {
         })
     }
 
-    fn emit_finished_and_into_expect_server_finished(mut self, sess: &mut ClientSessionImpl) -> hs::NextState {
+    fn emit_finished_and_into_expect_server_finished(mut self, sess: &mut ClientSessionImpl) -> hs::NextStateOrError {
+        hs::check_aligned_handshake(sess)?;
+        let suite = sess.common.get_suite_assert();
         let mut ks = self.key_schedule.into_traffic_with_server_finished_pending(None);
         {
             let handshake_hash = &self.handshake.transcript.get_current_hash();
-            emit_finished_tls13(&mut self.handshake, &ks, sess, handshake_hash, false);
+            emit_finished_tls13(&mut self.handshake, sess, handshake_hash,
+                                ks.sign_client_finish(handshake_hash));
         }
         let write_key = ks
             .client_application_traffic_secret(&self.handshake.transcript.get_current_hash(),
                                                &*sess.config.key_log,
                                                &self.handshake.randoms.client);
-        let suite = sess.common.get_suite_assert();
         self.handshake.print_runtime("DERIVED MS");
         sess.common
             .record_layer
@@ -549,15 +784,19 @@ impl ExpectCertificate {
         self.handshake.print_runtime("WRITING TO SERVER");
         sess.common.start_traffic();
 
-        Box::new(ExpectKEMTLSFinished {
+        Ok(Box::new(ExpectKEMTLSFinished {
             handshake: self.handshake,
             key_schedule: ks,
             client_auth: false,
-        })
+        }))
     }
 }
 
 impl hs::State for ExpectCertificate {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::Certificate
+    }
+
     fn handle(mut self: Box<Self>, sess: &mut ClientSessionImpl, m: Message) -> hs::NextStateOrError {
         trace!("trying to parse certificate");
         let cert_chain = require_handshake_msg!(m, HandshakeType::Certificate, HandshakePayload::CertificateTLS13)?;
@@ -588,6 +827,22 @@ impl hs::State for ExpectCertificate {
         self.server_cert.ocsp_response = cert_chain.get_end_entity_ocsp();
         self.server_cert.scts = cert_chain.get_end_entity_scts();
         self.server_cert.cert_chain = cert_chain.convert();
+        hs::check_cert_chain_size(sess, &self.server_cert.cert_chain)?;
+        hs::check_cert_chain_key_algorithms(sess, &self.server_cert.cert_chain)?;
+
+        let stapled = cert_chain.entries.first()
+            .and_then(CertificateEntry::get_ocsp_response)
+            .is_some();
+        if stapled && !sess.config.request_ocsp_response {
+            if sess.config.allowed_unsolicited_extensions.contains(&ExtensionType::StatusRequest) {
+                debug!("Ignoring unsolicited OCSP response");
+                self.server_cert.ocsp_response.clear();
+            } else {
+                sess.common.send_fatal_alert(AlertDescription::UnsupportedExtension);
+                return Err(TLSError::PeerMisbehavedError("server sent unsolicited OCSP response"
+                                                         .to_string()));
+            }
+        }
 
         if let Some(sct_list) = self.server_cert.scts.as_ref() {
             if hs::sct_list_is_invalid(sct_list) {
@@ -602,14 +857,51 @@ impl hs::State for ExpectCertificate {
         }
 
         // branch KEMTLS
-        if eecert.is_kem_cert() {
+        let is_kem_cert = eecert.is_kem_cert();
+        if is_kem_cert {
+            if let Some(typ) = cert_chain.end_entity_extension_not_in(KEM_CERT_ENTRY_EXTENSIONS) {
+                warn!("KEM certificate entry has {:?} extension", typ);
+                sess.common.send_fatal_alert(AlertDescription::UnsupportedExtension);
+                return Err(TLSError::PeerMisbehavedError(
+                    format!("server sent {:?} extension for KEM certificate", typ)));
+            }
+        }
+
+        if !sess.config.auth_mode.accepts(is_kem_cert) {
+            sess.common.send_fatal_alert(AlertDescription::UnsupportedCertificate);
+            let why = if is_kem_cert {
+                "server sent KEM certificate but signature authentication is required"
+            } else {
+                "server sent signing certificate but KEMTLS authentication is required"
+            };
+            return Err(TLSError::PeerIncompatibleError(why.to_string()));
+        }
+
+        if is_kem_cert {
+            let min_level = sess.config.min_auth_kem_level;
+            let scheme = verify::kem_cert_scheme(&self.server_cert.cert_chain[0]);
+            let level = scheme.and_then(verify::KemSecurityLevel::of_kem_scheme);
+            if min_level > verify::KemSecurityLevel::Level1 && level.map_or(true, |level| level < min_level) {
+                sess.common.send_fatal_alert(AlertDescription::InsufficientSecurity);
+                return Err(TLSError::PeerIncompatibleError(
+                    format!("server's KEM certificate is {:?}, below {:?}", level, min_level)));
+            }
+
+            // We encapsulate to the certificate's own KEM, whichever
+            // group the ephemeral key exchange used.
             self.emit_ciphertext(sess, eecert)?;
+            sess.server_auth_kem = scheme;
+
+            // Remember the chain, so resumed sessions can tell which
+            // certificate they authenticated.
+            sess.server_cert_chain = self.server_cert.take_chain();
+            sess.server_ocsp_response = self.server_cert.take_ocsp_response();
             if let Some(client_auth) = self.client_auth.as_mut() {
                 self.key_schedule.assert_authenticated();
                 emit_certificate_tls13(&mut self.handshake, client_auth, sess);
                 Ok(self.into_expect_ciphertext())
             } else {
-                Ok(self.emit_finished_and_into_expect_server_finished(sess))
+                self.emit_finished_and_into_expect_server_finished(sess)
             }
         } else {
             Ok(self.into_expect_certificate_verify())
@@ -644,6 +936,10 @@ impl ExpectCertificateOrCertReq {
 }
 
 impl hs::State for ExpectCertificateOrCertReq {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::CertificateOrCertificateRequest
+    }
+
     fn handle(self: Box<Self>, sess: &mut ClientSessionImpl, m: Message) -> hs::NextStateOrError {
         check_message(&m,
                       &[ContentType::Handshake],
@@ -665,9 +961,9 @@ struct ExpectCiphertext {
 }
 
 impl ExpectCiphertext {
-    fn into_expect_finished(mut self, sess: &mut ClientSessionImpl, shared_secret: &[u8]) -> hs::NextState {
+    fn into_expect_finished(mut self, sess: &mut ClientSessionImpl, shared_secret: &[u8]) -> hs::NextStateOrError {
         if self.is_pdk {
-            Box::new(ExpectFinished {
+            Ok(Box::new(ExpectFinished {
                 handshake: self.handshake,
                 key_schedule: self.key_schedule,
                 cert_verified: verify::ServerCertVerified::assertion(),
@@ -675,18 +971,20 @@ impl ExpectCiphertext {
                 is_pdk: true,
                 client_auth: Some(self.client_auth),
                 client_auth_shared_secret: Some(shared_secret.to_vec()),
-            })
+            }))
         } else {
+            hs::check_aligned_handshake(sess)?;
+            let suite = sess.common.get_suite_assert();
             let mut ks = self.key_schedule.into_traffic_with_server_finished_pending(Some(shared_secret));
             {
                 let handshake_hash = &self.handshake.transcript.get_current_hash();
-                emit_finished_tls13(&mut self.handshake, &ks, sess, handshake_hash, false);
+                emit_finished_tls13(&mut self.handshake, sess, handshake_hash,
+                                    ks.sign_client_finish(handshake_hash));
             }
             let write_key = ks
                 .client_application_traffic_secret(&self.handshake.transcript.get_current_hash(),
                                                 &*sess.config.key_log,
                                                 &self.handshake.randoms.client);
-            let suite = sess.common.get_suite_assert();
             sess.common
                 .record_layer
                 .set_message_encrypter(cipher::new_tls13_write(suite, &write_key));
@@ -694,16 +992,20 @@ impl ExpectCiphertext {
             self.handshake.print_runtime("WRITING TO SERVER");
             sess.common.start_traffic();
 
-            Box::new(ExpectKEMTLSFinished {
+            Ok(Box::new(ExpectKEMTLSFinished {
                 handshake: self.handshake,
                 key_schedule: ks,
                 client_auth: true,
-            })
+            }))
         }
     }
 }
 
 impl hs::State for ExpectCiphertext {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::KemCiphertext
+    }
+
     fn handle(mut self: Box<Self>, sess: &mut ClientSessionImpl, m: Message) -> hs::NextStateOrError {
         let msg = require_handshake_msg!(m, HandshakeType::ClientKemCiphertext, HandshakePayload::ClientKemCiphertext)?;
 
@@ -711,14 +1013,25 @@ impl hs::State for ExpectCiphertext {
         self.handshake.print_runtime("AUTHENTICATED SERVER");
 
         let ciphertext = &msg.0;
+        if self.client_auth.kem_ciphertext_len != Some(ciphertext.len()) {
+            sess.common.send_fatal_alert(AlertDescription::DecodeError);
+            return Err(TLSError::KemtlsFlightFailed(KemtlsStep::CiphertextLength));
+        }
         let cert = self.client_auth.cert.take().unwrap();
         let eecert = webpki::EndEntityCert::from(&cert[0].0).map_err(TLSError::WebPKIError)?;
+        sess.charge_work(1)?;
         self.handshake.print_runtime("DECAPSULATING FROM CCERT");
-        let ss= eecert.decapsulate(&self.client_auth.private_key.take().unwrap(), ciphertext).map_err(TLSError::WebPKIError)?;
+        suites::count_cert_kem_op(suites::KemOp::Decapsulate);
+        let ss = eecert.decapsulate(&self.client_auth.private_key.take().unwrap(), ciphertext)
+            .map_err(|err| {
+                debug!("KEMTLS decapsulation failed: {:?}", err);
+                sess.common.send_fatal_alert(AlertDescription::DecryptError);
+                TLSError::KemtlsFlightFailed(KemtlsStep::Decapsulate)
+            })?;
         self.handshake.print_runtime("DECAPSULATED FROM CCERT");
 
         self.handshake.transcript.add_message(&m);
-        Ok(self.into_expect_finished(sess, &ss))
+        self.into_expect_finished(sess, &ss)
     }
 }
 
@@ -763,25 +1076,42 @@ fn send_cert_error_alert(sess: &mut ClientSessionImpl, err: TLSError) -> TLSErro
 }
 
 impl hs::State for ExpectCertificateVerify {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::CertificateVerify
+    }
+
     fn handle(mut self: Box<Self>, sess: &mut ClientSessionImpl, m: Message) -> hs::NextStateOrError {
         let cert_verify = require_handshake_msg!(m, HandshakeType::CertificateVerify, HandshakePayload::CertificateVerify)?;
 
         trace!("Server cert is {:?}", self.server_cert.cert_chain);
 
+        if let Some(max_size) = sess.config.max_signature_size {
+            if cert_verify.sig.0.len() > max_size {
+                sess.common.send_fatal_alert(AlertDescription::HandshakeFailure);
+                return Err(TLSError::PeerIncompatibleError(
+                    format!("server signature of {} bytes exceeds limit of {} bytes",
+                            cert_verify.sig.0.len(), max_size)));
+            }
+        }
+
         // 1. Verify the certificate chain.
         if self.server_cert.cert_chain.is_empty() {
             return Err(TLSError::NoCertificatesPresented);
         }
 
+        sess.charge_work(self.server_cert.cert_chain.len() + 1)?;
+
         let certv = sess.config
-            .get_verifier()
-            .verify_server_cert(&sess.config.root_store,
-                                &self.server_cert.cert_chain,
+            .verify_server_cert(&self.server_cert.cert_chain,
                                 self.handshake.dns_name.as_ref(),
                                 &self.server_cert.ocsp_response)
             .map_err(|err| send_cert_error_alert(sess, err))?;
 
         // 2. Verify their signature on the handshake.
+        if !sess.config.get_verify_schemes().contains(&cert_verify.scheme) {
+            return Err(hs::illegal_param(sess, "server used signature scheme we did not offer"));
+        }
+
         let handshake_hash = self.handshake.transcript.get_current_hash();
         let sigv = sess.config
             .get_verifier()
@@ -789,18 +1119,21 @@ impl hs::State for ExpectCertificateVerify {
                                     &self.server_cert.cert_chain[0],
                                     &cert_verify)
             .map_err(|err| send_cert_error_alert(sess, err))?;
+        sess.peer_signature_scheme = Some(cert_verify.scheme);
 
         // 3. Verify any included SCTs.
         match (self.server_cert.scts.as_ref(), sess.config.ct_logs) {
             (Some(scts), Some(logs)) => {
                 verify::verify_scts(&self.server_cert.cert_chain[0],
                                     scts,
-                                    logs)?;
+                                    logs)
+                    .map_err(|err| hs::send_sct_error_alert(sess, err))?;
             }
             (_, _) => {}
         }
 
         sess.server_cert_chain = self.server_cert.take_chain();
+        sess.server_ocsp_response = self.server_cert.take_ocsp_response();
         self.handshake.transcript.add_message(&m);
 
         self.handshake.print_runtime("AUTHENTICATED SERVER");
@@ -830,6 +1163,10 @@ impl ExpectCertificateRequest {
 }
 
 impl hs::State for ExpectCertificateRequest {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::CertificateRequest
+    }
+
     fn handle(mut self: Box<Self>, sess: &mut ClientSessionImpl, m: Message) -> hs::NextStateOrError {
         let certreq = &require_handshake_msg!(m, HandshakeType::CertificateRequest, HandshakePayload::CertificateRequestTLS13)?;
         self.handshake.transcript.add_message(&m);
@@ -845,47 +1182,58 @@ impl hs::State for ExpectCertificateRequest {
             return Err(TLSError::CorruptMessagePayload(ContentType::Handshake));
         }
 
-        let tls13_sign_schemes = sign::supported_sign_tls13();
-        let no_sigschemes = Vec::new();
-        let compat_sigschemes = certreq.get_sigalgs_extension()
-            .unwrap_or(&no_sigschemes)
-            .iter()
-            .cloned()
-            .filter(|scheme| tls13_sign_schemes.contains(scheme))
-            .collect::<Vec<SignatureScheme>>();
-
-        if compat_sigschemes.is_empty() {
-            sess.common.send_fatal_alert(AlertDescription::HandshakeFailure);
-            return Err(TLSError::PeerIncompatibleError("server sent bad certreq schemes".to_string()));
-        }
-
-        let no_canames = Vec::new();
-        let canames = certreq.get_authorities_extension()
-            .unwrap_or(&no_canames)
-            .iter()
-            .map(|p| p.0.as_slice())
-            .collect::<Vec<&[u8]>>();
-        let maybe_certkey =
-            sess.config.client_auth_cert_resolver.resolve(&canames, &compat_sigschemes);
+        let client_auth = resolve_client_auth(sess, certreq)?;
+        Ok(self.into_expect_certificate(client_auth))
+    }
+}
 
-        let mut client_auth = ClientAuthDetails::new();
-        if let Some(mut certkey) = maybe_certkey {
-            debug!("Attempting client auth");
-            if certkey.key.algorithm() == SignatureAlgorithm::KEMTLS {
-                client_auth.private_key = Some(certkey.key.get_bytes().to_vec());
-            } else {
-                let maybe_signer = certkey.key.choose_scheme(&compat_sigschemes);
-                client_auth.signer = maybe_signer;
-            }
-            client_auth.cert = Some(certkey.take_cert());
-            client_auth.auth_context = Some(certreq.context.0.clone());
+/// Choose how to answer `certreq`, during or after the handshake.
+fn resolve_client_auth(sess: &mut ClientSessionImpl,
+                       certreq: &CertificateRequestPayloadTLS13) -> Result<ClientAuthDetails, TLSError> {
+    let tls13_sign_schemes = sign::supported_sign_tls13();
+    let no_sigschemes = Vec::new();
+    let compat_sigschemes = certreq.get_sigalgs_extension()
+        .unwrap_or(&no_sigschemes)
+        .iter()
+        .cloned()
+        .filter(|scheme| tls13_sign_schemes.contains(scheme))
+        .collect::<Vec<SignatureScheme>>();
+
+    if compat_sigschemes.is_empty() {
+        sess.common.send_fatal_alert(AlertDescription::HandshakeFailure);
+        return Err(TLSError::PeerIncompatibleError("server sent bad certreq schemes".to_string()));
+    }
 
+    let no_canames = Vec::new();
+    let canames = certreq.get_authorities_extension()
+        .unwrap_or(&no_canames)
+        .iter()
+        .map(|p| p.0.as_slice())
+        .collect::<Vec<&[u8]>>();
+    let offers_kem = compat_sigschemes.iter()
+        .any(|scheme| sign::supported_kem_schemes().contains(scheme));
+    let resolver = &sess.config.client_auth_cert_resolver;
+    let maybe_certkey = resolver.resolve(&canames, &compat_sigschemes)
+        .or_else(|| if offers_kem { resolver.resolve_kem(&canames) } else { None });
+
+    let mut client_auth = ClientAuthDetails::new();
+    if let Some(mut certkey) = maybe_certkey {
+        debug!("Attempting client auth");
+        if certkey.key.algorithm() == SignatureAlgorithm::KEMTLS {
+            client_auth.private_key = Some(certkey.key.get_bytes().to_vec());
+            client_auth.kem_ciphertext_len = certkey.key.kem_ciphertext_len();
         } else {
-            debug!("Client auth requested but no cert selected");
+            let maybe_signer = certkey.key.choose_scheme(&compat_sigschemes);
+            client_auth.signer = maybe_signer;
         }
+        client_auth.cert = Some(certkey.take_cert());
+        client_auth.auth_context = Some(certreq.context.0.clone());
 
-        Ok(self.into_expect_certificate(client_auth))
+    } else {
+        debug!("Client auth requested but no cert selected");
     }
+
+    Ok(client_auth)
 }
 
 pub fn emit_certificate_tls13(handshake: &mut HandshakeDetails,
@@ -953,15 +1301,9 @@ fn emit_certverify_tls13(handshake: &mut HandshakeDetails,
 }
 
 fn emit_finished_tls13(handshake: &mut HandshakeDetails,
-                       key_schedule: &dyn KeyScheduleComputesClientFinish,
                        sess: &mut ClientSessionImpl,
                        handshake_hash: &[u8],
-                       is_pdk: bool) {
-    let verify_data = if is_pdk {
-        key_schedule.sign_client_finished_kemtlspdk(handshake_hash)
-    } else { 
-        key_schedule.sign_client_finish(handshake_hash)
-    };
+                       verify_data: Vec<u8>) {
     let verify_data_payload = Payload::new(verify_data);
 
     trace!("Sending finished for hash: {:x?}", handshake_hash);
@@ -998,6 +1340,30 @@ fn emit_end_of_early_data_tls13(handshake: &mut HandshakeDetails,
     sess.common.send_msg(m, true);
 }
 
+/// Check the server's Finished `received` against `expected`, computed
+/// over `handshake_hash`.  On mismatch, the values are reported to a
+/// configured `KeyScheduleTrace` so a transcript divergence can be
+/// diagnosed; they are never logged.
+#[cfg_attr(not(feature = "dangerous_configuration"), allow(unused_variables))]
+fn verify_server_finished(sess: &mut ClientSessionImpl,
+                          handshake_hash: &[u8],
+                          expected: &[u8],
+                          received: &[u8]) -> Result<verify::FinishedMessageVerified, TLSError> {
+    constant_time::verify_slices_are_equal(expected, received)
+        .map_err(|_| {
+            debug!("Server Finished mismatch");
+            #[cfg(feature = "dangerous_configuration")]
+            {
+                if let Some(trace) = &sess.config.key_schedule_trace {
+                    trace.finished_mismatch(handshake_hash, expected, received);
+                }
+            }
+            sess.common.send_fatal_alert(AlertDescription::DecryptError);
+            TLSError::DecryptError
+        })
+        .map(|_| verify::FinishedMessageVerified::assertion())
+}
+
 struct ExpectFinished {
     handshake: HandshakeDetails,
     key_schedule: KeyScheduleHandshake,
@@ -1018,6 +1384,9 @@ impl ExpectFinished {
             handshake,
             key_schedule,
             want_write_key_update: false,
+            tickets_stored: 0,
+            ticket_nonces: SeenTicketNonces::new(),
+            pending_kem_auth: None,
             _cert_verified: cert_verified,
             _sig_verified: sig_verified,
             _fin_verified: fin_verified,
@@ -1026,8 +1395,23 @@ impl ExpectFinished {
 }
 
 impl hs::State for ExpectFinished {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::Finished
+    }
+
     fn handle(self: Box<Self>, sess: &mut ClientSessionImpl, m: Message) -> hs::NextStateOrError {
         let mut st = *self;
+
+        // A server that accepted our PSK has already authenticated itself
+        // by knowing it; it can't then go on to authenticate afresh.
+        if sess.resumed &&
+            (m.is_handshake_type(HandshakeType::Certificate) ||
+             m.is_handshake_type(HandshakeType::CertificateRequest)) {
+            sess.common.send_fatal_alert(AlertDescription::UnexpectedMessage);
+            return Err(TLSError::PeerMisbehavedError(
+                "server resumed a session but then sent a certificate".to_string()));
+        }
+
         let finished = require_handshake_msg!(m, HandshakeType::Finished, HandshakePayload::Finished)?;
         st.handshake.print_runtime("RECEIVED FINISHED");
 
@@ -1054,12 +1438,10 @@ impl hs::State for ExpectFinished {
             (ks.sign_server_finished_kemtlspdk(&handshake_hash), ks)
         };
 
-        let fin = constant_time::verify_slices_are_equal(&expect_verify_data, &finished.0)
-            .map_err(|_| {
-                         sess.common.send_fatal_alert(AlertDescription::DecryptError);
-                         TLSError::DecryptError
-                    })
-            .map(|_| verify::FinishedMessageVerified::assertion())?;
+        let fin = verify_server_finished(sess, &handshake_hash, &expect_verify_data, &finished.0)?;
+
+        /* Our flight ends in a move to our application traffic keys. */
+        hs::check_aligned_handshake(sess)?;
 
         st.handshake.transcript.add_message(&m);
         trace!("AUTHENTICATED SERVER");
@@ -1098,10 +1480,12 @@ impl hs::State for ExpectFinished {
         let mut key_schedule_finished = key_schedule;
         // use hash_after_handshake, which is equal to the current hash in KEMTLS(PDK)
         // and equal to the SFIN hash in TLS 1.3
-        emit_finished_tls13(&mut st.handshake, &key_schedule_finished, sess, &hash_after_handshake, st.is_pdk);
-
-        /* Now move to our application traffic keys. */
-        hs::check_aligned_handshake(sess)?;
+        let verify_data = if st.is_pdk {
+            key_schedule_finished.sign_client_finished_kemtlspdk(&hash_after_handshake)
+        } else {
+            key_schedule_finished.sign_client_finish(&hash_after_handshake)
+        };
+        emit_finished_tls13(&mut st.handshake, sess, &hash_after_handshake, verify_data);
 
         /* Traffic from server is now decrypted with application data keys. */
         let read_key = key_schedule_finished
@@ -1169,6 +1553,9 @@ impl ExpectKEMTLSFinished {
             handshake: self.handshake,
             key_schedule: self.key_schedule.into_traffic(),
             want_write_key_update: false,
+            tickets_stored: 0,
+            ticket_nonces: SeenTicketNonces::new(),
+            pending_kem_auth: None,
             _cert_verified: verify::ServerCertVerified::assertion(),
             _sig_verified: verify::HandshakeSignatureValid::assertion(),
             _fin_verified: fin,
@@ -1177,6 +1564,10 @@ impl ExpectKEMTLSFinished {
 }
 
 impl hs::State for ExpectKEMTLSFinished {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::KemtlsFinished
+    }
+
     fn handle(mut self: Box<Self>, sess: &mut ClientSessionImpl, m: Message) -> hs::NextStateOrError {
         let finished = require_handshake_msg!(m, HandshakeType::Finished, HandshakePayload::Finished)?;
         self.handshake.print_runtime("RECEIVED FINISHED");
@@ -1184,12 +1575,8 @@ impl hs::State for ExpectKEMTLSFinished {
         let handshake_hash = self.handshake.transcript.get_current_hash();
         let expect_verify_data = self.key_schedule.sign_server_finish(&handshake_hash);
 
-        let fin = constant_time::verify_slices_are_equal(&expect_verify_data, &finished.0)
-            .map_err(|_| {
-                         sess.common.send_fatal_alert(AlertDescription::DecryptError);
-                         TLSError::DecryptError
-                    })
-            .map(|_| verify::FinishedMessageVerified::assertion())?;
+        let fin = verify_server_finished(sess, &handshake_hash, &expect_verify_data, &finished.0)
+            .map_err(|_| TLSError::KemtlsFlightFailed(KemtlsStep::Finished))?;
         self.handshake.transcript.add_message(&m);
 
         if !self.client_auth {
@@ -1218,20 +1605,165 @@ impl hs::State for ExpectKEMTLSFinished {
     }
 }
 
+/// A post-handshake authentication with a KEM certificate, waiting
+/// for the server's ciphertext.
+struct PendingKemAuth {
+    transcript: HandshakeHash,
+    client_auth: ClientAuthDetails,
+}
+
 // -- Traffic transit state (TLS1.3) --
 // In this state we can be sent tickets, keyupdates,
 // and application data.
+/// How many ticket nonces `SeenTicketNonces` remembers.
+const MAX_SEEN_TICKET_NONCES: usize = 16;
+
+/// The nonces of the most recent NewSessionTickets on a connection.
+/// Tickets with the same nonce get the same resumption secret, so a
+/// repeat suggests a broken server.
+struct SeenTicketNonces(Vec<Vec<u8>>);
+
+impl SeenTicketNonces {
+    fn new() -> SeenTicketNonces {
+        SeenTicketNonces(Vec::new())
+    }
+
+    /// Remember `nonce`.  Returns false if it was seen recently.
+    fn insert(&mut self, nonce: &[u8]) -> bool {
+        if self.0.iter().any(|seen| seen.as_slice() == nonce) {
+            return false;
+        }
+
+        if self.0.len() >= MAX_SEEN_TICKET_NONCES {
+            self.0.remove(0);
+        }
+        self.0.push(nonce.to_vec());
+        true
+    }
+}
+
 struct ExpectTraffic {
     handshake: HandshakeDetails,
     key_schedule: KeyScheduleTraffic,
     want_write_key_update: bool,
+    tickets_stored: usize,
+    ticket_nonces: SeenTicketNonces,
+    pending_kem_auth: Option<PendingKemAuth>,
     _cert_verified: verify::ServerCertVerified,
     _sig_verified: verify::HandshakeSignatureValid,
     _fin_verified: verify::FinishedMessageVerified,
 }
 
 impl ExpectTraffic {
+    fn handle_certificate_request(&mut self,
+                                  sess: &mut ClientSessionImpl,
+                                  m: &Message,
+                                  certreq: &CertificateRequestPayloadTLS13) -> Result<(), TLSError> {
+        if !sess.config.enable_post_handshake_auth {
+            sess.common.send_fatal_alert(AlertDescription::UnexpectedMessage);
+            return Err(TLSError::PeerMisbehavedError("server sent unsolicited post-handshake CertificateRequest"
+                                                     .to_string()));
+        }
+
+        // Must be non-empty after the handshake.
+        if certreq.context.0.is_empty() {
+            warn!("Server sent empty post-handshake certreq context");
+            sess.common.send_fatal_alert(AlertDescription::DecodeError);
+            return Err(TLSError::CorruptMessagePayload(ContentType::Handshake));
+        }
+
+        if self.pending_kem_auth.is_some() {
+            sess.common.send_fatal_alert(AlertDescription::UnexpectedMessage);
+            return Err(TLSError::PeerMisbehavedError("server sent CertificateRequest during post-handshake auth"
+                                                     .to_string()));
+        }
+
+        let mut client_auth = resolve_client_auth(sess, certreq)?;
+        client_auth.auth_context = Some(certreq.context.0.clone());
+
+        // Each post-handshake authentication's transcript continues from
+        // the end of the handshake, not from any previous authentication.
+        let handshake_transcript = self.handshake.transcript.clone();
+        self.handshake.transcript.add_message(m);
+        emit_certificate_tls13(&mut self.handshake, &mut client_auth, sess);
+
+        if client_auth.private_key.is_some() {
+            let transcript = mem::replace(&mut self.handshake.transcript, handshake_transcript);
+            self.pending_kem_auth = Some(PendingKemAuth { transcript, client_auth });
+            return Ok(());
+        }
+
+        emit_certverify_tls13(&mut self.handshake, &mut client_auth, sess)?;
+        let handshake_hash = self.handshake.transcript.get_current_hash();
+        self.handshake.transcript = handshake_transcript;
+        self.emit_post_handshake_finished(sess, None, &handshake_hash);
+        Ok(())
+    }
+
+    fn handle_kem_ciphertext(&mut self,
+                             sess: &mut ClientSessionImpl,
+                             m: &Message,
+                             ciphertext: &Payload) -> Result<(), TLSError> {
+        let mut pending = match self.pending_kem_auth.take() {
+            Some(pending) => pending,
+            None => {
+                sess.common.send_fatal_alert(AlertDescription::UnexpectedMessage);
+                return Err(TLSError::PeerMisbehavedError("server sent unexpected KEM ciphertext"
+                                                         .to_string()));
+            }
+        };
+
+        if pending.client_auth.kem_ciphertext_len != Some(ciphertext.0.len()) {
+            sess.common.send_fatal_alert(AlertDescription::DecodeError);
+            return Err(TLSError::KemtlsFlightFailed(KemtlsStep::CiphertextLength));
+        }
+
+        let cert = pending.client_auth.cert.take().unwrap();
+        let eecert = webpki::EndEntityCert::from(&cert[0].0).map_err(TLSError::WebPKIError)?;
+        sess.charge_work(1)?;
+        suites::count_cert_kem_op(suites::KemOp::Decapsulate);
+        let ss = eecert.decapsulate(&pending.client_auth.private_key.take().unwrap(), &ciphertext.0)
+            .map_err(|err| {
+                debug!("KEMTLS decapsulation failed: {:?}", err);
+                sess.common.send_fatal_alert(AlertDescription::DecryptError);
+                TLSError::KemtlsFlightFailed(KemtlsStep::Decapsulate)
+            })?;
+
+        pending.transcript.add_message(m);
+        let handshake_hash = pending.transcript.get_current_hash();
+        self.emit_post_handshake_finished(sess, Some(ss.as_ref()), &handshake_hash);
+        Ok(())
+    }
+
+    fn emit_post_handshake_finished(&self,
+                                    sess: &mut ClientSessionImpl,
+                                    ss: Option<&[u8]>,
+                                    handshake_hash: &[u8]) {
+        let verify_data = self.key_schedule.sign_client_post_handshake_finish(ss, handshake_hash);
+        let m = Message {
+            typ: ContentType::Handshake,
+            version: ProtocolVersion::TLSv1_3,
+            payload: MessagePayload::Handshake(HandshakeMessagePayload {
+                typ: HandshakeType::Finished,
+                payload: HandshakePayload::Finished(Payload::new(verify_data)),
+            }),
+        };
+
+        sess.common.send_msg(m, true);
+    }
+
     fn handle_new_ticket_tls13(&mut self, sess: &mut ClientSessionImpl, nst: &NewSessionTicketPayloadTLS13) -> Result<(), TLSError> {
+        if !self.ticket_nonces.insert(&nst.nonce.0) {
+            warn!("Server reused ticket nonce {:?}: its tickets share a resumption secret",
+                  nst.nonce.0);
+        }
+
+        if self.tickets_stored >= sess.config.max_tickets_stored {
+            debug!("Ticket dropped: already stored {} for this connection",
+                   self.tickets_stored);
+            return Ok(());
+        }
+
         let handshake_hash = self.handshake.transcript.get_current_hash();
         let secret = self.key_schedule
             .resumption_master_secret_and_derive_ticket_psk(&handshake_hash, &nst.nonce.0);
@@ -1245,6 +1777,9 @@ impl ExpectTraffic {
         value.set_times(ticketer::timebase(),
                         nst.lifetime,
                         nst.age_add);
+        if let Some(group) = self.handshake.kx_group {
+            value.set_kx_group(group);
+        }
 
         if let Some(sz) = nst.get_max_early_data_size() {
             value.set_max_early_data_size(sz);
@@ -1272,6 +1807,8 @@ impl ExpectTraffic {
 
         if worked {
             debug!("Ticket saved");
+            sess.config.session_saved(self.handshake.dns_name.as_ref());
+            self.tickets_stored += 1;
         } else {
             debug!("Ticket not saved");
         }
@@ -1291,6 +1828,7 @@ impl ExpectTraffic {
 
         // Mustn't be interleaved with other handshake messages.
         hs::check_aligned_handshake(sess)?;
+        sess.charge_work(1)?;
 
         match kur {
             KeyUpdateRequest::UpdateNotRequested => {}
@@ -1313,6 +1851,10 @@ impl ExpectTraffic {
 }
 
 impl hs::State for ExpectTraffic {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::Traffic
+    }
+
     fn handle(mut self: Box<Self>, sess: &mut ClientSessionImpl, mut m: Message) -> hs::NextStateOrError {
         if m.is_content_type(ContentType::ApplicationData) {
             self.handshake.print_runtime("RECEIVED SERVER REPLY");
@@ -1321,10 +1863,15 @@ impl hs::State for ExpectTraffic {
             self.handle_new_ticket_tls13(sess, new_ticket)?;
         } else if let Ok(ref key_update) = require_handshake_msg!(m, HandshakeType::KeyUpdate, HandshakePayload::KeyUpdate) {
             self.handle_key_update(sess, key_update)?;
+        } else if let Ok(certreq) = require_handshake_msg!(m, HandshakeType::CertificateRequest, HandshakePayload::CertificateRequestTLS13) {
+            self.handle_certificate_request(sess, &m, certreq)?;
+        } else if let Ok(ciphertext) = require_handshake_msg!(m, HandshakeType::ClientKemCiphertext, HandshakePayload::ClientKemCiphertext) {
+            self.handle_kem_ciphertext(sess, &m, ciphertext)?;
         } else {
             check_message(&m,
                           &[ContentType::ApplicationData, ContentType::Handshake],
-                          &[HandshakeType::NewSessionTicket, HandshakeType::KeyUpdate])?;
+                          &[HandshakeType::NewSessionTicket, HandshakeType::KeyUpdate,
+                            HandshakeType::CertificateRequest, HandshakeType::ClientKemCiphertext])?;
         }
 
         Ok(self)
@@ -1337,6 +1884,10 @@ impl hs::State for ExpectTraffic {
         self.key_schedule.export_keying_material(output, label, context)
     }
 
+    fn transcript_hash(&self) -> Option<Vec<u8>> {
+        Some(self.handshake.transcript.get_current_hash())
+    }
+
     fn perhaps_write_key_update(&mut self, sess: &mut ClientSessionImpl) {
         if self.want_write_key_update {
             self.want_write_key_update = false;
@@ -1347,6 +1898,32 @@ impl hs::State for ExpectTraffic {
             sess.common.record_layer.set_message_encrypter(cipher::new_tls13_write(scs, &write_key));
         }
     }
+
+    fn send_key_update(&mut self,
+                       sess: &mut ClientSessionImpl,
+                       update_requested: bool) -> Result<(), TLSError> {
+        #[cfg(feature = "quic")]
+        {
+            if let Protocol::Quic = sess.common.protocol {
+                return Err(TLSError::General("KeyUpdate not allowed in QUIC connection".to_string()));
+            }
+        }
+
+        let kur = if update_requested {
+            KeyUpdateRequest::UpdateRequested
+        } else {
+            KeyUpdateRequest::UpdateNotRequested
+        };
+
+        // This also answers any KeyUpdate the server asked us for.
+        self.want_write_key_update = false;
+        sess.common.send_msg_encrypt(Message::build_key_update(kur));
+
+        let write_key = self.key_schedule.next_client_application_traffic_secret();
+        let scs = sess.common.get_suite_assert();
+        sess.common.record_layer.set_message_encrypter(cipher::new_tls13_write(scs, &write_key));
+        Ok(())
+    }
 }
 
 #[cfg(feature = "quic")]
@@ -1354,6 +1931,10 @@ pub struct ExpectQUICTraffic(ExpectTraffic);
 
 #[cfg(feature = "quic")]
 impl hs::State for ExpectQUICTraffic {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::QuicTraffic
+    }
+
     fn handle(mut self: Box<Self>, sess: &mut ClientSessionImpl, m: Message) -> hs::NextStateOrError {
         let nst = require_handshake_msg!(m, HandshakeType::NewSessionTicket, HandshakePayload::NewSessionTicketTLS13)?;
         self.0.handle_new_ticket_tls13(sess, nst)?;
@@ -1366,4 +1947,704 @@ impl hs::State for ExpectQUICTraffic {
                               context: Option<&[u8]>) -> Result<(), TLSError> {
         self.0.export_keying_material(output, label, context)
     }
+
+    fn transcript_hash(&self) -> Option<Vec<u8>> {
+        self.0.transcript_hash()
+    }
+}
+
+/// Fixtures for driving one client state at a time, with no server
+/// and no I/O.  Each `expect_*` function makes a state as if the
+/// handshake had just reached it, and the message builders make the
+/// messages its `handle` consumes.
+///
+/// Key schedules are derived from all-zero secrets and transcripts
+/// start empty, so MACs and signatures from a real server won't
+/// verify: these are for exercising checks and error paths.
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use super::*;
+    use crate::client::ClientConfig;
+    use crate::keylog::NoKeyLog;
+    use crate::key;
+    use crate::msgs::enums::AlertLevel;
+    use crate::msgs::handshake::CertReqExtension;
+    use std::sync::Arc;
+
+    /// A session which has negotiated TLS1.3 with
+    /// TLS13_AES_128_GCM_SHA256.
+    pub fn session(config: ClientConfig) -> ClientSessionImpl {
+        let mut sess = ClientSessionImpl::new(&Arc::new(config));
+        sess.common.negotiated_version = Some(ProtocolVersion::TLSv1_3);
+        sess.common.set_suite(&suites::TLS13_AES_128_GCM_SHA256);
+        sess
+    }
+
+    pub fn handshake_details() -> HandshakeDetails {
+        let dns_name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let mut handshake = HandshakeDetails::new(dns_name.into(), Vec::new());
+        handshake.transcript.start_hash(&ring::digest::SHA256);
+        handshake
+    }
+
+    /// A key schedule with both handshake traffic secrets derived.
+    pub fn handshake_key_schedule() -> KeyScheduleHandshake {
+        let (hash, random) = ([0u8; 32], [0u8; 32]);
+        let mut key_schedule = KeyScheduleNonSecret::new(ring::hkdf::HKDF_SHA256)
+            .into_handshake(&[0u8; 32]);
+        key_schedule.client_handshake_traffic_secret(&hash, &NoKeyLog, &random);
+        key_schedule.server_handshake_traffic_secret(&hash, &NoKeyLog, &random);
+        key_schedule
+    }
+
+    /// A key schedule with the application traffic and exporter
+    /// secrets derived.
+    pub fn traffic_key_schedule() -> KeyScheduleTraffic {
+        let (hash, random) = ([0u8; 32], [0u8; 32]);
+        let mut key_schedule = handshake_key_schedule().into_traffic_with_client_finished_pending();
+        key_schedule.server_application_traffic_secret(&hash, &NoKeyLog, &random);
+        key_schedule.client_application_traffic_secret(&hash, &NoKeyLog, &random);
+        key_schedule.exporter_master_secret(&hash, &NoKeyLog, &random);
+        key_schedule.into_traffic()
+    }
+
+    pub fn server_cert_details(chain: &[key::Certificate]) -> ServerCertDetails {
+        let mut server_cert = ServerCertDetails::new();
+        server_cert.cert_chain = chain.to_vec();
+        server_cert
+    }
+
+    pub fn expect_encrypted_extensions() -> ExpectEncryptedExtensions {
+        ExpectEncryptedExtensions {
+            handshake: handshake_details(),
+            key_schedule: handshake_key_schedule(),
+            server_cert: ServerCertDetails::new(),
+            hello: ClientHelloDetails::new(),
+            is_pdk: false,
+            client_auth: None,
+        }
+    }
+
+    pub fn expect_certificate_or_cert_req() -> ExpectCertificateOrCertReq {
+        ExpectCertificateOrCertReq {
+            handshake: handshake_details(),
+            key_schedule: handshake_key_schedule(),
+            server_cert: ServerCertDetails::new(),
+        }
+    }
+
+    pub fn expect_certificate() -> ExpectCertificate {
+        ExpectCertificate {
+            handshake: handshake_details(),
+            key_schedule: handshake_key_schedule(),
+            server_cert: ServerCertDetails::new(),
+            client_auth: None,
+        }
+    }
+
+    /// Expecting a CertificateVerify, having received `chain`.
+    pub fn expect_certificate_verify(chain: &[key::Certificate]) -> ExpectCertificateVerify {
+        ExpectCertificateVerify {
+            handshake: handshake_details(),
+            key_schedule: handshake_key_schedule(),
+            server_cert: server_cert_details(chain),
+            client_auth: None,
+        }
+    }
+
+    pub fn expect_certificate_request() -> ExpectCertificateRequest {
+        ExpectCertificateRequest {
+            handshake: handshake_details(),
+            key_schedule: handshake_key_schedule(),
+            server_cert: ServerCertDetails::new(),
+        }
+    }
+
+    /// Expecting the server's KEMTLS ciphertext, having sent a KEM
+    /// client certificate whose ciphertexts are `ciphertext_len` bytes.
+    pub fn expect_ciphertext(ciphertext_len: usize) -> ExpectCiphertext {
+        let mut client_auth = ClientAuthDetails::new();
+        client_auth.kem_ciphertext_len = Some(ciphertext_len);
+        ExpectCiphertext {
+            handshake: handshake_details(),
+            key_schedule: handshake_key_schedule(),
+            client_auth,
+            is_pdk: false,
+        }
+    }
+
+    pub fn expect_kemtls_finished() -> ExpectKEMTLSFinished {
+        ExpectKEMTLSFinished {
+            handshake: handshake_details(),
+            key_schedule: handshake_key_schedule().into_traffic_with_server_finished_pending(None),
+            client_auth: false,
+        }
+    }
+
+    pub fn expect_finished() -> ExpectFinished {
+        ExpectFinished {
+            handshake: handshake_details(),
+            key_schedule: handshake_key_schedule(),
+            client_auth: None,
+            cert_verified: verify::ServerCertVerified::assertion(),
+            sig_verified: verify::HandshakeSignatureValid::assertion(),
+            is_pdk: false,
+            client_auth_shared_secret: None,
+        }
+    }
+
+    pub fn expect_traffic() -> ExpectTraffic {
+        ExpectTraffic {
+            handshake: handshake_details(),
+            key_schedule: traffic_key_schedule(),
+            want_write_key_update: false,
+            tickets_stored: 0,
+            ticket_nonces: SeenTicketNonces::new(),
+            pending_kem_auth: None,
+            _cert_verified: verify::ServerCertVerified::assertion(),
+            _sig_verified: verify::HandshakeSignatureValid::assertion(),
+            _fin_verified: verify::FinishedMessageVerified::assertion(),
+        }
+    }
+
+    pub fn handshake_message(typ: HandshakeType, payload: HandshakePayload) -> Message {
+        Message {
+            typ: ContentType::Handshake,
+            version: ProtocolVersion::TLSv1_3,
+            payload: MessagePayload::Handshake(HandshakeMessagePayload { typ, payload }),
+        }
+    }
+
+    pub fn encrypted_extensions(exts: EncryptedExtensions) -> Message {
+        handshake_message(HandshakeType::EncryptedExtensions,
+                          HandshakePayload::EncryptedExtensions(exts))
+    }
+
+    pub fn certificate(entries: Vec<CertificateEntry>) -> Message {
+        handshake_message(HandshakeType::Certificate,
+                          HandshakePayload::CertificateTLS13(CertificatePayloadTLS13::new(entries)))
+    }
+
+    /// A Certificate carrying `chain`, with no extensions.
+    pub fn certificate_of(chain: &[key::Certificate]) -> Message {
+        certificate(chain.iter()
+                    .map(|cert| CertificateEntry { cert: cert.clone(), exts: Vec::new() })
+                    .collect())
+    }
+
+    pub fn certificate_verify(scheme: SignatureScheme, sig: &[u8]) -> Message {
+        handshake_message(HandshakeType::CertificateVerify,
+                          HandshakePayload::CertificateVerify(
+                              DigitallySignedStruct::new(scheme, sig.to_vec())))
+    }
+
+    pub fn certificate_request(context: &[u8], extensions: Vec<CertReqExtension>) -> Message {
+        handshake_message(HandshakeType::CertificateRequest,
+                          HandshakePayload::CertificateRequestTLS13(CertificateRequestPayloadTLS13 {
+                              context: PayloadU8::new(context.to_vec()),
+                              extensions,
+                          }))
+    }
+
+    /// The server's KEMTLS ciphertext, encapsulated to our certificate.
+    pub fn kem_ciphertext(ciphertext: &[u8]) -> Message {
+        handshake_message(HandshakeType::ClientKemCiphertext,
+                          HandshakePayload::ClientKemCiphertext(Payload::new(ciphertext.to_vec())))
+    }
+
+    pub fn finished(verify_data: &[u8]) -> Message {
+        handshake_message(HandshakeType::Finished,
+                          HandshakePayload::Finished(Payload::new(verify_data.to_vec())))
+    }
+
+    pub fn new_session_ticket(nonce: &[u8], ticket: &[u8]) -> NewSessionTicketPayloadTLS13 {
+        NewSessionTicketPayloadTLS13::new(3600, 0, nonce.to_vec(), ticket.to_vec())
+    }
+
+    /// Takes the next record `sess` queued for sending, and asserts it
+    /// is a fatal alert with description `desc`.
+    pub fn assert_sent_fatal_alert(sess: &mut ClientSessionImpl, desc: AlertDescription) {
+        let alert = sess.common.sendable_tls.take_one();
+        assert_eq!(alert[0], ContentType::Alert.get_u8());
+        assert_eq!(&alert[alert.len() - 2..],
+                   &[ AlertLevel::Fatal.get_u8(), desc.get_u8() ]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::fixtures::*;
+    use crate::client::ClientConfig;
+    use crate::msgs::handshake::ServerExtension;
+    use crate::msgs::enums::CipherSuite;
+    use crate::anchors::RootCertStore;
+    use crate::client::hs::State;
+    use crate::key;
+    #[cfg(feature = "dangerous_configuration")]
+    use crate::keylog::KeyScheduleTrace;
+    use std::sync::Arc;
+    #[cfg(feature = "dangerous_configuration")]
+    use std::sync::Mutex;
+
+    #[cfg(feature = "dangerous_configuration")]
+    #[derive(Default)]
+    struct RecordMismatch(Mutex<Option<(Vec<u8>, Vec<u8>, Vec<u8>)>>);
+
+    #[cfg(feature = "dangerous_configuration")]
+    impl KeyScheduleTrace for RecordMismatch {
+        fn derived(&self, _label: &[u8], _hs_hash: &[u8], _secret: &[u8]) {}
+
+        fn finished_mismatch(&self, hs_hash: &[u8], expected: &[u8], received: &[u8]) {
+            *self.0.lock().unwrap() = Some((hs_hash.to_vec(), expected.to_vec(), received.to_vec()));
+        }
+    }
+
+    #[cfg(feature = "dangerous_configuration")]
+    fn session_with_trace(trace: &Arc<RecordMismatch>) -> ClientSessionImpl {
+        let mut config = ClientConfig::new();
+        config.key_schedule_trace = Some(trace.clone());
+        ClientSessionImpl::new(&Arc::new(config))
+    }
+
+    #[cfg(feature = "dangerous_configuration")]
+    #[test]
+    fn test_finished_mismatch_is_reported() {
+        let trace = Arc::new(RecordMismatch::default());
+        let mut sess = session_with_trace(&trace);
+
+        let result = verify_server_finished(&mut sess, b"hash", b"expected", b"received");
+        assert_eq!(result.err(), Some(TLSError::DecryptError));
+        assert_eq!(*trace.0.lock().unwrap(),
+                   Some((b"hash".to_vec(), b"expected".to_vec(), b"received".to_vec())));
+    }
+
+    #[cfg(feature = "dangerous_configuration")]
+    #[test]
+    fn test_finished_match_is_not_reported() {
+        let trace = Arc::new(RecordMismatch::default());
+        let mut sess = session_with_trace(&trace);
+
+        assert!(verify_server_finished(&mut sess, b"hash", b"same", b"same").is_ok());
+        assert_eq!(*trace.0.lock().unwrap(), None);
+    }
+
+    fn validate(sent: ExtensionType, ext: ServerExtension) -> Result<(), TLSError> {
+        let mut sess = ClientSessionImpl::new(&Arc::new(ClientConfig::new()));
+        let mut hello = ClientHelloDetails::new();
+        hello.sent_extensions.push(sent);
+        validate_encrypted_extensions(&mut sess, &hello, &vec![ ext ])
+    }
+
+    #[test]
+    fn key_share_in_encrypted_extensions_is_named() {
+        let share = KeyShareEntry::new(NamedGroup::Kyber512, &[0u8; 16]);
+        assert_eq!(validate(ExtensionType::KeyShare, ServerExtension::KeyShare(share)),
+                   Err(TLSError::PeerMisbehavedError(
+                       "server sent KeyShare extension in EncryptedExtensions, not ServerHello"
+                       .to_string())));
+    }
+
+    #[test]
+    fn certificate_before_encrypted_extensions_is_rejected() {
+        let mut sess = ClientSessionImpl::new(&Arc::new(ClientConfig::new()));
+        sess.state = Some(Box::new(expect_encrypted_extensions()));
+
+        assert_eq!(sess.process_main_protocol(certificate(vec![])),
+                   Err(TLSError::PeerMisbehavedError(
+                       "server sent Certificate before EncryptedExtensions".to_string())));
+
+        assert_sent_fatal_alert(&mut sess, AlertDescription::UnexpectedMessage);
+    }
+
+    #[test]
+    fn sct_timestamp_in_future_gets_bad_status_alert() {
+        // A future timestamp is only found after the SCT's signature
+        // verifies, which no test log can do.
+        let mut sess = session(ClientConfig::new());
+        let err = TLSError::InvalidSCT(sct::Error::TimestampInFuture);
+
+        assert_eq!(hs::send_sct_error_alert(&mut sess, err.clone()), err);
+        assert_sent_fatal_alert(&mut sess, AlertDescription::BadCertificateStatusResponse);
+    }
+
+    struct AcceptAnyServerCert;
+
+    impl verify::ServerCertVerifier for AcceptAnyServerCert {
+        fn verify_server_cert(&self,
+                              _roots: &RootCertStore,
+                              _presented_certs: &[key::Certificate],
+                              _dns_name: webpki::DNSNameRef,
+                              _ocsp_response: &[u8]) -> Result<verify::ServerCertVerified, TLSError> {
+            Ok(verify::ServerCertVerified::assertion())
+        }
+    }
+
+    const GITHUB_CERT: &[u8] = include_bytes!("../testdata/cert-github.0.der");
+
+    fn github_chain() -> Vec<key::Certificate> {
+        vec![ key::Certificate(GITHUB_CERT.to_vec()) ]
+    }
+
+    fn github_dns_name() -> webpki::DNSName {
+        webpki::DNSNameRef::try_from_ascii_str("github.com")
+            .unwrap()
+            .to_owned()
+    }
+
+    fn expect_certificate_with_chain() -> ExpectCertificate {
+        let mut expect = expect_certificate();
+        expect.handshake.dns_name = github_dns_name();
+        expect.server_cert = server_cert_details(&github_chain());
+        expect
+    }
+
+    #[test]
+    fn certificate_after_resumption_is_rejected() {
+        let mut sess = ClientSessionImpl::new(&Arc::new(ClientConfig::new()));
+        sess.resumed = true;
+        sess.state = Some(Box::new(expect_finished()));
+
+        assert_eq!(sess.process_main_protocol(certificate(vec![])),
+                   Err(TLSError::PeerMisbehavedError(
+                       "server resumed a session but then sent a certificate".to_string())));
+
+        assert_sent_fatal_alert(&mut sess, AlertDescription::UnexpectedMessage);
+    }
+
+    /// Expecting EncryptedExtensions, having resumed a session over TCP
+    /// and started sending early data.
+    fn expect_encrypted_extensions_after_early_data(sess: &mut ClientSessionImpl)
+                                                     -> ExpectEncryptedExtensions {
+        sess.early_data.enable(1024);
+        sess.common.early_traffic = true;
+
+        let mut expect = expect_encrypted_extensions();
+        expect.hello.sent_extensions.push(ExtensionType::EarlyData);
+        expect.handshake.resuming_session = Some(persist::ClientSessionValue::new(
+            ProtocolVersion::TLSv1_3,
+            CipherSuite::TLS13_AES_128_GCM_SHA256,
+            &SessionID::empty(),
+            b"ticket".to_vec(),
+            vec![0u8; 32],
+            &Vec::new()));
+        expect
+    }
+
+    #[test]
+    fn early_data_is_accepted_without_quic() {
+        let mut sess = session(ClientConfig::new());
+        let expect = expect_encrypted_extensions_after_early_data(&mut sess);
+
+        Box::new(expect)
+            .handle(&mut sess, encrypted_extensions(vec![ ServerExtension::EarlyData ]))
+            .unwrap();
+        assert_eq!(sess.early_data.outcome(), Some(true));
+        assert!(sess.common.early_traffic);
+    }
+
+    #[test]
+    fn early_data_is_rejected_without_quic() {
+        let mut sess = session(ClientConfig::new());
+        let expect = expect_encrypted_extensions_after_early_data(&mut sess);
+
+        Box::new(expect)
+            .handle(&mut sess, encrypted_extensions(vec![]))
+            .unwrap();
+        assert_eq!(sess.early_data.outcome(), Some(false));
+        assert!(!sess.common.early_traffic);
+    }
+
+    #[test]
+    fn ticket_with_empty_nonce_is_stored() {
+        let mut sess = session(ClientConfig::new());
+        let mut traffic = expect_traffic();
+        let nst = new_session_ticket(b"", b"ticket");
+
+        assert_eq!(traffic.handle_new_ticket_tls13(&mut sess, &nst), Ok(()));
+        assert_eq!(traffic.tickets_stored, 1);
+        assert!(sess.common.sendable_tls.is_empty());
+    }
+
+    #[test]
+    fn ticket_with_repeated_nonce_is_stored() {
+        let mut sess = session(ClientConfig::new());
+        let mut traffic = expect_traffic();
+
+        for ticket in &[ b"first", b"again" ] {
+            let nst = new_session_ticket(&[ 1 ], &ticket[..]);
+            assert_eq!(traffic.handle_new_ticket_tls13(&mut sess, &nst), Ok(()));
+        }
+        assert_eq!(traffic.tickets_stored, 2);
+        assert!(!traffic.ticket_nonces.insert(&[ 1 ]));
+    }
+
+    #[test]
+    fn seen_ticket_nonces_forgets_oldest() {
+        let mut nonces = SeenTicketNonces::new();
+        for i in 0..MAX_SEEN_TICKET_NONCES as u8 {
+            assert!(nonces.insert(&[ i ]));
+        }
+        assert!(!nonces.insert(&[ 0 ]));
+        assert!(!nonces.insert(&[ MAX_SEEN_TICKET_NONCES as u8 - 1 ]));
+
+        assert!(nonces.insert(&[ 0xff ]));
+        assert!(nonces.insert(&[ 0 ]));
+        assert!(!nonces.insert(&[ 0xff ]));
+    }
+
+    fn certificate_with_ocsp_staple() -> Message {
+        use crate::msgs::handshake::{CertificateExtension, CertificateStatus};
+
+        let entry = CertificateEntry {
+            cert: key::Certificate(GITHUB_CERT.to_vec()),
+            exts: vec![ CertificateExtension::CertificateStatus(CertificateStatus::new(b"ocsp".to_vec())) ],
+        };
+        certificate(vec![ entry ])
+    }
+
+    #[test]
+    fn unsolicited_ocsp_staple_is_rejected() {
+        let mut config = ClientConfig::new();
+        config.request_ocsp_response = false;
+        let mut sess = ClientSessionImpl::new(&Arc::new(config));
+
+        let err = Box::new(expect_certificate_with_chain())
+            .handle(&mut sess, certificate_with_ocsp_staple())
+            .err();
+        assert_eq!(err, Some(TLSError::PeerMisbehavedError(
+            "server sent unsolicited OCSP response".to_string())));
+
+        assert_sent_fatal_alert(&mut sess, AlertDescription::UnsupportedExtension);
+    }
+
+    #[test]
+    fn unsolicited_ocsp_staple_can_be_ignored() {
+        let mut config = ClientConfig::new();
+        config.request_ocsp_response = false;
+        config.allowed_unsolicited_extensions.push(ExtensionType::StatusRequest);
+        let mut sess = ClientSessionImpl::new(&Arc::new(config));
+
+        assert!(Box::new(expect_certificate_with_chain())
+                .handle(&mut sess, certificate_with_ocsp_staple())
+                .is_ok());
+        assert!(sess.common.sendable_tls.is_empty());
+    }
+
+    #[test]
+    fn duplicate_encrypted_extensions_are_rejected() {
+        let mut sess = session(ClientConfig::new());
+        let exts = vec![ ServerExtension::ServerNameAck, ServerExtension::ServerNameAck ];
+
+        let err = Box::new(expect_encrypted_extensions())
+            .handle(&mut sess, encrypted_extensions(exts))
+            .err();
+        assert_eq!(err, Some(TLSError::PeerMisbehavedError(
+            "server sent duplicate encrypted extensions".to_string())));
+
+        assert_sent_fatal_alert(&mut sess, AlertDescription::DecodeError);
+    }
+
+    #[test]
+    fn certificate_or_cert_req_takes_signing_certificate() {
+        let mut sess = session(ClientConfig::new());
+
+        let mut expect = expect_certificate_or_cert_req();
+        expect.handshake.dns_name = github_dns_name();
+        assert!(Box::new(expect)
+                .handle(&mut sess, certificate_of(&github_chain()))
+                .is_ok());
+        assert!(sess.common.sendable_tls.is_empty());
+
+        let err = Box::new(expect_certificate_or_cert_req())
+            .handle(&mut sess, finished(&[0u8; 32]))
+            .err();
+        assert_eq!(err, Some(TLSError::InappropriateHandshakeMessage {
+            expect_types: vec![ HandshakeType::Certificate, HandshakeType::CertificateRequest ],
+            got_type: HandshakeType::Finished,
+        }));
+    }
+
+    #[test]
+    fn certificate_verify_with_unoffered_scheme_is_rejected() {
+        let mut config = ClientConfig::new();
+        config.verifier = Arc::new(AcceptAnyServerCert);
+        let mut sess = session(config);
+
+        let mut expect = expect_certificate_verify(&github_chain());
+        expect.handshake.dns_name = github_dns_name();
+        let err = Box::new(expect)
+            .handle(&mut sess, certificate_verify(SignatureScheme::Unknown(0xfefe), &[0u8; 64]))
+            .err();
+        assert_eq!(err, Some(TLSError::PeerMisbehavedError(
+            "server used signature scheme we did not offer".to_string())));
+
+        assert_sent_fatal_alert(&mut sess, AlertDescription::IllegalParameter);
+        assert_eq!(sess.peer_signature_scheme, None);
+    }
+
+    #[test]
+    fn certificate_verify_with_bad_signature_is_rejected() {
+        let mut config = ClientConfig::new();
+        config.verifier = Arc::new(AcceptAnyServerCert);
+        let mut sess = session(config);
+
+        let mut expect = expect_certificate_verify(&github_chain());
+        expect.handshake.dns_name = github_dns_name();
+        let scheme = SignatureScheme::ECDSA_NISTP256_SHA256;
+        assert!(Box::new(expect)
+                .handle(&mut sess, certificate_verify(scheme, &[0u8; 64]))
+                .is_err());
+        assert!(!sess.common.sendable_tls.is_empty());
+        assert_eq!(sess.peer_signature_scheme, None);
+    }
+
+    #[test]
+    fn finished_with_wrong_verify_data_is_rejected() {
+        let mut sess = session(ClientConfig::new());
+
+        let err = Box::new(expect_finished())
+            .handle(&mut sess, finished(&[0u8; 32]))
+            .err();
+        assert_eq!(err, Some(TLSError::DecryptError));
+
+        assert_sent_fatal_alert(&mut sess, AlertDescription::DecryptError);
+    }
+
+    #[test]
+    fn certificate_request_with_context_is_rejected() {
+        let mut sess = session(ClientConfig::new());
+
+        let err = Box::new(expect_certificate_request())
+            .handle(&mut sess, certificate_request(b"ctx", Vec::new()))
+            .err();
+        assert_eq!(err, Some(TLSError::CorruptMessagePayload(ContentType::Handshake)));
+
+        assert_sent_fatal_alert(&mut sess, AlertDescription::DecodeError);
+    }
+
+    #[test]
+    fn kemtls_ciphertext_with_wrong_length_is_rejected() {
+        let mut sess = session(ClientConfig::new());
+
+        let err = Box::new(expect_ciphertext(768))
+            .handle(&mut sess, kem_ciphertext(&[0u8; 767]))
+            .err();
+        assert_eq!(err, Some(TLSError::KemtlsFlightFailed(KemtlsStep::CiphertextLength)));
+
+        assert_sent_fatal_alert(&mut sess, AlertDescription::DecodeError);
+    }
+
+    #[test]
+    fn kemtls_decapsulation_failure_is_labeled() {
+        let mut sess = session(ClientConfig::new());
+
+        // a signing certificate, which can't decapsulate anything
+        let mut expect = expect_ciphertext(768);
+        expect.client_auth.cert = Some(github_chain());
+        expect.client_auth.private_key = Some(vec![0u8; 32]);
+
+        let err = Box::new(expect)
+            .handle(&mut sess, kem_ciphertext(&[0u8; 768]))
+            .err();
+        assert_eq!(err, Some(TLSError::KemtlsFlightFailed(KemtlsStep::Decapsulate)));
+
+        assert_sent_fatal_alert(&mut sess, AlertDescription::DecryptError);
+    }
+
+    #[test]
+    fn kemtls_finished_with_wrong_verify_data_is_rejected() {
+        let mut sess = session(ClientConfig::new());
+
+        let err = Box::new(expect_kemtls_finished())
+            .handle(&mut sess, finished(&[0u8; 32]))
+            .err();
+        assert_eq!(err, Some(TLSError::KemtlsFlightFailed(KemtlsStep::Finished)));
+
+        assert_sent_fatal_alert(&mut sess, AlertDescription::DecryptError);
+    }
+
+    #[test]
+    fn kemtls_encapsulation_failure_is_labeled() {
+        let mut config = ClientConfig::new();
+        config.verifier = Arc::new(AcceptAnyServerCert);
+        let mut sess = ClientSessionImpl::new(&Arc::new(config));
+        let cert = webpki::EndEntityCert::from(GITHUB_CERT).unwrap();
+
+        assert_eq!(expect_certificate_with_chain().emit_ciphertext(&mut sess, cert),
+                   Err(TLSError::KemtlsFlightFailed(KemtlsStep::Encapsulate)));
+    }
+
+    #[test]
+    fn kemtls_encapsulates_before_reporting_bad_certificate() {
+        // No roots, so the chain doesn't verify.
+        let mut sess = ClientSessionImpl::new(&Arc::new(ClientConfig::new()));
+        let cert = webpki::EndEntityCert::from(GITHUB_CERT).unwrap();
+        let before = suites::cert_kem_operation_counts()
+            .get(&suites::KemOp::Encapsulate)
+            .cloned()
+            .unwrap_or(0);
+
+        match expect_certificate_with_chain().emit_ciphertext(&mut sess, cert) {
+            Err(TLSError::WebPKIError(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(suites::cert_kem_operation_counts()[&suites::KemOp::Encapsulate] > before);
+
+        // we sent only the alert, and no ciphertext
+        assert_sent_fatal_alert(&mut sess, AlertDescription::BadCertificate);
+        assert!(sess.common.sendable_tls.is_empty());
+    }
+
+    fn pending_handshake_fragment(sess: &mut ClientSessionImpl) {
+        // the header promises a 16 byte Finished, but only 4 bytes arrive
+        let fragment = Message {
+            typ: ContentType::Handshake,
+            version: ProtocolVersion::TLSv1_3,
+            payload: MessagePayload::new_opaque(vec![ 0x14, 0x00, 0x00, 0x10, 0x01, 0x02, 0x03, 0x04 ]),
+        };
+        assert_eq!(sess.common.handshake_joiner.take_message(fragment), Some(0));
+        assert!(!sess.common.handshake_joiner.is_empty());
+    }
+
+    fn assert_unaligned_handshake_rejected(sess: &mut ClientSessionImpl, result: Result<(), TLSError>) {
+        assert_eq!(result,
+                   Err(TLSError::PeerMisbehavedError(
+                       "key epoch or handshake flight with pending fragment".to_string())));
+
+        // only the alert went out
+        assert_sent_fatal_alert(sess, AlertDescription::UnexpectedMessage);
+        assert!(sess.common.sendable_tls.is_empty());
+    }
+
+    #[test]
+    fn kemtls_ciphertext_with_pending_fragment_is_rejected() {
+        let mut config = ClientConfig::new();
+        config.verifier = Arc::new(AcceptAnyServerCert);
+        let mut sess = ClientSessionImpl::new(&Arc::new(config));
+        pending_handshake_fragment(&mut sess);
+        let cert = webpki::EndEntityCert::from(GITHUB_CERT).unwrap();
+
+        let result = expect_certificate_with_chain().emit_ciphertext(&mut sess, cert);
+        assert_unaligned_handshake_rejected(&mut sess, result);
+    }
+
+    #[test]
+    fn kemtls_finished_with_pending_fragment_is_rejected() {
+        let mut sess = ClientSessionImpl::new(&Arc::new(ClientConfig::new()));
+        pending_handshake_fragment(&mut sess);
+
+        let result = expect_certificate().emit_finished_and_into_expect_server_finished(&mut sess)
+            .map(|_| ());
+        assert_unaligned_handshake_rejected(&mut sess, result);
+    }
+
+    #[test]
+    fn tls12_extension_in_encrypted_extensions_is_named() {
+        assert_eq!(validate(ExtensionType::ExtendedMasterSecret,
+                            ServerExtension::ExtendedMasterSecretAck),
+                   Err(TLSError::PeerMisbehavedError(
+                       "server sent ExtendedMasterSecret extension, which is not allowed in TLS1.3"
+                       .to_string())));
+    }
 }
@@ -1,6 +1,6 @@
 use std::fmt;
 use std::error::Error;
-use crate::msgs::enums::{ContentType, HandshakeType, AlertDescription};
+use crate::msgs::enums::{ContentType, HandshakeType, AlertDescription, NamedGroup};
 use webpki;
 use sct;
 
@@ -73,6 +73,98 @@ pub enum TLSError {
 
     /// An incoming connection did not support any known application protocol.
     NoApplicationProtocol,
+
+    /// A step of our KEMTLS flight after the server's certificate
+    /// failed.
+    KemtlsFlightFailed(KemtlsStep),
+
+    /// We could not decapsulate the peer's KEM ciphertext for the given
+    /// group, or it gave an all-zero shared secret where that is not
+    /// a legitimate result.
+    KemDecapsulationFailed(NamedGroup),
+
+    /// We could not generate a key share for any of the key exchange
+    /// groups we wanted to offer.
+    NoUsableKexGroup,
+
+    /// The application aborted the session by sending the given
+    /// fatal alert.
+    AbortedWithAlert(AlertDescription),
+
+    /// The peer has sent close_notify, so the session can no longer be
+    /// used for what was asked.
+    PeerClosed,
+}
+
+/// The steps the client takes in KEMTLS after receiving the server's
+/// certificate which can fail.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum KemtlsStep {
+    /// Encapsulating to the server certificate's KEM public key.
+    Encapsulate,
+    /// Receiving the server's ciphertext for our KEM client
+    /// certificate, which had the wrong length.
+    CiphertextLength,
+    /// Decapsulating the server's ciphertext with our KEM client
+    /// certificate's private key.
+    Decapsulate,
+    /// Checking the server's KEMTLS Finished.
+    Finished,
+}
+
+/// The handshake states a session can fail in, named after the message
+/// the session was waiting for.  See `ClientSession::get_failed_state`
+/// and `ServerSession::get_failed_state`; `get_protocol_version` tells
+/// apart the TLS1.2 and TLS1.3 states of the same name.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum HandshakeState {
+    /// Server waiting for a ClientHello.
+    ClientHello,
+    /// Client waiting for a ServerHello, after a HelloRetryRequest or
+    /// when not offering TLS1.3.
+    ServerHello,
+    /// Client waiting for a ServerHello or HelloRetryRequest.
+    ServerHelloOrHelloRetryRequest,
+    /// Client waiting for EncryptedExtensions.
+    EncryptedExtensions,
+    /// Waiting for the peer's Certificate.
+    Certificate,
+    /// Client waiting for a Certificate or CertificateRequest.
+    CertificateOrCertificateRequest,
+    /// Server waiting for the client's Certificate in KEMTLS-PDK.
+    PdkCertificate,
+    /// Client waiting for CertificateStatus.
+    CertificateStatus,
+    /// Client waiting for CertificateStatus or ServerKeyExchange.
+    CertificateStatusOrServerKeyExchange,
+    /// Client waiting for ServerKeyExchange.
+    ServerKeyExchange,
+    /// Client waiting for CertificateRequest.
+    CertificateRequest,
+    /// Client waiting for ServerHelloDone or CertificateRequest.
+    ServerHelloDoneOrCertificateRequest,
+    /// Client waiting for ServerHelloDone.
+    ServerHelloDone,
+    /// Server waiting for ClientKeyExchange.
+    ClientKeyExchange,
+    /// Waiting for a KEMTLS ciphertext.
+    KemCiphertext,
+    /// Waiting for the peer's CertificateVerify.
+    CertificateVerify,
+    /// Waiting for ChangeCipherSpec.
+    ChangeCipherSpec,
+    /// Client waiting for NewSessionTicket.
+    NewSessionTicket,
+    /// Waiting for the peer's Finished.
+    Finished,
+    /// Waiting for the peer's KEMTLS Finished.
+    KemtlsFinished,
+    /// Handshake complete, exchanging application data.
+    Traffic,
+    /// Handshake complete, exchanging QUIC data.
+    QuicTraffic,
+    /// The peer has sent close_notify.
+    Closed,
 }
 
 fn join<T: fmt::Debug>(items: &[T]) -> String {
@@ -112,6 +204,13 @@ impl fmt::Display for TLSError {
             TLSError::NoApplicationProtocol => write!(f, "peer doesn't support any known protocol"),
             TLSError::InvalidSCT(ref err) => write!(f, "invalid certificate timestamp: {:?}", err),
             TLSError::FailedToGetCurrentTime => write!(f, "failed to get current time"),
+            TLSError::KemtlsFlightFailed(ref step) => write!(f, "KEMTLS {:?} step failed", step),
+            TLSError::KemDecapsulationFailed(ref group) => {
+                write!(f, "decapsulation of peer's {:?} ciphertext failed", group)
+            }
+            TLSError::NoUsableKexGroup => write!(f, "no usable key exchange group"),
+            TLSError::AbortedWithAlert(ref alert) => write!(f, "aborted with fatal alert: {:?}", alert),
+            TLSError::PeerClosed => write!(f, "peer has closed the session"),
             TLSError::General(ref err) => write!(f, "unexpected error: {}", err), // (please file a bug)
         }
     }
@@ -124,8 +223,8 @@ impl Error for TLSError {
 mod tests {
     #[test]
     fn smoke() {
-        use super::TLSError;
-        use crate::msgs::enums::{ContentType, HandshakeType, AlertDescription};
+        use super::{TLSError, KemtlsStep};
+        use crate::msgs::enums::{ContentType, HandshakeType, AlertDescription, NamedGroup};
         use webpki;
         use sct;
 
@@ -150,7 +249,15 @@ mod tests {
                        TLSError::FailedToGetCurrentTime,
                        TLSError::HandshakeNotComplete,
                        TLSError::PeerSentOversizedRecord,
-                       TLSError::NoApplicationProtocol];
+                       TLSError::NoApplicationProtocol,
+                       TLSError::KemtlsFlightFailed(KemtlsStep::Encapsulate),
+                       TLSError::KemtlsFlightFailed(KemtlsStep::CiphertextLength),
+                       TLSError::KemtlsFlightFailed(KemtlsStep::Decapsulate),
+                       TLSError::KemtlsFlightFailed(KemtlsStep::Finished),
+                       TLSError::KemDecapsulationFailed(NamedGroup::Kyber512),
+                       TLSError::NoUsableKexGroup,
+                       TLSError::AbortedWithAlert(AlertDescription::AccessDenied),
+                       TLSError::PeerClosed];
 
         for err in all {
             println!("{:?}:", err);
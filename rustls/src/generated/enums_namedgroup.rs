@@ -16,6 +16,7 @@ enum_builder! {
         FFDHE4096 => 0x0102,
         FFDHE6144 => 0x0103,
         FFDHE8192 => 0x0104,
+        X25519Kyber768 => 0x6399,
         Kyber512 => 0x01fc,
         Kyber768 => 0x01fd,
         Kyber1024 => 0x01fe,
@@ -2,271 +2,271 @@ match group {
 
         NamedGroup::Kyber512 => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::Kyber768 => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber768).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::Kyber1024 => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber1024).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::ClassicMcEliece348864 => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::ClassicMcEliece348864).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::ClassicMcEliece348864f => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::ClassicMcEliece348864f).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::ClassicMcEliece460896 => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::ClassicMcEliece460896).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::ClassicMcEliece460896f => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::ClassicMcEliece460896f).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::ClassicMcEliece6688128 => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::ClassicMcEliece6688128).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::ClassicMcEliece6688128f => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::ClassicMcEliece6688128f).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::ClassicMcEliece6960119 => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::ClassicMcEliece6960119).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::ClassicMcEliece6960119f => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::ClassicMcEliece6960119f).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::ClassicMcEliece8192128 => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::ClassicMcEliece8192128).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::ClassicMcEliece8192128f => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::ClassicMcEliece8192128f).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::Lightsaber => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Lightsaber).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::Saber => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Saber).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::Firesaber => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Firesaber).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::NtruHps2048509 => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::NtruHps2048509).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::NtruHps2048677 => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::NtruHps2048677).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::NtruHps4096821 => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::NtruHps4096821).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::NtruHrss701 => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::NtruHrss701).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::NtruPrimeNtrulpr653 => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::NtruPrimeNtrulpr653).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::NtruPrimeNtrulpr761 => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::NtruPrimeNtrulpr761).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::NtruPrimeNtrulpr857 => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::NtruPrimeNtrulpr857).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::NtruPrimeSntrup653 => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::NtruPrimeSntrup653).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::NtruPrimeSntrup761 => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::NtruPrimeSntrup761).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::NtruPrimeSntrup857 => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::NtruPrimeSntrup857).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::FrodoKem640Aes => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::FrodoKem640Aes).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::FrodoKem640Shake => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::FrodoKem640Shake).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::FrodoKem976Aes => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::FrodoKem976Aes).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::FrodoKem976Shake => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::FrodoKem976Shake).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::FrodoKem1344Aes => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::FrodoKem1344Aes).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::FrodoKem1344Shake => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::FrodoKem1344Shake).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::SikeP434 => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::SikeP434).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::SikeP434Compressed => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::SikeP434Compressed).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::SikeP503 => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::SikeP503).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::SikeP503Compressed => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::SikeP503Compressed).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::SikeP610 => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::SikeP610).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::SikeP610Compressed => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::SikeP610Compressed).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::SikeP751 => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::SikeP751).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::SikeP751Compressed => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::SikeP751Compressed).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::BikeL1Fo => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::BikeL1Fo).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::BikeL3Fo => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::BikeL3Fo).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::Hqc128 => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Hqc128).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::Hqc192 => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Hqc192).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 
         NamedGroup::Hqc256 => {
             oqs::init();
-            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+            let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Hqc256).ok()?;
             Some(KexAlgorithm::KEM(kem))
         },
 _ => None,
@@ -1,4 +1,4 @@
-match self.scheme {
+match scheme {
     SignatureScheme::DILITHIUM2 => SignatureAlgorithm::DILITHIUM2,
     SignatureScheme::DILITHIUM3 => SignatureAlgorithm::DILITHIUM3,
     SignatureScheme::DILITHIUM5 => SignatureAlgorithm::DILITHIUM5,
@@ -44,6 +44,7 @@ NamedGroup::BikeL3Fo,
 NamedGroup::Hqc128,
 NamedGroup::Hqc192,
 NamedGroup::Hqc256,
+    NamedGroup::X25519Kyber768,
     NamedGroup::X25519,
     NamedGroup::secp384r1,
     NamedGroup::secp256r1,
@@ -2,6 +2,10 @@ use crate::cipher::{Iv, IvLen};
 use crate::error::TLSError;
 use crate::msgs::base::PayloadU8;
 use crate::KeyLog;
+#[cfg(feature = "dangerous_configuration")]
+use crate::keylog::KeyScheduleTrace;
+#[cfg(feature = "dangerous_configuration")]
+use std::sync::Arc;
 /// Key schedule maintenance for TLS1.3
 use ring::{
     aead, digest,
@@ -70,15 +74,13 @@ impl SecretKind {
 struct KeySchedule {
     current: hkdf::Prk,
     algorithm: ring::hkdf::Algorithm,
+    #[cfg(feature = "dangerous_configuration")]
+    trace: Option<Arc<dyn KeyScheduleTrace>>,
 }
 
 /// KeySchedule that can compute a CFIN for us
 pub trait KeyScheduleComputesClientFinish {
     fn sign_client_finish(&self, hs_hash: &[u8]) -> Vec<u8>;
-
-    fn sign_client_finished_kemtlspdk(&self, _hs_hash: &[u8]) -> Vec<u8> {
-        panic!("Don't call this for this struct!");
-    }
 }
 
 /// KeySchedule that can compute a SFIN for us
@@ -104,6 +106,11 @@ impl KeyScheduleEarly {
         }
     }
 
+    #[cfg(feature = "dangerous_configuration")]
+    pub fn set_trace(&mut self, trace: Option<Arc<dyn KeyScheduleTrace>>) {
+        self.ks.trace = trace;
+    }
+
     pub fn client_early_traffic_secret(
         &self,
         hs_hash: &[u8],
@@ -150,6 +157,11 @@ impl KeyScheduleNonSecret {
         }
     }
 
+    #[cfg(feature = "dangerous_configuration")]
+    pub fn set_trace(&mut self, trace: Option<Arc<dyn KeyScheduleTrace>>) {
+        self.ks.trace = trace;
+    }
+
     pub fn into_handshake(mut self, secret: &[u8]) -> KeyScheduleHandshake {
         self.ks.input_secret(secret);
         KeyScheduleHandshake {
@@ -400,10 +412,6 @@ impl KeyScheduleComputesClientFinish for KeyScheduleTrafficWithClientFinishedPen
         self.ks
             .sign_finish(&self.handshake_client_traffic_secret, hs_hash)
     }
-
-    fn sign_client_finished_kemtlspdk(&self, hs_hash: &[u8]) -> Vec<u8> {
-        self.ks.sign_finish_kemtls(&self.ks.current, hs_hash, false)
-    }
 }
 
 impl KeyScheduleTrafficWithClientFinishedPending {
@@ -466,6 +474,10 @@ impl KeyScheduleTrafficWithClientFinishedPending {
     pub fn sign_server_finished_kemtlspdk(&self, hs_hash: &[u8]) -> Vec<u8> {
         self.ks.sign_finish_kemtls(&self.ks.current, hs_hash, true)
     }
+
+    pub fn sign_client_finished_kemtlspdk(&self, hs_hash: &[u8]) -> Vec<u8> {
+        self.ks.sign_finish_kemtls(&self.ks.current, hs_hash, false)
+    }
 }
 
 /// KeySchedule during traffic stage.  All traffic & exporter keys are guaranteed
@@ -490,6 +502,30 @@ impl KeyScheduleTraffic {
         secret
     }
 
+    /// Sign the client's Finished for post-handshake authentication,
+    /// using the current client application traffic secret.  If the
+    /// client authenticated with a KEM certificate, `ss` is the secret
+    /// encapsulated to it, which the finished key then depends on too.
+    ///
+    /// Neither RFC 8446 nor the KEMTLS papers define post-handshake
+    /// authentication with a KEM certificate, so the "c pha kem" label
+    /// is our own.  The finished key is then derived, as in RFC 8446
+    /// section 4.4.4, from
+    /// `HKDF-Expand-Label(client_application_traffic_secret_N, "c pha kem", ss, Hash.length)`
+    /// rather than from the traffic secret itself.
+    pub fn sign_client_post_handshake_finish(&self, ss: Option<&[u8]>, hs_hash: &[u8]) -> Vec<u8> {
+        match ss {
+            Some(ss) => {
+                let base_key: hkdf::Prk = hkdf_expand(&self.current_client_traffic_secret,
+                                                      self.ks.algorithm(),
+                                                      b"c pha kem",
+                                                      ss);
+                self.ks.sign_finish(&base_key, hs_hash)
+            }
+            None => self.ks.sign_finish(&self.current_client_traffic_secret, hs_hash),
+        }
+    }
+
     pub fn resumption_master_secret_and_derive_ticket_psk(
         &self,
         hs_hash: &[u8],
@@ -522,6 +558,8 @@ impl KeySchedule {
         KeySchedule {
             current: salt.extract(secret),
             algorithm,
+            #[cfg(feature = "dangerous_configuration")]
+            trace: None,
         }
     }
 
@@ -541,7 +579,9 @@ impl KeySchedule {
         self.input_secret(&zeroes[..self.algorithm.len()]);
     }
 
-    /// Input the given secret.
+    /// Input the given secret.  This is the IKM of HKDF-Extract, so
+    /// it may be any length: KEM shared secrets needn't match the
+    /// hash length.
     fn input_secret(&mut self, secret: &[u8]) {
         let salt: hkdf::Salt = self.derive_for_empty_hash(SecretKind::DerivedSecret);
         self.current = salt.extract(secret);
@@ -553,9 +593,25 @@ impl KeySchedule {
         T: for<'a> From<hkdf::Okm<'a, L>>,
         L: hkdf::KeyType,
     {
+        #[cfg(feature = "dangerous_configuration")]
+        self.trace_derivation(kind, hs_hash);
         hkdf_expand(&self.current, key_type, kind.to_bytes(), hs_hash)
     }
 
+    /// Pass the secret of given `kind` to the trace, if there is one.
+    #[cfg(feature = "dangerous_configuration")]
+    fn trace_derivation(&self, kind: SecretKind, hs_hash: &[u8]) {
+        if let Some(trace) = &self.trace {
+            let secret: PayloadU8 = hkdf_expand(
+                &self.current,
+                PayloadU8Len(self.algorithm.len()),
+                kind.to_bytes(),
+                hs_hash,
+            );
+            trace.derived(kind.to_bytes(), hs_hash, &secret.into_inner());
+        }
+    }
+
     fn derive_logged_secret(
         &self,
         kind: SecretKind,
@@ -565,10 +621,14 @@ impl KeySchedule {
     ) -> hkdf::Prk {
         let log_label = kind.log_label().expect("not a loggable secret");
         if key_log.will_log(log_label) {
-            let secret = self
-                .derive::<PayloadU8, _>(PayloadU8Len(self.algorithm.len()), kind, hs_hash)
-                .into_inner();
-            key_log.log(log_label, client_random, &secret);
+            // Not `derive`: the trace sees this derivation once, below.
+            let secret: PayloadU8 = hkdf_expand(
+                &self.current,
+                PayloadU8Len(self.algorithm.len()),
+                kind.to_bytes(),
+                hs_hash,
+            );
+            key_log.log(log_label, client_random, &secret.into_inner());
         }
         self.derive(self.algorithm, kind, hs_hash)
     }
@@ -731,7 +791,8 @@ pub(crate) fn derive_traffic_iv(secret: &hkdf::Prk) -> Iv {
 mod test {
     use super::{derive_traffic_iv, derive_traffic_key, KeySchedule, SecretKind};
     use crate::KeyLog;
-    use ring::{aead, hkdf};
+    use ring::{aead, digest, hkdf, hmac};
+    use std::sync::Mutex;
 
     #[test]
     fn test_vectors() {
@@ -887,6 +948,122 @@ mod test {
         assert_eq!(iv.value(), expected_iv);
     }
 
+    struct CaptureLog(Mutex<Vec<u8>>);
+
+    impl KeyLog for CaptureLog {
+        fn log(&self, _label: &str, _client_random: &[u8], secret: &[u8]) {
+            *self.0.lock().unwrap() = secret.to_vec();
+        }
+    }
+
+    #[cfg(feature = "dangerous_configuration")]
+    #[test]
+    fn test_logged_secret_is_traced_once() {
+        use crate::keylog::KeyScheduleTrace;
+        use std::sync::Arc;
+
+        struct CountTrace(Mutex<usize>);
+
+        impl KeyScheduleTrace for CountTrace {
+            fn derived(&self, _label: &[u8], _hs_hash: &[u8], _secret: &[u8]) {
+                *self.0.lock().unwrap() += 1;
+            }
+        }
+
+        let trace = Arc::new(CountTrace(Mutex::new(0)));
+        let mut ks = KeySchedule::new_with_empty_secret(hkdf::HKDF_SHA256);
+        ks.trace = Some(trace.clone());
+
+        let log = CaptureLog(Mutex::new(Vec::new()));
+        ks.derive_logged_secret(SecretKind::ClientHandshakeTrafficSecret, &[0u8; 32], &log, &[0; 32]);
+        assert!(!log.0.lock().unwrap().is_empty());
+        assert_eq!(*trace.0.lock().unwrap(), 1);
+    }
+
+    fn derived_secret(ks: &KeySchedule, kind: SecretKind, hash: &[u8]) -> Vec<u8> {
+        let log = CaptureLog(Mutex::new(Vec::new()));
+        ks.derive_logged_secret(kind, hash, &log, &[0; 32]);
+        log.0.into_inner().unwrap()
+    }
+
+    // RFC5869 and RFC8446 section 7.1, from HMAC.  Outputs are one
+    // hash long, so HKDF-Expand needs only its first block.
+    fn hmac(alg: hmac::Algorithm, key: &[u8], data: &[&[u8]]) -> Vec<u8> {
+        let mut ctx = hmac::Context::with_key(&hmac::Key::new(alg, key));
+        for d in data {
+            ctx.update(d);
+        }
+        ctx.sign().as_ref().to_vec()
+    }
+
+    fn expand_label(alg: hmac::Algorithm, secret: &[u8], label: &[u8], context: &[u8]) -> Vec<u8> {
+        let len = alg.digest_algorithm().output_len as u16;
+        let label = [ &b"tls13 "[..], label ].concat();
+        hmac(alg, secret, &[ &len.to_be_bytes()[..], &[ label.len() as u8 ][..], &label[..],
+                            &[ context.len() as u8 ][..], context, &[ 1u8 ][..] ])
+    }
+
+    fn next_secret(alg: hmac::Algorithm, secret: &[u8], input: &[u8]) -> Vec<u8> {
+        let empty_hash = digest::digest(alg.digest_algorithm(), &[]);
+        let salt = expand_label(alg, secret, b"derived", empty_hash.as_ref());
+        hmac(alg, &salt, &[ input ])
+    }
+
+    #[test]
+    fn test_kem_secrets_of_any_length() {
+        use crate::suites::{kex_sizes, KeyExchange};
+
+        let mut lengths = KeyExchange::supported_groups()
+            .iter()
+            .filter_map(|group| kex_sizes(*group))
+            .map(|sizes| sizes.shared_secret_len)
+            .collect::<Vec<_>>();
+        lengths.sort();
+        lengths.dedup();
+        // None is so short that it would be the weakest part of the
+        // key schedule, and they don't all match the hash length.
+        assert!(lengths[0] >= 16);
+        assert!(lengths.len() > 1);
+
+        let hash = [0x42u8; 48];
+        for &(hkdf, alg) in &[ (hkdf::HKDF_SHA256, hmac::HMAC_SHA256),
+                               (hkdf::HKDF_SHA384, hmac::HMAC_SHA384) ] {
+            let hash = &hash[..hkdf.len()];
+            let zeroes = vec![0u8; hkdf.len()];
+            let early_secret = hmac(alg, &zeroes, &[ &zeroes[..] ]);
+
+            for &len in &lengths {
+                // an ephemeral secret, then a KEMTLS authentication secret
+                let ephemeral: Vec<u8> = (0..len).map(|i| i as u8).collect();
+                let auth: Vec<u8> = (0..len).map(|i| !(i as u8)).collect();
+
+                let mut ks = KeySchedule::new_with_empty_secret(hkdf);
+                ks.input_secret(&ephemeral);
+                let handshake_secret = next_secret(alg, &early_secret, &ephemeral);
+                assert_eq!(derived_secret(&ks, SecretKind::ClientHandshakeTrafficSecret, hash),
+                           expand_label(alg, &handshake_secret, b"c hs traffic", hash));
+                assert_eq!(derived_secret(&ks, SecretKind::ServerHandshakeTrafficSecret, hash),
+                           expand_label(alg, &handshake_secret, b"s hs traffic", hash));
+
+                ks.input_secret(&auth);
+                let ahs_secret = next_secret(alg, &handshake_secret, &auth);
+                assert_eq!(derived_secret(&ks, SecretKind::ClientAuthenticatedHandshakeTrafficSecret, hash),
+                           expand_label(alg, &ahs_secret, b"c ahs traffic", hash));
+
+                ks.input_empty();
+                let master_secret = next_secret(alg, &ahs_secret, &zeroes);
+                assert_eq!(derived_secret(&ks, SecretKind::ServerApplicationTrafficSecret, hash),
+                           expand_label(alg, &master_secret, b"s ap traffic", hash));
+
+                // a secret is never confused with one a byte longer
+                let mut longer = KeySchedule::new_with_empty_secret(hkdf);
+                longer.input_secret(&[ &ephemeral[..], &[ 0u8 ][..] ].concat());
+                assert_ne!(derived_secret(&longer, SecretKind::ClientHandshakeTrafficSecret, hash),
+                           expand_label(alg, &handshake_secret, b"c hs traffic", hash));
+            }
+        }
+    }
+
     fn seal_zeroes(key: aead::UnboundKey) -> Vec<u8> {
         let key = aead::LessSafeKey::new(key);
         let mut seal_output = vec![0; 32];
@@ -41,6 +41,22 @@ pub trait KeyLog : Send + Sync {
     ///
     /// These strings are selected to match the NSS key log format:
     /// https://developer.mozilla.org/en-US/docs/Mozilla/Projects/NSS/Key_Log_Format
+    ///
+    /// KEMTLS adds an authenticated handshake stage, between the
+    /// handshake and traffic stages, with its own secrets.  They are
+    /// logged under their own labels, so a dissector that only knows
+    /// the NSS labels won't try to use them for the wrong records:
+    ///
+    /// - `CLIENT_AUTHENTICATED_HANDSHAKE_TRAFFIC_SECRET`: `secret`
+    ///   encrypts handshake messages from the client once it has
+    ///   encapsulated to the server's certificate (`c ahs traffic`).
+    /// - `SERVER_AUTHENTICATED_HANDSHAKE_TRAFFIC_SECRET`: `secret`
+    ///   encrypts handshake messages from the server from the same
+    ///   point on (`s ahs traffic`).
+    ///
+    /// A KEMTLS handshake also logs the usual
+    /// `CLIENT_HANDSHAKE_TRAFFIC_SECRET` and
+    /// `SERVER_HANDSHAKE_TRAFFIC_SECRET` for the records before that.
     fn log(&self, label: &str, client_random: &[u8], secret: &[u8]);
 
     /// Indicates whether the secret with label `label` will be logged.
@@ -51,6 +67,29 @@ pub trait KeyLog : Send + Sync {
     fn will_log(&self, _label: &str) -> bool { true }
 }
 
+/// This trait receives a trace of every secret derived by the TLS1.3
+/// key schedule.  It is meant for comparing rustls against a reference
+/// model of the (KEMTLS) key schedule, for example in formal verification.
+///
+/// For each derivation, `label` is the HKDF label (without the
+/// `"tls13 "` prefix), `hs_hash` is the transcript hash used as context,
+/// and `secret` is the derived secret.
+///
+/// As with `KeyLog`, the secrets passed over this interface are
+/// *extremely* sensitive.
+#[cfg(feature = "dangerous_configuration")]
+pub trait KeyScheduleTrace : Send + Sync {
+    /// Record a single key schedule derivation.
+    fn derived(&self, label: &[u8], hs_hash: &[u8], secret: &[u8]);
+
+    /// Record that the peer's Finished message did not match ours.
+    /// `hs_hash` is our transcript hash, `expected` is the verify data
+    /// we computed over it and `received` is what the peer sent.
+    ///
+    /// This usually means our transcript diverged from the peer's.
+    fn finished_mismatch(&self, _hs_hash: &[u8], _expected: &[u8], _received: &[u8]) {}
+}
+
 /// KeyLog that does exactly nothing.
 pub struct NoKeyLog;
 
@@ -189,6 +189,15 @@
 //!   details of these.  You will only need this if you're writing a QUIC
 //!   implementation.
 //!
+//! - `print_runtime`: this prints the time elapsed at each step of the
+//!   handshake to stdout, for benchmarking.  It is not in the default set.
+//!
+//! - `kem_cpu_time`: this records the CPU time spent in each KEM operation,
+//!   see `kem_cpu_times`.  It is not in the default set.
+//!
+//! Without these two features the handshake does no timing or printing of
+//! its own.
+//!
 
 // Require docs for public APIs, deny unsafe code, etc.
 #![forbid(unsafe_code,
@@ -257,6 +266,7 @@ pub mod internal {
     /// Functions for parsing PEM files containing certificates/keys.
     pub mod pemfile {
         pub use crate::pemfile::{certs, rsa_private_keys, pkcs8_private_keys};
+        pub use crate::pemfile::{kem_private_keys, der_certs};
     }
 
     /// Low-level TLS message parsing and encoding functions.
@@ -269,13 +279,21 @@ pub mod internal {
 pub use crate::msgs::enums::ProtocolVersion;
 pub use crate::msgs::enums::SignatureScheme;
 pub use crate::msgs::enums::CipherSuite;
-pub use crate::error::TLSError;
-pub use crate::session::Session;
+pub use crate::msgs::enums::HashAlgorithm;
+pub use crate::msgs::enums::AlertDescription;
+pub use crate::msgs::enums::HandshakeType;
+pub use crate::msgs::enums::NamedGroup;
+pub use crate::error::{TLSError, KemtlsStep, HandshakeState};
+pub use crate::session::{Session, ParsedChain};
 pub use crate::stream::{Stream, StreamOwned};
 pub use crate::anchors::{OwnedTrustAnchor, DistinguishedNames, RootCertStore};
 pub use crate::client::StoresClientSessions;
 pub use crate::client::handy::{NoClientSessionStorage, ClientSessionMemoryCache};
-pub use crate::client::{ClientConfig, ClientSession, WriteEarlyData};
+pub use crate::client::handy::ResolvesClientKemCertUsingIssuers;
+pub use crate::client::{ClientConfig, ClientSession, WriteEarlyData, KxHintStatus, CompatCCSPlacement};
+pub use crate::client::CertChainKeyAlgorithms;
+pub use crate::client::{AuthModePreference, EphemeralKeyPool};
+pub use crate::verify::{KemSecurityLevel, kem_spki_pin};
 pub use crate::client::ResolvesClientCert;
 pub use crate::server::StoresServerSessions;
 pub use crate::server::handy::{NoServerSessionStorage, ServerSessionMemoryCache};
@@ -286,6 +304,11 @@ pub use crate::ticketer::Ticketer;
 pub use crate::verify::{NoClientAuth, AllowAnyAuthenticatedClient,
                  AllowAnyAnonymousOrAuthenticatedClient};
 pub use crate::suites::{ALL_CIPHERSUITES, BulkAlgorithm, SupportedCipherSuite};
+pub use crate::suites::{KexSizes, kex_sizes};
+pub use crate::suites::{KemOp, kem_operation_counts, cert_kem_operation_counts};
+#[cfg(feature = "kem_cpu_time")]
+#[cfg_attr(docsrs, doc(cfg(feature = "kem_cpu_time")))]
+pub use crate::suites::{KemCpuClock, set_kem_cpu_clock, kem_cpu_times, reset_kem_cpu_times};
 pub use crate::key::{Certificate, PrivateKey};
 pub use crate::keylog::{KeyLog, NoKeyLog, KeyLogFile};
 
@@ -329,6 +352,20 @@ pub use crate::verify::{ServerCertVerifier, ServerCertVerified,
 #[cfg(feature = "dangerous_configuration")]
 #[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
 pub use crate::client::danger::DangerousClientConfig;
+#[cfg(feature = "dangerous_configuration")]
+#[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
+pub use crate::server::danger::DangerousServerConfig;
+#[cfg(feature = "dangerous_configuration")]
+#[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
+pub use crate::keylog::KeyScheduleTrace;
+
+#[cfg(feature = "bench")]
+#[cfg_attr(docsrs, doc(cfg(feature = "bench")))]
+/// Helpers for timing complete handshakes in memory, without any
+/// network I/O to add noise.  `HandshakeBench` records when each
+/// handshake message was sent, so runs are comparable across key
+/// exchange groups and authentication modes.
+pub mod bench;
 
 /// This is the rustls manual.
 pub mod manual;
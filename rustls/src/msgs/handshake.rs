@@ -229,7 +229,21 @@ impl DecomposedSignatureScheme for SignatureScheme {
             SignatureScheme::ECDSA_NISTP256_SHA256 |
                 SignatureScheme::ECDSA_NISTP384_SHA384 |
                 SignatureScheme::ECDSA_NISTP521_SHA512 => SignatureAlgorithm::ECDSA,
-            _ => SignatureAlgorithm::Unknown(0),
+            SignatureScheme::ED25519 => SignatureAlgorithm::ED25519,
+            SignatureScheme::ED448 => SignatureAlgorithm::ED448,
+            scheme => {
+                // Post-quantum schemes each have their own algorithm, so they
+                // never look compatible with a TLS1.2 RSA/ECDSA suite.
+                let pq_schemes: &[SignatureScheme] = include!("../generated/pq_sigschemes.rs");
+                let kem_schemes: &[SignatureScheme] = include!("../generated/pq_kemschemes.rs");
+                if pq_schemes.contains(&scheme) {
+                    include!("../generated/pq_sigscheme_to_sigalg.rs")
+                } else if kem_schemes.contains(&scheme) {
+                    SignatureAlgorithm::KEMTLS
+                } else {
+                    SignatureAlgorithm::Unknown(0)
+                }
+            }
         }
     }
 
@@ -399,6 +413,8 @@ impl KeyShareEntry {
 
 impl Codec for KeyShareEntry {
     fn encode(&self, bytes: &mut Vec<u8>) {
+        // large KEM public keys would otherwise cause repeated reallocation
+        bytes.reserve(4 + self.payload.0.len());
         self.group.encode(bytes);
         self.payload.encode(bytes);
     }
@@ -603,6 +619,7 @@ pub enum ClientExtension {
     ECPointFormats(ECPointFormatList),
     NamedGroups(NamedGroups),
     SignatureAlgorithms(SupportedSignatureSchemes),
+    SignatureAlgorithmsCert(SupportedSignatureSchemes),
     ServerName(ServerNameRequest),
     SessionTicketRequest,
     SessionTicketOffer(Payload),
@@ -621,6 +638,7 @@ pub enum ClientExtension {
     CachedInformation(CachedInfo),
     ProactiveCiphertext(ProactiveCiphertextOffer),
     ProactiveClientAuth,
+    PostHandshakeAuth,
 }
 
 impl ClientExtension {
@@ -629,6 +647,7 @@ impl ClientExtension {
             ClientExtension::ECPointFormats(_) => ExtensionType::ECPointFormats,
             ClientExtension::NamedGroups(_) => ExtensionType::EllipticCurves,
             ClientExtension::SignatureAlgorithms(_) => ExtensionType::SignatureAlgorithms,
+            ClientExtension::SignatureAlgorithmsCert(_) => ExtensionType::SignatureAlgorithmsCert,
             ClientExtension::ServerName(_) => ExtensionType::ServerName,
             ClientExtension::SessionTicketRequest |
                 ClientExtension::SessionTicketOffer(_) => ExtensionType::SessionTicket,
@@ -646,6 +665,7 @@ impl ClientExtension {
             ClientExtension::CachedInformation(_) => ExtensionType::CachedInformation,
             ClientExtension::ProactiveCiphertext(_) => ExtensionType::ProactiveCiphertext,
             ClientExtension::ProactiveClientAuth => ExtensionType::ProactiveClientAuth,
+            ClientExtension::PostHandshakeAuth => ExtensionType::PostHandshakeAuth,
             ClientExtension::Unknown(ref r) => r.typ,
         }
     }
@@ -660,11 +680,13 @@ impl Codec for ClientExtension {
             ClientExtension::ECPointFormats(ref r) => r.encode(&mut sub),
             ClientExtension::NamedGroups(ref r) => r.encode(&mut sub),
             ClientExtension::SignatureAlgorithms(ref r) => r.encode(&mut sub),
+            ClientExtension::SignatureAlgorithmsCert(ref r) => r.encode(&mut sub),
             ClientExtension::ServerName(ref r) => r.encode(&mut sub),
             ClientExtension::SessionTicketRequest |
                 ClientExtension::ExtendedMasterSecretRequest |
                 ClientExtension::SignedCertificateTimestampRequest |
                 ClientExtension::ProactiveClientAuth |
+                ClientExtension::PostHandshakeAuth |
                 ClientExtension::EarlyData => (),
             ClientExtension::SessionTicketOffer(ref r) => r.encode(&mut sub),
             ClientExtension::Protocols(ref r) => r.encode(&mut sub),
@@ -700,6 +722,10 @@ impl Codec for ClientExtension {
                 let schemes = SupportedSignatureSchemes::read(&mut sub)?;
                 ClientExtension::SignatureAlgorithms(schemes)
             }
+            ExtensionType::SignatureAlgorithmsCert => {
+                let schemes = SupportedSignatureSchemes::read(&mut sub)?;
+                ClientExtension::SignatureAlgorithmsCert(schemes)
+            }
             ExtensionType::ServerName => {
                 ClientExtension::ServerName(ServerNameRequest::read(&mut sub)?)
             }
@@ -749,6 +775,9 @@ impl Codec for ClientExtension {
             ExtensionType::ProactiveClientAuth if !sub.any_left() => {
                 ClientExtension::ProactiveClientAuth
             },
+            ExtensionType::PostHandshakeAuth if !sub.any_left() => {
+                ClientExtension::PostHandshakeAuth
+            },
             ExtensionType::CachedInformation => ClientExtension::CachedInformation(CachedInfo::read(&mut sub)?),
             _ => ClientExtension::Unknown(UnknownExtension::read(typ, &mut sub)?),
         })
@@ -801,7 +830,9 @@ impl ClientExtension {
         for cert in certs.iter() {
             let eecert = webpki::EndEntityCert::from(&cert.0);
             if let Ok(eecert) = eecert {
-                if eecert.is_kem_cert() && eecert.verify_is_valid_for_dns_name(hostname).is_ok() {
+                if eecert.is_kem_cert() && crate::verify::kem_cert_has_valid_key_len(cert) &&
+                    eecert.verify_is_valid_for_dns_name(hostname).is_ok() {
+                    crate::suites::count_cert_kem_op(crate::suites::KemOp::Encapsulate);
                     let (ct, ss) = eecert.encapsulate().ok()?;
                     return Some(
                         (ClientExtension::ProactiveCiphertext(
@@ -907,7 +938,20 @@ impl Codec for ServerExtension {
                 ServerExtension::Protocols(ProtocolNameList::read(&mut sub)?)
             }
             ExtensionType::KeyShare => {
-                ServerExtension::KeyShare(KeyShareEntry::read(&mut sub)?)
+                let body = sub.rest();
+                let mut entries = Reader::init(body);
+                let share = KeyShareEntry::read(&mut entries)?;
+
+                // A server may only send one entry.  Keep any others
+                // opaque, so the handshake can reject them.
+                if entries.any_left() {
+                    ServerExtension::Unknown(UnknownExtension {
+                        typ,
+                        payload: Payload::new(body.to_vec()),
+                    })
+                } else {
+                    ServerExtension::KeyShare(share)
+                }
             }
             ExtensionType::PreSharedKey => {
                 ServerExtension::PresharedKey(u16::read(&mut sub)?)
@@ -1027,6 +1071,14 @@ impl ClientHelloPayload {
         }
     }
 
+    pub fn get_sigalgs_cert_extension(&self) -> Option<&SupportedSignatureSchemes> {
+        let ext = self.find_extension(ExtensionType::SignatureAlgorithmsCert)?;
+        match *ext {
+            ClientExtension::SignatureAlgorithmsCert(ref req) => Some(req),
+            _ => None,
+        }
+    }
+
     pub fn get_namedgroups_extension(&self) -> Option<&NamedGroups> {
         let ext = self.find_extension(ExtensionType::EllipticCurves)?;
         match *ext {
@@ -1445,6 +1497,18 @@ impl Codec for CertificatePayload {
 // That's annoying. It means the parsing is not
 // context-free any more.
 
+/// The extensions we understand on a TLS1.3 CertificateEntry.
+pub const CERT_ENTRY_EXTENSIONS: &[ExtensionType] = &[
+    ExtensionType::StatusRequest,
+    ExtensionType::SCT,
+];
+
+/// The extensions we accept on the CertificateEntry of a KEM
+/// end-entity certificate: none.  In KEMTLS nothing checks a stapled
+/// OCSP response or SCTs against a KEM certificate, so we refuse them
+/// rather than ignore them.
+pub const KEM_CERT_ENTRY_EXTENSIONS: &[ExtensionType] = &[];
+
 #[derive(Debug, Clone)]
 pub enum CertificateExtension {
     CertificateStatus(CertificateStatus),
@@ -1562,12 +1626,16 @@ impl CertificateEntry {
     }
 
     pub fn has_unknown_extension(&self) -> bool {
+        self.extension_not_in(CERT_ENTRY_EXTENSIONS).is_some()
+    }
+
+    /// The type of the first extension on this entry which isn't
+    /// one of `allowed`, if any.
+    pub fn extension_not_in(&self, allowed: &[ExtensionType]) -> Option<ExtensionType> {
         self.exts
             .iter()
-            .any(|ext| {
-                 ext.get_type() != ExtensionType::StatusRequest &&
-                 ext.get_type() != ExtensionType::SCT
-                 })
+            .map(CertificateExtension::get_type)
+            .find(|typ| !allowed.contains(typ))
     }
 
     pub fn get_ocsp_response(&self) -> Option<&Vec<u8>> {
@@ -1643,6 +1711,11 @@ impl CertificatePayloadTLS13 {
         false
     }
 
+    pub fn end_entity_extension_not_in(&self, allowed: &[ExtensionType]) -> Option<ExtensionType> {
+        self.entries.first()
+            .and_then(|entry| entry.extension_not_in(allowed))
+    }
+
     pub fn get_end_entity_ocsp(&self) -> Vec<u8> {
         self.entries.first()
             .and_then(CertificateEntry::get_ocsp_response)
@@ -2448,9 +2521,13 @@ impl HandshakeMessagePayload {
     }
 
     pub fn build_key_update_notify() -> HandshakeMessagePayload {
+        HandshakeMessagePayload::build_key_update(KeyUpdateRequest::UpdateNotRequested)
+    }
+
+    pub fn build_key_update(kur: KeyUpdateRequest) -> HandshakeMessagePayload {
         HandshakeMessagePayload {
             typ: HandshakeType::KeyUpdate,
-            payload: HandshakePayload::KeyUpdate(KeyUpdateRequest::UpdateNotRequested),
+            payload: HandshakePayload::KeyUpdate(kur),
         }
     }
 
@@ -88,6 +88,27 @@ fn can_roundtrip_unknown_client_ext() {
     assert_eq!(bytes.to_vec(), ext.get_encoding());
 }
 
+#[test]
+fn server_key_share_with_several_entries_is_kept_opaque() {
+    let single = [0x00u8, 0x33, 0, 5, 0x00, 0x1d, 0, 1, 0xaa];
+    let ext = ServerExtension::read_bytes(&single)
+        .unwrap();
+    match ext {
+        ServerExtension::KeyShare(ref share) => assert_eq!(share.group, NamedGroup::X25519),
+        _ => panic!("expected a key share"),
+    }
+
+    let double = [0x00u8, 0x33, 0, 10, 0x00, 0x1d, 0, 1, 0xaa, 0x00, 0x17, 0, 1, 0xbb];
+    let ext = ServerExtension::read_bytes(&double)
+        .unwrap();
+    match ext {
+        ServerExtension::Unknown(_) => {}
+        _ => panic!("expected an opaque extension"),
+    }
+    assert_eq!(ext.get_type(), ExtensionType::KeyShare);
+    assert_eq!(double.to_vec(), ext.get_encoding());
+}
+
 #[test]
 fn can_roundtrip_single_sni() {
     let bytes = [
@@ -350,6 +371,57 @@ fn decomposed_signature_scheme_has_correct_mappings() {
                SignatureScheme::ECDSA_NISTP384_SHA384);
     assert_eq!(SignatureScheme::make(SignatureAlgorithm::ECDSA, HashAlgorithm::SHA512),
                SignatureScheme::ECDSA_NISTP521_SHA512);
+
+    assert_eq!(SignatureScheme::ED25519.sign(), SignatureAlgorithm::ED25519);
+    assert_eq!(SignatureScheme::ED448.sign(), SignatureAlgorithm::ED448);
+    assert_eq!(SignatureScheme::Unknown(0xfefe).sign(), SignatureAlgorithm::Unknown(0));
+}
+
+#[test]
+fn decomposed_signature_scheme_roundtrips_classical_schemes() {
+    for &scheme in &[SignatureScheme::RSA_PKCS1_SHA1,
+                     SignatureScheme::RSA_PKCS1_SHA256,
+                     SignatureScheme::RSA_PKCS1_SHA384,
+                     SignatureScheme::RSA_PKCS1_SHA512,
+                     SignatureScheme::ECDSA_NISTP256_SHA256,
+                     SignatureScheme::ECDSA_NISTP384_SHA384,
+                     SignatureScheme::ECDSA_NISTP521_SHA512] {
+        let hash = match scheme {
+            SignatureScheme::RSA_PKCS1_SHA1 => HashAlgorithm::SHA1,
+            SignatureScheme::RSA_PKCS1_SHA256 |
+                SignatureScheme::ECDSA_NISTP256_SHA256 => HashAlgorithm::SHA256,
+            SignatureScheme::RSA_PKCS1_SHA384 |
+                SignatureScheme::ECDSA_NISTP384_SHA384 => HashAlgorithm::SHA384,
+            _ => HashAlgorithm::SHA512,
+        };
+        assert_eq!(SignatureScheme::make(scheme.sign(), hash), scheme);
+    }
+}
+
+#[test]
+fn decomposed_signature_scheme_maps_pq_signature_schemes() {
+    let schemes: &[SignatureScheme] = include!("../generated/pq_sigschemes.rs");
+    assert!(!schemes.is_empty());
+    for &scheme in schemes {
+        let alg = scheme.sign();
+        assert_ne!(alg, SignatureAlgorithm::RSA, "{:?}", scheme);
+        assert_ne!(alg, SignatureAlgorithm::ECDSA, "{:?}", scheme);
+        assert_ne!(alg, SignatureAlgorithm::KEMTLS, "{:?}", scheme);
+        if let SignatureAlgorithm::Unknown(_) = alg {
+            panic!("{:?} has no signature algorithm", scheme);
+        }
+        // each PQ scheme maps to the algorithm of the same name
+        assert_eq!(format!("{:?}", alg), format!("{:?}", scheme));
+    }
+}
+
+#[test]
+fn decomposed_signature_scheme_maps_kem_schemes() {
+    let schemes: &[SignatureScheme] = include!("../generated/pq_kemschemes.rs");
+    assert!(!schemes.is_empty());
+    for &scheme in schemes {
+        assert_eq!(scheme.sign(), SignatureAlgorithm::KEMTLS, "{:?}", scheme);
+    }
 }
 
 fn get_sample_clienthellopayload() -> ClientHelloPayload {
@@ -363,6 +435,7 @@ fn get_sample_clienthellopayload() -> ClientHelloPayload {
             ClientExtension::ECPointFormats(ECPointFormatList::supported()),
             ClientExtension::NamedGroups(vec![ NamedGroup::X25519 ]),
             ClientExtension::SignatureAlgorithms(vec![ SignatureScheme::ECDSA_NISTP256_SHA256 ]),
+            ClientExtension::SignatureAlgorithmsCert(vec![ SignatureScheme::ED25519 ]),
             ClientExtension::make_sni(DNSNameRef::try_from_ascii_str("hello").unwrap()),
             ClientExtension::SessionTicketRequest,
             ClientExtension::SessionTicketOffer(Payload(vec![])),
@@ -385,6 +458,7 @@ fn get_sample_clienthellopayload() -> ClientHelloPayload {
             ClientExtension::CertificateStatusRequest(CertificateStatusRequest::build_ocsp()),
             ClientExtension::SignedCertificateTimestampRequest,
             ClientExtension::TransportParameters(vec![ 1, 2, 3 ]),
+            ClientExtension::PostHandshakeAuth,
             ClientExtension::Unknown(UnknownExtension {
                 typ: ExtensionType::Unknown(12345),
                 payload: Payload(vec![ 1, 2, 3 ])
@@ -516,6 +590,12 @@ fn client_get_sigalgs_extension() {
                                  |chp| chp.get_sigalgs_extension().is_some());
 }
 
+#[test]
+fn client_get_sigalgs_cert_extension() {
+    test_client_extension_getter(ExtensionType::SignatureAlgorithmsCert,
+                                 |chp| chp.get_sigalgs_cert_extension().is_some());
+}
+
 #[test]
 fn client_get_namedgroups_extension() {
     test_client_extension_getter(ExtensionType::EllipticCurves,
@@ -745,6 +825,21 @@ fn certentry_get_scts() {
                                |ce| ce.get_scts().is_some());
 }
 
+#[test]
+fn certentry_extension_not_in() {
+    let mut cert = get_sample_certificatepayloadtls13();
+    assert_eq!(cert.end_entity_extension_not_in(CERT_ENTRY_EXTENSIONS),
+               Some(ExtensionType::Unknown(12345)));
+    assert_eq!(cert.end_entity_extension_not_in(KEM_CERT_ENTRY_EXTENSIONS),
+               Some(ExtensionType::StatusRequest));
+
+    cert.entries[0].exts.retain(|ext| ext.get_type() != ExtensionType::Unknown(12345));
+    assert_eq!(cert.end_entity_extension_not_in(CERT_ENTRY_EXTENSIONS), None);
+
+    cert.entries[0].exts.clear();
+    assert_eq!(cert.end_entity_extension_not_in(KEM_CERT_ENTRY_EXTENSIONS), None);
+}
+
 fn get_sample_serverhellopayload() -> ServerHelloPayload {
     ServerHelloPayload {
         legacy_version: ProtocolVersion::TLSv1_2,
@@ -6,7 +6,7 @@ use crate::msgs::ccs::ChangeCipherSpecPayload;
 use crate::msgs::handshake::HandshakeMessagePayload;
 use crate::msgs::enums::{ContentType, ProtocolVersion};
 use crate::msgs::enums::{AlertLevel, AlertDescription};
-use crate::msgs::enums::HandshakeType;
+use crate::msgs::enums::{HandshakeType, KeyUpdateRequest};
 
 use std::mem;
 
@@ -221,10 +221,14 @@ impl Message {
     }
 
     pub fn build_key_update_notify() -> Message {
+        Message::build_key_update(KeyUpdateRequest::UpdateNotRequested)
+    }
+
+    pub fn build_key_update(kur: KeyUpdateRequest) -> Message {
         Message {
             typ: ContentType::Handshake,
             version: ProtocolVersion::TLSv1_3,
-            payload: MessagePayload::Handshake(HandshakeMessagePayload::build_key_update_notify()),
+            payload: MessagePayload::Handshake(HandshakeMessagePayload::build_key_update(kur)),
         }
     }
 }
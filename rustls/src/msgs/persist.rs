@@ -1,5 +1,5 @@
 use crate::msgs::handshake::SessionID;
-use crate::msgs::enums::{CipherSuite, ProtocolVersion};
+use crate::msgs::enums::{CipherSuite, ProtocolVersion, NamedGroup};
 use crate::msgs::codec::{Reader, Codec};
 use crate::msgs::handshake::CertificatePayload;
 use crate::msgs::base::{PayloadU8, PayloadU16};
@@ -48,6 +48,14 @@ impl ClientSessionKey {
             dns_name: PayloadU8::new(dns_name_str.as_bytes().to_vec()),
         }
     }
+
+    pub fn kem_pubkey_for_dns_name(dns_name: webpki::DNSNameRef) -> ClientSessionKey {
+        let dns_name_str: &str = dns_name.into();
+        ClientSessionKey {
+            kind: b"kem-pubkey",
+            dns_name: PayloadU8::new(dns_name_str.as_bytes().to_vec()),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -63,6 +71,9 @@ pub struct ClientSessionValue {
     pub extended_ms: bool,
     pub max_early_data_size: u32,
     pub server_cert_chain: CertificatePayload,
+    /// The key exchange group of the handshake this session came from,
+    /// if known.
+    pub kx_group: Option<NamedGroup>,
 }
 
 impl Codec for ClientSessionValue {
@@ -78,6 +89,12 @@ impl Codec for ClientSessionValue {
         (if self.extended_ms { 1u8 } else { 0u8 }).encode(bytes);
         self.max_early_data_size.encode(bytes);
         self.server_cert_chain.encode(bytes);
+        if let Some(group) = self.kx_group {
+            1u8.encode(bytes);
+            group.encode(bytes);
+        } else {
+            0u8.encode(bytes);
+        }
     }
 
     fn read(r: &mut Reader) -> Option<ClientSessionValue> {
@@ -92,6 +109,14 @@ impl Codec for ClientSessionValue {
         let extended_ms = u8::read(r)?;
         let max_early_data_size = u32::read(r)?;
         let server_cert_chain = CertificatePayload::read(r)?;
+        // Sessions stored before we recorded the group end here.
+        let kx_group = if !r.any_left() {
+            None
+        } else if u8::read(r)? == 1 {
+            Some(NamedGroup::read(r)?)
+        } else {
+            None
+        };
 
         Some(ClientSessionValue {
             version: v,
@@ -105,6 +130,7 @@ impl Codec for ClientSessionValue {
             extended_ms: extended_ms == 1u8,
             max_early_data_size,
             server_cert_chain,
+            kx_group,
         })
     }
 }
@@ -131,6 +157,7 @@ impl ClientSessionValue {
             extended_ms: false,
             max_early_data_size: 0,
             server_cert_chain: server_cert_chain.clone(),
+            kx_group: None,
         }
     }
 
@@ -149,9 +176,18 @@ impl ClientSessionValue {
         self.lifetime != 0 && self.epoch + u64::from(self.lifetime) < time_now
     }
 
+    /// True if this session claims to have been issued after `time_now`.
+    /// This happens if the clock has gone backwards since.
+    pub fn is_from_future(&self, time_now: u64) -> bool {
+        self.epoch > time_now
+    }
+
     pub fn get_obfuscated_ticket_age(&self, time_now: u64) -> u32 {
-        let age_secs = time_now.saturating_sub(self.epoch);
-        let age_millis = age_secs as u32 * 1000;
+        let mut age_secs = time_now.saturating_sub(self.epoch);
+        if self.lifetime != 0 {
+            age_secs = cmp::min(age_secs, u64::from(self.lifetime));
+        }
+        let age_millis = (age_secs as u32).saturating_mul(1000);
         age_millis.wrapping_add(self.age_add)
     }
 
@@ -164,6 +200,10 @@ impl ClientSessionValue {
     pub fn set_max_early_data_size(&mut self, sz: u32) {
         self.max_early_data_size = sz;
     }
+
+    pub fn set_kx_group(&mut self, group: NamedGroup) {
+        self.kx_group = Some(group);
+    }
 }
 
 // --- Server types ---
@@ -30,6 +30,64 @@ fn clientsessionvalue_is_debug() {
     println!("{:?}", csv);
 }
 
+fn clientsessionvalue_issued_at(epoch: u64, lifetime: u32) -> ClientSessionValue {
+    let mut csv = ClientSessionValue::new(ProtocolVersion::TLSv1_3,
+                                          CipherSuite::TLS13_AES_128_GCM_SHA256,
+                                          &SessionID::empty(),
+                                          vec![],
+                                          vec![1, 2, 3],
+                                          &vec![]);
+    csv.set_times(epoch, lifetime, 0);
+    csv
+}
+
+#[test]
+fn clientsessionvalue_ticket_age() {
+    let csv = clientsessionvalue_issued_at(1000, 60);
+    assert_eq!(csv.get_obfuscated_ticket_age(1000), 0);
+    assert_eq!(csv.get_obfuscated_ticket_age(1010), 10_000);
+    assert!(!csv.is_from_future(1000));
+}
+
+#[test]
+fn clientsessionvalue_ticket_age_clamped_to_lifetime() {
+    let csv = clientsessionvalue_issued_at(1000, 60);
+    assert_eq!(csv.get_obfuscated_ticket_age(1_000_000_000), 60_000);
+}
+
+#[test]
+fn clientsessionvalue_from_future() {
+    let csv = clientsessionvalue_issued_at(1000, 60);
+    assert!(csv.is_from_future(999));
+    assert_eq!(csv.get_obfuscated_ticket_age(999), 0);
+}
+
+#[test]
+fn clientsessionvalue_kx_group_round_trips() {
+    let mut csv = clientsessionvalue_issued_at(1000, 60);
+    csv.kx_group = Some(NamedGroup::Kyber512);
+    let bytes = csv.get_encoding();
+    let mut rd = Reader::init(&bytes);
+    let read = ClientSessionValue::read(&mut rd).unwrap();
+    assert_eq!(read.kx_group, Some(NamedGroup::Kyber512));
+    assert!(!rd.any_left());
+}
+
+#[test]
+fn clientsessionvalue_reads_encoding_without_kx_group() {
+    // Sessions stored before the kx_group flag byte existed.
+    let csv = clientsessionvalue_issued_at(1000, 60);
+    let mut bytes = csv.get_encoding();
+    assert_eq!(bytes.pop(), Some(0));
+
+    let mut rd = Reader::init(&bytes);
+    let read = ClientSessionValue::read(&mut rd).unwrap();
+    assert_eq!(read.kx_group, None);
+    assert_eq!(read.epoch, 1000);
+    assert_eq!(read.lifetime, 60);
+    assert_eq!(read.master_secret.0, vec![1, 2, 3]);
+}
+
 #[test]
 fn serversessionvalue_is_debug() {
     let ssv = ServerSessionValue::new(None,
@@ -72,3 +130,15 @@ fn serversessionvalue_with_cert() {
     let ssv = ServerSessionValue::read(&mut rd).unwrap();
     assert_eq!(ssv.get_encoding(), bytes);
 }
+
+#[test]
+fn clientsessionvalue_kx_group_round_trips() {
+    let mut csv = clientsessionvalue_issued_at(1000, 60);
+    let bytes = csv.get_encoding();
+    assert_eq!(ClientSessionValue::read_bytes(&bytes).unwrap().kx_group, None);
+
+    csv.set_kx_group(NamedGroup::Kyber512);
+    let bytes = csv.get_encoding();
+    assert_eq!(ClientSessionValue::read_bytes(&bytes).unwrap().kx_group,
+               Some(NamedGroup::Kyber512));
+}
@@ -1,6 +1,7 @@
 use std::io;
 use base64;
 use crate::key;
+use crate::sign;
 
 /// Extract and decode all PEM sections from `rd`, which begin with `start_mark`
 /// and end with `end_mark`.  Apply the functor `f` to each decoded buffer,
@@ -72,3 +73,61 @@ pub fn pkcs8_private_keys(rd: &mut dyn io::BufRead) -> Result<Vec<key::PrivateKe
             "-----END PRIVATE KEY-----",
             &|v| key::PrivateKey(v))
 }
+
+/// Extract the PKCS8-encoded KEM private keys usable for KEMTLS
+/// authentication from rd, skipping keys of any other type.  Keys
+/// are recognised by the algorithm OIDs of the supported KEM schemes.
+pub fn kem_private_keys(rd: &mut dyn io::BufRead) -> Result<Vec<key::PrivateKey>, ()> {
+    let keys = pkcs8_private_keys(rd)?;
+    Ok(keys.into_iter()
+        .filter(|key| sign::any_kem_type(key).is_ok())
+        .collect())
+}
+
+/// Split rd, a series of concatenated DER-encoded certificates (as
+/// some tools write KEM certificate chains), into `key::Certificate`s.
+pub fn der_certs(rd: &mut dyn io::Read) -> Result<Vec<key::Certificate>, ()> {
+    let mut buf = Vec::new();
+    rd.read_to_end(&mut buf)
+        .map_err(|_| ())?;
+
+    let mut certs = Vec::new();
+    let mut rest = &buf[..];
+    while !rest.is_empty() {
+        let len = der_sequence_len(rest)?;
+        let (cert, tail) = rest.split_at(len);
+        certs.push(key::Certificate(cert.to_vec()));
+        rest = tail;
+    }
+
+    Ok(certs)
+}
+
+/// The length of the DER SEQUENCE at the start of `der`, including its
+/// tag and length octets.
+fn der_sequence_len(der: &[u8]) -> Result<usize, ()> {
+    if der.len() < 2 || der[0] != 0x30 {
+        return Err(());
+    }
+
+    let (header_len, body_len) = match der[1] {
+        len @ 0x00..=0x7f => (2, usize::from(len)),
+        0x81..=0x84 => {
+            let len_octets = usize::from(der[1] & 0x7f);
+            if der.len() < 2 + len_octets {
+                return Err(());
+            }
+            let body_len = der[2..2 + len_octets].iter()
+                .fold(0usize, |acc, octet| (acc << 8) | usize::from(*octet));
+            (2 + len_octets, body_len)
+        }
+        _ => return Err(()),
+    };
+
+    let total = header_len.checked_add(body_len)
+        .ok_or(())?;
+    if total > der.len() {
+        return Err(());
+    }
+    Ok(total)
+}
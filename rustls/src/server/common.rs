@@ -4,7 +4,9 @@ use crate::suites;
 use crate::key;
 use crate::hash_hs;
 
-use std::{mem, time::Instant};
+use std::mem;
+#[cfg(feature = "print_runtime")]
+use std::time::Instant;
 
 pub struct HandshakeDetails {
     pub transcript: hash_hs::HandshakeHash,
@@ -12,7 +14,9 @@ pub struct HandshakeDetails {
     pub session_id: SessionID,
     pub randoms: SessionRandoms,
     pub using_ems: bool,
+    pub client_offered_pha: bool,
     pub extra_exts: Vec<ServerExtension>,
+    #[cfg(feature = "print_runtime")]
     start_time: Instant,
 }
 
@@ -24,18 +28,27 @@ impl HandshakeDetails {
             session_id: SessionID::empty(),
             randoms: SessionRandoms::for_server(),
             using_ems: false,
+            client_offered_pha: false,
             extra_exts,
+            #[cfg(feature = "print_runtime")]
             start_time: Instant::now(),
         }
     }
 
+    /// Print `label` and the time elapsed since the handshake started.
+    /// This does nothing unless the `print_runtime` feature is enabled.
+    #[cfg_attr(not(feature = "print_runtime"), allow(unused_variables))]
     pub(crate) fn print_runtime(&self, label: &str) {
+        #[cfg(feature = "print_runtime")]
         println!("{}: {} ns", label, self.start_time.elapsed().as_nanos())
     }
 
     #[inline]
     pub(crate) fn reset_runtime(&mut self) {
-        self.start_time = Instant::now();
+        #[cfg(feature = "print_runtime")]
+        {
+            self.start_time = Instant::now();
+        }
     }
 }
 
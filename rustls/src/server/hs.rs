@@ -23,7 +23,7 @@ use crate::rand;
 use crate::sign;
 #[cfg(feature = "logging")]
 use crate::log::{trace, debug};
-use crate::error::TLSError;
+use crate::error::{TLSError, HandshakeState};
 use webpki;
 #[cfg(feature = "quic")]
 use crate::session::Protocol;
@@ -46,6 +46,13 @@ pub trait State {
 
     fn perhaps_write_key_update(&mut self, _sess: &mut ServerSessionImpl) {
     }
+
+    fn request_client_auth(&mut self, _sess: &mut ServerSessionImpl) -> Result<(), TLSError> {
+        Err(TLSError::HandshakeNotComplete)
+    }
+
+    /// Which state this is, for `get_failed_state`.
+    fn state(&self) -> HandshakeState;
 }
 
 pub fn incompatible(sess: &mut ServerSessionImpl, why: &str) -> TLSError {
@@ -68,6 +75,42 @@ pub fn decode_error(sess: &mut ServerSessionImpl, why: &str) -> TLSError {
     TLSError::PeerMisbehavedError(why.to_string())
 }
 
+/// Where we end up once the peer has sent close_notify.  Plaintext
+/// received before the alert stays readable; nothing may follow it.
+pub struct ExpectClosed {
+    traffic: NextState,
+}
+
+impl ExpectClosed {
+    pub fn new(traffic: NextState) -> NextState {
+        Box::new(ExpectClosed { traffic })
+    }
+}
+
+impl State for ExpectClosed {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::Closed
+    }
+
+    fn handle(self: Box<Self>, _sess: &mut ServerSessionImpl, m: Message) -> NextStateOrError {
+        Err(TLSError::InappropriateMessage {
+            expect_types: vec![],
+            got_type: m.typ,
+        })
+    }
+
+    fn export_keying_material(&self,
+                              output: &mut [u8],
+                              label: &[u8],
+                              context: Option<&[u8]>) -> Result<(), TLSError> {
+        self.traffic.export_keying_material(output, label, context)
+    }
+
+    fn request_client_auth(&mut self, _sess: &mut ServerSessionImpl) -> Result<(), TLSError> {
+        Err(TLSError::PeerClosed)
+    }
+}
+
 pub fn can_resume(sess: &ServerSessionImpl,
                   handshake: &HandshakeDetails,
                   resumedata: &Option<persist::ServerSessionValue>) -> bool {
@@ -309,6 +352,12 @@ impl ExpectClientHello {
             ech.handshake.transcript.set_client_auth_enabled();
         }
 
+        #[cfg(feature = "dangerous_configuration")] {
+            if let Some(random) = server_config.fixed_server_random {
+                ech.handshake.randoms.server = random;
+            }
+        }
+
         ech
     }
 
@@ -562,6 +611,10 @@ impl ExpectClientHello {
 }
 
 impl State for ExpectClientHello {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::ClientHello
+    }
+
     fn handle(mut self: Box<Self>, sess: &mut ServerSessionImpl, m: Message) -> NextStateOrError {
         let client_hello = require_handshake_msg!(m, HandshakeType::ClientHello, HandshakePayload::ClientHello)?;
         self.handshake.reset_runtime();
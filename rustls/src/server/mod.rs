@@ -1,17 +1,17 @@
-use crate::session::{Session, SessionCommon, MiddleboxCCS};
+use crate::session::{Session, SessionCommon, MiddleboxCCS, ParsedChain};
 use crate::keylog::{KeyLog, NoKeyLog};
 use crate::suites::{SupportedCipherSuite, ALL_CIPHERSUITES};
 use crate::msgs::enums::ContentType;
 use crate::msgs::enums::SignatureScheme;
-use crate::msgs::enums::{AlertDescription, HandshakeType, ProtocolVersion};
+use crate::msgs::enums::{AlertDescription, HandshakeType, HashAlgorithm, ProtocolVersion};
 use crate::msgs::handshake::ServerExtension;
 use crate::msgs::message::Message;
-use crate::error::TLSError;
+use crate::error::{TLSError, HandshakeState};
 use crate::sign;
 use crate::verify;
 use crate::key;
 #[cfg(feature = "logging")]
-use crate::log::trace;
+use crate::log::{debug, trace};
 
 use webpki;
 
@@ -180,10 +180,22 @@ pub struct ServerConfig {
     /// does nothing.
     pub key_log: Arc<dyn KeyLog>,
 
+    /// Whether to skip client authentication during the handshake
+    /// with clients that offer post-handshake authentication, and
+    /// instead authenticate them when `ServerSession::request_client_auth`
+    /// is called.  Other clients are asked during the handshake as usual.
+    ///
+    /// The default is false.
+    pub defer_client_auth: bool,
+
     /// Amount of early data to accept; 0 to disable.
     #[cfg(feature = "quic")]    // TLS support unimplemented
     #[doc(hidden)]
     pub max_early_data_size: u32,
+
+    /// Server random to use instead of a fresh one.
+    #[cfg(feature = "dangerous_configuration")]
+    fixed_server_random: Option<[u8; 32]>,
 }
 
 impl ServerConfig {
@@ -230,8 +242,11 @@ impl ServerConfig {
             versions: vec![ ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2 ],
             verifier: client_cert_verifier,
             key_log: Arc::new(NoKeyLog {}),
+            defer_client_auth: false,
             #[cfg(feature = "quic")]
             max_early_data_size: 0,
+            #[cfg(feature = "dangerous_configuration")]
+            fixed_server_random: None,
         }
     }
 
@@ -308,10 +323,58 @@ impl ServerConfig {
         self.alpn_protocols.extend_from_slice(protocols);
     }
 
+    /// Restricts the ciphersuites we will choose to those whose hash is
+    /// `hash`, keeping their order.  This fixes the hash used by the key
+    /// schedule; see `ServerSession::negotiated_suite_hash`.
+    ///
+    /// An error is returned, and the config left unchanged, if no
+    /// configured suite uses `hash`.
+    pub fn restrict_suite_hash(&mut self, hash: HashAlgorithm) -> Result<(), TLSError> {
+        if !self.ciphersuites.iter().any(|cs| cs.hash == hash) {
+            return Err(TLSError::General(format!("no ciphersuite uses {:?}", hash)));
+        }
+
+        self.ciphersuites.retain(|cs| cs.hash == hash);
+        Ok(())
+    }
+
     /// Overrides the default `ClientCertVerifier` with something else.
     pub fn set_client_certificate_verifier(&mut self, verifier: Arc<dyn verify::ClientCertVerifier>) {
         self.verifier = verifier;
     }
+
+    /// Access configuration options whose use is dangerous and requires
+    /// extra care.
+    #[cfg(feature = "dangerous_configuration")]
+    pub fn dangerous(&mut self) -> danger::DangerousServerConfig {
+        danger::DangerousServerConfig { cfg: self }
+    }
+}
+
+/// Container for unsafe APIs
+#[cfg(feature = "dangerous_configuration")]
+pub mod danger {
+    use super::ServerConfig;
+
+    /// Accessor for dangerous configuration options.
+    pub struct DangerousServerConfig<'a> {
+        /// The underlying ServerConfig
+        pub cfg: &'a mut ServerConfig
+    }
+
+    impl<'a> DangerousServerConfig<'a> {
+        /// Use `random` as the server random of every handshake, instead
+        /// of fresh randomness.  When we negotiate TLS1.2 despite supporting
+        /// TLS1.3, its last 8 bytes are still replaced by the downgrade
+        /// marker.
+        ///
+        /// This is for testing only, such as reproducing known-answer
+        /// handshakes.  It must never be used in production: a repeated
+        /// random undermines the freshness of every handshake.
+        pub fn set_fixed_server_random(&mut self, random: [u8; 32]) {
+            self.cfg.fixed_server_random = Some(random);
+        }
+    }
 }
 
 pub struct ServerSessionImpl {
@@ -329,6 +392,7 @@ pub struct ServerSessionImpl {
     pub reject_early_data: bool,
     /// RFC 7924
     cached_certificate_hashes: Vec<Vec<u8>>,
+    pub failed_state: Option<HandshakeState>,
 }
 
 impl fmt::Debug for ServerSessionImpl {
@@ -353,6 +417,7 @@ impl ServerSessionImpl {
             client_cert_chain: None,
             reject_early_data: false,
             cached_certificate_hashes: Vec::new(),
+            failed_state: None,
         }
     }
 
@@ -363,7 +428,7 @@ impl ServerSessionImpl {
         //
         // This also covers the handshake case, because we don't have
         // readable plaintext before handshake has completed.
-        !self.common.has_readable_plaintext()
+        !self.common.has_readable_plaintext() && !self.common.is_read_blocked()
     }
 
     pub fn wants_write(&self) -> bool {
@@ -406,7 +471,13 @@ impl ServerSessionImpl {
         msg.decode_payload();
 
         if msg.is_content_type(ContentType::Alert) {
-            return self.common.process_alert(msg);
+            let was_closed = self.common.peer_has_closed();
+            self.common.process_alert(msg)?;
+            if !was_closed && self.common.peer_has_closed() && self.common.traffic {
+                let traffic = self.state.take().unwrap();
+                self.state = Some(hs::ExpectClosed::new(traffic));
+            }
+            return Ok(());
         }
 
         self.process_main_protocol(msg)
@@ -443,13 +514,41 @@ impl ServerSessionImpl {
         }
 
         let state = self.state.take().unwrap();
-        let maybe_next_state = state.handle(self, msg);
+        let failed_state = state.state();
+        let maybe_next_state = state.handle(self, msg)
+            .map_err(|err| {
+                debug!("handshake failed in {:?}: {:?}", failed_state, err);
+                self.failed_state = Some(failed_state);
+                err
+            });
         let next_state = self.maybe_send_unexpected_alert(maybe_next_state)?;
         self.state = Some(next_state);
 
         Ok(())
     }
 
+    pub fn abort_with_alert(&mut self, desc: AlertDescription) {
+        if self.error.is_some() {
+            return;
+        }
+
+        self.common.send_fatal_alert(desc);
+        self.state = None;
+        self.error = Some(TLSError::AbortedWithAlert(desc));
+    }
+
+    /// Plaintext reads and writes fail once the session is aborted with
+    /// `abort_with_alert`.  Other errors leave them alone: buffered
+    /// plaintext can still be read after (eg) a decrypt error.
+    fn check_not_aborted(&self) -> io::Result<()> {
+        match self.error {
+            Some(ref err @ TLSError::AbortedWithAlert(_)) => {
+                Err(io::Error::new(io::ErrorKind::ConnectionAborted, err.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+
     pub fn process_new_packets(&mut self) -> Result<(), TLSError> {
         if let Some(ref err) = self.error {
             return Err(err.clone());
@@ -459,7 +558,12 @@ impl ServerSessionImpl {
             return Err(TLSError::CorruptMessage);
         }
 
-        while let Some(msg) = self.common.message_deframer.frames.pop_front() {
+        while !self.common.is_read_blocked() {
+            let msg = match self.common.message_deframer.frames.pop_front() {
+                Some(msg) => msg,
+                None => break,
+            };
+
             match self.process_msg(msg) {
                 Ok(_) => {}
                 Err(err) => {
@@ -517,6 +621,15 @@ impl ServerSessionImpl {
         self.state = st;
         self.common.send_some_plaintext(buf)
     }
+
+    fn request_client_auth(&mut self) -> Result<(), TLSError> {
+        let mut st = self.state.take();
+        let rc = st.as_mut()
+            .ok_or_else(|| TLSError::HandshakeNotComplete)
+            .and_then(|st| st.request_client_auth(self));
+        self.state = st;
+        rc
+    }
 }
 
 /// This represents a single TLS server session.
@@ -556,6 +669,12 @@ impl ServerSession {
         self.imp.get_sni().map(|s| s.as_ref().into())
     }
 
+    /// Returns the handshake state which failed, if processing a
+    /// message has failed.
+    pub fn get_failed_state(&self) -> Option<HandshakeState> {
+        self.imp.failed_state
+    }
+
     /// Application-controlled portion of the resumption ticket supplied by the client, if any.
     ///
     /// Recovered from the prior session's `set_resumption_data`. Integrity is guaranteed by rustls.
@@ -587,6 +706,83 @@ impl ServerSession {
         assert!(self.is_handshaking(), "cannot retroactively reject early data");
         self.imp.reject_early_data = true;
     }
+
+    /// Ask the client to authenticate, after the handshake has completed.
+    ///
+    /// This sends a CertificateRequest; the client's certificate is
+    /// available from `get_peer_certificates` once its response has
+    /// been processed.  Clients with KEM certificates are sent a
+    /// ciphertext encapsulated to their certificate, and prove they
+    /// could decapsulate it in their Finished message.
+    ///
+    /// This fails if the handshake is not complete, if the client did
+    /// not offer post-handshake authentication, if no client
+    /// authentication is configured, if a previous request has not
+    /// been answered yet, or if the client has sent close_notify.  See
+    /// `ServerConfig::defer_client_auth`.
+    pub fn request_client_auth(&mut self) -> Result<(), TLSError> {
+        self.imp.request_client_auth()
+    }
+
+    /// Queues the fatal alert `desc` to be sent in the next `write_tls`
+    /// call, and aborts the session.  Use this to reject a peer for
+    /// application-level reasons, including mid-handshake.
+    ///
+    /// Afterwards `process_new_packets`, reads and writes fail with
+    /// `TLSError::AbortedWithAlert`.  This does nothing if the session
+    /// has already failed.
+    pub fn abort_with_alert(&mut self, desc: AlertDescription) {
+        self.imp.abort_with_alert(desc)
+    }
+
+    /// Sets a soft limit on the buffer of received plaintext which
+    /// has not yet been `read`.
+    ///
+    /// Once the limit is reached, `process_new_packets` stops
+    /// decrypting records, `wants_read` returns false, and `read_tls`
+    /// fails rather than buffer more records.  Call
+    /// `process_new_packets` again after reading to continue.  The
+    /// buffer may exceed the limit by up to one record.
+    ///
+    /// By default, there is no limit.  This has no effect during the
+    /// handshake.
+    pub fn set_received_plaintext_limit(&mut self, limit: Option<usize>) {
+        self.imp.common.set_received_plaintext_limit(limit)
+    }
+
+    /// Pauses or resumes processing of received records, for callers
+    /// not ready to `read` more plaintext.
+    ///
+    /// While paused, this behaves as if `set_received_plaintext_limit`
+    /// had been reached.  Call `process_new_packets` after resuming to
+    /// process records received meanwhile.  This has no effect during
+    /// the handshake.
+    pub fn set_read_paused(&mut self, paused: bool) {
+        self.imp.common.set_read_paused(paused)
+    }
+
+    /// Like `get_peer_certificates`, but with the peer's own certificate
+    /// parsed, for inspecting (eg) which KEM or signature algorithm it
+    /// uses.  This borrows the chain rather than copying it, and parses
+    /// it on every call.
+    ///
+    /// Fails with `TLSError::NoCertificatesPresented` until there is a
+    /// chain, and with `TLSError::WebPKIError` if its first certificate
+    /// doesn't parse.
+    pub fn peer_cert_chain_parsed(&self) -> Result<ParsedChain<'_>, TLSError> {
+        let chain = self.imp.client_cert_chain
+            .as_ref()
+            .map_or(&[][..], |chain| &chain[..]);
+        ParsedChain::parse(chain)
+    }
+
+    /// Retrieves the hash algorithm of the ciphersuite agreed with the
+    /// peer.  This is the hash used throughout the key schedule.
+    ///
+    /// This returns None until the ciphersuite is agreed.
+    pub fn negotiated_suite_hash(&self) -> Option<HashAlgorithm> {
+        self.imp.get_negotiated_ciphersuite().map(|suite| suite.hash)
+    }
 }
 
 impl Session for ServerSession {
@@ -661,6 +857,7 @@ impl io::Read for ServerSession {
     /// This means applications using rustls must both handle ErrorKind::ConnectionAborted
     /// from this function, *and* unexpected closure of the underlying TCP connection.
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.imp.check_not_aborted()?;
         self.imp.common.read(buf)
     }
 }
@@ -677,10 +874,12 @@ impl io::Write for ServerSession {
     /// writing much data before it can be sent will
     /// cause excess memory usage.
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.imp.check_not_aborted()?;
         Ok(self.imp.send_some_plaintext(buf))
     }
 
     fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.imp.check_not_aborted()?;
         let mut sz = 0;
         for buf in bufs {
             sz += self.imp.send_some_plaintext(buf);
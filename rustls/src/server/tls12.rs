@@ -13,7 +13,7 @@ use crate::server::ServerSessionImpl;
 use crate::verify;
 #[cfg(feature = "logging")]
 use crate::log::{trace, debug};
-use crate::error::TLSError;
+use crate::error::{TLSError, HandshakeState};
 use crate::check::check_message;
 
 use crate::server::common::{HandshakeDetails, ServerKXDetails, ClientCertDetails};
@@ -40,6 +40,10 @@ impl ExpectCertificate {
 }
 
 impl hs::State for ExpectCertificate {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::Certificate
+    }
+
     fn handle(mut self: Box<Self>, sess: &mut ServerSessionImpl, m: Message) -> hs::NextStateOrError {
         let cert_chain = require_handshake_msg!(m, HandshakeType::Certificate, HandshakePayload::Certificate)?;
         self.handshake.transcript.add_message(&m);
@@ -104,6 +108,10 @@ impl ExpectClientKX {
 }
 
 impl hs::State for ExpectClientKX {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::ClientKeyExchange
+    }
+
     fn handle(mut self: Box<Self>, sess: &mut ServerSessionImpl, m: Message) -> hs::NextStateOrError {
         let client_kx = require_handshake_msg!(m, HandshakeType::ClientKeyExchange, HandshakePayload::ClientKeyExchange)?;
         self.handshake.transcript.add_message(&m);
@@ -165,6 +173,10 @@ impl ExpectCertificateVerify {
 }
 
 impl hs::State for ExpectCertificateVerify {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::CertificateVerify
+    }
+
     fn handle(mut self: Box<Self>, sess: &mut ServerSessionImpl, m: Message) -> hs::NextStateOrError {
         let rc = {
             let sig = require_handshake_msg!(m, HandshakeType::CertificateVerify, HandshakePayload::CertificateVerify)?;
@@ -209,6 +221,10 @@ impl ExpectCCS {
 }
 
 impl hs::State for ExpectCCS {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::ChangeCipherSpec
+    }
+
     fn handle(self: Box<Self>, sess: &mut ServerSessionImpl, m: Message) -> hs::NextStateOrError {
         check_message(&m, &[ContentType::ChangeCipherSpec], &[])?;
 
@@ -321,6 +337,10 @@ impl ExpectFinished {
 }
 
 impl hs::State for ExpectFinished {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::Finished
+    }
+
     fn handle(mut self: Box<Self>, sess: &mut ServerSessionImpl, m: Message) -> hs::NextStateOrError {
         let finished = require_handshake_msg!(m, HandshakeType::Finished, HandshakePayload::Finished)?;
 
@@ -379,6 +399,10 @@ impl ExpectTraffic {
 }
 
 impl hs::State for ExpectTraffic {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::Traffic
+    }
+
     fn handle(self: Box<Self>, sess: &mut ServerSessionImpl, mut m: Message) -> hs::NextStateOrError {
         check_message(&m, &[ContentType::ApplicationData], &[])?;
         sess.common.take_received_plaintext(m.take_opaque_payload().unwrap());
@@ -40,7 +40,7 @@ use crate::sign;
 use crate::suites;
 #[cfg(feature = "logging")]
 use crate::log::{warn, trace, debug};
-use crate::error::TLSError;
+use crate::error::{TLSError, HandshakeState};
 use crate::check::check_message;
 #[cfg(feature = "quic")]
 use crate::{
@@ -51,6 +51,7 @@ use crate::{
 
 use crate::server::common::{HandshakeDetails, ClientCertDetails};
 use crate::server::hs;
+use crate::hash_hs::HandshakeHash;
 
 use oqs::kem::SharedSecret;
 use ring::constant_time;
@@ -308,6 +309,11 @@ impl CompleteClientHelloHandling {
             return Ok(false);
         }
 
+        if sess.config.defer_client_auth && self.handshake.client_offered_pha {
+            debug!("deferring client auth until after the handshake");
+            return Ok(false);
+        }
+
         let mut cr = CertificateRequestPayloadTLS13 {
             context: PayloadU8::empty(),
             extensions: Vec::new(),
@@ -543,6 +549,7 @@ impl CompleteClientHelloHandling {
             let retry_group_maybe = supported_groups
                 .iter()
                 .filter(|group| groups_ext.contains(group))
+                .filter(|group| suites::KeyExchange::fits_key_share(**group))
                 .nth(0)
                 .cloned();
             self.handshake.transcript.add_message(chm);
@@ -618,10 +625,14 @@ impl CompleteClientHelloHandling {
         let mut proactive_ss_certificate_hash = None;
         if chosen_psk_index.is_none() {
             if let Ok(offer) = doing_pdk {
+                if server_key.key.kem_ciphertext_len() != Some(offer.ciphertext.0.len()) {
+                    return Err(hs::decode_error(sess, "proactive ciphertext has wrong length"));
+                }
                 let eecrt = webpki::EndEntityCert::from(server_key.cert[0].as_ref()).unwrap();
                 // accept KEMTLS-PDK
                 proactive_ss_certificate_hash = Some(offer.certificate_hash.clone());
                 self.handshake.print_runtime("PDK DECAPSULATING FROM CERTIFICATE");
+                suites::count_cert_kem_op(suites::KemOp::Decapsulate);
                 let ss = eecrt
                         .decapsulate(server_key.key.get_bytes(), offer.ciphertext.0.as_ref())
                         .unwrap();
@@ -634,6 +645,7 @@ impl CompleteClientHelloHandling {
             .map(|ss| KeyScheduleEarly::new(suite.hkdf_algorithm, &ss));
         let doing_pdk = doing_pdk.is_ok();
         let pdk_client_auth = doing_pdk && client_hello.find_extension(crate::msgs::enums::ExtensionType::ProactiveClientAuth).is_some();
+        self.handshake.client_offered_pha = client_hello.find_extension(crate::msgs::enums::ExtensionType::PostHandshakeAuth).is_some();
 
         if let Some(ref resume) = resumedata {
             sess.received_resumption_data = Some(resume.application_data.0.clone());
@@ -738,10 +750,14 @@ impl CompleteClientHelloHandling {
                 }
                 let cert = cert.unwrap();
                 // emit ciphertext XXX copied from ExpectCertificate.emit_ciphertext
-                let certificate = webpki::EndEntityCert::from(&cert.cert_chain[0].0)
-                    .map_err(TLSError::WebPKIError)?;
+                let certificate = kem_client_cert(sess, &cert)?;
                 self.handshake.print_runtime("PDK ENCAPSULATING TO CCERT");
-                let (ct, ss) = certificate.encapsulate().map_err(|_| TLSError::DecryptError)?;
+                suites::count_cert_kem_op(suites::KemOp::Encapsulate);
+                let (ct, ss) = certificate.encapsulate()
+                    .map_err(|_| {
+                        sess.common.send_fatal_alert(AlertDescription::BadCertificate);
+                        TLSError::DecryptError
+                    })?;
                 self.handshake.print_runtime("PDK ENCAPSULATED TO CCERT");
                 let m = Message {
                     typ: ContentType::Handshake,
@@ -813,6 +829,10 @@ impl ExpectPDKCertificate {
 }
 
 impl hs::State for ExpectPDKCertificate {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::PdkCertificate
+    }
+
     fn handle(mut self: Box<Self>, sess: &mut ServerSessionImpl, m: Message) -> hs::NextStateOrError {
         let certp = require_handshake_msg!(m, HandshakeType::Certificate, HandshakePayload::CertificateTLS13)?;
         trace!("Received PDK certificate");
@@ -844,6 +864,27 @@ impl hs::State for ExpectPDKCertificate {
     }
 }
 
+/// Parse the client's end-entity certificate for encapsulation,
+/// rejecting it if it isn't a KEM certificate.
+fn kem_client_cert<'a>(sess: &mut ServerSessionImpl,
+                       cert: &'a ClientCertDetails) -> Result<webpki::EndEntityCert<'a>, TLSError> {
+    let certificate = webpki::EndEntityCert::from(&cert.cert_chain[0].0)
+        .map_err(TLSError::WebPKIError)?;
+
+    if !certificate.is_kem_cert() {
+        sess.common.send_fatal_alert(AlertDescription::BadCertificate);
+        return Err(TLSError::PeerMisbehavedError("client sent non-KEM certificate in KEMTLS handshake"
+                                                 .to_string()));
+    }
+
+    if !verify::kem_cert_has_valid_key_len(&cert.cert_chain[0]) {
+        sess.common.send_fatal_alert(AlertDescription::BadCertificate);
+        return Err(TLSError::PeerMisbehavedError("client KEM public key has wrong length"
+                                                 .to_string()));
+    }
+
+    Ok(certificate)
+}
 
 fn emit_finished_kemtlspdk(
     handshake: &mut HandshakeDetails, sess: &mut ServerSessionImpl, key_schedule: KeyScheduleHandshake,
@@ -920,17 +961,25 @@ impl ExpectCiphertext {
 }
 
 impl hs::State for ExpectCiphertext {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::KemCiphertext
+    }
+
     fn handle(mut self: Box<Self>, sess: &mut ServerSessionImpl, m: Message) -> hs::NextStateOrError {
         let ctmsg = require_handshake_msg!(m, HandshakeType::ServerKemCiphertext, HandshakePayload::ServerKemCiphertext)?;
         self.handshake.print_runtime("RECEIVED CKEX");
         
         // decapsulate
         let ciphertext = &ctmsg.0;
+        if self.server_key.key.kem_ciphertext_len() != Some(ciphertext.len()) {
+            return Err(hs::decode_error(sess, "ciphertext has wrong length"));
+        }
         let eecrt = self.server_key.end_entity_cert()
         .map_err(|_| TLSError::NoCertificatesPresented)
         .and_then(|crt| webpki::EndEntityCert::from(&crt.0).map_err(TLSError::WebPKIError))?;
 
         self.handshake.print_runtime("DECAPSULATING FROM CERTIFICATE");
+        suites::count_cert_kem_op(suites::KemOp::Decapsulate);
         let ss = eecrt.decapsulate(self.server_key.key.get_bytes(), ciphertext).map_err(TLSError::WebPKIError)?;
         self.handshake.print_runtime("DECAPSULATED FROM CERTIFICATE");
         
@@ -1025,10 +1074,14 @@ impl ExpectCertificate {
     }
 
     fn emit_ciphertext(&mut self, sess: &mut ServerSessionImpl, cert: ClientCertDetails) -> Result<SharedSecret, TLSError> {
-        let certificate = webpki::EndEntityCert::from(&cert.cert_chain[0].0)
-            .map_err(TLSError::WebPKIError)?;
+        let certificate = kem_client_cert(sess, &cert)?;
         self.handshake.print_runtime("ENCAPSULATING TO CLIENT");
-        let (ct, ss) = certificate.encapsulate().map_err(|_| TLSError::DecryptError)?;
+        suites::count_cert_kem_op(suites::KemOp::Encapsulate);
+        let (ct, ss) = certificate.encapsulate()
+            .map_err(|_| {
+                sess.common.send_fatal_alert(AlertDescription::BadCertificate);
+                TLSError::DecryptError
+            })?;
         let m = Message {
             typ: ContentType::Handshake,
             version: ProtocolVersion::TLSv1_3,
@@ -1055,6 +1108,10 @@ impl ExpectCertificate {
 }
 
 impl hs::State for ExpectCertificate {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::Certificate
+    }
+
     fn handle(mut self: Box<Self>, sess: &mut ServerSessionImpl, m: Message) -> hs::NextStateOrError {
         let certp = require_handshake_msg!(m, HandshakeType::Certificate, HandshakePayload::CertificateTLS13)?;
         self.handshake.transcript.add_message(&m);
@@ -1123,6 +1180,10 @@ impl ExpectCertificateVerify {
 }
 
 impl hs::State for ExpectCertificateVerify {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::CertificateVerify
+    }
+
     fn handle(mut self: Box<Self>, sess: &mut ServerSessionImpl, m: Message) -> hs::NextStateOrError {
         let rc = {
             let sig = require_handshake_msg!(m, HandshakeType::CertificateVerify, HandshakePayload::CertificateVerify)?;
@@ -1187,6 +1248,10 @@ impl ExpectKEMTLSFinished {
 }
 
 impl hs::State for ExpectKEMTLSFinished {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::KemtlsFinished
+    }
+
     fn handle(mut self: Box<Self>, sess: &mut ServerSessionImpl, m: Message) -> hs::NextStateOrError {
         let finished = require_handshake_msg!(m, HandshakeType::Finished, HandshakePayload::Finished)?;
         trace!("Received KEMTLS finished");
@@ -1252,6 +1317,7 @@ impl hs::State for ExpectKEMTLSFinished {
                                     &self.handshake.randoms.client);
                                                
         let key_schedule_traffic = self.key_schedule.into_traffic();
+        let handshake_transcript = post_handshake_transcript(&self.handshake);
 
         if self.send_ticket {
             if sess.config.ticketer.enabled() {
@@ -1264,11 +1330,7 @@ impl hs::State for ExpectKEMTLSFinished {
         self.handshake.print_runtime("HANDSHAKE COMPLETED");
         sess.common.start_traffic();
 
-        Ok(Box::new(ExpectTraffic {
-            _fin_verified: fin,
-            key_schedule: key_schedule_traffic,
-            want_write_key_update: false,
-        }))
+        Ok(ExpectFinished::into_expect_traffic(fin, key_schedule_traffic, handshake_transcript))
     }
 }
 
@@ -1280,15 +1342,30 @@ pub struct ExpectFinished {
 }
 
 impl ExpectFinished {
-    fn into_expect_traffic(fin: verify::FinishedMessageVerified, ks: KeyScheduleTraffic) -> hs::NextState {
+    fn into_expect_traffic(fin: verify::FinishedMessageVerified,
+                           ks: KeyScheduleTraffic,
+                           handshake_transcript: Option<HandshakeHash>) -> hs::NextState {
         Box::new(ExpectTraffic {
             key_schedule: ks,
             want_write_key_update: false,
+            handshake_transcript,
+            pending_auth: None,
             _fin_verified: fin,
         })
     }
 }
 
+/// The transcript post-handshake authentication continues from, if the
+/// client offered it.  This must be taken before any tickets are sent:
+/// the client doesn't include those in its transcript.
+fn post_handshake_transcript(handshake: &HandshakeDetails) -> Option<HandshakeHash> {
+    if handshake.client_offered_pha {
+        Some(handshake.transcript.clone())
+    } else {
+        None
+    }
+}
+
 fn emit_stateless_ticket(handshake: &mut HandshakeDetails,
                             sess: &mut ServerSessionImpl,
                             key_schedule: &KeyScheduleTraffic) {
@@ -1367,6 +1444,10 @@ fn emit_stateful_ticket(handshake: &mut HandshakeDetails,
 }
 
 impl hs::State for ExpectFinished {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::Finished
+    }
+
     fn handle(mut self: Box<Self>, sess: &mut ServerSessionImpl, m: Message) -> hs::NextStateOrError {
         let finished = require_handshake_msg!(m, HandshakeType::Finished, HandshakePayload::Finished)?;
         trace!("received Finished");
@@ -1428,6 +1509,7 @@ impl hs::State for ExpectFinished {
         }
 
         let key_schedule_traffic = self.key_schedule.into_traffic();
+        let handshake_transcript = post_handshake_transcript(&self.handshake);
 
         if self.send_ticket {
             if sess.config.ticketer.enabled() {
@@ -1450,18 +1532,196 @@ impl hs::State for ExpectFinished {
             }
         }
 
-        Ok(Self::into_expect_traffic(fin, key_schedule_traffic))
+        Ok(Self::into_expect_traffic(fin, key_schedule_traffic, handshake_transcript))
     }
 }
 
 // --- Process traffic ---
+/// Where a post-handshake client authentication has got to.
+enum PendingClientAuthState {
+    ExpectCertificate,
+    ExpectCertificateVerify(ClientCertDetails),
+    ExpectFinished(Option<ClientCertDetails>, Option<SharedSecret>),
+}
+
+/// A post-handshake client authentication we have requested.
+struct PendingClientAuth {
+    context: Vec<u8>,
+    transcript: HandshakeHash,
+    state: PendingClientAuthState,
+}
+
 pub struct ExpectTraffic {
     key_schedule: KeyScheduleTraffic,
     want_write_key_update: bool,
+    handshake_transcript: Option<HandshakeHash>,
+    pending_auth: Option<PendingClientAuth>,
     _fin_verified: verify::FinishedMessageVerified,
 }
 
 impl ExpectTraffic {
+    fn take_pending_auth(&mut self, sess: &mut ServerSessionImpl) -> Result<PendingClientAuth, TLSError> {
+        self.pending_auth.take()
+            .ok_or_else(|| {
+                sess.common.send_fatal_alert(AlertDescription::UnexpectedMessage);
+                TLSError::PeerMisbehavedError("client sent unsolicited post-handshake auth message"
+                                              .to_string())
+            })
+    }
+
+    fn handle_certificate(&mut self,
+                          sess: &mut ServerSessionImpl,
+                          m: &Message,
+                          certp: &CertificatePayloadTLS13) -> Result<(), TLSError> {
+        let mut pending = self.take_pending_auth(sess)?;
+        match pending.state {
+            PendingClientAuthState::ExpectCertificate => {}
+            _ => {
+                sess.common.send_fatal_alert(AlertDescription::UnexpectedMessage);
+                return Err(TLSError::PeerMisbehavedError("client sent unexpected Certificate"
+                                                         .to_string()));
+            }
+        }
+
+        if certp.context.0 != pending.context {
+            sess.common.send_fatal_alert(AlertDescription::IllegalParameter);
+            return Err(TLSError::PeerMisbehavedError("client sent wrong certificate request context"
+                                                     .to_string()));
+        }
+
+        if certp.any_entry_has_extension() {
+            return Err(TLSError::PeerMisbehavedError("client sent unsolicited cert extension"
+                                                     .to_string()));
+        }
+
+        pending.transcript.add_message(m);
+        let cert_chain = certp.convert();
+
+        let mandatory = sess.config.verifier.client_auth_mandatory(sess.get_sni())
+            .ok_or_else(|| {
+                debug!("could not determine if client auth is mandatory based on SNI");
+                sess.common.send_fatal_alert(AlertDescription::AccessDenied);
+                TLSError::General("client rejected by client_auth_mandatory".into())
+            })?;
+
+        if cert_chain.is_empty() {
+            if !mandatory {
+                debug!("post-handshake client auth requested but no certificate supplied");
+                pending.state = PendingClientAuthState::ExpectFinished(None, None);
+                self.pending_auth = Some(pending);
+                return Ok(());
+            }
+
+            sess.common.send_fatal_alert(AlertDescription::CertificateRequired);
+            return Err(TLSError::NoCertificatesPresented);
+        }
+
+        sess.config.get_verifier().verify_client_cert(&cert_chain, sess.get_sni())
+            .or_else(|err| {
+                 hs::incompatible(sess, "certificate invalid");
+                 Err(err)
+                })?;
+        let cert = ClientCertDetails::new(cert_chain);
+
+        let is_kem_cert = webpki::EndEntityCert::from(&cert.cert_chain[0].0)
+            .map(|crt| crt.is_kem_cert())
+            .map_err(TLSError::WebPKIError)?;
+
+        if !is_kem_cert {
+            pending.state = PendingClientAuthState::ExpectCertificateVerify(cert);
+            self.pending_auth = Some(pending);
+            return Ok(());
+        }
+
+        let ss = {
+            let certificate = kem_client_cert(sess, &cert)?;
+            suites::count_cert_kem_op(suites::KemOp::Encapsulate);
+            let (ct, ss) = certificate.encapsulate()
+                .map_err(|_| {
+                    sess.common.send_fatal_alert(AlertDescription::BadCertificate);
+                    TLSError::DecryptError
+                })?;
+            let m = Message {
+                typ: ContentType::Handshake,
+                version: ProtocolVersion::TLSv1_3,
+                payload: MessagePayload::Handshake(HandshakeMessagePayload {
+                    typ: HandshakeType::ClientKemCiphertext,
+                    payload: HandshakePayload::ClientKemCiphertext(Payload::new(ct.into_vec())),
+                }),
+            };
+            pending.transcript.add_message(&m);
+            sess.common.send_msg(m, true);
+            ss
+        };
+
+        pending.state = PendingClientAuthState::ExpectFinished(Some(cert), Some(ss));
+        self.pending_auth = Some(pending);
+        Ok(())
+    }
+
+    fn handle_certificate_verify(&mut self,
+                                 sess: &mut ServerSessionImpl,
+                                 m: &Message,
+                                 sig: &DigitallySignedStruct) -> Result<(), TLSError> {
+        let mut pending = self.take_pending_auth(sess)?;
+        let cert = match pending.state {
+            PendingClientAuthState::ExpectCertificateVerify(cert) => cert,
+            _ => {
+                sess.common.send_fatal_alert(AlertDescription::UnexpectedMessage);
+                return Err(TLSError::PeerMisbehavedError("client sent unexpected CertificateVerify"
+                                                         .to_string()));
+            }
+        };
+
+        let handshake_hash = pending.transcript.get_current_hash();
+        let msg = verify::construct_tls13_client_verify_message(&handshake_hash);
+        let rc = sess.config
+            .get_verifier()
+            .verify_tls13_signature(&msg, &cert.cert_chain[0], sig);
+
+        if let Err(e) = rc {
+            sess.common.send_fatal_alert(AlertDescription::AccessDenied);
+            return Err(e);
+        }
+
+        trace!("client post-handshake CertificateVerify OK");
+        pending.transcript.add_message(m);
+        pending.state = PendingClientAuthState::ExpectFinished(Some(cert), None);
+        self.pending_auth = Some(pending);
+        Ok(())
+    }
+
+    fn handle_finished(&mut self,
+                       sess: &mut ServerSessionImpl,
+                       finished: &Payload) -> Result<(), TLSError> {
+        let pending = self.take_pending_auth(sess)?;
+        let (cert, ss) = match pending.state {
+            PendingClientAuthState::ExpectFinished(cert, ss) => (cert, ss),
+            _ => {
+                sess.common.send_fatal_alert(AlertDescription::UnexpectedMessage);
+                return Err(TLSError::PeerMisbehavedError("client sent unexpected Finished"
+                                                         .to_string()));
+            }
+        };
+
+        let handshake_hash = pending.transcript.get_current_hash();
+        let expect_verify_data = self.key_schedule
+            .sign_client_post_handshake_finish(ss.as_ref().map(|ss| ss.as_ref()), &handshake_hash);
+
+        constant_time::verify_slices_are_equal(&expect_verify_data, &finished.0)
+            .map_err(|_| {
+                     sess.common.send_fatal_alert(AlertDescription::DecryptError);
+                     warn!("post-handshake Finished wrong");
+                     TLSError::DecryptError
+                     })?;
+
+        trace!("client post-handshake authentication complete");
+        if let Some(mut cert) = cert {
+            sess.client_cert_chain = Some(cert.take_chain());
+        }
+        Ok(())
+    }
+
     fn handle_traffic(&self, sess: &mut ServerSessionImpl, mut m: Message) -> Result<(), TLSError> {
         sess.common.take_received_plaintext(m.take_opaque_payload().unwrap());
         Ok(())
@@ -1501,20 +1761,85 @@ impl ExpectTraffic {
 }
 
 impl hs::State for ExpectTraffic {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::Traffic
+    }
+
     fn handle(mut self: Box<Self>, sess: &mut ServerSessionImpl, m: Message) -> hs::NextStateOrError {
         if m.is_content_type(ContentType::ApplicationData) {
             self.handle_traffic(sess, m)?;
         } else if let Ok(key_update) = require_handshake_msg!(m, HandshakeType::KeyUpdate, HandshakePayload::KeyUpdate) {
             self.handle_key_update(sess, key_update)?;
+        } else if let Ok(certp) = require_handshake_msg!(m, HandshakeType::Certificate, HandshakePayload::CertificateTLS13) {
+            self.handle_certificate(sess, &m, certp)?;
+        } else if let Ok(sig) = require_handshake_msg!(m, HandshakeType::CertificateVerify, HandshakePayload::CertificateVerify) {
+            self.handle_certificate_verify(sess, &m, sig)?;
+        } else if let Ok(finished) = require_handshake_msg!(m, HandshakeType::Finished, HandshakePayload::Finished) {
+            self.handle_finished(sess, finished)?;
         } else {
             check_message(&m,
                           &[ContentType::ApplicationData, ContentType::Handshake],
-                          &[HandshakeType::KeyUpdate])?;
+                          &[HandshakeType::KeyUpdate, HandshakeType::Certificate,
+                            HandshakeType::CertificateVerify, HandshakeType::Finished])?;
         }
 
         Ok(self)
     }
 
+    fn request_client_auth(&mut self, sess: &mut ServerSessionImpl) -> Result<(), TLSError> {
+        let mut transcript = match self.handshake_transcript {
+            Some(ref transcript) => transcript.clone(),
+            None => return Err(TLSError::General("client did not offer post-handshake auth".to_string())),
+        };
+
+        if self.pending_auth.is_some() {
+            return Err(TLSError::General("post-handshake auth already in progress".to_string()));
+        }
+
+        if !sess.config.verifier.offer_client_auth() {
+            return Err(TLSError::General("client auth is not configured".to_string()));
+        }
+
+        let mut context = vec![0u8; 32];
+        rand::fill_random(&mut context);
+
+        let mut cr = CertificateRequestPayloadTLS13 {
+            context: PayloadU8::new(context.clone()),
+            extensions: Vec::new(),
+        };
+
+        let schemes = sess.config.get_verifier()
+            .supported_verify_schemes();
+        cr.extensions.push(CertReqExtension::SignatureAlgorithms(schemes.to_vec()));
+
+        let names = sess.config.verifier.client_auth_root_subjects(sess.get_sni())
+            .ok_or_else(|| TLSError::General("client rejected by client_auth_root_subjects".into()))?;
+
+        if !names.is_empty() {
+            cr.extensions.push(CertReqExtension::AuthorityNames(names));
+        }
+
+        let m = Message {
+            typ: ContentType::Handshake,
+            version: ProtocolVersion::TLSv1_3,
+            payload: MessagePayload::Handshake(HandshakeMessagePayload {
+                typ: HandshakeType::CertificateRequest,
+                payload: HandshakePayload::CertificateRequestTLS13(cr),
+            }),
+        };
+
+        trace!("Sending post-handshake CertificateRequest {:?}", m);
+        transcript.add_message(&m);
+        sess.common.send_msg(m, true);
+
+        self.pending_auth = Some(PendingClientAuth {
+            context,
+            transcript,
+            state: PendingClientAuthState::ExpectCertificate,
+        });
+        Ok(())
+    }
+
     fn export_keying_material(&self,
                               output: &mut [u8],
                               label: &[u8],
@@ -1542,6 +1867,10 @@ pub struct ExpectQUICTraffic {
 
 #[cfg(feature = "quic")]
 impl hs::State for ExpectQUICTraffic {
+    fn state(&self) -> HandshakeState {
+        HandshakeState::QuicTraffic
+    }
+
     fn handle(self: Box<Self>, _: &mut ServerSessionImpl, m: Message) -> hs::NextStateOrError {
         // reject all messages
         check_message(&m, &[], &[])?;
@@ -7,6 +7,7 @@ use crate::msgs::hsjoiner::HandshakeJoiner;
 use crate::msgs::base::Payload;
 use crate::msgs::codec::Codec;
 use crate::msgs::enums::{ContentType, ProtocolVersion, AlertDescription, AlertLevel};
+use crate::msgs::enums::HandshakeType;
 use crate::error::TLSError;
 use crate::suites::SupportedCipherSuite;
 use crate::cipher;
@@ -21,6 +22,38 @@ use crate::log::{warn, debug, error};
 
 use std::io;
 use std::collections::VecDeque;
+use std::sync::Arc;
+
+use webpki;
+
+/// A peer's certificate chain, split into the peer's own certificate
+/// and the rest.  See `ClientSession::peer_cert_chain_parsed` and
+/// `ServerSession::peer_cert_chain_parsed`.
+pub struct ParsedChain<'a> {
+    /// The peer's own certificate.
+    pub end_entity: webpki::EndEntityCert<'a>,
+
+    /// The certificates sent after it, in the order they were sent.
+    /// These are not parsed.
+    pub intermediates: &'a [key::Certificate],
+}
+
+impl<'a> ParsedChain<'a> {
+    /// Parse `chain`.  The result borrows from `chain`, so can't be
+    /// stored alongside it on the session: callers parse afresh each
+    /// time, which is a walk over the end-entity certificate's DER.
+    pub(crate) fn parse(chain: &'a [key::Certificate]) -> Result<ParsedChain<'a>, TLSError> {
+        let (end_entity, intermediates) = chain.split_first()
+            .ok_or(TLSError::NoCertificatesPresented)?;
+        let end_entity = webpki::EndEntityCert::from(&end_entity.0)
+            .map_err(TLSError::WebPKIError)?;
+
+        Ok(ParsedChain { end_entity, intermediates })
+    }
+}
+
+/// The exporter label `Session::unique_channel_binding` uses.
+const UNIQUE_CHANNEL_BINDING_LABEL: &[u8] = b"EXPORTER-rustls-unique-channel-binding";
 
 /// Generalises `ClientSession` and `ServerSession`
 pub trait Session: quic::QuicExt + Read + Write + Send + Sync {
@@ -111,7 +144,10 @@ pub trait Session: quic::QuicExt + Read + Write + Send + Sync {
 
     /// Retrieves the protocol version agreed with the peer.
     ///
-    /// This returns None until the version is agreed.
+    /// This returns None until the version is agreed: for clients,
+    /// until the ServerHello or HelloRetryRequest is processed.
+    /// KEMTLS is a variant of TLS1.3, so KEMTLS connections give
+    /// `ProtocolVersion::TLSv1_3`.
     fn get_protocol_version(&self) -> Option<ProtocolVersion>;
 
     /// Derives key material from the agreed session secrets.
@@ -132,6 +168,28 @@ pub trait Session: quic::QuicExt + Read + Write + Send + Sync {
                               label: &[u8],
                               context: Option<&[u8]>) -> Result<(), TLSError>;
 
+    /// Returns a value unique to this connection, for binding protocols
+    /// run over it to this particular handshake.
+    ///
+    /// This is derived from the exporter secret with a label reserved for
+    /// this purpose, so don't use that label with `export_keying_material`.
+    /// The exporter secret depends on the whole handshake transcript, so
+    /// every successful TLS1.3 or KEMTLS handshake gives a different value.
+    ///
+    /// This is only meaningful once the handshake is complete: it returns
+    /// None until the peer's Finished message has been verified.  It also
+    /// returns None for TLS1.2 connections.
+    fn unique_channel_binding(&self) -> Option<[u8; 32]> {
+        if self.get_protocol_version() != Some(ProtocolVersion::TLSv1_3) {
+            return None;
+        }
+
+        let mut binding = [0u8; 32];
+        self.export_keying_material(&mut binding, UNIQUE_CHANNEL_BINDING_LABEL, None)
+            .ok()?;
+        Some(binding)
+    }
+
     /// Retrieves the ciphersuite agreed with the peer.
     ///
     /// This returns None until the ciphersuite is agreed.
@@ -419,10 +477,16 @@ pub struct SessionCommon {
     pub handshake_joiner: HandshakeJoiner,
     pub message_fragmenter: MessageFragmenter,
     received_plaintext: ChunkVecBuffer,
+    received_plaintext_limit: Option<usize>,
+    read_paused: bool,
     sendable_plaintext: ChunkVecBuffer,
     pub sendable_tls: ChunkVecBuffer,
     /// Protocol whose key schedule should be used. Unused for TLS < 1.3.
     pub protocol: Protocol,
+    /// Called with each handshake message we send.
+    pub handshake_observer: Option<Arc<dyn Fn(HandshakeType, &[u8]) + Send + Sync>>,
+    /// Whether `handshake_observer` sees messages we encrypt.
+    pub observe_encrypted_handshake: bool,
     #[cfg(feature = "quic")]
     pub(crate) quic: Quic,
 }
@@ -443,9 +507,13 @@ impl SessionCommon {
             handshake_joiner: HandshakeJoiner::new(),
             message_fragmenter: MessageFragmenter::new(mtu.unwrap_or(MAX_FRAGMENT_LEN)),
             received_plaintext: ChunkVecBuffer::new(),
+            received_plaintext_limit: None,
+            read_paused: false,
             sendable_plaintext: ChunkVecBuffer::new(),
             sendable_tls: ChunkVecBuffer::new(),
             protocol: Protocol::Tls13,
+            handshake_observer: None,
+            observe_encrypted_handshake: false,
             #[cfg(feature = "quic")]
             quic: Quic::new(),
         }
@@ -521,6 +589,26 @@ impl SessionCommon {
         self.sendable_tls.set_limit(limit);
     }
 
+    pub fn set_received_plaintext_limit(&mut self, limit: Option<usize>) {
+        self.received_plaintext_limit = limit;
+    }
+
+    pub fn set_read_paused(&mut self, paused: bool) {
+        self.read_paused = paused;
+    }
+
+    /// Whether the application isn't ready for more plaintext, so we
+    /// should stop processing records.
+    pub fn is_read_blocked(&self) -> bool {
+        if !self.traffic {
+            return false;
+        }
+
+        self.read_paused ||
+            self.received_plaintext_limit
+                .map_or(false, |limit| self.received_plaintext.len() >= limit)
+    }
+
     pub fn process_alert(&mut self, msg: Message) -> Result<(), TLSError> {
         if let MessagePayload::Alert(ref alert) = msg.payload {
             // Reject unknown AlertLevels.
@@ -614,10 +702,25 @@ impl SessionCommon {
         self.peer_eof && !self.message_deframer.has_pending()
     }
 
+    /// Has the peer sent a close_notify?
+    pub fn peer_has_closed(&self) -> bool {
+        self.peer_eof
+    }
+
     /// Read TLS content from `rd`.  This method does internal
     /// buffering, so `rd` can supply TLS messages in arbitrary-
     /// sized chunks (like a socket or pipe might).
     pub fn read_tls(&mut self, rd: &mut dyn Read) -> io::Result<usize> {
+        // Don't buffer records without bound while we can't process them.
+        if self.is_read_blocked() && !self.message_deframer.frames.is_empty() {
+            let msg = if self.read_paused {
+                "reading is paused"
+            } else {
+                "received plaintext buffer full"
+            };
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+
         self.message_deframer.read(rd)
     }
 
@@ -697,6 +800,14 @@ impl SessionCommon {
 
     /// Send a raw TLS message, fragmenting it if needed.
     pub fn send_msg(&mut self, m: Message, must_encrypt: bool) {
+        if let Some(ref observer) = self.handshake_observer {
+            if !must_encrypt || self.observe_encrypted_handshake {
+                if let MessagePayload::Handshake(ref hs) = m.payload {
+                    observer(hs.typ, &hs.get_encoding());
+                }
+            }
+        }
+
         #[cfg(feature = "quic")]
         {
             if let Protocol::Quic = self.protocol {
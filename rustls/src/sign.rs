@@ -24,6 +24,12 @@ pub trait SigningKey : Send + Sync {
     fn get_bytes(&self) -> &[u8] {
         unimplemented!()
     }
+
+    /// For KEM keys, the length of a ciphertext encapsulated to our
+    /// public key.  None for signing keys.
+    fn kem_ciphertext_len(&self) -> Option<usize> {
+        None
+    }
 }
 
 /// A thing that can sign a message.
@@ -185,7 +191,13 @@ pub fn any_pqsig_type(der: &key::PrivateKey) -> Result<Box<dyn SigningKey>, ()>
             return Ok(Box::new(key));
         }
     }
-    for scheme in include!("generated/pq_kemschemes.rs") {
+    any_kem_type(der)
+}
+
+/// Parse `der` as any KEM key type usable for KEMTLS authentication,
+/// returning the first which works.
+pub fn any_kem_type(der: &key::PrivateKey) -> Result<Box<dyn SigningKey>, ()> {
+    for scheme in supported_kem_schemes() {
         if let Ok(key) = PQKemKey::new(der, *scheme) {
             return Ok(Box::new(key));
         }
@@ -445,7 +457,8 @@ impl SigningKey for PQSigningKey {
     }
 
     fn algorithm(&self) -> SignatureAlgorithm {
-        include!("generated/pq_sigscheme_to_sigalg.rs")
+        use crate::msgs::handshake::DecomposedSignatureScheme;
+        self.scheme.sign()
     }
 }
 
@@ -537,6 +550,15 @@ impl SigningKey for PQKemKey {
     fn get_bytes(&self) -> &[u8] {
         &self.key
     }
+
+    fn kem_ciphertext_len(&self) -> Option<usize> {
+        let scheme = self.scheme;
+        let oqsalg = include!("generated/kemscheme_to_oqsalg.rs");
+        oqs::init();
+        oqs::kem::Kem::new(oqsalg)
+            .ok()
+            .map(|kem| kem.length_ciphertext())
+    }
 }
 
 /// The set of schemes we support for signatures and
@@ -554,3 +576,8 @@ pub fn supported_sign_tls13() -> &'static [SignatureScheme] {
     //     SignatureScheme::ED25519,
     // ]
 }
+
+/// The set of KEM schemes we support for KEMTLS authentication.
+pub fn supported_kem_schemes() -> &'static [SignatureScheme] {
+    include!("generated/pq_kemschemes.rs")
+}
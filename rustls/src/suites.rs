@@ -5,21 +5,33 @@ use crate::msgs::handshake::KeyExchangeAlgorithm;
 use crate::msgs::handshake::{ClientECDHParams, ServerECDHParams};
 use crate::msgs::codec::{Reader, Codec};
 use crate::cipher;
+#[cfg(feature = "logging")]
+use crate::log::warn;
 
 use oqs;
 
 use ring;
+use std::collections::HashMap;
 use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::cell::RefCell;
+#[cfg(feature = "kem_cpu_time")]
+use std::time::Duration;
 
 pub enum KexAlgorithm {
     RingAlg(&'static ring::agreement::Algorithm),
     KEM(oqs::kem::Kem),
+    /// A classical ECDH group and a KEM, used together.  Public keys,
+    /// ciphertexts and shared secrets are the ECDH value followed by
+    /// the KEM value.
+    Hybrid(NamedGroup, oqs::kem::Kem),
 }
 
 
 pub enum KexPrivateKey {
     RingKey(ring::agreement::EphemeralPrivateKey),
     KEM(oqs::kem::SecretKey),
+    Hybrid(ring::agreement::EphemeralPrivateKey, oqs::kem::SecretKey),
 }
 
 impl KexPrivateKey {
@@ -35,11 +47,19 @@ impl KexPrivateKey {
             _ => panic!("Wrong key type!"),
         }
     }
+    fn into_hybrid_keys(self) -> (ring::agreement::EphemeralPrivateKey, oqs::kem::SecretKey) {
+        match self {
+            Self::Hybrid(ecdh, kem) => (ecdh, kem),
+            _ => panic!("Wrong key type!"),
+        }
+    }
 }
 
 pub enum KexPublicKey {
     RingKey(ring::agreement::PublicKey),
     KEM(oqs::kem::PublicKey),
+    /// The ECDH public key followed by the KEM public key.
+    Hybrid(Vec<u8>),
 }
 
 // impl KexPublicKey {
@@ -56,6 +76,7 @@ impl AsRef<[u8]> for KexPublicKey {
         match self {
             Self::RingKey(key) => key.as_ref(),
             Self::KEM(key) => key.as_ref(),
+            Self::Hybrid(key) => key.as_ref(),
         }
     }
 }
@@ -82,6 +103,175 @@ pub struct KeyExchangeResult {
     pub shared_secret: Vec<u8>,
 }
 
+/// The sizes of the values a key exchange group produces, so a peer's
+/// values can be checked, and callers can allocate buffers up front.
+/// For (EC)DH groups the "ciphertext" is our public key, sent in reply
+/// to the peer's.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KexSizes {
+    /// Length of an encoded public key (a key share).
+    pub public_key_len: usize,
+    /// Length of a ciphertext encapsulated to a public key.
+    pub ciphertext_len: usize,
+    /// Length of the resulting shared secret.
+    pub shared_secret_len: usize,
+}
+
+/// Return the sizes of values exchanged using `group`, or None
+/// if we don't support it.
+pub fn kex_sizes(group: NamedGroup) -> Option<KexSizes> {
+    KeyExchange::sizes(group)
+}
+
+/// A kind of key exchange operation, as counted by
+/// `kem_operation_counts`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KemOp {
+    /// Generating an ephemeral key pair.
+    Keygen,
+    /// Encapsulating to a peer's public key.  For (EC)DH groups this
+    /// is generating our key and agreeing with the peer's.
+    Encapsulate,
+    /// Decapsulating a peer's ciphertext with our private key.
+    Decapsulate,
+}
+
+const KEM_OPS: [KemOp; 3] = [KemOp::Keygen, KemOp::Encapsulate, KemOp::Decapsulate];
+
+macro_rules! repeat8 {
+    ($e:expr) => { [$e, $e, $e, $e, $e, $e, $e, $e] }
+}
+
+// One counter per (group, operation), indexed by the group's position
+// in `KeyExchange::supported_groups()`.  Room for 64 groups.
+static KEM_OP_COUNTS: [[[AtomicUsize; 3]; 8]; 8] = repeat8!(repeat8!(
+    [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)]
+));
+
+// Certificate KEMs aren't identified by a NamedGroup, so are
+// counted separately.
+static CERT_KEM_OP_COUNTS: [AtomicUsize; 3] =
+    [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)];
+
+fn kem_op_counter(group: NamedGroup, op: KemOp) -> Option<&'static AtomicUsize> {
+    let idx = KeyExchange::supported_groups()
+        .iter()
+        .position(|g| *g == group)?;
+    KEM_OP_COUNTS.get(idx / 8)
+        .map(|row| &row[idx % 8][op as usize])
+}
+
+pub(crate) fn count_kem_op(group: NamedGroup, op: KemOp) {
+    if let Some(counter) = kem_op_counter(group, op) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub(crate) fn count_cert_kem_op(op: KemOp) {
+    CERT_KEM_OP_COUNTS[op as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Return how many key exchange operations this process has done,
+/// for each supported group.  Operations which haven't happened are
+/// omitted.
+///
+/// The counters are process-wide and updated without locking, so
+/// the result is a snapshot which may be slightly out of date if
+/// other threads are handshaking.
+pub fn kem_operation_counts() -> HashMap<(NamedGroup, KemOp), u64> {
+    let mut ret = HashMap::new();
+
+    for group in KeyExchange::supported_groups() {
+        for op in KEM_OPS.iter() {
+            let count = kem_op_counter(*group, *op)
+                .map(|c| c.load(Ordering::Relaxed))
+                .unwrap_or(0);
+            if count > 0 {
+                ret.insert((*group, *op), count as u64);
+            }
+        }
+    }
+
+    ret
+}
+
+/// Like `kem_operation_counts`, but for KEM operations using
+/// certificate keys during KEMTLS authentication.
+pub fn cert_kem_operation_counts() -> HashMap<KemOp, u64> {
+    KEM_OPS.iter()
+        .map(|op| (*op, CERT_KEM_OP_COUNTS[*op as usize].load(Ordering::Relaxed) as u64))
+        .filter(|&(_, count)| count > 0)
+        .collect()
+}
+
+/// A clock for `set_kem_cpu_clock`.  Only the differences between its
+/// readings are used, so it can count from any starting point.
+#[cfg(feature = "kem_cpu_time")]
+pub type KemCpuClock = Box<dyn Fn() -> Duration>;
+
+thread_local! {
+    /// `KeyExchange::sizes` of each group asked about so far.  Finding
+    /// a KEM's sizes means instantiating it in liboqs, and they're
+    /// asked for several times per handshake.
+    static KEX_SIZES: RefCell<HashMap<NamedGroup, Option<KexSizes>>> =
+        RefCell::new(HashMap::new());
+}
+
+#[cfg(feature = "kem_cpu_time")]
+thread_local! {
+    static KEM_CPU_CLOCK: RefCell<Option<KemCpuClock>> = RefCell::new(None);
+    static KEM_CPU_TIMES: RefCell<HashMap<(NamedGroup, KemOp), Duration>> =
+        RefCell::new(HashMap::new());
+}
+
+/// Measure the key exchange operations done by this thread with
+/// `clock`, which should read the thread's CPU time (for example,
+/// `CLOCK_THREAD_CPUTIME_ID`).  With None, stop measuring.
+///
+/// Only ephemeral key exchanges are measured: certificate KEM
+/// operations aren't identified by a NamedGroup.
+#[cfg(feature = "kem_cpu_time")]
+pub fn set_kem_cpu_clock(clock: Option<KemCpuClock>) {
+    KEM_CPU_CLOCK.with(|c| *c.borrow_mut() = clock);
+}
+
+/// Return the time spent on each kind of key exchange operation by
+/// this thread, as measured by the clock given to
+/// `set_kem_cpu_clock`.  Operations which weren't measured are
+/// omitted.
+#[cfg(feature = "kem_cpu_time")]
+pub fn kem_cpu_times() -> HashMap<(NamedGroup, KemOp), Duration> {
+    KEM_CPU_TIMES.with(|times| times.borrow().clone())
+}
+
+/// Forget the times reported by `kem_cpu_times`.
+#[cfg(feature = "kem_cpu_time")]
+pub fn reset_kem_cpu_times() {
+    KEM_CPU_TIMES.with(|times| times.borrow_mut().clear());
+}
+
+#[cfg(feature = "kem_cpu_time")]
+fn timed<T>(group: NamedGroup, op: KemOp, f: impl FnOnce() -> T) -> T {
+    let now = || KEM_CPU_CLOCK.with(|c| c.borrow().as_ref().map(|clock| clock()));
+
+    let start = now();
+    let ret = f();
+    if let (Some(start), Some(end)) = (start, now()) {
+        KEM_CPU_TIMES.with(|times| {
+            *times.borrow_mut()
+                .entry((group, op))
+                .or_insert_with(Duration::default) += end.checked_sub(start).unwrap_or_default();
+        });
+    }
+    ret
+}
+
+#[cfg(not(feature = "kem_cpu_time"))]
+#[inline(always)]
+fn timed<T>(_group: NamedGroup, _op: KemOp, f: impl FnOnce() -> T) -> T {
+    f()
+}
+
 /// An in-progress key exchange.  This has the algorithm,
 /// our private key, and our public key.
 pub struct KeyExchange {
@@ -97,10 +287,65 @@ impl KeyExchange {
             NamedGroup::X25519 => Some(KexAlgorithm::RingAlg(&ring::agreement::X25519)),
             NamedGroup::secp256r1 => Some(KexAlgorithm::RingAlg(&ring::agreement::ECDH_P256)),
             NamedGroup::secp384r1 => Some(KexAlgorithm::RingAlg(&ring::agreement::ECDH_P384)),
+            NamedGroup::X25519Kyber768 => {
+                oqs::init();
+                let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber768).ok()?;
+                Some(KexAlgorithm::Hybrid(NamedGroup::X25519, kem))
+            },
             group => include!("generated/named_group_to_kex.rs"),
         }
     }
 
+    pub fn sizes(group: NamedGroup) -> Option<KexSizes> {
+        KEX_SIZES.with(|cache| {
+            if let Some(sizes) = cache.borrow().get(&group) {
+                return *sizes;
+            }
+
+            let sizes = KeyExchange::compute_sizes(group);
+            cache.borrow_mut().insert(group, sizes);
+            sizes
+        })
+    }
+
+    fn compute_sizes(group: NamedGroup) -> Option<KexSizes> {
+        let ecdh = |public_key_len, shared_secret_len| Some(KexSizes {
+            public_key_len,
+            ciphertext_len: public_key_len,
+            shared_secret_len,
+        });
+
+        match group {
+            NamedGroup::X25519 => ecdh(32, 32),
+            NamedGroup::secp256r1 => ecdh(65, 32),
+            NamedGroup::secp384r1 => ecdh(97, 48),
+            group => match KeyExchange::named_group_to_ecdh_alg(group)? {
+                KexAlgorithm::KEM(kem) => Some(KexSizes {
+                    public_key_len: kem.length_public_key(),
+                    ciphertext_len: kem.length_ciphertext(),
+                    shared_secret_len: kem.length_shared_secret(),
+                }),
+                KexAlgorithm::Hybrid(classical, kem) => {
+                    let ecdh = KeyExchange::sizes(classical)?;
+                    Some(KexSizes {
+                        public_key_len: ecdh.public_key_len + kem.length_public_key(),
+                        ciphertext_len: ecdh.ciphertext_len + kem.length_ciphertext(),
+                        shared_secret_len: ecdh.shared_secret_len + kem.length_shared_secret(),
+                    })
+                },
+                KexAlgorithm::RingAlg(_) => None,
+            },
+        }
+    }
+
+    /// The groups we support for TLS1.3, in preference order.
+    ///
+    /// This order is fixed: post-quantum KEMs first, in ascending code
+    /// point order; then the X25519Kyber768 hybrid; then X25519,
+    /// secp384r1 and secp256r1.  A client's NamedGroups extension keeps
+    /// this order, leaving out groups it can't key-share (see
+    /// `ClientConfig::offered_groups`), so that ClientHellos stay
+    /// byte-for-byte comparable across builds.
     pub fn supported_groups() -> &'static [NamedGroup] {
         // in preference order
         include!("generated/supported_kex_groups.rs")
@@ -111,6 +356,57 @@ impl KeyExchange {
         // ]
     }
 
+    /// Can a public key for `group` be sent as a key share?  The
+    /// KeyShareEntries vector has a 16-bit length, which some KEMs'
+    /// public keys (Classic McEliece's, for example) don't fit in.
+    pub fn fits_key_share(group: NamedGroup) -> bool {
+        // group and length fields of the KeyShareEntry
+        const MAX_KEY_SHARE_LEN: usize = 0xffff - 4;
+
+        KeyExchange::sizes(group)
+            .map(|sizes| sizes.public_key_len <= MAX_KEY_SHARE_LEN)
+            .unwrap_or(false)
+    }
+
+    /// Is `group` an elliptic curve group, for which ECPointFormats
+    /// is meaningful?
+    pub fn is_ecdh_group(group: NamedGroup) -> bool {
+        match group {
+            NamedGroup::X25519 | NamedGroup::secp256r1 | NamedGroup::secp384r1 => true,
+            _ => false,
+        }
+    }
+
+    /// Whether `group` is a KEM whose decapsulation implicitly rejects
+    /// invalid ciphertexts, by returning a pseudorandom shared secret
+    /// rather than an error.  KEMs not listed here, including any added
+    /// later, are assumed not to.
+    pub fn rejects_implicitly(group: NamedGroup) -> bool {
+        match group {
+            NamedGroup::Kyber512 | NamedGroup::Kyber768 | NamedGroup::Kyber1024 |
+                NamedGroup::X25519Kyber768 |
+                NamedGroup::Lightsaber | NamedGroup::Saber | NamedGroup::Firesaber |
+                NamedGroup::FrodoKem640Aes | NamedGroup::FrodoKem640Shake |
+                NamedGroup::FrodoKem976Aes | NamedGroup::FrodoKem976Shake |
+                NamedGroup::FrodoKem1344Aes | NamedGroup::FrodoKem1344Shake |
+                NamedGroup::NtruHps2048509 | NamedGroup::NtruHps2048677 |
+                NamedGroup::NtruHps4096821 | NamedGroup::NtruHrss701 |
+                NamedGroup::NtruPrimeNtrulpr653 | NamedGroup::NtruPrimeNtrulpr761 |
+                NamedGroup::NtruPrimeNtrulpr857 | NamedGroup::NtruPrimeSntrup653 |
+                NamedGroup::NtruPrimeSntrup761 | NamedGroup::NtruPrimeSntrup857 |
+                NamedGroup::SikeP434 | NamedGroup::SikeP503 |
+                NamedGroup::SikeP610 | NamedGroup::SikeP751 |
+                NamedGroup::SikeP434Compressed | NamedGroup::SikeP503Compressed |
+                NamedGroup::SikeP610Compressed | NamedGroup::SikeP751Compressed |
+                NamedGroup::ClassicMcEliece348864 | NamedGroup::ClassicMcEliece348864f |
+                NamedGroup::ClassicMcEliece460896 | NamedGroup::ClassicMcEliece460896f |
+                NamedGroup::ClassicMcEliece6688128 | NamedGroup::ClassicMcEliece6688128f |
+                NamedGroup::ClassicMcEliece6960119 | NamedGroup::ClassicMcEliece6960119f |
+                NamedGroup::ClassicMcEliece8192128 | NamedGroup::ClassicMcEliece8192128f => true,
+            _ => false,
+        }
+    }
+
     pub fn supported_groups_tls12() -> &'static [NamedGroup] {
         // in preference order
         &[
@@ -120,13 +416,25 @@ impl KeyExchange {
         ]
     }
 
-    // Generate's the public key keyshare
+    // Generate's the public key keyshare.  None for groups whose
+    // public key can't be sent as a key share: see `fits_key_share`.
     pub fn start_kex(named_group: NamedGroup) -> Option<KeyExchange> {
+        KeyExchange::start_kex_with_retries(named_group, 0)
+    }
+
+    /// Like `start_kex`, but if the KEM backend fails to generate a key
+    /// pair, try again up to `retries` more times before giving up on
+    /// `named_group`.
+    pub fn start_kex_with_retries(named_group: NamedGroup, retries: u32) -> Option<KeyExchange> {
+        if !KeyExchange::fits_key_share(named_group) {
+            return None;
+        }
         let alg = KeyExchange::named_group_to_ecdh_alg(named_group)?;
-        match alg {
+        count_kem_op(named_group, KemOp::Keygen);
+        timed(named_group, KemOp::Keygen, || match alg {
             KexAlgorithm::RingAlg(alg) => Self::start_ecdhe(named_group, alg),
             KexAlgorithm::KEM(kem) => {
-                let (pk, sk) = kem.keypair().unwrap();
+                let (pk, sk) = retry_keygen(named_group, retries, || kem.keypair())?;
                 Some(KeyExchange {
                     group: named_group,
                     alg: KexAlgorithm::KEM(kem),
@@ -134,17 +442,64 @@ impl KeyExchange {
                     pubkey: KexPublicKey::KEM(pk),
                 })
             },
+            KexAlgorithm::Hybrid(classical, kem) => {
+                let alg = ring_alg(classical)?;
+                let rng = ring::rand::SystemRandom::new();
+                let ecdh_key = ring::agreement::EphemeralPrivateKey::generate(alg, &rng).ok()?;
+                let mut pubkey = ecdh_key.compute_public_key().ok()?.as_ref().to_vec();
+                let (pk, sk) = retry_keygen(named_group, retries, || kem.keypair())?;
+                pubkey.extend_from_slice(pk.as_ref());
+                Some(KeyExchange {
+                    group: named_group,
+                    alg: KexAlgorithm::Hybrid(classical, kem),
+                    privkey: KexPrivateKey::Hybrid(ecdh_key, sk),
+                    pubkey: KexPublicKey::Hybrid(pubkey),
+                })
+            },
+        })
+    }
+
+    /// A KEM key exchange using a key pair made elsewhere, rather than
+    /// a fresh one.  Only plain KEM groups work: we can't import ECDH
+    /// private keys.  Returns None for other groups, if a key has
+    /// the wrong length, or if the keys aren't a pair: we check that by
+    /// encapsulating to `public_key` and decapsulating with `private_key`.
+    pub fn from_kem_keypair(group: NamedGroup,
+                            public_key: &[u8],
+                            private_key: &[u8]) -> Option<KeyExchange> {
+        match KeyExchange::named_group_to_ecdh_alg(group)? {
+            KexAlgorithm::KEM(kem) => {
+                let pk = kem.public_key_from_bytes(public_key)?.to_owned();
+                let sk = kem.secret_key_from_bytes(private_key)?.to_owned();
+                let (ct, ss) = kem.encapsulate(&pk).ok()?;
+                if kem.decapsulate(&sk, &ct).ok()? != ss {
+                    return None;
+                }
+                Some(KeyExchange {
+                    group,
+                    alg: KexAlgorithm::KEM(kem),
+                    privkey: KexPrivateKey::KEM(sk),
+                    pubkey: KexPublicKey::KEM(pk),
+                })
+            },
+            _ => None,
         }
     }
 
     // Encapsulates to the server's share
     pub fn encapsulate(named_group: NamedGroup, peer: &[u8]) -> Option<KeyExchangeResult> {
         let alg = KeyExchange::named_group_to_ecdh_alg(named_group)?;
-        match alg {
+        // Some KEM backends panic on input of the wrong length, so
+        // check it here rather than rely on them.
+        if peer.len() != KeyExchange::sizes(named_group)?.public_key_len {
+            return None;
+        }
+        count_kem_op(named_group, KemOp::Encapsulate);
+        timed(named_group, KemOp::Encapsulate, || match alg {
             KexAlgorithm::RingAlg(alg) => {
                 let kex = Self::start_ecdhe(named_group, alg)?;
                 let ciphertext = kex.pubkey.as_ref().to_vec();
-                let shared_secret = kex.decapsulate(peer)?;
+                let shared_secret = kex.complete(peer)?;
                 Some(KeyExchangeResult {
                     ciphertext,
                     shared_secret,
@@ -155,7 +510,19 @@ impl KeyExchange {
                 let (ciphertext, shared_secret) = kem.encapsulate(pk).ok()?;
                 Some(KeyExchangeResult {ciphertext: ciphertext.into_vec(), shared_secret: shared_secret.into_vec()})
             },
-        }
+            KexAlgorithm::Hybrid(classical, kem) => {
+                let (peer_ecdh, peer_kem) = split_hybrid(classical, peer)?;
+                let kex = Self::start_ecdhe(classical, ring_alg(classical)?)?;
+                let mut ciphertext = kex.pubkey.as_ref().to_vec();
+                let mut shared_secret = kex.complete(peer_ecdh)?;
+
+                let pk = kem.public_key_from_bytes(peer_kem)?;
+                let (kem_ciphertext, kem_secret) = kem.encapsulate(pk).ok()?;
+                ciphertext.extend_from_slice(kem_ciphertext.as_ref());
+                shared_secret.extend_from_slice(kem_secret.as_ref());
+                Some(KeyExchangeResult { ciphertext, shared_secret })
+            },
+        })
     }
 
     fn start_ecdhe(
@@ -202,6 +569,18 @@ impl KeyExchange {
     }
 
     pub fn decapsulate(self, peer: &[u8]) -> Option<Vec<u8>> {
+        // As for `encapsulate`, don't hand the backend a ciphertext
+        // of the wrong length.
+        if peer.len() != KeyExchange::sizes(self.group)?.ciphertext_len {
+            return None;
+        }
+        count_kem_op(self.group, KemOp::Decapsulate);
+        let group = self.group;
+        let secret = timed(group, KemOp::Decapsulate, || self.complete(peer))?;
+        check_kem_secret(group, secret)
+    }
+
+    fn complete(self, peer: &[u8]) -> Option<Vec<u8>> {
         match self.alg {
             KexAlgorithm::RingAlg(alg) => {
                 let peer_key = ring::agreement::UnparsedPublicKey::new(alg, peer);
@@ -210,11 +589,7 @@ impl KeyExchange {
                         .into_ring_key(),
                     &peer_key,
                     (),
-                    |v| {
-                        let mut r = Vec::new();
-                        r.extend_from_slice(v);
-                        Ok(r)
-                    },
+                    |v| Ok(v.to_vec()),
                 );
 
                 if secret.is_err() {
@@ -228,10 +603,83 @@ impl KeyExchange {
                 let ct = kem.ciphertext_from_bytes(peer)?;
                 Some(kem.decapsulate(&sk, ct).ok()?.into_vec())
             }
+            KexAlgorithm::Hybrid(classical, kem) => {
+                let (peer_ecdh, peer_kem) = split_hybrid(classical, peer)?;
+                let (ecdh_key, sk) = self.privkey.into_hybrid_keys();
+                let peer_key = ring::agreement::UnparsedPublicKey::new(ring_alg(classical)?, peer_ecdh);
+                let mut secret = ring::agreement::agree_ephemeral(ecdh_key,
+                                                                  &peer_key,
+                                                                  (),
+                                                                  |v| Ok(v.to_vec()))
+                    .ok()?;
+
+                let ct = kem.ciphertext_from_bytes(peer_kem)?;
+                secret.extend_from_slice(kem.decapsulate(&sk, ct).ok()?.as_ref());
+                Some(secret)
+            }
+        }
+    }
+}
+
+/// Reject an all-zero shared secret from a KEM that rejects invalid
+/// ciphertexts explicitly, as some implementations return zeros on
+/// failure instead of an error.
+///
+/// This is deliberately skipped for implicitly-rejecting KEMs: their
+/// output is pseudorandom even for invalid ciphertexts, and branching
+/// on it would give up their constant-time behaviour.  ECDH outputs are
+/// already checked by ring.
+fn check_kem_secret(group: NamedGroup, secret: Vec<u8>) -> Option<Vec<u8>> {
+    if KeyExchange::is_ecdh_group(group) || KeyExchange::rejects_implicitly(group) {
+        return Some(secret);
+    }
+
+    let zeros = vec![0u8; secret.len()];
+    if ring::constant_time::verify_slices_are_equal(&secret, &zeros).is_ok() {
+        None
+    } else {
+        Some(secret)
+    }
+}
+
+/// Call `keygen` until it succeeds, at most `retries + 1` times.
+#[cfg_attr(not(feature = "logging"), allow(unused_variables))]
+fn retry_keygen<T, E: fmt::Debug>(group: NamedGroup,
+                                  retries: u32,
+                                  mut keygen: impl FnMut() -> Result<T, E>) -> Option<T> {
+    let mut attempt = 0;
+    loop {
+        match keygen() {
+            Ok(keypair) => return Some(keypair),
+            Err(err) if attempt < retries => {
+                attempt += 1;
+                warn!("{:?} key generation failed ({:?}), retry {} of {}",
+                      group, err, attempt, retries);
+            }
+            Err(err) => {
+                warn!("{:?} key generation failed ({:?}), giving up", group, err);
+                return None;
+            }
         }
     }
 }
 
+fn ring_alg(group: NamedGroup) -> Option<&'static ring::agreement::Algorithm> {
+    match KeyExchange::named_group_to_ecdh_alg(group)? {
+        KexAlgorithm::RingAlg(alg) => Some(alg),
+        _ => None,
+    }
+}
+
+/// Split a hybrid public key or ciphertext into its ECDH and KEM parts.
+fn split_hybrid(classical: NamedGroup, value: &[u8]) -> Option<(&[u8], &[u8])> {
+    let ecdh_len = KeyExchange::sizes(classical)?.public_key_len;
+    if value.len() < ecdh_len {
+        return None;
+    }
+    Some(value.split_at(ecdh_len))
+}
+
 /// A cipher suite supported by rustls.
 ///
 /// All possible instances of this class are provided by the library in
@@ -626,6 +1074,20 @@ mod test {
     use super::*;
     use crate::msgs::enums::CipherSuite;
 
+    #[test]
+    fn test_supported_groups_order_is_stable() {
+        let groups = KeyExchange::supported_groups();
+        let (kems, rest) = groups.split_at(groups.len() - 4);
+
+        assert!(kems.windows(2).all(|w| w[0].get_u16() < w[1].get_u16()));
+        assert_eq!(rest, &[
+            NamedGroup::X25519Kyber768,
+            NamedGroup::X25519,
+            NamedGroup::secp384r1,
+            NamedGroup::secp256r1,
+        ]);
+    }
+
     #[test]
     fn test_client_pref() {
         let client = vec![
@@ -670,6 +1132,303 @@ mod test {
         .is_none());
     }
 
+    #[test]
+    fn test_is_ecdh_group() {
+        assert!(KeyExchange::is_ecdh_group(NamedGroup::X25519));
+        assert!(KeyExchange::is_ecdh_group(NamedGroup::secp256r1));
+        assert!(KeyExchange::is_ecdh_group(NamedGroup::secp384r1));
+        assert!(!KeyExchange::is_ecdh_group(NamedGroup::Kyber512));
+        assert!(!KeyExchange::is_ecdh_group(NamedGroup::FFDHE2048));
+    }
+
+    #[test]
+    fn test_kex_sizes_match_key_exchange() {
+        for group in &[NamedGroup::X25519, NamedGroup::secp256r1,
+                       NamedGroup::secp384r1, NamedGroup::Kyber512,
+                       NamedGroup::X25519Kyber768] {
+            let sizes = kex_sizes(*group).unwrap();
+            let kx = KeyExchange::start_kex(*group).unwrap();
+            assert_eq!(kx.pubkey.as_ref().len(), sizes.public_key_len);
+
+            let result = KeyExchange::encapsulate(*group, kx.pubkey.as_ref()).unwrap();
+            assert_eq!(result.ciphertext.len(), sizes.ciphertext_len);
+            assert_eq!(result.shared_secret.len(), sizes.shared_secret_len);
+        }
+
+        assert_eq!(kex_sizes(NamedGroup::FFDHE2048), None);
+    }
+
+    #[test]
+    fn test_keygen_retries_transient_failure() {
+        let mut calls = 0;
+        let flaky = || {
+            calls += 1;
+            if calls == 1 { Err("transient") } else { Ok(calls) }
+        };
+        assert_eq!(retry_keygen(NamedGroup::Kyber512, 1, flaky), Some(2));
+        assert_eq!(calls, 2);
+
+        let mut calls = 0;
+        let flaky = || {
+            calls += 1;
+            if calls == 1 { Err("transient") } else { Ok(calls) }
+        };
+        assert_eq!(retry_keygen(NamedGroup::Kyber512, 0, flaky), None);
+        assert_eq!(calls, 1);
+
+        let mut calls = 0;
+        let broken = || -> Result<(), &str> {
+            calls += 1;
+            Err("broken")
+        };
+        assert_eq!(retry_keygen(NamedGroup::Kyber512, 3, broken), None);
+        assert_eq!(calls, 4);
+    }
+
+    #[test]
+    fn test_oversized_public_keys_are_not_key_shares() {
+        let group = NamedGroup::ClassicMcEliece348864;
+        assert!(kex_sizes(group).unwrap().public_key_len > 0xffff);
+        assert!(!KeyExchange::fits_key_share(group));
+        assert!(KeyExchange::start_kex(group).is_none());
+
+        for group in &[NamedGroup::X25519, NamedGroup::Kyber1024, NamedGroup::X25519Kyber768] {
+            assert!(KeyExchange::fits_key_share(*group));
+            assert!(KeyExchange::start_kex(*group).is_some());
+        }
+    }
+
+    #[test]
+    fn test_kem_groups_use_their_own_algorithm() {
+        use oqs::kem::Algorithm;
+
+        // One group from each family; none of them is Kyber512 in
+        // disguise any more.
+        let kyber512 = kex_sizes(NamedGroup::Kyber512).unwrap();
+        for &(group, alg) in &[ (NamedGroup::Kyber768, Algorithm::Kyber768),
+                                (NamedGroup::Saber, Algorithm::Saber),
+                                (NamedGroup::NtruHps2048509, Algorithm::NtruHps2048509),
+                                (NamedGroup::NtruPrimeSntrup653, Algorithm::NtruPrimeSntrup653),
+                                (NamedGroup::FrodoKem640Aes, Algorithm::FrodoKem640Aes),
+                                (NamedGroup::SikeP434, Algorithm::SikeP434),
+                                (NamedGroup::BikeL1Fo, Algorithm::BikeL1Fo),
+                                (NamedGroup::Hqc128, Algorithm::Hqc128) ] {
+            let kem = oqs::kem::Kem::new(alg).unwrap();
+            let sizes = kex_sizes(group).unwrap();
+            assert_eq!(sizes.public_key_len, kem.length_public_key(), "{:?}", group);
+            assert_eq!(sizes.ciphertext_len, kem.length_ciphertext(), "{:?}", group);
+            assert_eq!(sizes.shared_secret_len, kem.length_shared_secret(), "{:?}", group);
+            assert_ne!(sizes.public_key_len, kyber512.public_key_len, "{:?}", group);
+        }
+    }
+
+    #[test]
+    fn test_compressed_sike_uses_compressed_encoding() {
+        let compressed = kex_sizes(NamedGroup::SikeP434Compressed).unwrap();
+        assert_eq!(compressed.public_key_len, 197);
+        assert_eq!(compressed.ciphertext_len, 236);
+        assert_eq!(compressed.shared_secret_len, 16);
+
+        let uncompressed = kex_sizes(NamedGroup::SikeP434).unwrap();
+        assert_eq!(uncompressed.public_key_len, 330);
+        assert_eq!(uncompressed.ciphertext_len, 346);
+
+        let kx = KeyExchange::start_kex(NamedGroup::SikeP434Compressed).unwrap();
+        assert_eq!(kx.pubkey.as_ref().len(), compressed.public_key_len);
+        let result = KeyExchange::encapsulate(NamedGroup::SikeP434Compressed, kx.pubkey.as_ref())
+            .unwrap();
+        assert_eq!(result.ciphertext.len(), compressed.ciphertext_len);
+        assert_eq!(kx.decapsulate(&result.ciphertext).unwrap(), result.shared_secret);
+    }
+
+    #[test]
+    fn test_kex_sizes_are_remembered() {
+        for &group in KeyExchange::supported_groups() {
+            let computed = KeyExchange::compute_sizes(group);
+            assert_eq!(kex_sizes(group), computed, "{:?}", group);
+            assert_eq!(KEX_SIZES.with(|cache| cache.borrow().get(&group).cloned()),
+                       Some(computed), "{:?}", group);
+        }
+    }
+
+    #[test]
+    fn test_every_compressed_sike_group_is_smaller() {
+        for &(compressed, uncompressed) in &[
+            (NamedGroup::SikeP434Compressed, NamedGroup::SikeP434),
+            (NamedGroup::SikeP503Compressed, NamedGroup::SikeP503),
+            (NamedGroup::SikeP610Compressed, NamedGroup::SikeP610),
+            (NamedGroup::SikeP751Compressed, NamedGroup::SikeP751),
+        ] {
+            let small = kex_sizes(compressed).unwrap();
+            let large = kex_sizes(uncompressed).unwrap();
+            assert!(small.public_key_len < large.public_key_len, "{:?}", compressed);
+            assert!(small.ciphertext_len < large.ciphertext_len, "{:?}", compressed);
+            assert_eq!(small.shared_secret_len, large.shared_secret_len, "{:?}", compressed);
+
+            let kx = KeyExchange::start_kex(compressed).unwrap();
+            assert_eq!(kx.pubkey.as_ref().len(), small.public_key_len, "{:?}", compressed);
+            let result = KeyExchange::encapsulate(compressed, kx.pubkey.as_ref())
+                .unwrap();
+            assert_eq!(result.ciphertext.len(), small.ciphertext_len, "{:?}", compressed);
+            assert_eq!(kx.decapsulate(&result.ciphertext).unwrap(), result.shared_secret);
+        }
+    }
+
+    #[test]
+    fn test_hybrid_kex_combines_secrets() {
+        let group = NamedGroup::X25519Kyber768;
+
+        // build a hybrid share from separately-generated halves
+        let x25519 = KeyExchange::start_kex(NamedGroup::X25519).unwrap();
+        oqs::init();
+        let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber768).unwrap();
+        let (kem_pk, kem_sk) = kem.keypair().unwrap();
+        let mut share = x25519.pubkey.as_ref().to_vec();
+        share.extend_from_slice(kem_pk.as_ref());
+
+        let result = KeyExchange::encapsulate(group, &share).unwrap();
+        assert_eq!(result.ciphertext.len(), kex_sizes(group).unwrap().ciphertext_len);
+        let (ecdh_ct, kem_ct) = result.ciphertext.split_at(32);
+
+        let mut expected = x25519.decapsulate(ecdh_ct).unwrap();
+        let kem_ct = kem.ciphertext_from_bytes(kem_ct).unwrap();
+        expected.extend_from_slice(kem.decapsulate(&kem_sk, kem_ct).unwrap().as_ref());
+        assert_eq!(result.shared_secret, expected);
+
+        // and a full round trip agrees
+        let kx = KeyExchange::start_kex(group).unwrap();
+        let result = KeyExchange::encapsulate(group, kx.pubkey.as_ref()).unwrap();
+        assert_eq!(kx.decapsulate(&result.ciphertext).unwrap(), result.shared_secret);
+    }
+
+    #[test]
+    fn test_hybrid_kex_rejects_short_share() {
+        assert!(KeyExchange::encapsulate(NamedGroup::X25519Kyber768, &[0u8; 31]).is_none());
+    }
+
+    #[test]
+    fn test_kex_rejects_random_length_inputs() {
+        for group in &[NamedGroup::X25519, NamedGroup::secp256r1,
+                       NamedGroup::Kyber512, NamedGroup::X25519Kyber768] {
+            let sizes = kex_sizes(*group).unwrap();
+
+            for _ in 0..64 {
+                let mut len = [0u8; 2];
+                crate::rand::fill_random(&mut len);
+                let len = usize::from(u16::from_be_bytes(len)) % (2 * sizes.ciphertext_len + 2);
+                let mut input = vec![0u8; len];
+                crate::rand::fill_random(&mut input);
+
+                if len != sizes.public_key_len {
+                    assert!(KeyExchange::encapsulate(*group, &input).is_none());
+                }
+                if len != sizes.ciphertext_len {
+                    let kx = KeyExchange::start_kex(*group).unwrap();
+                    assert!(kx.decapsulate(&input).is_none());
+                }
+            }
+
+            // off-by-one lengths, which a random length rarely hits
+            for len in &[0, sizes.ciphertext_len - 1, sizes.ciphertext_len + 1] {
+                let kx = KeyExchange::start_kex(*group).unwrap();
+                assert!(kx.decapsulate(&vec![0u8; *len]).is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn test_kex_from_kem_keypair() {
+        oqs::init();
+        let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+        let (pk, sk) = kem.keypair().unwrap();
+
+        let kx = KeyExchange::from_kem_keypair(NamedGroup::Kyber512, pk.as_ref(), sk.as_ref())
+            .unwrap();
+        assert_eq!(kx.pubkey.as_ref(), pk.as_ref());
+
+        let result = KeyExchange::encapsulate(NamedGroup::Kyber512, pk.as_ref()).unwrap();
+        assert_eq!(kx.decapsulate(&result.ciphertext), Some(result.shared_secret));
+
+        assert!(KeyExchange::from_kem_keypair(NamedGroup::Kyber512, &pk.as_ref()[1..], sk.as_ref())
+                .is_none());
+        let (_, other_sk) = kem.keypair().unwrap();
+        assert!(KeyExchange::from_kem_keypair(NamedGroup::Kyber512, pk.as_ref(), other_sk.as_ref())
+                .is_none());
+        assert!(KeyExchange::from_kem_keypair(NamedGroup::X25519, &[0u8; 32], &[0u8; 32])
+                .is_none());
+    }
+
+    #[test]
+    fn test_kem_operation_counts() {
+        let count = |op| kem_operation_counts()
+            .get(&(NamedGroup::Kyber512, op))
+            .cloned()
+            .unwrap_or(0);
+        let before = [count(KemOp::Keygen), count(KemOp::Encapsulate), count(KemOp::Decapsulate)];
+
+        let kx = KeyExchange::start_kex(NamedGroup::Kyber512).unwrap();
+        let result = KeyExchange::encapsulate(NamedGroup::Kyber512, kx.pubkey.as_ref()).unwrap();
+        kx.decapsulate(&result.ciphertext).unwrap();
+
+        // other tests run concurrently, so counts may have moved further
+        assert!(count(KemOp::Keygen) > before[0]);
+        assert!(count(KemOp::Encapsulate) > before[1]);
+        assert!(count(KemOp::Decapsulate) > before[2]);
+        assert!(!kem_operation_counts().contains_key(&(NamedGroup::FFDHE2048, KemOp::Keygen)));
+    }
+
+    #[cfg(feature = "kem_cpu_time")]
+    #[test]
+    fn test_kem_cpu_times() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+        use std::time::Duration;
+
+        // a clock which advances by 1ms every time it is read
+        let ticks = Rc::new(Cell::new(0));
+        let clock_ticks = ticks.clone();
+        set_kem_cpu_clock(Some(Box::new(move || {
+            clock_ticks.set(clock_ticks.get() + 1);
+            Duration::from_millis(clock_ticks.get())
+        })));
+        reset_kem_cpu_times();
+
+        let kx = KeyExchange::start_kex(NamedGroup::Kyber512).unwrap();
+        let result = KeyExchange::encapsulate(NamedGroup::Kyber512, kx.pubkey.as_ref()).unwrap();
+        kx.decapsulate(&result.ciphertext).unwrap();
+        KeyExchange::start_kex(NamedGroup::X25519).unwrap();
+
+        let times = kem_cpu_times();
+        assert_eq!(times.len(), 4);
+        assert_eq!(times[&(NamedGroup::Kyber512, KemOp::Keygen)], Duration::from_millis(1));
+        assert_eq!(times[&(NamedGroup::Kyber512, KemOp::Encapsulate)], Duration::from_millis(1));
+        assert_eq!(times[&(NamedGroup::Kyber512, KemOp::Decapsulate)], Duration::from_millis(1));
+        assert_eq!(times[&(NamedGroup::X25519, KemOp::Keygen)], Duration::from_millis(1));
+
+        set_kem_cpu_clock(None);
+        KeyExchange::start_kex(NamedGroup::Kyber512).unwrap();
+        assert_eq!(kem_cpu_times(), times);
+        assert_eq!(ticks.get(), 8);
+
+        reset_kem_cpu_times();
+        assert!(kem_cpu_times().is_empty());
+    }
+
+    #[test]
+    fn test_zero_kem_secret_rejected() {
+        assert!(!KeyExchange::rejects_implicitly(NamedGroup::Hqc128));
+        assert!(KeyExchange::rejects_implicitly(NamedGroup::Kyber512));
+        assert!(!KeyExchange::rejects_implicitly(NamedGroup::X25519));
+        assert!(!KeyExchange::rejects_implicitly(NamedGroup::Unknown(0xfe00)));
+
+        assert_eq!(check_kem_secret(NamedGroup::Hqc128, vec![0u8; 64]), None);
+        assert_eq!(check_kem_secret(NamedGroup::Hqc128, vec![1u8; 64]), Some(vec![1u8; 64]));
+
+        // implicit rejection: zeros are (astronomically unlikely but) passed through
+        assert_eq!(check_kem_secret(NamedGroup::Kyber512, vec![0u8; 32]), Some(vec![0u8; 32]));
+        assert_eq!(check_kem_secret(NamedGroup::X25519, vec![0u8; 32]), Some(vec![0u8; 32]));
+    }
+
     #[test]
     fn test_scs_is_debug() {
         println!("{:?}", ALL_CIPHERSUITES);
@@ -711,4 +1470,19 @@ mod test {
         assert!(TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256
             .can_resume_to(&TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256));
     }
+
+    #[test]
+    fn test_pq_schemes_not_compatible_with_tls12_suites() {
+        let tls12 = reduce_given_version(&ALL_CIPHERSUITES, ProtocolVersion::TLSv1_2);
+        let tls13 = reduce_given_version(&ALL_CIPHERSUITES, ProtocolVersion::TLSv1_3);
+        let pq_schemes: &[SignatureScheme] = include!("generated/pq_sigschemes.rs");
+
+        for &scheme in pq_schemes.iter().chain(crate::sign::supported_kem_schemes()) {
+            assert!(!compatible_sigscheme_for_suites(scheme, &tls12), "{:?}", scheme);
+            assert!(compatible_sigscheme_for_suites(scheme, &tls13), "{:?}", scheme);
+        }
+
+        assert!(compatible_sigscheme_for_suites(SignatureScheme::RSA_PSS_SHA256, &tls12));
+        assert!(compatible_sigscheme_for_suites(SignatureScheme::ECDSA_NISTP256_SHA256, &tls12));
+    }
 }
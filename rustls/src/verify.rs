@@ -6,10 +6,11 @@ use std::sync::Arc;
 use crate::key::Certificate;
 use crate::msgs::handshake::DigitallySignedStruct;
 use crate::msgs::handshake::SCTList;
-use crate::msgs::enums::SignatureScheme;
+use crate::msgs::enums::{SignatureAlgorithm, SignatureScheme};
 use crate::error::TLSError;
 use crate::anchors::{DistinguishedNames, RootCertStore};
 use crate::anchors::OwnedTrustAnchor;
+use crate::x509;
 #[cfg(feature = "logging")]
 use crate::log::{warn, debug, trace};
 
@@ -134,6 +135,43 @@ pub trait ServerCertVerifier : Send + Sync {
     fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
         WebPKIVerifier::verification_schemes()
     }
+
+    /// Like `verify_server_cert`, but only accept a chain whose
+    /// signatures use one of `cert_schemes`.  This is used when we
+    /// sent a signature_algorithms_cert extension, so the schemes
+    /// allowed in certificates differ from those allowed in
+    /// CertificateVerify.
+    ///
+    /// This trait method has a default implementation that ignores
+    /// `cert_schemes` and calls `verify_server_cert`.
+    fn verify_server_cert_signed_with(&self,
+                                      roots: &RootCertStore,
+                                      presented_certs: &[Certificate],
+                                      dns_name: webpki::DNSNameRef,
+                                      ocsp_response: &[u8],
+                                      _cert_schemes: &[SignatureScheme])
+                                      -> Result<ServerCertVerified, TLSError> {
+        self.verify_server_cert(roots, presented_certs, dns_name, ocsp_response)
+    }
+
+    /// Verify the certificate chain `presented_certs` against the roots
+    /// configured in `roots`, without checking which names it is valid
+    /// for.  This is used for servers without a DNS name, such as those
+    /// addressed by IP; see `ClientConfig::verify_server_name`.
+    ///
+    /// If `cert_schemes` is not empty, only accept a chain whose
+    /// signatures use one of those schemes.
+    ///
+    /// This trait method has a default implementation that refuses,
+    /// so that verifiers must opt in to skipping the name check.
+    fn verify_server_cert_without_name(&self,
+                                       _roots: &RootCertStore,
+                                       _presented_certs: &[Certificate],
+                                       _ocsp_response: &[u8],
+                                       _cert_schemes: &[SignatureScheme])
+                                       -> Result<ServerCertVerified, TLSError> {
+        Err(TLSError::General("verifier cannot skip the server name check".to_string()))
+    }
 }
 
 /// Something that can verify a client certificate chain
@@ -239,9 +277,58 @@ impl ServerCertVerifier for WebPKIVerifier {
                           presented_certs: &[Certificate],
                           dns_name: webpki::DNSNameRef,
                           ocsp_response: &[u8]) -> Result<ServerCertVerified, TLSError> {
+        self.verify_server_cert_using(SUPPORTED_SIG_ALGS, roots, presented_certs,
+                                      Some(dns_name), ocsp_response)
+    }
+
+    fn verify_server_cert_signed_with(&self,
+                                      roots: &RootCertStore,
+                                      presented_certs: &[Certificate],
+                                      dns_name: webpki::DNSNameRef,
+                                      ocsp_response: &[u8],
+                                      cert_schemes: &[SignatureScheme])
+                                      -> Result<ServerCertVerified, TLSError> {
+        let algs = cert_schemes.iter()
+            .flat_map(|scheme| convert_cert_scheme(*scheme))
+            .collect::<Vec<_>>();
+        self.verify_server_cert_using(&algs, roots, presented_certs, Some(dns_name),
+                                      ocsp_response)
+    }
+
+    fn verify_server_cert_without_name(&self,
+                                       roots: &RootCertStore,
+                                       presented_certs: &[Certificate],
+                                       ocsp_response: &[u8],
+                                       cert_schemes: &[SignatureScheme])
+                                       -> Result<ServerCertVerified, TLSError> {
+        if cert_schemes.is_empty() {
+            return self.verify_server_cert_using(SUPPORTED_SIG_ALGS, roots, presented_certs,
+                                                 None, ocsp_response);
+        }
+
+        let algs = cert_schemes.iter()
+            .flat_map(|scheme| convert_cert_scheme(*scheme))
+            .collect::<Vec<_>>();
+        self.verify_server_cert_using(&algs, roots, presented_certs, None, ocsp_response)
+    }
+}
+
+/// Default `ServerCertVerifier`, see the trait impl for more information.
+pub struct WebPKIVerifier {
+    /// time provider
+    pub time: fn() -> Result<webpki::Time, TLSError>,
+}
+
+impl WebPKIVerifier {
+    fn verify_server_cert_using(&self,
+                                algs: &[&'static webpki::SignatureAlgorithm],
+                                roots: &RootCertStore,
+                                presented_certs: &[Certificate],
+                                dns_name: Option<webpki::DNSNameRef>,
+                                ocsp_response: &[u8]) -> Result<ServerCertVerified, TLSError> {
         let (cert, chain, trustroots) = prepare(roots, presented_certs)?;
         let now = (self.time)()?;
-        let cert = cert.verify_is_valid_tls_server_cert(SUPPORTED_SIG_ALGS,
+        let cert = cert.verify_is_valid_tls_server_cert(algs,
                 &webpki::TLSServerTrustAnchors(&trustroots), &chain, now)
             .map_err(TLSError::WebPKIError)
             .map(|_| cert)?;
@@ -250,18 +337,15 @@ impl ServerCertVerifier for WebPKIVerifier {
             trace!("Unvalidated OCSP response: {:?}", ocsp_response.to_vec());
         }
 
-        cert.verify_is_valid_for_dns_name(dns_name)
-            .map_err(TLSError::WebPKIError)
-            .map(|_| ServerCertVerified::assertion())
+        match dns_name {
+            Some(dns_name) => cert.verify_is_valid_for_dns_name(dns_name)
+                .map_err(TLSError::WebPKIError)
+                .map(|_| ServerCertVerified::assertion()),
+            None => Ok(ServerCertVerified::assertion()),
+        }
     }
 }
 
-/// Default `ServerCertVerifier`, see the trait impl for more information.
-pub struct WebPKIVerifier {
-    /// time provider
-    pub time: fn() -> Result<webpki::Time, TLSError>,
-}
-
 impl WebPKIVerifier {
     /// Create a new `WebPKIVerifier`
     pub fn new() -> WebPKIVerifier {
@@ -460,6 +544,18 @@ fn convert_scheme(scheme: SignatureScheme) -> Result<SignatureAlgorithms, TLSErr
     }
 }
 
+/// The webpki algorithms a certificate signed according to `scheme`
+/// might use.  Unlike in CertificateVerify, PKCS#1 is fine here.
+fn convert_cert_scheme(scheme: SignatureScheme) -> Vec<&'static webpki::SignatureAlgorithm> {
+    match scheme {
+        SignatureScheme::RSA_PKCS1_SHA256 => vec![ &webpki::RSA_PKCS1_2048_8192_SHA256 ],
+        SignatureScheme::RSA_PKCS1_SHA384 => vec![ &webpki::RSA_PKCS1_2048_8192_SHA384,
+                                                   &webpki::RSA_PKCS1_3072_8192_SHA384 ],
+        SignatureScheme::RSA_PKCS1_SHA512 => vec![ &webpki::RSA_PKCS1_2048_8192_SHA512 ],
+        _ => convert_alg_tls13(scheme).into_iter().collect(),
+    }
+}
+
 fn verify_sig_using_any_alg(cert: &webpki::EndEntityCert,
                             algs: SignatureAlgorithms,
                             message: &[u8],
@@ -486,10 +582,31 @@ fn verify_signed_struct(message: &[u8],
         .map_err(TLSError::WebPKIError)?;
 
     verify_sig_using_any_alg(&cert, possible_algs, message, &dss.sig.0)
-        .map_err(TLSError::WebPKIError)
+        .map_err(|err| convert_webpki_sig_error(dss.scheme, err))
         .map(|_| HandshakeSignatureValid::assertion())
 }
 
+fn is_pq_sig_scheme(scheme: SignatureScheme) -> bool {
+    let pq_sigschemes: &[SignatureScheme] = include!("generated/pq_sigschemes.rs");
+    pq_sigschemes.contains(&scheme)
+}
+
+/// The error for a post-quantum signature `scheme` which we know
+/// about, but which the webpki verifier can't check.
+fn unsupported_by_webpki(scheme: SignatureScheme) -> TLSError {
+    TLSError::PeerIncompatibleError(format!("peer signed with {:?}, which webpki cannot verify",
+                                            scheme))
+}
+
+fn convert_webpki_sig_error(scheme: SignatureScheme, err: webpki::Error) -> TLSError {
+    match err {
+        webpki::Error::UnsupportedSignatureAlgorithm if is_pq_sig_scheme(scheme) => {
+            unsupported_by_webpki(scheme)
+        }
+        err => TLSError::WebPKIError(err),
+    }
+}
+
 fn convert_alg_tls13(scheme: SignatureScheme)
                      -> Result<&'static webpki::SignatureAlgorithm, TLSError> {
     use crate::msgs::enums::SignatureScheme::*;
@@ -522,14 +639,19 @@ fn verify_tls13(msg: &[u8],
                 cert: &Certificate,
                 dss: &DigitallySignedStruct)
                 -> Result<HandshakeSignatureValid, TLSError> {
-    let alg = convert_alg_tls13(dss.scheme)?;
-
+    // Other schemes are either unknown or not allowed in TLS1.3.
+    let alg = convert_alg_tls13(dss.scheme)
+        .map_err(|err| if is_pq_sig_scheme(dss.scheme) {
+            unsupported_by_webpki(dss.scheme)
+        } else {
+            err
+        })?;
 
     let cert = webpki::EndEntityCert::from(&cert.0)
         .map_err(TLSError::WebPKIError)?;
 
     cert.verify_signature(alg, &msg, &dss.sig.0)
-        .map_err(TLSError::WebPKIError)
+        .map_err(|err| convert_webpki_sig_error(dss.scheme, err))
         .map(|_| HandshakeSignatureValid::assertion())
 }
 
@@ -576,3 +698,290 @@ pub fn verify_scts(cert: &Certificate,
 
     Ok(())
 }
+
+/// The NIST post-quantum security categories, as claimed by a KEM's
+/// designers.  Level 1 is about as hard to break as AES-128, level 3
+/// as AES-192 and level 5 as AES-256.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum KemSecurityLevel {
+    /// NIST level 1.
+    Level1 = 1,
+    /// NIST level 2.
+    Level2,
+    /// NIST level 3.
+    Level3,
+    /// NIST level 4.
+    Level4,
+    /// NIST level 5.
+    Level5,
+}
+
+impl KemSecurityLevel {
+    fn from_nist_level(level: u8) -> Option<KemSecurityLevel> {
+        match level {
+            1 => Some(KemSecurityLevel::Level1),
+            2 => Some(KemSecurityLevel::Level2),
+            3 => Some(KemSecurityLevel::Level3),
+            4 => Some(KemSecurityLevel::Level4),
+            5 => Some(KemSecurityLevel::Level5),
+            _ => None,
+        }
+    }
+
+    /// The level claimed for KEMTLS scheme `scheme`, if it is one we
+    /// support.
+    pub fn of_kem_scheme(scheme: SignatureScheme) -> Option<KemSecurityLevel> {
+        if !crate::sign::supported_kem_schemes().contains(&scheme) {
+            return None;
+        }
+
+        let oqsalg = include!("generated/kemscheme_to_oqsalg.rs");
+        oqs::init();
+        oqs::kem::Kem::new(oqsalg)
+            .ok()
+            .and_then(|kem| KemSecurityLevel::from_nist_level(kem.claimed_nist_level()))
+    }
+}
+
+/// The DER encoding of the SubjectPublicKeyInfo in `cert`, including
+/// its tag and length.
+fn cert_spki(cert: &Certificate) -> Option<untrusted::Input> {
+    x509::tbs_certificate_field(&cert.0, x509::TbsField::SubjectPublicKeyInfo)
+        .map(untrusted::Input::from)
+}
+
+/// The contents of the AlgorithmIdentifier and the subjectPublicKey
+/// (without the BIT STRING's unused-bits byte) of `cert`'s
+/// SubjectPublicKeyInfo.
+fn cert_spki_fields(cert: &Certificate) -> Option<(untrusted::Input, untrusted::Input)> {
+    use ring::error::Unspecified;
+    use ring::io::der;
+
+    cert_spki(cert)?.read_all(Unspecified, |input| {
+        let spki = der::expect_tag_and_get_value(input, der::Tag::Sequence)?;
+        spki.read_all(Unspecified, |spki| {
+            let alg_id = der::expect_tag_and_get_value(spki, der::Tag::Sequence)?;
+            let key = der::expect_tag_and_get_value(spki, der::Tag::BitString)?;
+            let key = key.read_all(Unspecified, |key| {
+                if key.read_byte()? != 0 {
+                    return Err(Unspecified);
+                }
+                Ok(key.read_bytes_to_end())
+            })?;
+            Ok((alg_id, key))
+        })
+    }).ok()
+}
+
+/// The contents of the AlgorithmIdentifier in the SubjectPublicKeyInfo
+/// of `cert`.
+fn cert_spki_alg_id(cert: &Certificate) -> Option<untrusted::Input> {
+    cert_spki_fields(cert).map(|(alg_id, _)| alg_id)
+}
+
+/// The subjectPublicKey in `cert`'s SubjectPublicKeyInfo, without the
+/// BIT STRING's unused-bits byte.
+fn cert_spki_public_key(cert: &Certificate) -> Option<untrusted::Input> {
+    cert_spki_fields(cert).map(|(_, key)| key)
+}
+
+/// The scheme among `schemes` whose public key algorithm is `alg_id`.
+fn scheme_with_alg_id(schemes: &[SignatureScheme], alg_id: &[u8]) -> Option<SignatureScheme> {
+    schemes.iter()
+        .cloned()
+        .find(|&scheme| {
+            let expected_alg_id: &[u8] = include!("generated/scheme_to_oid.rs");
+            alg_id == expected_alg_id
+        })
+}
+
+/// The KEMTLS scheme of the public key in `cert`, found by matching
+/// its SubjectPublicKeyInfo algorithm against the schemes we support.
+pub fn kem_cert_scheme(cert: &Certificate) -> Option<SignatureScheme> {
+    let alg_id = cert_spki_alg_id(cert)?;
+    scheme_with_alg_id(crate::sign::supported_kem_schemes(), alg_id.as_slice_less_safe())
+}
+
+/// Whether `cert` holds a KEM public key of a scheme we support, of
+/// the length that scheme requires.  Some KEM backends panic when
+/// given a public key of the wrong length, so we check this before
+/// encapsulating to a peer's certificate.
+pub(crate) fn kem_cert_has_valid_key_len(cert: &Certificate) -> bool {
+    match (kem_cert_scheme(cert), cert_spki_public_key(cert)) {
+        (Some(scheme), Some(key)) => kem_key_len_is_valid(scheme, key.len()),
+        _ => false,
+    }
+}
+
+/// Whether `len` is the public key length for KEMTLS `scheme`.
+fn kem_key_len_is_valid(scheme: SignatureScheme, len: usize) -> bool {
+    let oqsalg = include!("generated/kemscheme_to_oqsalg.rs");
+    oqs::init();
+    oqs::kem::Kem::new(oqsalg)
+        .map(|kem| kem.length_public_key() == len)
+        .unwrap_or(false)
+}
+
+/// The algorithm of the public key in `cert`: `KEMTLS` for any KEM
+/// we support, or the signature algorithm the key is used with.
+/// Returns None for keys of any other type.
+pub fn cert_key_algorithm(cert: &Certificate) -> Option<SignatureAlgorithm> {
+    use crate::msgs::handshake::DecomposedSignatureScheme;
+
+    // rsaEncryption, id-ecPublicKey, id-Ed25519 and id-Ed448.  The
+    // AlgorithmIdentifier may carry parameters after the OID.
+    const CLASSICAL_OIDS: &[(&[u8], SignatureAlgorithm)] = &[
+        (&[0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01], SignatureAlgorithm::RSA),
+        (&[0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01], SignatureAlgorithm::ECDSA),
+        (&[0x06, 0x03, 0x2b, 0x65, 0x70], SignatureAlgorithm::ED25519),
+        (&[0x06, 0x03, 0x2b, 0x65, 0x71], SignatureAlgorithm::ED448),
+    ];
+
+    let alg_id = cert_spki_alg_id(cert)?.as_slice_less_safe();
+    if let Some(&(_, alg)) = CLASSICAL_OIDS.iter().find(|(oid, _)| alg_id.starts_with(oid)) {
+        return Some(alg);
+    }
+
+    if scheme_with_alg_id(crate::sign::supported_kem_schemes(), alg_id).is_some() {
+        return Some(SignatureAlgorithm::KEMTLS);
+    }
+
+    let pq_sigschemes: &[SignatureScheme] = include!("generated/pq_sigschemes.rs");
+    scheme_with_alg_id(pq_sigschemes, alg_id)
+        .map(|scheme| scheme.sign())
+}
+
+/// The SubjectPublicKeyInfo of `cert`, if it holds a KEM public key
+/// of a scheme we support.
+pub fn kem_cert_spki(cert: &Certificate) -> Option<Vec<u8>> {
+    kem_cert_scheme(cert)?;
+    cert_spki(cert).map(|spki| spki.as_slice_less_safe().to_vec())
+}
+
+/// The pin for the KEM public key in `cert`, for use in
+/// `ClientConfig::kem_spki_pins`: the SHA-256 hash of its DER
+/// SubjectPublicKeyInfo.  Returns None unless `cert` holds a KEM
+/// public key of a scheme we support.
+pub fn kem_spki_pin(cert: &Certificate) -> Option<[u8; 32]> {
+    let spki = kem_cert_spki(cert)?;
+    let hash = ring::digest::digest(&ring::digest::SHA256, &spki);
+    let mut pin = [0u8; 32];
+    pin.copy_from_slice(hash.as_ref());
+    Some(pin)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn verify_with_scheme(scheme: SignatureScheme) -> Result<HandshakeSignatureValid, TLSError> {
+        let cert = Certificate(include_bytes!("testdata/cert-reddit.0.der").to_vec());
+        let dss = DigitallySignedStruct::new(scheme, vec![0u8; 64]);
+        verify_tls13(b"message", &cert, &dss)
+    }
+
+    #[test]
+    fn pq_scheme_unsupported_by_webpki_is_incompatible() {
+        // What webpki says when it was built without the algorithm.
+        let err = webpki::Error::UnsupportedSignatureAlgorithm;
+        match convert_webpki_sig_error(SignatureScheme::FALCON512, err) {
+            TLSError::PeerIncompatibleError(msg) => assert!(msg.contains("FALCON512")),
+            _ => panic!("expected PeerIncompatibleError"),
+        }
+    }
+
+    #[test]
+    fn scheme_forbidden_in_tls13_is_misbehaviour() {
+        match verify_with_scheme(SignatureScheme::RSA_PKCS1_SHA256) {
+            Err(TLSError::PeerMisbehavedError(_)) => {}
+            _ => panic!("expected PeerMisbehavedError"),
+        }
+        match verify_with_scheme(SignatureScheme::KEMTLS_KYBER512) {
+            Err(TLSError::PeerMisbehavedError(_)) => {}
+            _ => panic!("expected PeerMisbehavedError"),
+        }
+    }
+
+    #[test]
+    fn kem_cert_scheme_finds_kyber512() {
+        let pem = include_bytes!("../../test-ca/kyber/end.cert");
+        let certs = crate::pemfile::certs(&mut &pem[..]).unwrap();
+        assert_eq!(kem_cert_scheme(&certs[0]), Some(SignatureScheme::KEMTLS_KYBER512));
+
+        let signing = Certificate(include_bytes!("testdata/cert-reddit.0.der").to_vec());
+        assert_eq!(kem_cert_scheme(&signing), None);
+        assert_eq!(kem_cert_scheme(&Certificate(vec![0x30, 0x00])), None);
+    }
+
+    #[test]
+    fn cert_key_algorithms() {
+        let kyber_chain = include_bytes!("../../test-ca/kyber/end.fullchain");
+        let kyber_chain = crate::pemfile::certs(&mut &kyber_chain[..]).unwrap();
+        assert_eq!(cert_key_algorithm(&kyber_chain[0]), Some(SignatureAlgorithm::KEMTLS));
+        assert_eq!(cert_key_algorithm(&kyber_chain[1]), Some(SignatureAlgorithm::DILITHIUM2));
+
+        let ecdsa = include_bytes!("../../test-ca/ecdsa/end.cert");
+        let ecdsa = crate::pemfile::certs(&mut &ecdsa[..]).unwrap();
+        assert_eq!(cert_key_algorithm(&ecdsa[0]), Some(SignatureAlgorithm::ECDSA));
+
+        let rsa = Certificate(include_bytes!("testdata/cert-reddit.0.der").to_vec());
+        assert_eq!(cert_key_algorithm(&rsa), Some(SignatureAlgorithm::RSA));
+        assert_eq!(cert_key_algorithm(&Certificate(vec![0x30, 0x00])), None);
+    }
+
+    #[test]
+    fn kem_cert_spki_is_taken_from_cert() {
+        let pem = include_bytes!("../../test-ca/kyber/end.cert");
+        let certs = crate::pemfile::certs(&mut &pem[..]).unwrap();
+        let spki = kem_cert_spki(&certs[0]).unwrap();
+        assert_eq!(spki[0], 0x30);
+        assert!(certs[0].0.windows(spki.len()).any(|w| w == &spki[..]));
+
+        let signing = Certificate(include_bytes!("testdata/cert-reddit.0.der").to_vec());
+        assert_eq!(kem_cert_spki(&signing), None);
+    }
+
+    #[test]
+    fn kem_cert_key_len_is_checked() {
+        let pem = include_bytes!("../../test-ca/kyber/end.cert");
+        let certs = crate::pemfile::certs(&mut &pem[..]).unwrap();
+        assert!(kem_cert_has_valid_key_len(&certs[0]));
+
+        assert_eq!(cert_spki_public_key(&certs[0]).unwrap().len(), 800);
+        assert!(kem_key_len_is_valid(SignatureScheme::KEMTLS_KYBER512, 800));
+        assert!(!kem_key_len_is_valid(SignatureScheme::KEMTLS_KYBER512, 799));
+
+        let signing = Certificate(include_bytes!("testdata/cert-reddit.0.der").to_vec());
+        assert!(!kem_cert_has_valid_key_len(&signing));
+    }
+
+    #[test]
+    fn kem_spki_pin_hashes_spki() {
+        let pem = include_bytes!("../../test-ca/kyber/end.cert");
+        let certs = crate::pemfile::certs(&mut &pem[..]).unwrap();
+        let spki = kem_cert_spki(&certs[0]).unwrap();
+        let pin = kem_spki_pin(&certs[0]).unwrap();
+        assert_eq!(&pin[..], ring::digest::digest(&ring::digest::SHA256, &spki).as_ref());
+
+        let signing = Certificate(include_bytes!("testdata/cert-reddit.0.der").to_vec());
+        assert_eq!(kem_spki_pin(&signing), None);
+    }
+
+    #[test]
+    fn kem_security_levels() {
+        assert_eq!(KemSecurityLevel::of_kem_scheme(SignatureScheme::KEMTLS_KYBER512),
+                   Some(KemSecurityLevel::Level1));
+        assert_eq!(KemSecurityLevel::of_kem_scheme(SignatureScheme::KEMTLS_KYBER768),
+                   Some(KemSecurityLevel::Level3));
+        assert_eq!(KemSecurityLevel::of_kem_scheme(SignatureScheme::ED25519), None);
+        assert!(KemSecurityLevel::Level1 < KemSecurityLevel::Level3);
+    }
+
+    #[test]
+    fn unknown_scheme_is_misbehaviour() {
+        match verify_with_scheme(SignatureScheme::Unknown(0xfefe)) {
+            Err(TLSError::PeerMisbehavedError(_)) => {}
+            _ => panic!("expected PeerMisbehavedError"),
+        }
+    }
+}
@@ -25,6 +25,76 @@ pub fn wrap_in_sequence(bytes: &mut Vec<u8>) {
     bytes.insert(0, der::Tag::Sequence as u8);
 }
 
+/// The fields of a TBSCertificate after the optional version, in
+/// order.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TbsField {
+    SerialNumber,
+    Signature,
+    Issuer,
+    Validity,
+    Subject,
+    SubjectPublicKeyInfo,
+}
+
+const TBS_FIELDS: [TbsField; 6] = [
+    TbsField::SerialNumber,
+    TbsField::Signature,
+    TbsField::Issuer,
+    TbsField::Validity,
+    TbsField::Subject,
+    TbsField::SubjectPublicKeyInfo,
+];
+
+/// Return `field` of the TBSCertificate of the DER-encoded certificate
+/// `cert`, including its tag and length.
+pub fn tbs_certificate_field(cert: &[u8], field: TbsField) -> Option<&[u8]> {
+    let mut rd = untrusted::Reader::new(untrusted::Input::from(cert));
+    let cert = der::expect_tag_and_get_value(&mut rd, der::Tag::Sequence).ok()?;
+
+    let mut rd = untrusted::Reader::new(cert);
+    let tbs = der::expect_tag_and_get_value(&mut rd, der::Tag::Sequence).ok()?;
+
+    let mut rd = untrusted::Reader::new(tbs);
+    if rd.peek(der::Tag::ContextSpecificConstructed0 as u8) {
+        // version
+        der::read_tag_and_get_value(&mut rd).ok()?;
+    }
+
+    for &next in TBS_FIELDS.iter() {
+        let tag = match next {
+            TbsField::SerialNumber => der::Tag::Integer,
+            _ => der::Tag::Sequence,
+        };
+        let (value, _) = rd.read_partial(|r| der::expect_tag_and_get_value(r, tag))
+            .ok()?;
+        if next == field {
+            return Some(value.as_slice_less_safe());
+        }
+    }
+
+    None
+}
+
+/// Return the issuer Name of the DER-encoded certificate `cert`,
+/// including its SEQUENCE header.  This is the same encoding as
+/// the `DistinguishedName`s sent in a CertificateRequest.
+pub fn certificate_issuer(cert: &[u8]) -> Option<&[u8]> {
+    tbs_certificate_field(cert, TbsField::Issuer)
+}
+
+#[test]
+fn test_tbs_certificate_fields() {
+    let cert = include_bytes!("testdata/cert-reddit.0.der");
+    let spki = tbs_certificate_field(cert, TbsField::SubjectPublicKeyInfo).unwrap();
+    assert_eq!(spki[0], der::Tag::Sequence as u8);
+    assert_eq!(certificate_issuer(cert),
+               tbs_certificate_field(cert, TbsField::Issuer));
+    assert_ne!(tbs_certificate_field(cert, TbsField::Issuer),
+               tbs_certificate_field(cert, TbsField::Subject));
+    assert_eq!(tbs_certificate_field(&[0x30, 0x00], TbsField::Issuer), None);
+}
+
 #[test]
 fn test_empty() {
     let mut val = Vec::new();
@@ -5,16 +5,18 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 use std::mem;
 use std::fmt;
 use std::env;
+use std::collections;
 use std::io::{self, Write, Read, IoSlice};
 
 use rustls;
 
 use rustls::{ClientConfig, ClientSession, ResolvesClientCert};
+use rustls::AuthModePreference;
 use rustls::{ServerConfig, ServerSession, ResolvesServerCert};
 use rustls::Session;
 use rustls::{Stream, StreamOwned};
-use rustls::{ProtocolVersion, SignatureScheme, CipherSuite};
-use rustls::TLSError;
+use rustls::{ProtocolVersion, SignatureScheme, CipherSuite, HashAlgorithm};
+use rustls::{TLSError, HandshakeState};
 use rustls::sign;
 use rustls::{ALL_CIPHERSUITES, SupportedCipherSuite};
 use rustls::KeyLog;
@@ -206,6 +208,133 @@ fn buffered_kemtls_client_data_sent() {
 
 }
 
+#[test]
+fn client_reports_peer_signature_scheme() {
+    let server_config = Arc::new(make_server_config(KeyType::Dilithium2));
+    let client_config = Arc::new(make_client_config(KeyType::Dilithium2));
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+
+    assert_eq!(client.peer_signature_scheme(), None);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.peer_signature_scheme(), Some(SignatureScheme::DILITHIUM2));
+
+    // a resumed session has no signature
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.peer_signature_scheme(), None);
+}
+
+#[test]
+fn kemtls_client_reports_no_peer_signature_scheme() {
+    let server_config = Arc::new(make_server_config(KeyType::Kyber512));
+    let client_config = Arc::new(make_client_config(KeyType::Kyber512));
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.peer_signature_scheme(), None);
+}
+
+#[test]
+fn kemtls_auth_kem_is_independent_of_ephemeral_group() {
+    use rustls::NamedGroup;
+
+    // the server's certificate is always Kyber512 (the test CA has no
+    // certificates for other KEMs); steer the ephemeral key exchange
+    // with a kx hint.
+    let server_config = Arc::new(make_server_config(KeyType::Kyber512));
+    for group in &[ NamedGroup::X25519, NamedGroup::Kyber512, NamedGroup::Kyber768,
+                    NamedGroup::Kyber1024 ] {
+        let store = Arc::new(MemorySessionStore::new());
+        let client_config = make_client_config_with_store(KeyType::Kyber512, &store);
+        put_kx_hint(&store, *group);
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        assert_eq!(client.negotiated_kx_group(), None);
+        assert_eq!(client.server_auth_kem(), None);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.negotiated_kx_group(), Some(*group));
+        assert_eq!(client.server_auth_kem(), Some(SignatureScheme::KEMTLS_KYBER512));
+    }
+}
+
+#[test]
+fn client_checks_key_algorithms_across_chain() {
+    use rustls::CertChainKeyAlgorithms;
+    use rustls::internal::msgs::enums::SignatureAlgorithm;
+
+    // the kyber chain is a KEM end-entity certificate, under Dilithium2 CAs
+    let server_config = Arc::new(make_server_config(KeyType::Kyber512));
+    let policy = |end_entity, intermediates| {
+        let mut client_config = make_client_config(KeyType::Kyber512);
+        client_config.cert_chain_key_algorithms = Some(CertChainKeyAlgorithms {
+            end_entity: vec![ end_entity ],
+            intermediates: vec![ intermediates ],
+        });
+        Arc::new(client_config)
+    };
+
+    let client_config = policy(SignatureAlgorithm::KEMTLS, SignatureAlgorithm::DILITHIUM2);
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let client_config = policy(SignatureAlgorithm::KEMTLS, SignatureAlgorithm::RSA);
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert_eq!(do_handshake_until_error(&mut client, &mut server),
+               Err(TLSErrorFromPeer::Client(TLSError::PeerIncompatibleError(
+                   "server certificate 1 has DILITHIUM2 key, which is not allowed".to_string()))));
+
+    let client_config = policy(SignatureAlgorithm::DILITHIUM2, SignatureAlgorithm::DILITHIUM2);
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert_eq!(do_handshake_until_error(&mut client, &mut server),
+               Err(TLSErrorFromPeer::Client(TLSError::PeerIncompatibleError(
+                   "server certificate 0 has KEMTLS key, which is not allowed".to_string()))));
+}
+
+#[test]
+fn kemtls_protocol_version() {
+    let server_config = Arc::new(make_server_config(KeyType::Kyber512));
+    let client_config = Arc::new(make_client_config(KeyType::Kyber512));
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+
+    assert_eq!(client.get_protocol_version(), None);
+    assert_eq!(server.get_protocol_version(), None);
+
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.get_protocol_version(), Some(ProtocolVersion::TLSv1_3));
+    assert_eq!(server.get_protocol_version(), Some(ProtocolVersion::TLSv1_3));
+}
+
+#[test]
+fn kemtls_client_caches_server_kem_pubkey() {
+    let store = Arc::new(MemorySessionStore::new());
+    let client_config = make_client_config_with_store(KeyType::Kyber512, &store);
+    let server_config = Arc::new(make_server_config(KeyType::Kyber512));
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+
+    assert!(!client.cache_server_kem_pubkey());
+    do_handshake(&mut client, &mut server);
+    assert!(client_config.cached_server_kem_pubkey("localhost").is_none());
+
+    assert!(client.cache_server_kem_pubkey());
+    let spki = client_config.cached_server_kem_pubkey("localhost").unwrap();
+    let cert = &client.get_peer_certificates().unwrap()[0];
+    assert!(cert.0.windows(spki.len()).any(|w| w == &spki[..]));
+    assert_eq!(store.get_kind(b"kem-pubkey"), Some(spki));
+
+    assert!(client_config.cached_server_kem_pubkey("otherhost").is_none());
+}
+
+#[test]
+fn signing_server_has_no_kem_pubkey_to_cache() {
+    let store = Arc::new(MemorySessionStore::new());
+    let client_config = make_client_config_with_store(KeyType::RSA, &store);
+    let server_config = Arc::new(make_server_config(KeyType::RSA));
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    assert!(!client.cache_server_kem_pubkey());
+    assert!(client_config.cached_server_kem_pubkey("localhost").is_none());
+}
+
 #[test]
 fn buffered_kemtls_clientauth_client_data_sent() {
     let server_config = Arc::new(make_server_config_with_mandatory_client_auth(KeyType::Kyber512));
@@ -224,6 +353,379 @@ fn buffered_kemtls_clientauth_client_data_sent() {
 
 }
 
+#[test]
+fn kemtls_clientauth_with_kem_resolver() {
+    let server_config = Arc::new(make_server_config_with_mandatory_client_auth(KeyType::Kyber512));
+    let client_config = Arc::new(make_client_config_with_kem_auth(KeyType::Kyber512));
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+
+    do_handshake(&mut client, &mut server);
+    assert!(server.get_peer_certificates().is_some());
+}
+
+fn do_post_handshake_auth(kt: KeyType, client_config: ClientConfig) {
+    let mut server_config = make_server_config_with_mandatory_client_auth(kt);
+    server_config.defer_client_auth = true;
+    let mut client_config = client_config;
+    client_config.enable_post_handshake_auth = true;
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+    assert!(server.get_peer_certificates().is_none());
+
+    server.request_client_auth().unwrap();
+    assert!(server.request_client_auth().is_err());
+
+    while server.wants_write() || client.wants_write() {
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+    }
+
+    assert_eq!(server.get_peer_certificates(), Some(kt.get_client_chain()));
+}
+
+#[test]
+fn server_can_request_signed_client_auth_after_handshake() {
+    do_post_handshake_auth(KeyType::RSA, make_client_config_with_auth(KeyType::RSA));
+}
+
+#[test]
+fn server_can_request_kem_client_auth_after_handshake() {
+    do_post_handshake_auth(KeyType::Kyber512, make_client_config_with_kem_auth(KeyType::Kyber512));
+}
+
+#[test]
+fn server_cannot_request_client_auth_unless_client_offered_it() {
+    let kt = KeyType::RSA;
+    let mut server_config = make_server_config_with_mandatory_client_auth(kt);
+    server_config.defer_client_auth = true;
+    let client_config = make_client_config_with_auth(kt);
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    assert_eq!(server.request_client_auth(), Err(TLSError::HandshakeNotComplete));
+    do_handshake(&mut client, &mut server);
+
+    // Without the client's offer, authentication happened in the handshake.
+    assert!(server.get_peer_certificates().is_some());
+    assert!(server.request_client_auth().is_err());
+}
+
+#[test]
+fn kem_cert_and_key_load_from_concatenated_der_and_pem() {
+    use rustls::internal::pemfile;
+
+    let kt = KeyType::Kyber512;
+    let mut der = Vec::new();
+    for cert in kt.get_client_chain() {
+        der.extend_from_slice(&cert.0);
+    }
+    let chain = pemfile::der_certs(&mut &der[..]).unwrap();
+    assert_eq!(chain, kt.get_client_chain());
+
+    let keys = pemfile::kem_private_keys(
+        &mut io::BufReader::new(kt.bytes_for("client.key"))).unwrap();
+    assert!(keys == vec![ kt.get_client_key() ]);
+
+    let mut client_config = make_client_config(kt);
+    client_config.set_single_client_kem_cert(chain, keys[0].clone()).unwrap();
+    let server_config = make_server_config_with_mandatory_client_auth(kt);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.get_peer_certificates(), Some(kt.get_client_chain()));
+}
+
+#[test]
+fn kem_private_keys_skips_signing_keys() {
+    use rustls::internal::pemfile;
+
+    let keys = pemfile::kem_private_keys(
+        &mut io::BufReader::new(KeyType::ECDSA.bytes_for("end.key"))).unwrap();
+    assert!(keys.is_empty());
+}
+
+#[test]
+fn der_certs_rejects_truncated_input() {
+    use rustls::internal::pemfile;
+
+    let cert = KeyType::Kyber512.get_chain().remove(0);
+    assert!(pemfile::der_certs(&mut &cert.0[..cert.0.len() - 1]).is_err());
+    assert!(pemfile::der_certs(&mut &[0x30u8, 0x85, 0, 0, 0, 0, 0][..]).is_err());
+    assert!(pemfile::der_certs(&mut &[0u8; 0][..]).unwrap().is_empty());
+}
+
+#[test]
+fn kem_resolver_rejects_signing_key() {
+    let mut client_config = make_client_config(KeyType::RSA);
+    let kt = KeyType::RSA;
+    let err = client_config.set_single_client_kem_cert(kt.get_chain(), kt.get_key());
+    assert_eq!(err, Err(TLSError::General("invalid KEM private key".into())));
+}
+
+#[test]
+fn kemtls_server_rejects_signing_client_cert() {
+    // This must fail cleanly in release builds too, rather than
+    // trying to encapsulate to a signature public key.
+    let server_config = make_server_config_with_mandatory_client_auth(KeyType::Kyber512);
+    let mut client_config = make_client_config(KeyType::Kyber512);
+    client_config.set_single_client_cert(KeyType::RSA.get_client_chain(),
+                                         KeyType::RSA.get_client_key())
+        .unwrap();
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    let errs = do_handshake_until_both_error(&mut client, &mut server);
+    assert_eq!(errs,
+               Err(vec![
+                   TLSErrorFromPeer::Server(TLSError::PeerMisbehavedError(
+                       "client sent non-KEM certificate in KEMTLS handshake".into())),
+                   TLSErrorFromPeer::Client(TLSError::AlertReceived(
+                       rustls::AlertDescription::BadCertificate)),
+               ]));
+}
+
+#[test]
+fn kemtls_clientauth_selects_kem_cert_by_issuer() {
+    let kt = KeyType::Kyber512;
+    let server_config = Arc::new(make_server_config_with_mandatory_client_auth(kt));
+
+    // the first certificate is issued by a CA the server doesn't know
+    let mut resolver = rustls::ResolvesClientKemCertUsingIssuers::new();
+    resolver.add(KeyType::RSA.get_client_chain(), &kt.get_client_key()).unwrap();
+    resolver.add(kt.get_client_chain(), &kt.get_client_key()).unwrap();
+
+    let mut client_config = make_client_config(kt);
+    client_config.client_auth_cert_resolver = Arc::new(resolver);
+    let (mut client, mut server) = make_pair_for_arc_configs(&Arc::new(client_config),
+                                                             &server_config);
+
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.get_peer_certificates().unwrap()[0],
+               kt.get_client_chain()[0]);
+}
+
+#[test]
+fn kemtls_clientauth_sends_no_cert_without_matching_issuer() {
+    let kt = KeyType::Kyber512;
+    let server_config = Arc::new(make_server_config_with_mandatory_client_auth(kt));
+
+    let mut resolver = rustls::ResolvesClientKemCertUsingIssuers::new();
+    resolver.add(KeyType::RSA.get_client_chain(), &kt.get_client_key()).unwrap();
+
+    let mut client_config = make_client_config(kt);
+    client_config.client_auth_cert_resolver = Arc::new(resolver);
+    let (mut client, mut server) = make_pair_for_arc_configs(&Arc::new(client_config),
+                                                             &server_config);
+
+    let err = do_handshake_until_error(&mut client, &mut server);
+    assert_eq!(err,
+               Err(TLSErrorFromPeer::Server(TLSError::NoCertificatesPresented)));
+}
+
+#[test]
+fn client_work_budget_aborts_expensive_handshake() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut client_config = make_client_config(*kt);
+        client_config.max_work_units = Some(3);
+        let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                             make_server_config(*kt));
+
+        // key exchange + three certificates + one signature
+        let err = do_handshake_until_error(&mut client, &mut server);
+        assert_eq!(err,
+                   Err(TLSErrorFromPeer::Client(TLSError::PeerMisbehavedError(
+                       "handshake work budget exceeded".into()))));
+    }
+
+    // the server is told why
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.max_work_units = Some(3);
+    let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                         make_server_config(KeyType::RSA));
+    let errs = do_handshake_until_both_error(&mut client, &mut server);
+    assert_eq!(errs,
+               Err(vec![
+                   TLSErrorFromPeer::Client(TLSError::PeerMisbehavedError(
+                       "handshake work budget exceeded".into())),
+                   TLSErrorFromPeer::Server(TLSError::AlertReceived(
+                       rustls::AlertDescription::HandshakeFailure)),
+               ]));
+}
+
+#[test]
+fn client_work_budget_allows_normal_handshake() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut client_config = make_client_config(*kt);
+        client_config.max_work_units = Some(5);
+        let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                             make_server_config(*kt));
+        do_handshake(&mut client, &mut server);
+    }
+}
+
+#[test]
+fn client_rejects_oversized_pq_signature() {
+    let mut client_config = make_client_config(KeyType::Dilithium2);
+    client_config.max_signature_size = Some(1024);
+    let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                         make_server_config(KeyType::Dilithium2));
+
+    let err = do_handshake_until_error(&mut client, &mut server);
+    match err {
+        Err(TLSErrorFromPeer::Client(TLSError::PeerIncompatibleError(msg))) => {
+            assert!(msg.starts_with("server signature of "));
+            assert!(msg.ends_with(" exceeds limit of 1024 bytes"));
+        }
+        _ => panic!("unexpected result {:?}", err),
+    }
+}
+
+#[test]
+fn client_accepts_signature_within_limit() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut client_config = make_client_config(*kt);
+        client_config.max_signature_size = Some(1024);
+        let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                             make_server_config(*kt));
+        do_handshake(&mut client, &mut server);
+    }
+}
+
+fn sent_record_types(sess: &mut dyn Session) -> Vec<rustls::internal::msgs::enums::ContentType> {
+    use rustls::internal::msgs::codec::{Codec, Reader};
+    use rustls::internal::msgs::message::Message;
+
+    let mut buf = Vec::new();
+    while sess.wants_write() {
+        sess.write_tls(&mut buf).unwrap();
+    }
+
+    let mut rd = Reader::init(&buf);
+    let mut types = Vec::new();
+    while rd.any_left() {
+        types.push(Message::read(&mut rd).unwrap().typ);
+    }
+    types
+}
+
+#[test]
+fn client_final_flight_order() {
+    use rustls::internal::msgs::enums::ContentType::*;
+    use rustls::CompatCCSPlacement;
+
+    let kt = KeyType::RSA;
+    let server_config = Arc::new(make_server_config_with_mandatory_client_auth(kt));
+
+    for placement in &[CompatCCSPlacement::BeforeSecondFlight, CompatCCSPlacement::AfterClientHello] {
+        let mut client_config = make_client_config_with_auth(kt);
+        client_config.versions = vec![ ProtocolVersion::TLSv1_3 ];
+        client_config.compat_ccs_placement = *placement;
+        let (mut client, mut server) = make_pair_for_arc_configs(&Arc::new(client_config),
+                                                                 &server_config);
+
+        let first_flight = sent_record_types(&mut client);
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+        let final_flight = sent_record_types(&mut client);
+
+        // Certificate, CertificateVerify, Finished
+        match placement {
+            CompatCCSPlacement::BeforeSecondFlight => {
+                assert_eq!(first_flight, vec![ Handshake ]);
+                assert_eq!(final_flight, vec![ ChangeCipherSpec, ApplicationData,
+                                               ApplicationData, ApplicationData ]);
+            }
+            CompatCCSPlacement::AfterClientHello => {
+                assert_eq!(first_flight, vec![ Handshake, ChangeCipherSpec ]);
+                assert_eq!(final_flight, vec![ ApplicationData, ApplicationData,
+                                               ApplicationData ]);
+            }
+        }
+    }
+}
+
+#[test]
+fn client_requiring_signed_auth_rejects_kemtls_server() {
+    let mut client_config = make_client_config(KeyType::Kyber512);
+    client_config.auth_mode = AuthModePreference::RequireSigned;
+    let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                         make_server_config(KeyType::Kyber512));
+
+    let err = do_handshake_until_error(&mut client, &mut server);
+    assert_eq!(err,
+               Err(TLSErrorFromPeer::Client(TLSError::PeerIncompatibleError(
+                   "server sent KEM certificate but signature authentication is required".into()))));
+}
+
+#[test]
+fn client_requiring_signed_auth_accepts_signing_server() {
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.auth_mode = AuthModePreference::RequireSigned;
+    let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                         make_server_config(KeyType::RSA));
+    do_handshake(&mut client, &mut server);
+}
+
+#[test]
+fn client_requiring_kem_auth_rejects_signing_server() {
+    for client_config in AllClientVersions::new(make_client_config(KeyType::RSA)) {
+        let mut client_config = client_config;
+        client_config.auth_mode = AuthModePreference::RequireKem;
+        let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                             make_server_config(KeyType::RSA));
+
+        let err = do_handshake_until_error(&mut client, &mut server);
+        assert_eq!(err,
+                   Err(TLSErrorFromPeer::Client(TLSError::PeerIncompatibleError(
+                       "server sent signing certificate but KEMTLS authentication is required".into()))));
+    }
+}
+
+#[test]
+fn client_requiring_kem_auth_accepts_kemtls_server() {
+    let mut client_config = make_client_config(KeyType::Kyber512);
+    client_config.auth_mode = AuthModePreference::RequireKem;
+    let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                         make_server_config(KeyType::Kyber512));
+    do_handshake(&mut client, &mut server);
+}
+
+#[test]
+fn client_preferring_either_auth_mode_accepts_both() {
+    for mode in &[AuthModePreference::PreferKem, AuthModePreference::PreferSigned] {
+        for kt in &[KeyType::RSA, KeyType::Kyber512] {
+            let mut client_config = make_client_config(*kt);
+            client_config.auth_mode = *mode;
+            let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                                 make_server_config(*kt));
+            do_handshake(&mut client, &mut server);
+        }
+    }
+}
+
+#[test]
+fn client_lists_preferred_auth_mode_schemes_first() {
+    let kem = SignatureScheme::KEMTLS_KYBER512;
+    let signed = SignatureScheme::RSA_PSS_SHA256;
+
+    for mode in &[AuthModePreference::PreferKem, AuthModePreference::PreferSigned,
+                  AuthModePreference::RequireKem, AuthModePreference::RequireSigned] {
+        let mut client_config = make_client_config(KeyType::RSA);
+        client_config.set_signature_schemes(&[signed, kem]).unwrap();
+        client_config.auth_mode = *mode;
+
+        let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+        let hello = client_hello_from(&mut client);
+        let expected = match mode {
+            AuthModePreference::PreferKem | AuthModePreference::RequireKem => vec![kem, signed],
+            _ => vec![signed, kem],
+        };
+        assert_eq!(hello.get_sigalgs_extension(), Some(&expected));
+    }
+}
+
 #[test]
 fn buffered_server_data_sent() {
     let server_config = Arc::new(make_server_config(KeyType::RSA));
@@ -610,6 +1112,20 @@ fn client_checks_server_certificate_with_given_name() {
     }
 }
 
+#[test]
+fn kemtls_client_checks_server_certificate_with_given_name() {
+    let client_config = Arc::new(make_client_config(KeyType::Kyber512));
+    let server_config = Arc::new(make_server_config(KeyType::Kyber512));
+
+    let mut client = ClientSession::new(&client_config, dns_name("not-the-right-hostname.com"));
+    let mut server = ServerSession::new(&server_config);
+
+    let err = do_handshake_until_error(&mut client, &mut server);
+    assert_eq!(err,
+               Err(TLSErrorFromPeer::Client(
+                       TLSError::WebPKIError(webpki::Error::CertNotValidForName))));
+}
+
 struct ClientCheckCertResolve {
     query_count: AtomicUsize,
     expect_queries: usize
@@ -934,13 +1450,57 @@ fn server_error_is_sticky() {
 }
 
 #[test]
-fn server_is_send_and_sync() {
-    let (_, server) = make_pair(KeyType::RSA);
-    &server as &dyn Send;
-    &server as &dyn Sync;
-}
+fn client_abort_with_alert_mid_handshake() {
+    use rustls::AlertDescription;
 
-#[test]
+    let (mut client, mut server) = make_pair(KeyType::RSA);
+    transfer(&mut client, &mut server);
+
+    client.abort_with_alert(AlertDescription::AccessDenied);
+
+    let mut buf = Vec::new();
+    client.write_tls(&mut buf).unwrap();
+    assert_eq!(buf, vec![ 0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x31 ]);
+
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::AbortedWithAlert(AlertDescription::AccessDenied)));
+    assert_eq!(client.write(b"hello").unwrap_err().kind(),
+               io::ErrorKind::ConnectionAborted);
+    assert_eq!(client.read(&mut [0u8; 5]).unwrap_err().kind(),
+               io::ErrorKind::ConnectionAborted);
+
+    server.process_new_packets().unwrap();
+    server.read_tls(&mut &buf[..]).unwrap();
+    assert_eq!(server.process_new_packets(),
+               Err(TLSError::AlertReceived(AlertDescription::AccessDenied)));
+}
+
+#[test]
+fn server_abort_with_alert_after_handshake() {
+    use rustls::AlertDescription;
+
+    let (mut client, mut server) = make_pair(KeyType::RSA);
+    do_handshake(&mut client, &mut server);
+
+    server.abort_with_alert(AlertDescription::HandshakeFailure);
+    // a second abort is ignored
+    server.abort_with_alert(AlertDescription::AccessDenied);
+    assert_eq!(server.process_new_packets(),
+               Err(TLSError::AbortedWithAlert(AlertDescription::HandshakeFailure)));
+
+    transfer(&mut server, &mut client);
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::AlertReceived(AlertDescription::HandshakeFailure)));
+}
+
+#[test]
+fn server_is_send_and_sync() {
+    let (_, server) = make_pair(KeyType::RSA);
+    &server as &dyn Send;
+    &server as &dyn Sync;
+}
+
+#[test]
 fn client_is_send_and_sync() {
     let (client, _) = make_pair(KeyType::RSA);
     &client as &dyn Send;
@@ -1046,6 +1606,58 @@ fn client_respects_buffer_limit_post_handshake() {
     check_read(&mut server, b"01234567890123456789012345");
 }
 
+#[test]
+fn client_received_plaintext_stays_bounded_for_slow_reader() {
+    let (mut client, mut server) = make_pair(KeyType::RSA);
+    do_handshake(&mut client, &mut server);
+
+    let limit = 32 * 1024;
+    client.set_received_plaintext_limit(Some(limit));
+
+    let data = vec![0x5au8; 1024 * 1024];
+    server.write_all(&data).unwrap();
+    let mut tls = Vec::new();
+    while server.wants_write() {
+        server.write_tls(&mut tls).unwrap();
+    }
+
+    // A reader which ignores wants_read, and consumes 1000 bytes at a time.
+    let mut offs = 0;
+    let mut consumed = 0;
+    let mut buf = [0u8; 1000];
+    while consumed < data.len() {
+        if let Ok(sz) = client.read_tls(&mut &tls[offs..]) {
+            offs += sz;
+        }
+        client.process_new_packets().unwrap();
+        consumed += client.read(&mut buf).unwrap();
+
+        // Everything taken from the wire but not yet read is at most
+        // the limit, one more record, and the deframer's buffer.
+        assert!(offs - consumed <= limit + 2 * 18 * 1024);
+    }
+    assert_eq!(offs, tls.len());
+}
+
+#[test]
+fn client_paused_reading_processes_nothing() {
+    let (mut client, mut server) = make_pair(KeyType::RSA);
+    do_handshake(&mut client, &mut server);
+
+    client.set_read_paused(true);
+    server.write_all(b"hello").unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    assert!(!client.wants_read());
+    let mut buf = [0u8; 5];
+    assert_eq!(client.read(&mut buf).unwrap(), 0);
+
+    client.set_read_paused(false);
+    client.process_new_packets().unwrap();
+    check_read(&mut client, b"hello");
+}
+
 struct OtherSession<'a> {
     sess: &'a mut dyn Session,
     pub reads: usize,
@@ -1696,6 +2308,62 @@ fn test_tls13_exporter() {
     }
 }
 
+#[test]
+fn unique_channel_binding_matches_and_differs_per_handshake() {
+    for kt in &[ KeyType::RSA, KeyType::Kyber512 ] {
+        let client_config = Arc::new(make_client_config(*kt));
+        let server_config = Arc::new(make_server_config(*kt));
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        assert_eq!(client.unique_channel_binding(), None);
+        assert_eq!(server.unique_channel_binding(), None);
+        do_handshake(&mut client, &mut server);
+
+        let first = client.unique_channel_binding().unwrap();
+        assert_eq!(server.unique_channel_binding(), Some(first));
+
+        // it isn't what the exporter gives for some other label
+        let mut exported = [0u8; 32];
+        client.export_keying_material(&mut exported, b"EXPORTER-other", None).unwrap();
+        assert_ne!(exported, first);
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        let second = client.unique_channel_binding().unwrap();
+        assert_eq!(server.unique_channel_binding(), Some(second));
+        assert_ne!(first, second);
+    }
+}
+
+#[test]
+fn unique_channel_binding_unavailable_for_tls12() {
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.versions = vec![ ProtocolVersion::TLSv1_2 ];
+    let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                         make_server_config(KeyType::RSA));
+    do_handshake(&mut client, &mut server);
+
+    assert_eq!(client.unique_channel_binding(), None);
+    assert_eq!(server.unique_channel_binding(), None);
+}
+
+#[test]
+fn client_transcript_hash_available_after_handshake() {
+    for kt in &[KeyType::RSA, KeyType::Kyber512] {
+        let mut client_config = make_client_config(*kt);
+        client_config.versions = vec![ ProtocolVersion::TLSv1_3 ];
+        let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                             make_server_config(*kt));
+
+        assert_eq!(None, client.transcript_hash());
+        do_handshake(&mut client, &mut server);
+
+        let hash = client.transcript_hash().unwrap();
+        let suite = client.get_negotiated_ciphersuite().unwrap();
+        assert_eq!(hash.len(), suite.get_hash().output_len);
+    }
+}
+
 fn do_suite_test(client_config: ClientConfig,
                  server_config: ServerConfig,
                  expect_suite: &'static SupportedCipherSuite,
@@ -1705,6 +2373,8 @@ fn do_suite_test(client_config: ClientConfig,
 
     assert_eq!(None, client.get_negotiated_ciphersuite());
     assert_eq!(None, server.get_negotiated_ciphersuite());
+    assert_eq!(None, client.negotiated_suite_hash());
+    assert_eq!(None, server.negotiated_suite_hash());
     assert_eq!(None, client.get_protocol_version());
     assert_eq!(None, server.get_protocol_version());
     assert_eq!(true, client.is_handshaking());
@@ -1719,12 +2389,15 @@ fn do_suite_test(client_config: ClientConfig,
     assert_eq!(Some(expect_version), server.get_protocol_version());
     assert_eq!(None, client.get_negotiated_ciphersuite());
     assert_eq!(Some(expect_suite), server.get_negotiated_ciphersuite());
+    assert_eq!(None, client.negotiated_suite_hash());
+    assert_eq!(Some(expect_suite.hash), server.negotiated_suite_hash());
 
     transfer(&mut server, &mut client);
     client.process_new_packets().unwrap();
 
     assert_eq!(Some(expect_suite), client.get_negotiated_ciphersuite());
     assert_eq!(Some(expect_suite), server.get_negotiated_ciphersuite());
+    assert_eq!(Some(expect_suite.hash), client.negotiated_suite_hash());
 
     transfer(&mut client, &mut server);
     server.process_new_packets().unwrap();
@@ -1808,6 +2481,43 @@ fn negotiated_ciphersuite_server() {
     }
 }
 
+#[test]
+fn restrict_suite_hash_selects_sha384_suite() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut client_config = make_client_config(*kt);
+        client_config.restrict_suite_hash(HashAlgorithm::SHA384).unwrap();
+        client_config.versions = vec![ProtocolVersion::TLSv1_3];
+        assert!(client_config.ciphersuites.iter().all(|cs| cs.hash == HashAlgorithm::SHA384));
+
+        do_suite_test(client_config,
+                      make_server_config(*kt),
+                      find_suite(CipherSuite::TLS13_AES_256_GCM_SHA384),
+                      ProtocolVersion::TLSv1_3);
+
+        let mut server_config = make_server_config(*kt);
+        server_config.restrict_suite_hash(HashAlgorithm::SHA384).unwrap();
+        server_config.versions = vec![ProtocolVersion::TLSv1_3];
+
+        do_suite_test(make_client_config(*kt),
+                      server_config,
+                      find_suite(CipherSuite::TLS13_AES_256_GCM_SHA384),
+                      ProtocolVersion::TLSv1_3);
+    }
+}
+
+#[test]
+fn restrict_suite_hash_refuses_unused_hash() {
+    let mut client_config = make_client_config(KeyType::RSA);
+    let suites = client_config.ciphersuites.clone();
+    assert!(client_config.restrict_suite_hash(HashAlgorithm::SHA1).is_err());
+    assert_eq!(client_config.ciphersuites, suites);
+
+    let mut server_config = make_server_config(KeyType::RSA);
+    let suites = server_config.ciphersuites.clone();
+    assert!(server_config.restrict_suite_hash(HashAlgorithm::SHA1).is_err());
+    assert_eq!(server_config.ciphersuites, suites);
+}
+
 #[derive(Debug, PartialEq)]
 struct KeyLogItem {
     label: String,
@@ -1944,6 +2654,53 @@ fn key_log_for_tls13() {
     assert_eq!(client_resume_log[4], server_resume_log[4]);
 }
 
+#[test]
+fn key_log_for_kemtls() {
+    let client_key_log = Arc::new(KeyLogToVec::new("client"));
+    let server_key_log = Arc::new(KeyLogToVec::new("server"));
+
+    let kt = KeyType::Kyber512;
+    let mut client_config = make_client_config(kt);
+    client_config.key_log = client_key_log.clone();
+    let mut server_config = make_server_config(kt);
+    server_config.key_log = server_key_log.clone();
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+
+    let client_log = client_key_log.take();
+    let server_log = server_key_log.take();
+
+    let mut labels: Vec<&str> = client_log.iter()
+        .map(|item| item.label.as_str())
+        .collect();
+    labels.sort();
+    assert_eq!(labels,
+               vec![ "CLIENT_AUTHENTICATED_HANDSHAKE_TRAFFIC_SECRET",
+                     "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
+                     "CLIENT_TRAFFIC_SECRET_0",
+                     "EXPORTER_SECRET",
+                     "SERVER_AUTHENTICATED_HANDSHAKE_TRAFFIC_SECRET",
+                     "SERVER_HANDSHAKE_TRAFFIC_SECRET",
+                     "SERVER_TRAFFIC_SECRET_0" ]);
+
+    // The authenticated handshake secrets are new secrets, not the
+    // (unauthenticated) handshake secrets logged again.
+    let secret = |label: &str| client_log.iter()
+        .find(|item| item.label == label)
+        .map(|item| item.secret.clone())
+        .unwrap();
+    assert_ne!(secret("CLIENT_AUTHENTICATED_HANDSHAKE_TRAFFIC_SECRET"),
+               secret("CLIENT_HANDSHAKE_TRAFFIC_SECRET"));
+    assert_ne!(secret("SERVER_AUTHENTICATED_HANDSHAKE_TRAFFIC_SECRET"),
+               secret("SERVER_HANDSHAKE_TRAFFIC_SECRET"));
+
+    assert_eq!(client_log.len(), server_log.len());
+    for item in &client_log {
+        assert!(server_log.contains(item));
+    }
+}
+
 #[test]
 fn vectored_write_for_server_appdata() {
     let (mut client, mut server) = make_pair(KeyType::RSA);
@@ -2129,12 +2886,16 @@ fn tls13_stateful_resumption() {
     assert_eq!(storage.gets(), 0);
     assert_eq!(storage.takes(), 0);
     assert_eq!(client.get_peer_certificates().map(|certs| certs.len()), Some(3));
+    assert!(!client.did_resume());
+    assert_eq!(client.selected_psk_index(), None);
 
     // resumed
     let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
     let (resume_c2s, resume_s2c) = do_handshake(&mut client, &mut server);
     assert!(resume_c2s > full_c2s);
     assert!(resume_s2c < full_s2c);
+    assert!(client.did_resume());
+    assert_eq!(client.selected_psk_index(), Some(0));
     assert_eq!(storage.puts(), 2);
     assert_eq!(storage.gets(), 0);
     assert_eq!(storage.takes(), 1);
@@ -2193,373 +2954,2814 @@ fn tls13_stateless_resumption() {
     assert_eq!(client.get_peer_certificates().map(|certs| certs.len()), Some(3));
 }
 
-#[cfg(feature = "quic")]
-mod test_quic {
-    use super::*;
+fn client_hello_from(client: &mut ClientSession) -> rustls::internal::msgs::handshake::ClientHelloPayload {
+    use rustls::internal::msgs::codec::{Codec, Reader};
+    use rustls::internal::msgs::message::{Message, MessagePayload};
+    use rustls::internal::msgs::handshake::HandshakePayload;
 
-    // Returns the sender's next secrets to use, or the receiver's error.
-    fn step(send: &mut dyn Session, recv: &mut dyn Session) -> Result<Option<quic::Keys>, TLSError> {
-        let mut buf = Vec::new();
-        let secrets = loop {
-            let prev = buf.len();
-            if let Some(x) = send.write_hs(&mut buf) {
-                break Some(x);
-            }
-            if prev == buf.len() {
-                break None;
+    let mut buf = Vec::new();
+    while client.wants_write() {
+        client.write_tls(&mut buf).unwrap();
+    }
+
+    // skip any middlebox compatibility CCS
+    let mut rd = Reader::init(&buf);
+    while rd.any_left() {
+        let mut msg = Message::read(&mut rd)
+            .unwrap();
+        if !msg.decode_payload() {
+            continue;
+        }
+
+        if let MessagePayload::Handshake(hs) = msg.payload {
+            match hs.payload {
+                HandshakePayload::ClientHello(ch) => return ch,
+                _ => panic!("not a ClientHello"),
             }
-        };
-        if let Err(e) = recv.read_hs(&buf) {
-            return Err(e);
-        } else {
-            assert_eq!(recv.get_alert(), None);
         }
-        Ok(secrets)
     }
 
+    panic!("no ClientHello sent");
+}
+
 #[test]
-    fn test_quic_handshake() {
-        fn equal_dir_keys(x: &quic::DirectionalKeys, y: &quic::DirectionalKeys) -> bool {
-            // Check that these two sets of keys are equal. The quic module's unit tests validate
-            // that the IV and the keys are consistent, so we can just check the IV here.
-            x.packet.iv.nonce_for(42).as_ref() == y.packet.iv.nonce_for(42).as_ref()
-        }
-        fn compatible_keys(x: &quic::Keys, y: &quic::Keys) -> bool {
-            equal_dir_keys(&x.local, &y.remote) && equal_dir_keys(&x.remote, &y.local)
-        }
+fn client_named_groups_extension_is_stable() {
+    use rustls::internal::msgs::codec::Codec;
+    use rustls::internal::msgs::enums::ExtensionType;
+
+    let mut client = ClientSession::new(&Arc::new(make_client_config(KeyType::RSA)),
+                                        dns_name("localhost"));
+    let hello = client_hello_from(&mut client);
+    let ext = hello.find_extension(ExtensionType::EllipticCurves)
+        .unwrap();
 
-        let kt = KeyType::RSA;
-        let mut client_config = make_client_config(kt);
-        client_config.versions = vec![ProtocolVersion::TLSv1_3];
-        client_config.enable_early_data = true;
-        let client_config = Arc::new(client_config);
-        let mut server_config = make_server_config(kt);
-        server_config.versions = vec![ProtocolVersion::TLSv1_3];
-        server_config.max_early_data_size = 0xffffffff;
-        server_config.alpn_protocols = vec!["foo".into()];
-        let server_config = Arc::new(server_config);
-        let client_params = &b"client params"[..];
-        let server_params = &b"server params"[..];
+    // Every supported group but the Classic McEliece ones, whose public
+    // keys don't fit in a key share.
+    let expected: &[u8] = &[
+        0x00, 0x0a, 0x00, 0x50, 0x00, 0x4e, 0x01, 0xfc, 0x01, 0xfd, 0x01, 0xfe,
+        0x02, 0x09, 0x02, 0x0a, 0x02, 0x0b, 0x02, 0x0c, 0x02, 0x0d, 0x02, 0x0e,
+        0x02, 0x0f, 0x02, 0x10, 0x02, 0x11, 0x02, 0x12, 0x02, 0x13, 0x02, 0x14,
+        0x02, 0x15, 0x02, 0x16, 0x02, 0x17, 0x02, 0x18, 0x02, 0x19, 0x02, 0x1a,
+        0x02, 0x1b, 0x02, 0x1c, 0x02, 0x1d, 0x02, 0x1e, 0x02, 0x1f, 0x02, 0x20,
+        0x02, 0x21, 0x02, 0x22, 0x02, 0x23, 0x02, 0x24, 0x02, 0x25, 0x02, 0x26,
+        0x02, 0x27, 0x02, 0x28, 0x63, 0x99, 0x00, 0x1d, 0x00, 0x18, 0x00, 0x17,
+    ];
+    assert_eq!(ext.get_encoding(), expected);
+}
 
-        // full handshake
-        let mut client =
-            ClientSession::new_quic(&client_config, dns_name("localhost"), client_params.into());
-        let mut server = ServerSession::new_quic(&server_config, server_params.into());
-        let client_initial = step(&mut client, &mut server).unwrap();
-        assert!(client_initial.is_none());
-        assert!(client.get_0rtt_keys().is_none());
-        assert_eq!(server.get_quic_transport_parameters(), Some(client_params));
-        let server_hs = step(&mut server, &mut client).unwrap().unwrap();
-        assert!(server.get_0rtt_keys().is_none());
-        let client_hs = step(&mut client, &mut server).unwrap().unwrap();
-        assert!(compatible_keys(&server_hs, &client_hs));
-        assert!(client.is_handshaking());
-        let server_1rtt = step(&mut server, &mut client).unwrap().unwrap();
-        assert!(!client.is_handshaking());
-        assert_eq!(client.get_quic_transport_parameters(), Some(server_params));
-        assert!(server.is_handshaking());
-        let client_1rtt = step(&mut client, &mut server).unwrap().unwrap();
-        assert!(!server.is_handshaking());
-        assert!(compatible_keys(&server_1rtt, &client_1rtt));
-        assert!(!compatible_keys(&server_hs, &server_1rtt));
-        assert!(step(&mut client, &mut server).unwrap().is_none());
-        assert!(step(&mut server, &mut client).unwrap().is_none());
+/// An encoded HelloRetryRequest, answering the ClientHello that sent
+/// `session_id`, which asks for `group` and carries a cookie.
+fn hrr_message(session_id: rustls::internal::msgs::handshake::SessionID,
+               group: rustls::NamedGroup) -> Vec<u8> {
+    use rustls::internal::msgs::base::PayloadU16;
+    use rustls::internal::msgs::codec::Codec;
+    use rustls::internal::msgs::enums::{ContentType, HandshakeType};
+    use rustls::internal::msgs::handshake::{HandshakeMessagePayload, HandshakePayload};
+    use rustls::internal::msgs::handshake::{HelloRetryExtension, HelloRetryRequest};
+    use rustls::internal::msgs::message::{Message, MessagePayload};
+
+    Message {
+        typ: ContentType::Handshake,
+        version: ProtocolVersion::TLSv1_2,
+        payload: MessagePayload::Handshake(HandshakeMessagePayload {
+            typ: HandshakeType::HelloRetryRequest,
+            payload: HandshakePayload::HelloRetryRequest(HelloRetryRequest {
+                legacy_version: ProtocolVersion::TLSv1_2,
+                session_id,
+                cipher_suite: CipherSuite::TLS13_AES_128_GCM_SHA256,
+                extensions: vec![
+                    HelloRetryExtension::KeyShare(group),
+                    HelloRetryExtension::Cookie(PayloadU16::new(b"cookie".to_vec())),
+                    HelloRetryExtension::SupportedVersions(ProtocolVersion::TLSv1_3),
+                ],
+            }),
+        }),
+    }.get_encoding()
+}
 
-        // 0-RTT handshake
-        let mut client =
-            ClientSession::new_quic(&client_config, dns_name("localhost"), client_params.into());
-        assert!(client.get_negotiated_ciphersuite().is_some());
-        let mut server = ServerSession::new_quic(&server_config, server_params.into());
-        step(&mut client, &mut server).unwrap();
-        assert_eq!(client.get_quic_transport_parameters(), Some(server_params));
-        {
-            let client_early = client.get_0rtt_keys().unwrap();
-            let server_early = server.get_0rtt_keys().unwrap();
-            assert!(equal_dir_keys(&client_early, &server_early));
-        }
-        step(&mut server, &mut client).unwrap().unwrap();
-        step(&mut client, &mut server).unwrap().unwrap();
-        step(&mut server, &mut client).unwrap().unwrap();
-        assert!(client.is_early_data_accepted());
+#[test]
+fn client_sends_compat_session_id_when_resuming_tls13() {
+    let server_config = Arc::new(make_server_config(KeyType::RSA));
 
-        // 0-RTT rejection
-        {
-            let mut client_config = (*client_config).clone();
-            client_config.alpn_protocols = vec!["foo".into()];
-            let mut client = ClientSession::new_quic(
-                &Arc::new(client_config),
-                dns_name("localhost"),
-                client_params.into(),
-            );
-            let mut server = ServerSession::new_quic(&server_config, server_params.into());
-            step(&mut client, &mut server).unwrap();
-            assert_eq!(client.get_quic_transport_parameters(), Some(server_params));
-            assert!(client.get_0rtt_keys().is_some());
-            assert!(server.get_0rtt_keys().is_none());
-            step(&mut server, &mut client).unwrap().unwrap();
-            step(&mut client, &mut server).unwrap().unwrap();
-            step(&mut server, &mut client).unwrap().unwrap();
-            assert!(!client.is_early_data_accepted());
-        }
+    for &always in &[ false, true ] {
+        let mut client_config = make_client_config(KeyType::RSA);
+        client_config.versions = vec![ ProtocolVersion::TLSv1_3 ];
+        client_config.always_send_compat_session_id = always;
+        let client_config = Arc::new(client_config);
 
-        // failed handshake
-        let mut client = ClientSession::new_quic(
-            &client_config,
-            dns_name("example.com"),
-            client_params.into(),
-        );
-        let mut server = ServerSession::new_quic(&server_config, server_params.into());
-        step(&mut client, &mut server).unwrap();
-        step(&mut server, &mut client).unwrap().unwrap();
-        assert!(step(&mut server, &mut client).is_err());
-        assert_eq!(
-            client.get_alert(),
-            Some(rustls::internal::msgs::enums::AlertDescription::BadCertificate)
-        );
+        // a full handshake always has one
+        let (mut client, _) = make_pair_for_arc_configs(&client_config, &server_config);
+        assert_eq!(client_hello_from(&mut client).session_id.len(), 32);
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert!(client.did_resume());
+
+        let (mut client, _) = make_pair_for_arc_configs(&client_config, &server_config);
+        let hello = client_hello_from(&mut client);
+        assert!(hello.get_psk().is_some());
+        assert_eq!(hello.session_id.len(), if always { 32 } else { 0 });
     }
+}
 
 #[test]
-    fn test_quic_rejects_missing_alpn() {
-        let client_params = &b"client params"[..];
-        let server_params = &b"server params"[..];
-
-        for &kt in ALL_KEY_TYPES.iter() {
-            let mut client_config = make_client_config(kt);
-            client_config.versions = vec![ProtocolVersion::TLSv1_3];
-            client_config.alpn_protocols = vec!["bar".into()];
-            let client_config = Arc::new(client_config);
+fn client_does_not_resume_session_for_unsupported_version() {
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.versions = vec![ ProtocolVersion::TLSv1_2 ];
+    let tls12_config = Arc::new(client_config.clone());
+    client_config.versions = vec![ ProtocolVersion::TLSv1_3 ];
+    let tls13_config = Arc::new(client_config);
+    let server_config = Arc::new(make_server_config(KeyType::RSA));
 
-            let mut server_config = make_server_config(kt);
-            server_config.versions = vec![ProtocolVersion::TLSv1_3];
-            server_config.alpn_protocols = vec!["foo".into()];
-            let server_config = Arc::new(server_config);
+    let (mut client, mut server) = make_pair_for_arc_configs(&tls12_config, &server_config);
+    do_handshake(&mut client, &mut server);
 
-            let mut client = ClientSession::new_quic(&client_config,
-                                                     dns_name("localhost"),
-                                                     client_params.into());
-            let mut server = ServerSession::new_quic(&server_config,
-                                                     server_params.into());
+    // The TLS1.2 session is offered by session id...
+    let (mut client, _) = make_pair_for_arc_configs(&tls12_config, &server_config);
+    let cached = client_hello_from(&mut client).session_id;
+    let (mut client, _) = make_pair_for_arc_configs(&tls12_config, &server_config);
+    assert_eq!(client_hello_from(&mut client).session_id, cached);
 
-            assert_eq!(step(&mut client, &mut server).err().unwrap(),
-                       TLSError::NoApplicationProtocol);
+    // ... but not once we only support TLS1.3.
+    let (mut client, _) = make_pair_for_arc_configs(&tls13_config, &server_config);
+    assert_ne!(client_hello_from(&mut client).session_id, cached);
 
-            assert_eq!(server.get_alert(),
-                       Some(rustls::internal::msgs::enums::AlertDescription::NoApplicationProtocol));
-        }
-    }
+    let (mut client, mut server) = make_pair_for_arc_configs(&tls13_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert!(!client.did_resume());
+    assert_eq!(client.get_protocol_version(), Some(ProtocolVersion::TLSv1_3));
+}
 
 #[test]
-    fn test_quic_exporter() {
-        for  &kt in ALL_KEY_TYPES.iter() {
-            let mut client_config = make_client_config(kt);
-            client_config.versions = vec![ProtocolVersion::TLSv1_3];
-            client_config.alpn_protocols = vec!["bar".into()];
+fn client_offers_hinted_kx_group_first() {
+    use rustls::internal::msgs::enums::NamedGroup;
 
-            let mut server_config = make_server_config(kt);
-            server_config.versions = vec![ProtocolVersion::TLSv1_3];
-            server_config.alpn_protocols = vec!["foo".into()];
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.versions = vec![ ProtocolVersion::TLSv1_3 ];
+    let client_config = Arc::new(client_config);
+    let server_config = Arc::new(make_server_config(KeyType::RSA));
 
-            do_exporter_test(client_config, server_config);
-        }
-    }
-} // mod test_quic
+    // No hint yet: X25519 isn't our most preferred group.
+    let (mut client, _) = make_pair_for_arc_configs(&client_config, &server_config);
+    let first = client_hello_from(&mut client);
+    assert_ne!(first.get_namedgroups_extension().unwrap()[0], NamedGroup::X25519);
 
-#[test]
-fn test_client_does_not_offer_sha1() {
-    use rustls::internal::msgs::{message::Message, message::MessagePayload,
-        handshake::HandshakePayload, enums::HandshakeType, codec::Codec};
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
 
-    for kt in ALL_KEY_TYPES.iter() {
-        for client_config in AllClientVersions::new(make_client_config(*kt)) {
-            let (mut client, _) = make_pair_for_configs(client_config,
-                                                        make_server_config(*kt));
+    let (mut client, _) = make_pair_for_arc_configs(&client_config, &server_config);
+    let second = client_hello_from(&mut client);
+    assert_eq!(second.get_namedgroups_extension().unwrap()[0], NamedGroup::X25519);
+    assert_eq!(second.get_keyshare_extension().unwrap()[0].group, NamedGroup::X25519);
+}
 
-            assert!(client.wants_write());
-            let mut buf = [0u8; 262144];
-            let sz = client.write_tls(&mut buf.as_mut())
-                .unwrap();
-            let mut msg = Message::read_bytes(&buf[..sz])
-                .unwrap();
-            assert!(msg.decode_payload());
-            assert!(msg.is_handshake_type(HandshakeType::ClientHello));
+#[test]
+fn client_offers_grease_values() {
+    use rustls::internal::msgs::enums::{CipherSuite, NamedGroup, SignatureScheme};
 
-            let client_hello = match msg.payload {
-                MessagePayload::Handshake(hs) => match hs.payload {
-                    HandshakePayload::ClientHello(ch) => ch,
-                    _ => unreachable!()
-                }
-                _ => unreachable!()
-            };
+    fn is_grease(value: u16) -> bool {
+        value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+    }
 
-            let sigalgs = client_hello.get_sigalgs_extension()
-                .unwrap();
-            assert_eq!(sigalgs.contains(&SignatureScheme::RSA_PKCS1_SHA1), false,
-                       "sha1 unexpectedly offered");
-        }
+    let server_config = Arc::new(make_server_config(KeyType::RSA));
+    let client_config = Arc::new(make_client_config(KeyType::RSA));
+    let (mut client, _) = make_pair_for_arc_configs(&client_config, &server_config);
+    let hello = client_hello_from(&mut client);
+    assert!(!hello.cipher_suites.iter().any(|cs| is_grease(cs.get_u16())));
+
+    for version in &[ ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2 ] {
+        let mut client_config = make_client_config(KeyType::RSA);
+        client_config.versions = vec![ *version ];
+        client_config.enable_grease = true;
+        let client_config = Arc::new(client_config);
+
+        let (mut client, _) = make_pair_for_arc_configs(&client_config, &server_config);
+        let hello = client_hello_from(&mut client);
+        let grease_suite = hello.cipher_suites.iter()
+            .filter(|cs| is_grease(cs.get_u16()))
+            .collect::<Vec<&CipherSuite>>();
+        let grease_group = hello.get_namedgroups_extension().unwrap().iter()
+            .filter(|group| is_grease(group.get_u16()))
+            .collect::<Vec<&NamedGroup>>();
+        let grease_scheme = hello.get_sigalgs_extension().unwrap().iter()
+            .filter(|scheme| is_grease(scheme.get_u16()))
+            .collect::<Vec<&SignatureScheme>>();
+        assert_eq!(grease_suite.len(), 1);
+        assert_eq!(grease_group.len(), 1);
+        assert_eq!(grease_scheme.len(), 1);
+
+        // servers ignore them
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.get_protocol_version(), Some(*version));
     }
 }
 
 #[test]
-fn test_client_mtu_reduction() {
-    struct CollectWrites {
-        writevs: Vec<Vec<usize>>,
-    }
+fn client_strict_pq_only_offers_no_classical_algorithms() {
+    use rustls::internal::msgs::enums::{ExtensionType, NamedGroup, SignatureScheme};
 
-    impl io::Write for CollectWrites {
-        fn write(&mut self, _: &[u8]) -> io::Result<usize> { panic!() }
-        fn flush(&mut self) -> io::Result<()> { panic!() }
-        fn write_vectored<'b>(&mut self, b: &[io::IoSlice<'b>]) -> io::Result<usize> {
-            let writes = b.iter()
-                .map(|slice| slice.len())
-                .collect::<Vec<usize>>();
-            let len = writes.iter().sum();
-            self.writevs.push(writes);
-            Ok(len)
-        }
+    let mut client_config = make_client_config(KeyType::Dilithium2);
+    client_config.strict_pq_only = true;
+    let client_config = Arc::new(client_config);
+    let server_config = Arc::new(make_server_config(KeyType::Dilithium2));
+
+    let (mut client, _) = make_pair_for_arc_configs(&client_config, &server_config);
+    let hello = client_hello_from(&mut client);
+    let groups = hello.get_namedgroups_extension().unwrap();
+    assert!(!groups.is_empty());
+    for classical in &[ NamedGroup::X25519, NamedGroup::secp256r1, NamedGroup::secp384r1 ] {
+        assert!(!groups.contains(classical));
+    }
+    assert!(groups.contains(&NamedGroup::X25519Kyber768));
+    assert!(hello.find_extension(ExtensionType::ECPointFormats).is_none());
+    assert_eq!(hello.get_keyshare_extension().unwrap()[0].group, NamedGroup::Kyber512);
+    assert_eq!(hello.get_versions_extension().unwrap(), &vec![ ProtocolVersion::TLSv1_3 ]);
+
+    let schemes = hello.get_sigalgs_extension().unwrap();
+    assert!(schemes.contains(&SignatureScheme::DILITHIUM2));
+    for classical in &[ SignatureScheme::RSA_PSS_SHA256, SignatureScheme::ECDSA_NISTP256_SHA256,
+                        SignatureScheme::ED25519 ] {
+        assert!(!schemes.contains(classical));
     }
 
-    fn collect_write_lengths(client: &mut ClientSession) -> Vec<usize> {
-        let mut collector = CollectWrites { writevs: vec![] };
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.get_protocol_version(), Some(ProtocolVersion::TLSv1_3));
+}
 
-        client.write_tls(&mut collector)
-            .unwrap();
-        assert_eq!(collector.writevs.len(), 1);
-        collector.writevs[0].clone()
-    }
+#[test]
+fn client_strict_pq_only_refuses_classical_hrr_group() {
+    use rustls::internal::msgs::enums::{AlertDescription, NamedGroup};
 
-    for kt in ALL_KEY_TYPES.iter() {
-        let mut client_config = make_client_config(*kt);
-        client_config.set_mtu(&Some(64));
+    let mut client_config = make_client_config(KeyType::Dilithium2);
+    client_config.strict_pq_only = true;
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let first = client_hello_from(&mut client);
 
-        let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
-        let writes = collect_write_lengths(&mut client);
-        println!("writes at mtu=64: {:?}", writes);
-        assert!(writes.iter().all(|x| *x <= 64));
-        assert!(writes.len() > 1);
-    }
+    client.read_tls(&mut &hrr_message(first.session_id, NamedGroup::X25519)[..]).unwrap();
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::PeerIncompatibleError(
+                   "server requested hrr with classical group X25519".to_string())));
+
+    let mut alert = Vec::new();
+    client.write_tls(&mut alert).unwrap();
+    assert_eq!(alert.last(), Some(&AlertDescription::InsufficientSecurity.get_u8()));
 }
 
 #[test]
-fn exercise_key_log_file_for_client() {
-    let server_config = Arc::new(make_server_config(KeyType::RSA));
+fn client_rejects_second_hello_retry_request() {
+    use rustls::internal::msgs::enums::{AlertDescription, ContentType, HandshakeType, NamedGroup};
+
     let mut client_config = make_client_config(KeyType::RSA);
-    env::set_var("SSLKEYLOGFILE", "./sslkeylogfile.txt");
-    client_config.key_log = Arc::new(rustls::KeyLogFile::new());
+    client_config.versions = vec![ ProtocolVersion::TLSv1_3 ];
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
 
-    for client_config in AllClientVersions::new(client_config) {
-        let (mut client, mut server) = make_pair_for_arc_configs(&Arc::new(client_config),
-                                                                 &server_config);
+    let first = client_hello_from(&mut client);
+    client.read_tls(&mut &hrr_message(first.session_id, NamedGroup::Kyber512)[..]).unwrap();
+    client.process_new_packets().unwrap();
+    let second = client_hello_from(&mut client);
+
+    client.read_tls(&mut &hrr_message(second.session_id, NamedGroup::Kyber768)[..]).unwrap();
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::InappropriateHandshakeMessage {
+                   expect_types: vec![ HandshakeType::ServerHello ],
+                   got_type: HandshakeType::HelloRetryRequest,
+               }));
+
+    // we answer with an alert, not a third ClientHello
+    let mut alert = Vec::new();
+    client.write_tls(&mut alert).unwrap();
+    assert_eq!(alert[0], ContentType::Alert.get_u8());
+    assert_eq!(alert.last(), Some(&AlertDescription::UnexpectedMessage.get_u8()));
+    assert!(!client.wants_write());
+}
 
-        assert_eq!(5, client.write(b"hello").unwrap());
+#[test]
+fn client_rejects_server_hello_choosing_group_without_share() {
+    use rustls::internal::msgs::codec::Codec;
+    use rustls::internal::msgs::enums::{AlertDescription, Compression, ContentType};
+    use rustls::internal::msgs::enums::{HandshakeType, NamedGroup};
+    use rustls::internal::msgs::handshake::{HandshakeMessagePayload, HandshakePayload};
+    use rustls::internal::msgs::handshake::{KeyShareEntry, Random, ServerExtension, ServerHelloPayload};
+    use rustls::internal::msgs::message::{Message, MessagePayload};
 
-        do_handshake(&mut client, &mut server);
-        transfer(&mut client, &mut server);
-        server.process_new_packets().unwrap();
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.versions = vec![ ProtocolVersion::TLSv1_3 ];
+    let client_config = Arc::new(client_config);
+
+    for &(group, err) in &[
+        (NamedGroup::Kyber768,
+         "server chose Kyber768 without a HelloRetryRequest, though we sent no key share for it"),
+        (NamedGroup::FFDHE2048, "server chose FFDHE2048, which we did not offer"),
+    ] {
+        let mut client = ClientSession::new(&client_config, dns_name("localhost"));
+        let hello = client_hello_from(&mut client);
+        assert!(hello.get_keyshare_extension().unwrap().iter().all(|share| share.group != group));
+        assert_eq!(hello.get_namedgroups_extension().unwrap().contains(&group),
+                   group == NamedGroup::Kyber768);
+
+        let server_hello = Message {
+            typ: ContentType::Handshake,
+            version: ProtocolVersion::TLSv1_2,
+            payload: MessagePayload::Handshake(HandshakeMessagePayload {
+                typ: HandshakeType::ServerHello,
+                payload: HandshakePayload::ServerHello(ServerHelloPayload {
+                    legacy_version: ProtocolVersion::TLSv1_2,
+                    random: Random::from_slice(&[0x42; 32]),
+                    session_id: hello.session_id,
+                    cipher_suite: CipherSuite::TLS13_AES_128_GCM_SHA256,
+                    compression_method: Compression::Null,
+                    extensions: vec![
+                        ServerExtension::KeyShare(KeyShareEntry::new(group, &[0x42; 32])),
+                        ServerExtension::SupportedVersions(ProtocolVersion::TLSv1_3),
+                    ],
+                }),
+            }),
+        };
+        client.read_tls(&mut &server_hello.get_encoding()[..]).unwrap();
+        assert_eq!(client.process_new_packets(),
+                   Err(TLSError::PeerMisbehavedError(err.to_string())));
+
+        let mut alert = Vec::new();
+        client.write_tls(&mut alert).unwrap();
+        assert_eq!(alert.last(), Some(&AlertDescription::IllegalParameter.get_u8()));
     }
 }
 
 #[test]
-fn exercise_key_log_file_for_server() {
-    let mut server_config = make_server_config(KeyType::RSA);
+fn client_retries_with_cookie_and_requested_kem_group() {
+    use rustls::internal::msgs::enums::NamedGroup;
+    use rustls::internal::msgs::handshake::ClientExtension;
 
-    env::set_var("SSLKEYLOGFILE", "./sslkeylogfile.txt");
-    server_config.key_log = Arc::new(rustls::KeyLogFile::new());
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.versions = vec![ ProtocolVersion::TLSv1_3 ];
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
 
-    let server_config = Arc::new(server_config);
+    let first = client_hello_from(&mut client);
+    let first_shares = first.get_keyshare_extension().unwrap();
+    assert!(first_shares.iter().all(|share| share.group != NamedGroup::Kyber512));
 
-    for client_config in AllClientVersions::new(make_client_config(KeyType::RSA)) {
-        let (mut client, mut server) = make_pair_for_arc_configs(&Arc::new(client_config),
-                                                                 &server_config);
+    client.read_tls(&mut &hrr_message(first.session_id, NamedGroup::Kyber512)[..]).unwrap();
+    client.process_new_packets().unwrap();
 
-        assert_eq!(5, client.write(b"hello").unwrap());
+    let second = client_hello_from(&mut client);
+    let shares = second.get_keyshare_extension().unwrap();
+    assert_eq!(shares.len(), 1);
+    assert_eq!(shares[0].group, NamedGroup::Kyber512);
+
+    let cookie = second.extensions.iter()
+        .filter_map(|ext| match ext {
+            ClientExtension::Cookie(cookie) => Some(cookie.0.clone()),
+            _ => None,
+        })
+        .next();
+    assert_eq!(cookie, Some(b"cookie".to_vec()));
+}
 
-        do_handshake(&mut client, &mut server);
+#[test]
+fn recorded_kemtls_handshake_round_trips_through_fixture() {
+    let kt = KeyType::Kyber512;
+    let (mut client, mut server) = make_pair(kt);
+
+    let mut records = Vec::new();
+    while server.is_handshaking() || client.is_handshaking() {
         transfer(&mut client, &mut server);
         server.process_new_packets().unwrap();
+        transfer_recorded(&mut server, &mut client, &mut records);
+        client.process_new_packets().unwrap();
     }
-}
 
-fn assert_lt(left: usize, right: usize) {
-    if left >= right {
-        panic!("expected {} < {}", left, right);
-    }
+    assert!(!records.is_empty());
+    assert_eq!(records[0][0], 0x16);
+    assert_eq!(decode_fixture(&encode_fixture(&records)), records);
 }
 
-#[test]
-fn session_types_are_not_huge() {
-    // Arbitrary sizes
-    assert_lt(mem::size_of::<ServerSession>(), 1600);
-    assert_lt(mem::size_of::<ClientSession>(), 1600);
+/// The server's first flight of a Kyber512 KEMTLS handshake, and the
+/// client key share it answers (see tests/replay/README.md).
+#[cfg(feature = "dangerous_configuration")]
+const KEMTLS_KYBER512_FIXTURE: &str = "tests/replay/kemtls-kyber512.txt";
+#[cfg(feature = "dangerous_configuration")]
+const KEMTLS_KYBER512_FIXTURE_KEY: &str = "tests/replay/kemtls-kyber512.key";
+
+#[cfg(feature = "dangerous_configuration")]
+fn fixture_path(name: &str) -> std::path::PathBuf {
+    std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join(name)
 }
 
-use rustls::internal::msgs::{message::Message, message::MessagePayload,
-    handshake::HandshakePayload, handshake::ClientExtension};
+/// A client whose ClientHello is the same every time: fixed random
+/// (and so session id), and a fixed Kyber512 key share.
+#[cfg(feature = "dangerous_configuration")]
+fn fixed_kyber512_client(public_key: &[u8], private_key: &[u8]) -> ClientSession {
+    use rustls::internal::msgs::enums::NamedGroup;
+
+    let mut client_config = make_client_config(KeyType::Kyber512);
+    client_config.strict_pq_only = true;
+    client_config.dangerous()
+        .set_fixed_key_share(NamedGroup::Kyber512, public_key, private_key)
+        .unwrap();
+    client_config.dangerous().set_fixed_client_random([0x11; 32]);
+    ClientSession::new(&Arc::new(client_config), dns_name("localhost"))
+}
 
+/// Writes the Kyber512 KEMTLS fixture.  Run with `--ignored` to
+/// record it again, for example after a wire format change.
+#[cfg(feature = "dangerous_configuration")]
 #[test]
-fn test_server_rejects_duplicate_sni_names() {
-    fn duplicate_sni_payload(msg: &mut Message) {
-        if let MessagePayload::Handshake(hs) = &mut msg.payload {
-            if let HandshakePayload::ClientHello(ch) = &mut hs.payload {
-                for mut ext in ch.extensions.iter_mut() {
-                    if let ClientExtension::ServerName(snr) = &mut ext {
-                        snr.push(snr[0].clone());
-                    }
-                }
-            }
-        }
-    }
+#[ignore]
+fn record_kemtls_kyber512_fixture() {
+    oqs::init();
+    let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+    let (pk, sk) = kem.keypair().unwrap();
+    let mut client = fixed_kyber512_client(pk.as_ref(), sk.as_ref());
+    let mut server = ServerSession::new(&Arc::new(make_server_config(KeyType::Kyber512)));
 
-    let (mut client, mut server) = make_pair(KeyType::RSA);
-    transfer_altered(&mut client, duplicate_sni_payload, &mut server);
-    assert_eq!(server.process_new_packets(),
-               Err(TLSError::PeerMisbehavedError("ClientHello SNI contains duplicate name types".into())));
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    let mut records = Vec::new();
+    transfer_recorded(&mut server, &mut client, &mut records);
+    client.process_new_packets().unwrap();
+    assert_eq!(client.server_auth_kem(), Some(SignatureScheme::KEMTLS_KYBER512));
+
+    let key = encode_fixture(&[ pk.as_ref().to_vec(), sk.as_ref().to_vec() ]);
+    std::fs::write(fixture_path(KEMTLS_KYBER512_FIXTURE_KEY),
+                   "# Kyber512 public and private key of the client key share\n".to_string() + &key)
+        .unwrap();
+    std::fs::write(fixture_path(KEMTLS_KYBER512_FIXTURE),
+                   "# ServerHello, EncryptedExtensions and Certificate of a Kyber512\n\
+                    # KEMTLS handshake with the test-ca/kyber server certificate.\n\
+                    # Recorded by record_kemtls_kyber512_fixture in tests/api.rs.\n".to_string()
+                   + &encode_fixture(&records))
+        .unwrap();
 }
 
+#[cfg(feature = "dangerous_configuration")]
 #[test]
-fn test_server_rejects_empty_sni_extension() {
-    fn empty_sni_payload(msg: &mut Message) {
-        if let MessagePayload::Handshake(hs) = &mut msg.payload {
-            if let HandshakePayload::ClientHello(ch) = &mut hs.payload {
-                for mut ext in ch.extensions.iter_mut() {
-                    if let ClientExtension::ServerName(snr) = &mut ext {
-                        snr.clear();
-                    }
-                }
-            }
-        }
+fn replayed_kemtls_kyber512_fixture() {
+    let read = |name| std::fs::read_to_string(fixture_path(name))
+        .unwrap_or_else(|err| panic!("can't read {} ({}): record it with \
+                                      `cargo test --all-features -- --ignored \
+                                      record_kemtls_kyber512_fixture`", name, err));
+    let key = decode_fixture(&read(KEMTLS_KYBER512_FIXTURE_KEY));
+    let records = decode_fixture(&read(KEMTLS_KYBER512_FIXTURE));
+
+    // The client's ClientHello is the one the server answered: if it
+    // changed, so did the handshake keys, and the server's encrypted
+    // records won't decrypt.
+    let mut client = fixed_kyber512_client(&key[0], &key[1]);
+    let mut client_hello = Vec::new();
+    while client.wants_write() {
+        client.write_tls(&mut client_hello).unwrap();
     }
 
-    let (mut client, mut server) = make_pair(KeyType::RSA);
-    transfer_altered(&mut client, empty_sni_payload, &mut server);
-    assert_eq!(server.process_new_packets(),
-               Err(TLSError::PeerMisbehavedError("ClientHello SNI did not contain a hostname".into())));
+    // The whole flight is accepted, and the client has encapsulated to
+    // the server's certificate: its ciphertext and Finished are ready
+    // to send, and it is waiting for the server's Finished.
+    replay(&mut client, &records).unwrap();
+    assert_eq!(client.server_auth_kem(), Some(SignatureScheme::KEMTLS_KYBER512));
+    assert!(client.wants_write());
+    assert!(client.is_handshaking());
 }
 
+#[cfg(feature = "dangerous_configuration")]
 #[test]
-fn test_server_rejects_clients_without_any_kx_group_overlap() {
-    fn different_kx_group(msg: &mut Message) {
-        if let MessagePayload::Handshake(hs) = &mut msg.payload {
-            if let HandshakePayload::ClientHello(ch) = &mut hs.payload {
-                for mut ext in ch.extensions.iter_mut() {
-                    if let ClientExtension::NamedGroups(ngs) = &mut ext {
-                        ngs.clear();
-                    }
-                    if let ClientExtension::KeyShare(ks) = &mut ext {
-                        ks.clear();
-                    }
-                }
-            }
+fn fixed_randoms_give_reproducible_hellos() {
+    use rustls::internal::msgs::codec::{Codec, Reader};
+    use rustls::internal::msgs::enums::NamedGroup;
+    use rustls::internal::msgs::message::{Message, MessagePayload};
+
+    oqs::init();
+    let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+    let (pk, sk) = kem.keypair().unwrap();
+
+    let mut client_config = make_client_config(KeyType::Dilithium2);
+    client_config.strict_pq_only = true;
+    client_config.dangerous()
+        .set_fixed_key_share(NamedGroup::Kyber512, pk.as_ref(), sk.as_ref())
+        .unwrap();
+    client_config.dangerous().set_fixed_client_random([0x11; 32]);
+    let client_config = Arc::new(client_config);
+
+    let mut server_config = make_server_config(KeyType::Dilithium2);
+    server_config.dangerous().set_fixed_server_random([0x22; 32]);
+    let server_config = Arc::new(server_config);
+
+    let client_hello_bytes = |client: &mut ClientSession| {
+        let mut buf = Vec::new();
+        while client.wants_write() {
+            client.write_tls(&mut buf).unwrap();
         }
+        buf
+    };
+
+    let (mut client, _) = make_pair_for_arc_configs(&client_config, &server_config);
+    let first = client_hello_bytes(&mut client);
+    let (mut client, _) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert_eq!(client_hello_bytes(&mut client), first);
+
+    let (mut client, _) = make_pair_for_arc_configs(&client_config, &server_config);
+    let hello = client_hello_from(&mut client);
+    assert_eq!(hello.random.get_encoding(), vec![0x11; 32]);
+
+    // the server uses its fixed random too, and the handshake works
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    let mut buf = Vec::new();
+    server.write_tls(&mut buf).unwrap();
+    let mut msg = Message::read(&mut Reader::init(&buf)).unwrap();
+    assert!(msg.decode_payload());
+    match msg.payload {
+        MessagePayload::Handshake(hs) => match hs.payload {
+            HandshakePayload::ServerHello(sh) => assert_eq!(sh.random.get_encoding(), vec![0x22; 32]),
+            _ => panic!("expected ServerHello"),
+        },
+        _ => panic!("expected handshake message"),
     }
 
-    let (mut client, mut server) = make_pair(KeyType::RSA);
-    transfer_altered(&mut client, different_kx_group, &mut server);
-    assert_eq!(server.process_new_packets(),
-               Err(TLSError::PeerIncompatibleError("no kx group overlap with client".into())));
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+}
+
+#[cfg(feature = "dangerous_configuration")]
+#[test]
+fn client_offers_fixed_kem_key_share() {
+    use rustls::internal::msgs::enums::NamedGroup;
+
+    oqs::init();
+    let kem = oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber512).unwrap();
+    let (pk, sk) = kem.keypair().unwrap();
+
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.versions = vec![ ProtocolVersion::TLSv1_3 ];
+    assert!(client_config.dangerous()
+            .set_fixed_key_share(NamedGroup::X25519, &[0u8; 32], &[0u8; 32])
+            .is_err());
+    client_config.dangerous()
+        .set_fixed_key_share(NamedGroup::Kyber512, pk.as_ref(), sk.as_ref())
+        .unwrap();
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let first = client_hello_from(&mut client);
+
+    client.read_tls(&mut &hrr_message(first.session_id, NamedGroup::Kyber512)[..]).unwrap();
+    client.process_new_packets().unwrap();
+
+    let second = client_hello_from(&mut client);
+    let shares = second.get_keyshare_extension().unwrap();
+    assert_eq!(shares[0].group, NamedGroup::Kyber512);
+    assert_eq!(shares[0].payload.0, pk.as_ref());
+}
+
+#[test]
+fn client_rejects_hrr_for_group_it_cannot_key_share() {
+    use rustls::internal::msgs::enums::{AlertDescription, NamedGroup};
+
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.versions = vec![ ProtocolVersion::TLSv1_3 ];
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let first = client_hello_from(&mut client);
+
+    // McEliece public keys are too large for a key share, so the group
+    // is never offered, and a server may not ask for it
+    let group = NamedGroup::ClassicMcEliece348864;
+    client.read_tls(&mut &hrr_message(first.session_id, group)[..]).unwrap();
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::PeerMisbehavedError("server requested hrr with bad group".into())));
+
+    let mut alert = Vec::new();
+    client.write_tls(&mut alert).unwrap();
+    assert_eq!(alert.last(), Some(&AlertDescription::IllegalParameter.get_u8()));
+}
+
+/// A thread-safe, in-memory `StoresClientSessions` which counts
+/// accesses and allows tests to inspect and age what it holds.
+struct MemorySessionStore {
+    data: Mutex<collections::HashMap<Vec<u8>, Vec<u8>>>,
+    put_count: AtomicUsize,
+    get_count: AtomicUsize,
+}
+
+impl MemorySessionStore {
+    fn new() -> MemorySessionStore {
+        MemorySessionStore {
+            data: Mutex::new(collections::HashMap::new()),
+            put_count: AtomicUsize::new(0),
+            get_count: AtomicUsize::new(0),
+        }
+    }
+
+    fn puts(&self) -> usize { self.put_count.load(Ordering::SeqCst) }
+    fn gets(&self) -> usize { self.get_count.load(Ordering::SeqCst) }
+
+    fn get_kind(&self, kind: &[u8]) -> Option<Vec<u8>> {
+        self.data.lock()
+            .unwrap()
+            .iter()
+            .find(|(key, _)| key.starts_with(kind))
+            .map(|(_, value)| value.clone())
+    }
+
+    fn rewrite_sessions(&self, f: impl Fn(&mut rustls::internal::msgs::persist::ClientSessionValue)) {
+        use rustls::internal::msgs::codec::{Codec, Reader};
+        use rustls::internal::msgs::persist::ClientSessionValue;
+
+        let mut data = self.data.lock().unwrap();
+        for (key, value) in data.iter_mut() {
+            if !key.starts_with(b"session") {
+                continue;
+            }
+
+            let mut session = ClientSessionValue::read(&mut Reader::init(value))
+                .unwrap();
+            f(&mut session);
+            *value = session.get_encoding();
+        }
+    }
+
+    /// Rewrites every stored session so it expired long ago.
+    fn expire_sessions(&self) {
+        self.rewrite_sessions(|session| {
+            session.epoch = 0;
+            session.lifetime = 1;
+        });
+    }
+
+    /// Rewrites every stored session so it was issued an hour from now.
+    fn postdate_sessions(&self) {
+        self.rewrite_sessions(|session| session.epoch += 3600);
+    }
+}
+
+impl rustls::StoresClientSessions for MemorySessionStore {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.put_count.fetch_add(1, Ordering::SeqCst);
+        self.data.lock().unwrap().insert(key, value);
+        true
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.get_count.fetch_add(1, Ordering::SeqCst);
+        self.data.lock().unwrap().get(key).cloned()
+    }
+
+    fn remove(&self, key: &[u8]) -> bool {
+        self.data.lock().unwrap().remove(key);
+        true
+    }
+}
+
+fn make_client_config_with_store(kt: KeyType, store: &Arc<MemorySessionStore>) -> Arc<ClientConfig> {
+    let mut client_config = make_client_config(kt);
+    client_config.versions = vec![ ProtocolVersion::TLSv1_3 ];
+    client_config.set_persistence(store.clone());
+    Arc::new(client_config)
+}
+
+/// Store a kx hint in `store`, telling clients that "localhost" chose
+/// `group` last time.
+fn put_kx_hint(store: &Arc<MemorySessionStore>, group: rustls::NamedGroup) {
+    use rustls::internal::msgs::codec::Codec;
+    use rustls::internal::msgs::persist::ClientSessionKey;
+
+    let hint_key = ClientSessionKey::hint_for_dns_name(dns_name("localhost"));
+    rustls::StoresClientSessions::put(&**store,
+                                      hint_key.get_encoding(),
+                                      group.get_encoding());
+}
+
+#[test]
+fn memory_session_store_save_then_resume() {
+    let kt = KeyType::RSA;
+    let store = Arc::new(MemorySessionStore::new());
+    let client_config = make_client_config_with_store(kt, &store);
+    let server_config = Arc::new(make_server_config(kt));
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (full_c2s, full_s2c) = do_handshake(&mut client, &mut server);
+    assert!(store.get_kind(b"session").is_some());
+    assert!(store.puts() > 0);
+
+    let gets_before = store.gets();
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (resume_c2s, resume_s2c) = do_handshake(&mut client, &mut server);
+    assert!(store.gets() > gets_before);
+    assert!(resume_c2s > full_c2s);
+    assert!(resume_s2c < full_s2c);
+}
+
+#[test]
+fn memory_session_store_expired_session_not_resumed() {
+    let kt = KeyType::RSA;
+    let store = Arc::new(MemorySessionStore::new());
+    let client_config = make_client_config_with_store(kt, &store);
+    let server_config = Arc::new(make_server_config(kt));
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (_, full_s2c) = do_handshake(&mut client, &mut server);
+
+    store.expire_sessions();
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (_, second_s2c) = do_handshake(&mut client, &mut server);
+    assert_eq!(second_s2c, full_s2c);
+
+    // the expired session was replaced by a fresh one
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (_, third_s2c) = do_handshake(&mut client, &mut server);
+    assert!(third_s2c < full_s2c);
+}
+
+#[test]
+fn client_session_callbacks_fire_on_save_and_resume() {
+    let events = Arc::new(Mutex::new(Vec::new()));
+
+    let mut client_config = make_client_config(KeyType::RSA);
+    let saved = events.clone();
+    client_config.on_session_saved = Some(Arc::new(move |name: &str| {
+        saved.lock().unwrap().push(format!("saved {}", name));
+    }));
+    let loaded = events.clone();
+    client_config.on_session_loaded = Some(Arc::new(move |name: &str, resuming: bool| {
+        loaded.lock().unwrap().push(format!("loaded {} {}", name, resuming));
+    }));
+    let client_config = Arc::new(client_config);
+    let server_config = Arc::new(make_server_config(KeyType::RSA));
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    {
+        let events = events.lock().unwrap();
+        assert_eq!(events[0], "loaded localhost false");
+        assert!(events.len() > 1);
+        assert!(events[1..].iter().all(|e| e == "saved localhost"));
+    }
+
+    events.lock().unwrap().clear();
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(events.lock().unwrap()[0], "loaded localhost true");
+}
+
+#[test]
+fn client_does_not_resume_session_with_bad_secret_length() {
+    use rustls::internal::msgs::base::PayloadU8;
+
+    let kt = KeyType::RSA;
+    let store = Arc::new(MemorySessionStore::new());
+    let client_config = make_client_config_with_store(kt, &store);
+    let server_config = Arc::new(make_server_config(kt));
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (full_c2s, full_s2c) = do_handshake(&mut client, &mut server);
+
+    for secret_len in &[ 0, 16, 255 ] {
+        store.rewrite_sessions(|session| {
+            session.master_secret = PayloadU8::new(vec![0x42; *secret_len]);
+        });
+
+        let (mut client, _) = make_pair_for_arc_configs(&client_config, &server_config);
+        let hello = client_hello_from(&mut client);
+        assert!(hello.get_psk().is_none());
+
+        // we fall back to a full handshake
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        let (second_c2s, second_s2c) = do_handshake(&mut client, &mut server);
+        assert_eq!(second_c2s, full_c2s);
+        assert_eq!(second_s2c, full_s2c);
+    }
+}
+
+#[test]
+fn client_does_not_resume_session_from_future() {
+    let kt = KeyType::RSA;
+    let store = Arc::new(MemorySessionStore::new());
+    let client_config = make_client_config_with_store(kt, &store);
+    let server_config = Arc::new(make_server_config(kt));
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (full_c2s, full_s2c) = do_handshake(&mut client, &mut server);
+
+    store.postdate_sessions();
+
+    let (mut client, _) = make_pair_for_arc_configs(&client_config, &server_config);
+    let hello = client_hello_from(&mut client);
+    assert!(hello.get_psk().is_none());
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (second_c2s, second_s2c) = do_handshake(&mut client, &mut server);
+    assert_eq!(second_c2s, full_c2s);
+    assert_eq!(second_s2c, full_s2c);
+}
+
+#[test]
+fn client_early_data_not_attempted() {
+    let kt = KeyType::RSA;
+    let mut client_config = make_client_config(kt);
+    client_config.enable_early_data = true;
+    let client_config = Arc::new(client_config);
+    let server_config = Arc::new(make_server_config(kt));
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.early_data_accepted(), None);
+
+    // the server didn't offer early data in its ticket
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.early_data_accepted(), None);
+}
+
+#[test]
+fn client_does_not_resume_into_unavailable_suite() {
+    let kt = KeyType::RSA;
+    let store = Arc::new(MemorySessionStore::new());
+    let server_config = Arc::new(make_server_config(kt));
+
+    let mut client_config = make_client_config(kt);
+    client_config.versions = vec![ ProtocolVersion::TLSv1_3 ];
+    client_config.ciphersuites = vec![ find_suite(CipherSuite::TLS13_AES_256_GCM_SHA384) ];
+    client_config.set_persistence(store.clone());
+    let (mut client, mut server) = make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+    do_handshake(&mut client, &mut server);
+    assert!(store.get_kind(b"session").is_some());
+
+    // the cached session's suite (and its hash) is no longer available
+    let mut client_config = make_client_config(kt);
+    client_config.versions = vec![ ProtocolVersion::TLSv1_3 ];
+    client_config.ciphersuites = vec![ find_suite(CipherSuite::TLS13_AES_128_GCM_SHA256) ];
+    client_config.set_persistence(store.clone());
+    let client_config = Arc::new(client_config);
+
+    let (mut client, _) = make_pair_for_arc_configs(&client_config, &server_config);
+    let hello = client_hello_from(&mut client);
+    assert!(hello.get_psk().is_none());
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.get_negotiated_ciphersuite().map(|scs| scs.suite),
+               Some(CipherSuite::TLS13_AES_128_GCM_SHA256));
+}
+
+#[test]
+fn client_resumes_again_after_falling_back_to_full_handshake() {
+    let kt = KeyType::RSA;
+    let store = Arc::new(MemorySessionStore::new());
+    let server_config = Arc::new(make_server_config(kt));
+
+    let mut client_config = make_client_config(kt);
+    client_config.versions = vec![ ProtocolVersion::TLSv1_3 ];
+    client_config.ciphersuites = vec![ find_suite(CipherSuite::TLS13_AES_256_GCM_SHA384) ];
+    client_config.set_persistence(store.clone());
+    let (mut client, mut server) = make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let mut client_config = make_client_config(kt);
+    client_config.versions = vec![ ProtocolVersion::TLSv1_3 ];
+    client_config.ciphersuites = vec![ find_suite(CipherSuite::TLS13_AES_128_GCM_SHA256) ];
+    client_config.set_persistence(store.clone());
+    let client_config = Arc::new(client_config);
+
+    // the full handshake replaces the unusable session with a usable one
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (full_c2s, full_s2c) = do_handshake(&mut client, &mut server);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (resume_c2s, resume_s2c) = do_handshake(&mut client, &mut server);
+    assert!(resume_c2s > full_c2s);
+    assert!(resume_s2c < full_s2c);
+}
+
+#[test]
+fn client_reports_max_early_data_size_from_cached_session() {
+    let kt = KeyType::RSA;
+    let store = Arc::new(MemorySessionStore::new());
+    let mut client_config = make_client_config(kt);
+    client_config.versions = vec![ ProtocolVersion::TLSv1_3 ];
+    client_config.enable_early_data = true;
+    client_config.set_persistence(store.clone());
+    let client_config = Arc::new(client_config);
+
+    let mut server_config = make_server_config(kt);
+    server_config.max_early_data_size = 1234;
+    let server_config = Arc::new(server_config);
+
+    assert_eq!(client_config.max_early_data_size_for("localhost"), None);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert!(store.get_kind(b"session").is_some());
+
+    assert_eq!(client_config.max_early_data_size_for("localhost"), Some(1234));
+    assert_eq!(client_config.max_early_data_size_for("example.com"), None);
+    assert_eq!(client_config.max_early_data_size_for("not a dns name"), None);
+}
+
+#[test]
+fn memory_session_store_kx_hint_round_trip() {
+    use rustls::internal::msgs::codec::Codec;
+    use rustls::internal::msgs::enums::NamedGroup;
+
+    let kt = KeyType::RSA;
+    let store = Arc::new(MemorySessionStore::new());
+    let client_config = make_client_config_with_store(kt, &store);
+    let server_config = Arc::new(make_server_config(kt));
+
+    assert!(store.get_kind(b"kx-hint").is_none());
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let hint = store.get_kind(b"kx-hint")
+        .and_then(|value| NamedGroup::read_bytes(&value))
+        .unwrap();
+
+    let (mut client, _) = make_pair_for_arc_configs(&client_config, &server_config);
+    let hello = client_hello_from(&mut client);
+    assert_eq!(hello.get_keyshare_extension().unwrap()[0].group, hint);
+}
+
+#[test]
+fn kx_hint_status_reports_hint_use() {
+    let kt = KeyType::RSA;
+    let store = Arc::new(MemorySessionStore::new());
+    let client_config = make_client_config_with_store(kt, &store);
+    let server_config = Arc::new(make_server_config(kt));
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert_eq!(client.kx_hint_status(), rustls::KxHintStatus::None);
+    do_handshake(&mut client, &mut server);
+
+    let (client, _) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert_eq!(client.kx_hint_status(), rustls::KxHintStatus::Used);
+
+    let mut disabled_config = (*client_config).clone();
+    disabled_config.enable_kx_hints = false;
+    let (client, _) = make_pair_for_arc_configs(&Arc::new(disabled_config), &server_config);
+    assert_eq!(client.kx_hint_status(), rustls::KxHintStatus::Available);
+}
+
+#[test]
+fn kx_hint_for_group_we_do_not_offer_is_not_used() {
+    use rustls::internal::msgs::enums::NamedGroup;
+
+    let kt = KeyType::Dilithium2;
+    let store = Arc::new(MemorySessionStore::new());
+    let mut client_config = make_client_config(kt);
+    client_config.set_persistence(store.clone());
+    client_config.strict_pq_only = true;
+    let client_config = Arc::new(client_config);
+    let server_config = Arc::new(make_server_config(kt));
+
+    for hint in &[ NamedGroup::X25519, NamedGroup::ClassicMcEliece348864 ] {
+        put_kx_hint(&store, *hint);
+
+        let (mut client, _) = make_pair_for_arc_configs(&client_config, &server_config);
+        assert_eq!(client.kx_hint_status(), rustls::KxHintStatus::Available);
+        let hello = client_hello_from(&mut client);
+        assert_eq!(hello.get_keyshare_extension().unwrap()[0].group, NamedGroup::Kyber512);
+    }
+}
+
+#[cfg(feature = "quic")]
+mod test_quic {
+    use super::*;
+
+    // Returns the sender's next secrets to use, or the receiver's error.
+    fn step(send: &mut dyn Session, recv: &mut dyn Session) -> Result<Option<quic::Keys>, TLSError> {
+        let mut buf = Vec::new();
+        let secrets = loop {
+            let prev = buf.len();
+            if let Some(x) = send.write_hs(&mut buf) {
+                break Some(x);
+            }
+            if prev == buf.len() {
+                break None;
+            }
+        };
+        if let Err(e) = recv.read_hs(&buf) {
+            return Err(e);
+        } else {
+            assert_eq!(recv.get_alert(), None);
+        }
+        Ok(secrets)
+    }
+
+#[test]
+    fn test_quic_handshake() {
+        fn equal_dir_keys(x: &quic::DirectionalKeys, y: &quic::DirectionalKeys) -> bool {
+            // Check that these two sets of keys are equal. The quic module's unit tests validate
+            // that the IV and the keys are consistent, so we can just check the IV here.
+            x.packet.iv.nonce_for(42).as_ref() == y.packet.iv.nonce_for(42).as_ref()
+        }
+        fn compatible_keys(x: &quic::Keys, y: &quic::Keys) -> bool {
+            equal_dir_keys(&x.local, &y.remote) && equal_dir_keys(&x.remote, &y.local)
+        }
+
+        let kt = KeyType::RSA;
+        let mut client_config = make_client_config(kt);
+        client_config.versions = vec![ProtocolVersion::TLSv1_3];
+        client_config.enable_early_data = true;
+        let client_config = Arc::new(client_config);
+        let mut server_config = make_server_config(kt);
+        server_config.versions = vec![ProtocolVersion::TLSv1_3];
+        server_config.max_early_data_size = 0xffffffff;
+        server_config.alpn_protocols = vec!["foo".into()];
+        let server_config = Arc::new(server_config);
+        let client_params = &b"client params"[..];
+        let server_params = &b"server params"[..];
+
+        // full handshake
+        let mut client =
+            ClientSession::new_quic(&client_config, dns_name("localhost"), client_params.into());
+        let mut server = ServerSession::new_quic(&server_config, server_params.into());
+        let client_initial = step(&mut client, &mut server).unwrap();
+        assert!(client_initial.is_none());
+        assert!(client.get_0rtt_keys().is_none());
+        assert_eq!(server.get_quic_transport_parameters(), Some(client_params));
+        let server_hs = step(&mut server, &mut client).unwrap().unwrap();
+        assert!(server.get_0rtt_keys().is_none());
+        let client_hs = step(&mut client, &mut server).unwrap().unwrap();
+        assert!(compatible_keys(&server_hs, &client_hs));
+        assert!(client.is_handshaking());
+        let server_1rtt = step(&mut server, &mut client).unwrap().unwrap();
+        assert!(!client.is_handshaking());
+        assert_eq!(client.get_quic_transport_parameters(), Some(server_params));
+        assert!(server.is_handshaking());
+        let client_1rtt = step(&mut client, &mut server).unwrap().unwrap();
+        assert!(!server.is_handshaking());
+        assert!(compatible_keys(&server_1rtt, &client_1rtt));
+        assert!(!compatible_keys(&server_hs, &server_1rtt));
+        assert!(step(&mut client, &mut server).unwrap().is_none());
+        assert!(step(&mut server, &mut client).unwrap().is_none());
+        assert_eq!(client.early_data_accepted(), None);
+
+        // 0-RTT handshake
+        let mut client =
+            ClientSession::new_quic(&client_config, dns_name("localhost"), client_params.into());
+        assert!(client.get_negotiated_ciphersuite().is_some());
+        let mut server = ServerSession::new_quic(&server_config, server_params.into());
+        step(&mut client, &mut server).unwrap();
+        assert_eq!(client.get_quic_transport_parameters(), Some(server_params));
+        {
+            let client_early = client.get_0rtt_keys().unwrap();
+            let server_early = server.get_0rtt_keys().unwrap();
+            assert!(equal_dir_keys(&client_early, &server_early));
+        }
+        step(&mut server, &mut client).unwrap().unwrap();
+        step(&mut client, &mut server).unwrap().unwrap();
+        step(&mut server, &mut client).unwrap().unwrap();
+        assert!(client.is_early_data_accepted());
+        assert_eq!(client.early_data_accepted(), Some(true));
+
+        // 0-RTT rejection
+        {
+            let mut client_config = (*client_config).clone();
+            client_config.alpn_protocols = vec!["foo".into()];
+            let mut client = ClientSession::new_quic(
+                &Arc::new(client_config),
+                dns_name("localhost"),
+                client_params.into(),
+            );
+            let mut server = ServerSession::new_quic(&server_config, server_params.into());
+            step(&mut client, &mut server).unwrap();
+            assert_eq!(client.get_quic_transport_parameters(), Some(server_params));
+            assert!(client.get_0rtt_keys().is_some());
+            assert!(server.get_0rtt_keys().is_none());
+            step(&mut server, &mut client).unwrap().unwrap();
+            step(&mut client, &mut server).unwrap().unwrap();
+            step(&mut server, &mut client).unwrap().unwrap();
+            assert!(!client.is_early_data_accepted());
+            assert_eq!(client.early_data_accepted(), Some(false));
+        }
+
+        // failed handshake
+        let mut client = ClientSession::new_quic(
+            &client_config,
+            dns_name("example.com"),
+            client_params.into(),
+        );
+        let mut server = ServerSession::new_quic(&server_config, server_params.into());
+        step(&mut client, &mut server).unwrap();
+        step(&mut server, &mut client).unwrap().unwrap();
+        assert!(step(&mut server, &mut client).is_err());
+        assert_eq!(
+            client.get_alert(),
+            Some(rustls::internal::msgs::enums::AlertDescription::BadCertificate)
+        );
+    }
+
+#[test]
+    fn test_quic_rejects_missing_alpn() {
+        let client_params = &b"client params"[..];
+        let server_params = &b"server params"[..];
+
+        for &kt in ALL_KEY_TYPES.iter() {
+            let mut client_config = make_client_config(kt);
+            client_config.versions = vec![ProtocolVersion::TLSv1_3];
+            client_config.alpn_protocols = vec!["bar".into()];
+            let client_config = Arc::new(client_config);
+
+            let mut server_config = make_server_config(kt);
+            server_config.versions = vec![ProtocolVersion::TLSv1_3];
+            server_config.alpn_protocols = vec!["foo".into()];
+            let server_config = Arc::new(server_config);
+
+            let mut client = ClientSession::new_quic(&client_config,
+                                                     dns_name("localhost"),
+                                                     client_params.into());
+            let mut server = ServerSession::new_quic(&server_config,
+                                                     server_params.into());
+
+            assert_eq!(step(&mut client, &mut server).err().unwrap(),
+                       TLSError::NoApplicationProtocol);
+
+            assert_eq!(server.get_alert(),
+                       Some(rustls::internal::msgs::enums::AlertDescription::NoApplicationProtocol));
+        }
+    }
+
+#[test]
+    fn test_quic_exporter() {
+        for  &kt in ALL_KEY_TYPES.iter() {
+            let mut client_config = make_client_config(kt);
+            client_config.versions = vec![ProtocolVersion::TLSv1_3];
+            client_config.alpn_protocols = vec!["bar".into()];
+
+            let mut server_config = make_server_config(kt);
+            server_config.versions = vec![ProtocolVersion::TLSv1_3];
+            server_config.alpn_protocols = vec!["foo".into()];
+
+            do_exporter_test(client_config, server_config);
+        }
+    }
+} // mod test_quic
+
+#[test]
+fn test_client_does_not_offer_sha1() {
+    use rustls::internal::msgs::{message::Message, message::MessagePayload,
+        handshake::HandshakePayload, enums::HandshakeType, codec::Codec};
+
+    for kt in ALL_KEY_TYPES.iter() {
+        for client_config in AllClientVersions::new(make_client_config(*kt)) {
+            let (mut client, _) = make_pair_for_configs(client_config,
+                                                        make_server_config(*kt));
+
+            assert!(client.wants_write());
+            let mut buf = [0u8; 262144];
+            let sz = client.write_tls(&mut buf.as_mut())
+                .unwrap();
+            let mut msg = Message::read_bytes(&buf[..sz])
+                .unwrap();
+            assert!(msg.decode_payload());
+            assert!(msg.is_handshake_type(HandshakeType::ClientHello));
+
+            let client_hello = match msg.payload {
+                MessagePayload::Handshake(hs) => match hs.payload {
+                    HandshakePayload::ClientHello(ch) => ch,
+                    _ => unreachable!()
+                }
+                _ => unreachable!()
+            };
+
+            let sigalgs = client_hello.get_sigalgs_extension()
+                .unwrap();
+            assert_eq!(sigalgs.contains(&SignatureScheme::RSA_PKCS1_SHA1), false,
+                       "sha1 unexpectedly offered");
+        }
+    }
+}
+
+#[test]
+fn test_client_mtu_reduction() {
+    struct CollectWrites {
+        writevs: Vec<Vec<usize>>,
+    }
+
+    impl io::Write for CollectWrites {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> { panic!() }
+        fn flush(&mut self) -> io::Result<()> { panic!() }
+        fn write_vectored<'b>(&mut self, b: &[io::IoSlice<'b>]) -> io::Result<usize> {
+            let writes = b.iter()
+                .map(|slice| slice.len())
+                .collect::<Vec<usize>>();
+            let len = writes.iter().sum();
+            self.writevs.push(writes);
+            Ok(len)
+        }
+    }
+
+    fn collect_write_lengths(client: &mut ClientSession) -> Vec<usize> {
+        let mut collector = CollectWrites { writevs: vec![] };
+
+        client.write_tls(&mut collector)
+            .unwrap();
+        assert_eq!(collector.writevs.len(), 1);
+        collector.writevs[0].clone()
+    }
+
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut client_config = make_client_config(*kt);
+        client_config.set_mtu(&Some(64));
+
+        let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+        let writes = collect_write_lengths(&mut client);
+        println!("writes at mtu=64: {:?}", writes);
+        assert!(writes.iter().all(|x| *x <= 64));
+        assert!(writes.len() > 1);
+    }
+}
+
+#[test]
+fn exercise_key_log_file_for_client() {
+    let server_config = Arc::new(make_server_config(KeyType::RSA));
+    let mut client_config = make_client_config(KeyType::RSA);
+    env::set_var("SSLKEYLOGFILE", "./sslkeylogfile.txt");
+    client_config.key_log = Arc::new(rustls::KeyLogFile::new());
+
+    for client_config in AllClientVersions::new(client_config) {
+        let (mut client, mut server) = make_pair_for_arc_configs(&Arc::new(client_config),
+                                                                 &server_config);
+
+        assert_eq!(5, client.write(b"hello").unwrap());
+
+        do_handshake(&mut client, &mut server);
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+    }
+}
+
+#[test]
+fn exercise_key_log_file_for_server() {
+    let mut server_config = make_server_config(KeyType::RSA);
+
+    env::set_var("SSLKEYLOGFILE", "./sslkeylogfile.txt");
+    server_config.key_log = Arc::new(rustls::KeyLogFile::new());
+
+    let server_config = Arc::new(server_config);
+
+    for client_config in AllClientVersions::new(make_client_config(KeyType::RSA)) {
+        let (mut client, mut server) = make_pair_for_arc_configs(&Arc::new(client_config),
+                                                                 &server_config);
+
+        assert_eq!(5, client.write(b"hello").unwrap());
+
+        do_handshake(&mut client, &mut server);
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+    }
+}
+
+#[cfg(feature = "dangerous_configuration")]
+struct KeyScheduleTraceToVec {
+    labels: Mutex<Vec<Vec<u8>>>,
+}
+
+#[cfg(feature = "dangerous_configuration")]
+impl rustls::KeyScheduleTrace for KeyScheduleTraceToVec {
+    fn derived(&self, label: &[u8], hs_hash: &[u8], secret: &[u8]) {
+        assert!(!hs_hash.is_empty());
+        assert!(!secret.is_empty());
+        self.labels.lock().unwrap().push(label.to_vec());
+    }
+}
+
+#[cfg(feature = "dangerous_configuration")]
+#[test]
+fn key_schedule_trace_covers_kemtls_handshake() {
+    let trace = Arc::new(KeyScheduleTraceToVec { labels: Mutex::new(vec![]) });
+    let mut client_config = make_client_config(KeyType::Kyber512);
+    client_config.dangerous().set_key_schedule_trace(trace.clone());
+
+    let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                         make_server_config(KeyType::Kyber512));
+    do_handshake(&mut client, &mut server);
+
+    let labels = trace.labels.lock().unwrap();
+    for expected in &[&b"derived"[..],
+                      b"c hs traffic", b"s hs traffic",
+                      b"c ahs traffic", b"s ahs traffic",
+                      b"c ap traffic", b"s ap traffic",
+                      b"exp master"] {
+        assert!(labels.iter().any(|l| l == expected),
+                "missing {:?} in trace", String::from_utf8_lossy(expected));
+    }
+}
+
+fn assert_lt(left: usize, right: usize) {
+    if left >= right {
+        panic!("expected {} < {}", left, right);
+    }
+}
+
+#[test]
+fn session_types_are_not_huge() {
+    // Arbitrary sizes
+    assert_lt(mem::size_of::<ServerSession>(), 1600);
+    assert_lt(mem::size_of::<ClientSession>(), 1600);
+}
+
+use rustls::internal::msgs::{message::Message, message::MessagePayload,
+    handshake::HandshakePayload, handshake::ClientExtension};
+
+#[test]
+fn test_server_rejects_duplicate_sni_names() {
+    fn duplicate_sni_payload(msg: &mut Message) {
+        if let MessagePayload::Handshake(hs) = &mut msg.payload {
+            if let HandshakePayload::ClientHello(ch) = &mut hs.payload {
+                for mut ext in ch.extensions.iter_mut() {
+                    if let ClientExtension::ServerName(snr) = &mut ext {
+                        snr.push(snr[0].clone());
+                    }
+                }
+            }
+        }
+    }
+
+    let (mut client, mut server) = make_pair(KeyType::RSA);
+    transfer_altered(&mut client, duplicate_sni_payload, &mut server);
+    assert_eq!(server.process_new_packets(),
+               Err(TLSError::PeerMisbehavedError("ClientHello SNI contains duplicate name types".into())));
+}
+
+#[test]
+fn test_server_rejects_empty_sni_extension() {
+    fn empty_sni_payload(msg: &mut Message) {
+        if let MessagePayload::Handshake(hs) = &mut msg.payload {
+            if let HandshakePayload::ClientHello(ch) = &mut hs.payload {
+                for mut ext in ch.extensions.iter_mut() {
+                    if let ClientExtension::ServerName(snr) = &mut ext {
+                        snr.clear();
+                    }
+                }
+            }
+        }
+    }
+
+    let (mut client, mut server) = make_pair(KeyType::RSA);
+    transfer_altered(&mut client, empty_sni_payload, &mut server);
+    assert_eq!(server.process_new_packets(),
+               Err(TLSError::PeerMisbehavedError("ClientHello SNI did not contain a hostname".into())));
+}
+
+#[test]
+fn test_server_rejects_clients_without_any_kx_group_overlap() {
+    fn different_kx_group(msg: &mut Message) {
+        if let MessagePayload::Handshake(hs) = &mut msg.payload {
+            if let HandshakePayload::ClientHello(ch) = &mut hs.payload {
+                for mut ext in ch.extensions.iter_mut() {
+                    if let ClientExtension::NamedGroups(ngs) = &mut ext {
+                        ngs.clear();
+                    }
+                    if let ClientExtension::KeyShare(ks) = &mut ext {
+                        ks.clear();
+                    }
+                }
+            }
+        }
+    }
+
+    let (mut client, mut server) = make_pair(KeyType::RSA);
+    transfer_altered(&mut client, different_kx_group, &mut server);
+    assert_eq!(server.process_new_packets(),
+               Err(TLSError::PeerIncompatibleError("no kx group overlap with client".into())));
+}
+
+#[test]
+fn client_rejects_tls13_server_random_with_downgrade_marker() {
+    use rustls::internal::msgs::codec::Codec;
+    use rustls::internal::msgs::handshake::Random;
+
+    fn poison_server_random(msg: &mut Message) {
+        if let MessagePayload::Handshake(hs) = &mut msg.payload {
+            if let HandshakePayload::ServerHello(sh) = &mut hs.payload {
+                let mut random = sh.random.get_encoding();
+                random[24..].copy_from_slice(b"DOWNGRD\x01");
+                sh.random = Random::from_slice(&random);
+            }
+        }
+    }
+
+    let (mut client, mut server) = make_pair(KeyType::RSA);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer_altered(&mut server, poison_server_random, &mut client);
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::PeerMisbehavedError(
+                   "server chose TLS1.3 with a TLS1.2 downgrade marker".to_string())));
+}
+
+#[test]
+fn client_rejects_tls13_server_hello_with_other_session_id() {
+    use rustls::internal::msgs::handshake::SessionID;
+
+    fn replace_session_id(msg: &mut Message) {
+        if let MessagePayload::Handshake(hs) = &mut msg.payload {
+            if let HandshakePayload::ServerHello(sh) = &mut hs.payload {
+                sh.session_id = SessionID::new(&[0x55; 32]);
+            }
+        }
+    }
+
+    let (mut client, mut server) = make_pair(KeyType::RSA);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer_altered(&mut server, replace_session_id, &mut client);
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::PeerMisbehavedError(
+                   "server did not echo our session id".to_string())));
+}
+
+fn alter_server_key_share(msg: &mut Message, f: impl Fn(&mut Vec<u8>)) {
+    use rustls::internal::msgs::handshake::ServerExtension;
+
+    if let MessagePayload::Handshake(hs) = &mut msg.payload {
+        if let HandshakePayload::ServerHello(sh) = &mut hs.payload {
+            for ext in sh.extensions.iter_mut() {
+                if let ServerExtension::KeyShare(ks) = ext {
+                    f(&mut ks.payload.0);
+                }
+            }
+        }
+    }
+}
+
+/// Accepts any server certificate, and any TLS1.2 ServerKeyExchange
+/// signature, so tests can alter the key exchange parameters.
+#[cfg(feature = "dangerous_configuration")]
+struct AcceptAnyTls12KeyExchange;
+
+#[cfg(feature = "dangerous_configuration")]
+impl rustls::ServerCertVerifier for AcceptAnyTls12KeyExchange {
+    fn verify_server_cert(&self,
+                          _roots: &rustls::RootCertStore,
+                          _presented_certs: &[rustls::Certificate],
+                          _dns_name: webpki::DNSNameRef,
+                          _ocsp_response: &[u8]) -> Result<rustls::ServerCertVerified, TLSError> {
+        Ok(rustls::ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self,
+                              _message: &[u8],
+                              _cert: &rustls::Certificate,
+                              _dss: &rustls::internal::msgs::handshake::DigitallySignedStruct)
+        -> Result<rustls::HandshakeSignatureValid, TLSError> {
+        Ok(rustls::HandshakeSignatureValid::assertion())
+    }
+}
+
+#[cfg(feature = "dangerous_configuration")]
+#[test]
+fn client_reports_failed_tls12_key_exchange() {
+    use rustls::internal::msgs::enums::AlertDescription;
+    use rustls::internal::msgs::handshake::ServerKeyExchangePayload;
+
+    // Drop the last byte of the server's ECDHE public key, keeping the
+    // (now unchecked) signature parseable.
+    fn short_server_kx_key(msg: &mut Message) {
+        if let MessagePayload::Handshake(hs) = &mut msg.payload {
+            if let HandshakePayload::ServerKeyExchange(ServerKeyExchangePayload::Unknown(p)) =
+                &mut hs.payload {
+                // curve_type, named_group, then a u8-length public key
+                let key_len = p.0[3] as usize;
+                p.0[3] -= 1;
+                p.0.remove(3 + key_len);
+            }
+        }
+    }
+
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.versions = vec![ ProtocolVersion::TLSv1_2 ];
+    client_config.dangerous().set_certificate_verifier(Arc::new(AcceptAnyTls12KeyExchange));
+    let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                         make_server_config(KeyType::RSA));
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer_altered(&mut server, short_server_kx_key, &mut client);
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::PeerMisbehavedError("key exchange failed".to_string())));
+
+    transfer(&mut client, &mut server);
+    assert_eq!(server.process_new_packets(),
+               Err(TLSError::AlertReceived(AlertDescription::IllegalParameter)));
+}
+
+/// `cert` with the last byte of its Kyber512 public key cut off.  Only
+/// the lengths around the key change, so the certificate still parses.
+#[cfg(feature = "dangerous_configuration")]
+fn truncate_kyber512_public_key(cert: &rustls::Certificate) -> rustls::Certificate {
+    // A Kyber512 public key is 800 bytes, so a BIT STRING of 801.  The
+    // Certificate, TBSCertificate, SubjectPublicKeyInfo and BIT STRING
+    // all use two-byte lengths.
+    const KEY_HEADER: [u8; 5] = [ 0x03, 0x82, 0x03, 0x21, 0x00 ];
+    let mut der = cert.0.clone();
+    let len_at = |der: &[u8], at: usize| (usize::from(der[at + 2]) << 8) | usize::from(der[at + 3]);
+    let key_at = der.windows(KEY_HEADER.len())
+        .position(|w| w == KEY_HEADER)
+        .unwrap();
+    let key_end = key_at + 4 + 801;
+    let spki_at = (0..key_at).rev()
+        .find(|&at| der[at] == 0x30 && der[at + 1] == 0x82 && at + 4 + len_at(&der, at) == key_end)
+        .unwrap();
+
+    for &at in &[ 0, 4, spki_at, key_at ] {
+        let len = len_at(&der, at) - 1;
+        der[at + 2] = (len >> 8) as u8;
+        der[at + 3] = len as u8;
+    }
+    der.remove(key_end - 1);
+    rustls::Certificate(der)
+}
+
+#[cfg(feature = "dangerous_configuration")]
+#[test]
+fn client_reports_failed_kemtls_encapsulation() {
+    use rustls::KemtlsStep;
+
+    let kt = KeyType::Kyber512;
+    let mut chain = kt.get_chain();
+    chain[0] = truncate_kyber512_public_key(&chain[0]);
+    let mut server_config = ServerConfig::new(rustls::NoClientAuth::new());
+    server_config.set_single_cert(chain, kt.get_key()).unwrap();
+
+    let mut client_config = make_client_config(kt);
+    client_config.dangerous().set_certificate_verifier(Arc::new(AcceptAnyTls12KeyExchange));
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    let errs = do_handshake_until_both_error(&mut client, &mut server);
+    assert_eq!(errs,
+               Err(vec![
+                   TLSErrorFromPeer::Client(TLSError::KemtlsFlightFailed(KemtlsStep::Encapsulate)),
+                   TLSErrorFromPeer::Server(TLSError::AlertReceived(
+                       rustls::AlertDescription::BadCertificate)),
+               ]));
+}
+
+#[test]
+fn test_client_rejects_empty_server_key_share() {
+    fn empty_key_share(msg: &mut Message) {
+        alter_server_key_share(msg, |payload| payload.clear());
+    }
+
+    let (mut client, mut server) = make_pair(KeyType::RSA);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer_altered(&mut server, empty_key_share, &mut client);
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::PeerMisbehavedError("server key share has wrong length".into())));
+}
+
+#[test]
+fn test_client_rejects_short_server_key_share() {
+    fn short_key_share(msg: &mut Message) {
+        alter_server_key_share(msg, |payload| payload.truncate(payload.len() - 1));
+    }
+
+    let (mut client, mut server) = make_pair(KeyType::RSA);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer_altered(&mut server, short_key_share, &mut client);
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::PeerMisbehavedError("server key share has wrong length".into())));
 }
 
 #[test]
 fn test_ownedtrustanchor_to_trust_anchor_is_public() {
     let client_config = make_client_config(KeyType::RSA);
-    let _anchor: webpki::TrustAnchor = client_config.root_store.roots[0].to_trust_anchor();
+    let _anchor: webpki::TrustAnchor = client_config.root_store.roots[0].to_trust_anchor();
+}
+
+fn strip_ems_ack(msg: &mut Message) {
+    use rustls::internal::msgs::handshake::ServerExtension;
+
+    if let MessagePayload::Handshake(hs) = &mut msg.payload {
+        if let HandshakePayload::ServerHello(sh) = &mut hs.payload {
+            sh.extensions.retain(|ext| match ext {
+                ServerExtension::ExtendedMasterSecretAck => false,
+                _ => true,
+            });
+        }
+    }
+}
+
+fn add_unknown_server_hello_ext(msg: &mut Message) {
+    use rustls::internal::msgs::handshake::{ServerExtension, UnknownExtension};
+    use rustls::internal::msgs::enums::ExtensionType;
+    use rustls::internal::msgs::base::Payload;
+
+    if let MessagePayload::Handshake(hs) = &mut msg.payload {
+        if let HandshakePayload::ServerHello(sh) = &mut hs.payload {
+            sh.extensions.push(ServerExtension::Unknown(UnknownExtension {
+                typ: ExtensionType::Unknown(0xfe0d),
+                payload: Payload::new(vec![1, 2, 3]),
+            }));
+        }
+    }
+}
+
+#[test]
+fn client_rejects_unsolicited_server_hello_extension() {
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.versions = vec![ProtocolVersion::TLSv1_2];
+    let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                         make_server_config(KeyType::RSA));
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer_altered(&mut server, add_unknown_server_hello_ext, &mut client);
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::PeerMisbehavedError("server sent unsolicited extension".into())));
+}
+
+#[test]
+fn client_tolerates_configured_unsolicited_server_hello_extension() {
+    use rustls::internal::msgs::enums::ExtensionType;
+
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.versions = vec![ProtocolVersion::TLSv1_2];
+    client_config.allowed_unsolicited_extensions.push(ExtensionType::Unknown(0xfe0d));
+    let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                         make_server_config(KeyType::RSA));
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer_altered(&mut server, add_unknown_server_hello_ext, &mut client);
+    assert_eq!(client.process_new_packets(), Ok(()));
+}
+
+#[test]
+fn client_requiring_ems_accepts_tls12_server_with_ems() {
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.versions = vec![ProtocolVersion::TLSv1_2];
+    client_config.require_ems = true;
+    let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                         make_server_config(KeyType::RSA));
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.get_protocol_version(), Some(ProtocolVersion::TLSv1_2));
+}
+
+#[test]
+fn client_requiring_ems_rejects_tls12_server_without_ems() {
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.versions = vec![ProtocolVersion::TLSv1_2];
+    client_config.require_ems = true;
+    let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                         make_server_config(KeyType::RSA));
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer_altered(&mut server, strip_ems_ack, &mut client);
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::PeerIncompatibleError(
+                   "server does not support extended master secret".into())));
+}
+
+fn key_update_round_trip(client_config: ClientConfig, server_config: ServerConfig) {
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+
+    client.request_key_update(true).unwrap();
+    client.write_all(b"after client update").unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server, b"after client update");
+
+    // the server answers with its own KeyUpdate before its next data
+    server.write_all(b"after server update").unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    check_read(&mut client, b"after server update");
+
+    client.request_key_update(false).unwrap();
+    client.write_all(b"again").unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server, b"again");
+}
+
+#[test]
+fn client_key_update_round_trip() {
+    for kt in ALL_KEY_TYPES.iter() {
+        key_update_round_trip(make_client_config(*kt), make_server_config(*kt));
+    }
+}
+
+#[test]
+fn client_key_update_round_trip_kemtls() {
+    key_update_round_trip(make_client_config(KeyType::Kyber512),
+                          make_server_config(KeyType::Kyber512));
+}
+
+#[test]
+fn client_key_update_before_handshake_fails() {
+    let (mut client, _) = make_pair(KeyType::RSA);
+    assert_eq!(client.request_key_update(false),
+               Err(TLSError::HandshakeNotComplete));
+}
+
+#[test]
+fn client_key_update_needs_tls13() {
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.versions = vec![ProtocolVersion::TLSv1_2];
+    let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                         make_server_config(KeyType::RSA));
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.request_key_update(false),
+               Err(TLSError::General("KeyUpdate requires TLS1.3".into())));
+}
+
+#[test]
+fn client_drops_tickets_beyond_cap() {
+    let kt = KeyType::RSA;
+    let store = Arc::new(MemorySessionStore::new());
+    let mut client_config = make_client_config(kt);
+    client_config.set_persistence(store.clone());
+    client_config.max_tickets_stored = 0;
+
+    // the server sends one ticket, which is more than we allow
+    let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                         make_server_config(kt));
+    do_handshake(&mut client, &mut server);
+    assert!(store.get_kind(b"session").is_none());
+}
+
+#[test]
+fn client_stores_tickets_within_cap() {
+    let kt = KeyType::RSA;
+    let store = Arc::new(MemorySessionStore::new());
+    let mut client_config = make_client_config(kt);
+    client_config.set_persistence(store.clone());
+    client_config.max_tickets_stored = 1;
+
+    let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                         make_server_config(kt));
+    do_handshake(&mut client, &mut server);
+    assert!(store.get_kind(b"session").is_some());
+}
+
+#[test]
+fn failed_state_is_reported() {
+    fn empty_key_share(msg: &mut Message) {
+        alter_server_key_share(msg, |payload| payload.clear());
+    }
+
+    let (mut client, mut server) = make_pair(KeyType::RSA);
+    assert_eq!(client.get_failed_state(), None);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer_altered(&mut server, empty_key_share, &mut client);
+    assert!(client.process_new_packets().is_err());
+    assert_eq!(client.get_failed_state(), Some(HandshakeState::ServerHelloOrHelloRetryRequest));
+    assert_eq!(server.get_failed_state(), None);
+}
+
+#[test]
+fn server_failed_state_is_reported() {
+    let server_config = make_server_config_with_mandatory_client_auth(KeyType::Kyber512);
+    let mut client_config = make_client_config(KeyType::Kyber512);
+    client_config.set_single_client_cert(KeyType::RSA.get_client_chain(),
+                                         KeyType::RSA.get_client_key())
+        .unwrap();
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    assert!(do_handshake_until_error(&mut client, &mut server).is_err());
+    assert_eq!(server.get_failed_state(), Some(HandshakeState::Certificate));
+}
+
+#[test]
+fn hybrid_x25519_kyber768_handshake() {
+    use rustls::internal::msgs::codec::Codec;
+    use rustls::internal::msgs::enums::NamedGroup;
+
+    let kt = KeyType::RSA;
+    let store = Arc::new(MemorySessionStore::new());
+    let client_config = make_client_config_with_store(kt, &store);
+    let server_config = Arc::new(make_server_config(kt));
+
+    // steer the client into offering the hybrid group first
+    put_kx_hint(&store, NamedGroup::X25519Kyber768);
+
+    let (mut client, _) = make_pair_for_arc_configs(&client_config, &server_config);
+    let hello = client_hello_from(&mut client);
+    let shares = hello.get_keyshare_extension().unwrap();
+    assert_eq!(shares[0].group, NamedGroup::X25519Kyber768);
+    assert_eq!(shares[0].payload.0.len(),
+               rustls::kex_sizes(NamedGroup::X25519Kyber768).unwrap().public_key_len);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    // both sides derived the same secrets from the combined premaster secret
+    let mut client_secret = [0u8; 32];
+    let mut server_secret = [0u8; 32];
+    client.export_keying_material(&mut client_secret, b"label", None).unwrap();
+    server.export_keying_material(&mut server_secret, b"label", None).unwrap();
+    assert_eq!(client_secret, server_secret);
+
+    // and the server chose the hybrid group
+    let hint = store.get_kind(b"kx-hint")
+        .and_then(|value| NamedGroup::read_bytes(&value))
+        .unwrap();
+    assert_eq!(hint, NamedGroup::X25519Kyber768);
+}
+
+#[test]
+fn client_offers_only_configured_ciphersuites_in_order() {
+    let mut client_config = make_client_config(KeyType::Kyber512);
+    client_config.set_ciphersuites(&[ CipherSuite::TLS13_AES_128_GCM_SHA256 ]).unwrap();
+    let client_config = Arc::new(client_config);
+    let server_config = Arc::new(make_server_config(KeyType::Kyber512));
+
+    let (mut client, _) = make_pair_for_arc_configs(&client_config, &server_config);
+    let hello = client_hello_from(&mut client);
+    assert_eq!(hello.cipher_suites,
+               vec![ CipherSuite::TLS13_AES_128_GCM_SHA256,
+                     CipherSuite::TLS_EMPTY_RENEGOTIATION_INFO_SCSV ]);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.get_negotiated_ciphersuite().unwrap().suite,
+               CipherSuite::TLS13_AES_128_GCM_SHA256);
+
+    // the given order is kept
+    let order = [ CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+                  CipherSuite::TLS13_AES_256_GCM_SHA384 ];
+    let mut client_config = make_client_config(KeyType::Kyber512);
+    client_config.set_ciphersuites(&order).unwrap();
+    let (mut client, _) = make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+    assert_eq!(&client_hello_from(&mut client).cipher_suites[..2], &order[..]);
+}
+
+#[test]
+fn client_rejects_bad_ciphersuite_restriction() {
+    let mut client_config = make_client_config(KeyType::RSA);
+    let before = client_config.ciphersuites.len();
+
+    assert!(client_config.set_ciphersuites(&[]).is_err());
+    assert!(client_config.set_ciphersuites(&[ CipherSuite::TLS_RSA_WITH_NULL_MD5 ]).is_err());
+    assert!(client_config.set_ciphersuites(&[ CipherSuite::TLS13_AES_128_GCM_SHA256,
+                                              CipherSuite::TLS13_AES_128_GCM_SHA256 ]).is_err());
+    assert_eq!(client_config.ciphersuites.len(), before);
+}
+
+#[test]
+fn client_pads_client_hello_to_fixed_size() {
+    use rustls::internal::msgs::enums::NamedGroup;
+
+    fn padded_hello_len(mut client_config: ClientConfig, group: NamedGroup) -> usize {
+        client_config.clienthello_pad_to = Some(2048);
+        let client_config = Arc::new(client_config);
+        let server_config = Arc::new(make_server_config(KeyType::Dilithium2));
+
+        let (mut client, _) = make_pair_for_arc_configs(&client_config, &server_config);
+        let mut buf = Vec::new();
+        while client.wants_write() {
+            client.write_tls(&mut buf).unwrap();
+        }
+
+        let (mut client, _) = make_pair_for_arc_configs(&client_config, &server_config);
+        let hello = client_hello_from(&mut client);
+        assert_eq!(hello.get_keyshare_extension().unwrap()[0].group, group);
+
+        // and the server is happy with it
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+
+        buf.len()
+    }
+
+    let small = make_client_config(KeyType::Dilithium2);
+    let mut large = make_client_config(KeyType::Dilithium2);
+    large.strict_pq_only = true;
+
+    assert_eq!(padded_hello_len(small, NamedGroup::X25519), 2048);
+    assert_eq!(padded_hello_len(large, NamedGroup::Kyber512), 2048);
+}
+
+#[test]
+fn client_pads_client_hello_split_across_records() {
+    // 507 byte fragments, so 512 byte records
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.set_mtu(&Some(512));
+    client_config.clienthello_pad_to = Some(4 * 512 + 100);
+    let (mut client, _) = make_pair_for_arc_configs(&Arc::new(client_config),
+                                                    &Arc::new(make_server_config(KeyType::RSA)));
+
+    let mut buf = Vec::new();
+    while client.wants_write() {
+        client.write_tls(&mut buf).unwrap();
+    }
+    assert_eq!(buf.len(), 4 * 512 + 100);
+
+    // no ClientHello leaves a final record of only 3 bytes, all header
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.set_mtu(&Some(512));
+    client_config.clienthello_pad_to = Some(4 * 512 + 3);
+    let (mut client, _) = make_pair_for_arc_configs(&Arc::new(client_config),
+                                                    &Arc::new(make_server_config(KeyType::RSA)));
+
+    match client.process_new_packets() {
+        Err(TLSError::General(msg)) => assert!(msg.contains("no ClientHello is exactly 2051 bytes")),
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn client_hello_too_large_to_pad_is_refused() {
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.clienthello_pad_to = Some(100);
+    let (mut client, _) = make_pair_for_arc_configs(&Arc::new(client_config),
+                                                    &Arc::new(make_server_config(KeyType::RSA)));
+
+    match client.process_new_packets() {
+        Err(TLSError::General(msg)) => assert!(msg.contains("cannot be padded to 100 bytes")),
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn client_forgets_kx_hint() {
+    use rustls::internal::msgs::enums::NamedGroup;
+
+    let kt = KeyType::RSA;
+    let store = Arc::new(MemorySessionStore::new());
+    let client_config = make_client_config_with_store(kt, &store);
+    let server_config = Arc::new(make_server_config(kt));
+
+    put_kx_hint(&store, NamedGroup::Kyber512);
+
+    let (mut client, _) = make_pair_for_arc_configs(&client_config, &server_config);
+    let hello = client_hello_from(&mut client);
+    assert_eq!(hello.get_keyshare_extension().unwrap()[0].group,
+               NamedGroup::Kyber512);
+
+    assert!(client_config.forget_kx_hint("localhost"));
+    assert!(store.get_kind(b"kx-hint").is_none());
+
+    let (mut client, _) = make_pair_for_arc_configs(&client_config, &server_config);
+    let hello = client_hello_from(&mut client);
+    assert_eq!(hello.get_keyshare_extension().unwrap()[0].group,
+               NamedGroup::X25519);
+
+    // forgetting an absent hint, or an invalid name, is harmless
+    assert!(client_config.forget_kx_hint("localhost"));
+    assert!(!client_config.forget_kx_hint("not a dns name"));
+}
+
+#[test]
+fn kyber768_key_share_is_kyber768_sized() {
+    use rustls::internal::msgs::codec::Codec;
+    use rustls::internal::msgs::enums::NamedGroup;
+    use rustls::internal::msgs::persist::ClientSessionKey;
+
+    // This group used to be Kyber512 on the wire.
+    let group = NamedGroup::Kyber768;
+    let kt = KeyType::RSA;
+    let store = Arc::new(MemorySessionStore::new());
+    let client_config = make_client_config_with_store(kt, &store);
+    let server_config = Arc::new(make_server_config(kt));
+
+    let hint_key = ClientSessionKey::hint_for_dns_name(dns_name("localhost"));
+    rustls::StoresClientSessions::put(&*store,
+                                      hint_key.get_encoding(),
+                                      group.get_encoding());
+
+    let (mut client, _) = make_pair_for_arc_configs(&client_config, &server_config);
+    let hello = client_hello_from(&mut client);
+    let shares = hello.get_keyshare_extension().unwrap();
+    assert_eq!(shares[0].group, group);
+    assert_eq!(shares[0].payload.0.len(), 1184);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    let hint = store.get_kind(b"kx-hint")
+        .and_then(|value| NamedGroup::read_bytes(&value))
+        .unwrap();
+    assert_eq!(hint, group);
+}
+
+#[test]
+fn compressed_sike_handshake() {
+    use rustls::internal::msgs::codec::Codec;
+    use rustls::internal::msgs::enums::NamedGroup;
+
+    let group = NamedGroup::SikeP434Compressed;
+    let kt = KeyType::RSA;
+    let store = Arc::new(MemorySessionStore::new());
+    let client_config = make_client_config_with_store(kt, &store);
+    let server_config = Arc::new(make_server_config(kt));
+
+    put_kx_hint(&store, group);
+
+    // the share uses the compressed public key encoding
+    let (mut client, _) = make_pair_for_arc_configs(&client_config, &server_config);
+    let hello = client_hello_from(&mut client);
+    let shares = hello.get_keyshare_extension().unwrap();
+    assert_eq!(shares[0].group, group);
+    assert_eq!(shares[0].payload.0.len(), 197);
+    assert!(shares[0].payload.0.len() <
+            rustls::kex_sizes(NamedGroup::SikeP434).unwrap().public_key_len);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let mut client_secret = [0u8; 32];
+    let mut server_secret = [0u8; 32];
+    client.export_keying_material(&mut client_secret, b"label", None).unwrap();
+    server.export_keying_material(&mut server_secret, b"label", None).unwrap();
+    assert_eq!(client_secret, server_secret);
+
+    let hint = store.get_kind(b"kx-hint")
+        .and_then(|value| NamedGroup::read_bytes(&value))
+        .unwrap();
+    assert_eq!(hint, group);
+}
+
+#[test]
+fn client_does_not_offer_groups_too_big_for_a_key_share() {
+    use rustls::internal::msgs::enums::NamedGroup;
+
+    let client_config = Arc::new(make_client_config(KeyType::RSA));
+    let mut client = ClientSession::new(&client_config, dns_name("localhost"));
+    let hello = client_hello_from(&mut client);
+    let groups = hello.get_namedgroups_extension().unwrap();
+    assert!(groups.contains(&NamedGroup::Kyber512));
+    assert!(!groups.contains(&NamedGroup::ClassicMcEliece348864));
+}
+
+#[test]
+fn client_rejects_resumption_with_classical_group_after_kem() {
+    use rustls::internal::msgs::enums::NamedGroup;
+
+    let kt = KeyType::RSA;
+    let store = Arc::new(MemorySessionStore::new());
+    let client_config = make_client_config_with_store(kt, &store);
+    let server_config = Arc::new(make_server_config(kt));
+
+    // steer the original handshake to Kyber512
+    put_kx_hint(&store, NamedGroup::Kyber512);
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    // resuming with the same KEM is fine
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert!(client.did_resume());
+
+    // but without the hint we offer only an X25519 share, and the
+    // server resumes the Kyber512 session with that
+    let mut no_hints = ClientConfig::clone(&client_config);
+    no_hints.enable_kx_hints = false;
+    let (mut client, mut server) = make_pair_for_arc_configs(&Arc::new(no_hints), &server_config);
+    assert_eq!(do_handshake_until_both_error(&mut client, &mut server),
+               Err(vec![
+                   TLSErrorFromPeer::Client(TLSError::PeerMisbehavedError(
+                       "server resumed Kyber512 session with X25519".into())),
+                   TLSErrorFromPeer::Server(TLSError::AlertReceived(
+                       rustls::AlertDescription::IllegalParameter)),
+               ]));
+}
+
+#[test]
+fn client_rejects_mismatched_plaintext_supported_versions() {
+    use rustls::internal::msgs::handshake::ServerExtension;
+
+    fn claim_tls12_in_tls13_hello(msg: &mut Message) {
+        if let MessagePayload::Handshake(hs) = &mut msg.payload {
+            if let HandshakePayload::ServerHello(sh) = &mut hs.payload {
+                sh.legacy_version = ProtocolVersion::TLSv1_3;
+                for ext in sh.extensions.iter_mut() {
+                    if let ServerExtension::SupportedVersions(version) = ext {
+                        *version = ProtocolVersion::TLSv1_2;
+                    }
+                }
+            }
+        }
+    }
+
+    let (mut client, mut server) = make_pair(KeyType::RSA);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer_altered(&mut server, claim_tls12_in_tls13_hello, &mut client);
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::PeerMisbehavedError("server sent mismatched supported_versions".into())));
+}
+
+fn observed_handshakes(encrypted: bool) -> (Vec<rustls::HandshakeType>, Vec<u8>, Vec<u8>) {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let mut client_config = make_client_config(KeyType::RSA);
+    let seen_in_callback = seen.clone();
+    client_config.on_outgoing_handshake = Some(Arc::new(move |typ, bytes: &[u8]| {
+        seen_in_callback.lock().unwrap().push((typ, bytes.to_vec()));
+    }));
+    client_config.on_outgoing_handshake_encrypted = encrypted;
+
+    let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                         make_server_config(KeyType::RSA));
+    let mut wire = Vec::new();
+    client.write_tls(&mut wire).unwrap();
+    server.read_tls(&mut &wire[..]).unwrap();
+    do_handshake(&mut client, &mut server);
+
+    let seen = seen.lock().unwrap();
+    let types = seen.iter().map(|(typ, _)| *typ).collect();
+    (types, seen[0].1.clone(), wire)
+}
+
+#[test]
+fn on_outgoing_handshake_sees_client_hello_bytes() {
+    let (types, hello, wire) = observed_handshakes(false);
+    assert_eq!(types, vec![rustls::HandshakeType::ClientHello]);
+
+    // the observed bytes are exactly what follows the record header
+    assert_eq!(hello[0], 0x01);
+    assert_eq!(&wire[5..5 + hello.len()], &hello[..]);
+}
+
+#[test]
+fn on_outgoing_handshake_sees_encrypted_messages_when_enabled() {
+    let (types, _, _) = observed_handshakes(true);
+    assert_eq!(types, vec![rustls::HandshakeType::ClientHello,
+                           rustls::HandshakeType::Finished]);
+}
+
+#[test]
+fn client_advertises_only_configured_signature_schemes() {
+    let schemes = [SignatureScheme::RSA_PSS_SHA256, SignatureScheme::ECDSA_NISTP256_SHA256];
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.set_signature_schemes(&schemes).unwrap();
+
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let hello = client_hello_from(&mut client);
+    assert_eq!(hello.get_sigalgs_extension(), Some(&schemes.to_vec()));
+}
+
+#[test]
+fn client_rejects_unsupported_signature_scheme_override() {
+    let mut client_config = make_client_config(KeyType::RSA);
+    assert!(client_config.set_signature_schemes(&[]).is_err());
+    assert!(client_config.set_signature_schemes(&[SignatureScheme::Unknown(0xfefe)])
+            .is_err());
+}
+
+#[test]
+fn handshake_succeeds_with_restricted_signature_schemes() {
+    for version in &[ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3] {
+        let mut client_config = make_client_config(KeyType::RSA);
+        client_config.versions = vec![*version];
+        client_config.set_signature_schemes(&[SignatureScheme::RSA_PSS_SHA256]).unwrap();
+
+        let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                             make_server_config(KeyType::RSA));
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.get_protocol_version(), Some(*version));
+    }
+}
+
+#[test]
+fn appdata_sent_with_close_notify_is_readable() {
+    for client_config in AllClientVersions::new(make_client_config(KeyType::RSA)) {
+        let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                             make_server_config(KeyType::RSA));
+        do_handshake(&mut client, &mut server);
+
+        server.write_all(b"last words").unwrap();
+        server.send_close_notify();
+
+        // both records arrive in one read
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+        check_read_and_close(&mut client, b"last words");
+    }
+}
+
+#[test]
+fn client_after_close_notify_keeps_transcript_hash_but_cannot_update_keys() {
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.versions = vec![ ProtocolVersion::TLSv1_3 ];
+    let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                         make_server_config(KeyType::RSA));
+    do_handshake(&mut client, &mut server);
+    let hash = client.transcript_hash().unwrap();
+
+    server.send_close_notify();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    assert_eq!(client.transcript_hash(), Some(hash));
+    assert_eq!(client.request_key_update(false), Err(TLSError::PeerClosed));
+    assert_eq!(client.wants_write(), false);
+}
+
+#[test]
+fn appdata_after_close_notify_is_rejected() {
+    use rustls::internal::msgs::enums::ContentType;
+
+    for client_config in AllClientVersions::new(make_client_config(KeyType::RSA)) {
+        let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                             make_server_config(KeyType::RSA));
+        do_handshake(&mut client, &mut server);
+
+        server.write_all(b"before").unwrap();
+        server.send_close_notify();
+        server.write_all(b"after").unwrap();
+
+        transfer(&mut server, &mut client);
+        assert_eq!(client.process_new_packets(),
+                   Err(TLSError::InappropriateMessage {
+                       expect_types: vec![],
+                       got_type: ContentType::ApplicationData,
+                   }));
+        check_read_and_close(&mut client, b"before");
+    }
+}
+
+#[cfg(feature = "bench")]
+#[test]
+fn bench_run_handshake_records_both_sides() {
+    use rustls::HandshakeType;
+
+    let client_config = Arc::new(make_client_config(KeyType::RSA));
+    let server_config = Arc::new(make_server_config(KeyType::RSA));
+    let timings = rustls::bench::run_handshake(&client_config, &server_config,
+                                               dns_name("localhost"))
+        .unwrap();
+
+    let sent = timings.messages.iter()
+        .map(|msg| (msg.from_client, msg.typ))
+        .collect::<Vec<_>>();
+    assert_eq!(&sent[..4], &[(true, HandshakeType::ClientHello),
+                             (false, HandshakeType::ServerHello),
+                             (false, HandshakeType::EncryptedExtensions),
+                             (false, HandshakeType::Certificate)]);
+    assert!(sent.contains(&(true, HandshakeType::Finished)));
+    assert!(timings.messages.windows(2).all(|w| w[0].at <= w[1].at));
+    assert!(timings.messages.iter().all(|msg| msg.at <= timings.total));
+}
+
+#[cfg(feature = "bench")]
+#[test]
+fn bench_handshake_bench_records_each_run_afresh() {
+    let client_config = Arc::new(make_client_config(KeyType::RSA));
+    let server_config = Arc::new(make_server_config(KeyType::RSA));
+    let bench = rustls::bench::HandshakeBench::new(&client_config, &server_config);
+
+    let first = bench.run(dns_name("localhost")).unwrap();
+    let second = bench.run(dns_name("localhost")).unwrap();
+    assert!(second.messages.len() <= first.messages.len());
+    assert!(second.messages[0].from_client);
+    assert_eq!(second.messages[0].typ, rustls::HandshakeType::ClientHello);
+    assert!(second.messages.iter().all(|msg| msg.at <= second.total));
+    assert!(client_config.on_outgoing_handshake.is_none());
+}
+
+#[test]
+fn client_rejects_psk_only_resumption() {
+    use rustls::internal::msgs::message::{Message, MessagePayload};
+    use rustls::internal::msgs::handshake::{HandshakePayload, ServerExtension};
+
+    fn strip_key_share(msg: &mut Message) {
+        if let MessagePayload::Handshake(hs) = &mut msg.payload {
+            if let HandshakePayload::ServerHello(sh) = &mut hs.payload {
+                sh.extensions.retain(|ext| match ext {
+                    ServerExtension::KeyShare(_) => false,
+                    _ => true,
+                });
+            }
+        }
+    }
+
+    let kt = KeyType::RSA;
+    let store = Arc::new(MemorySessionStore::new());
+    let client_config = make_client_config_with_store(kt, &store);
+    let server_config = Arc::new(make_server_config(kt));
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    assert!(server.is_handshaking());
+    transfer_altered(&mut server, strip_key_share, &mut client);
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::PeerMisbehavedError("server resumed with psk_ke, which we did not offer"
+                                                 .into())));
+}
+
+#[test]
+fn kemtls_resumption_skips_certificate_encapsulation() {
+    let kt = KeyType::Kyber512;
+    let store = Arc::new(MemorySessionStore::new());
+    let client_config = make_client_config_with_store(kt, &store);
+    let server_config = Arc::new(make_server_config(kt));
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (full_c2s, full_s2c) = do_handshake(&mut client, &mut server);
+
+    // the server sends no Certificate, so the client has nothing to
+    // encapsulate to; the cached session supplies the server's chain.
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (resume_c2s, resume_s2c) = do_handshake(&mut client, &mut server);
+    assert!(resume_c2s > full_c2s);
+    assert!(resume_s2c < full_s2c);
+    assert!(client.did_resume());
+    assert_eq!(client.selected_psk_index(), Some(0));
+    assert_eq!(client.get_peer_certificates().unwrap()[0], kt.get_chain()[0]);
+
+    client.write_all(b"resumed").unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server, b"resumed");
+}
+
+#[test]
+fn kemtls_resumption_falls_back_when_server_cert_changed() {
+    use rustls::internal::msgs::enums::ExtensionType;
+
+    let kt = KeyType::Kyber512;
+    let store = Arc::new(MemorySessionStore::new());
+    let client_config = make_client_config_with_store(kt, &store);
+    let server_config = Arc::new(make_server_config(kt));
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    // we now expect the server to have a different KEM certificate
+    let mut client_config = ClientConfig::clone(&client_config);
+    client_config.known_certificates = vec![ kt.get_client_chain()[0].clone() ];
+    let client_config = Arc::new(client_config);
+
+    let mut client = ClientSession::new(&client_config, dns_name("localhost"));
+    let hello = client_hello_from(&mut client);
+    assert!(hello.find_extension(ExtensionType::PreSharedKey).is_none());
+
+    // with the certificate we expect, resumption is still offered
+    let mut client_config = ClientConfig::clone(&client_config);
+    client_config.known_certificates = vec![ kt.get_chain()[0].clone() ];
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let hello = client_hello_from(&mut client);
+    assert!(hello.find_extension(ExtensionType::PreSharedKey).is_some());
+}
+
+#[test]
+fn client_rejects_server_hello_with_two_key_shares() {
+    use rustls::internal::msgs::base::Payload;
+    use rustls::internal::msgs::codec::Codec;
+    use rustls::internal::msgs::enums::{AlertDescription, ExtensionType};
+    use rustls::internal::msgs::message::{Message, MessagePayload};
+    use rustls::internal::msgs::handshake::{HandshakePayload, ServerExtension, UnknownExtension};
+
+    fn duplicate_key_share(msg: &mut Message) {
+        if let MessagePayload::Handshake(hs) = &mut msg.payload {
+            if let HandshakePayload::ServerHello(sh) = &mut hs.payload {
+                for ext in sh.extensions.iter_mut() {
+                    if let ServerExtension::KeyShare(share) = ext {
+                        let mut body = share.get_encoding();
+                        body.extend_from_slice(&share.get_encoding());
+                        *ext = ServerExtension::Unknown(UnknownExtension {
+                            typ: ExtensionType::KeyShare,
+                            payload: Payload::new(body),
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    let (mut client, mut server) = make_pair(KeyType::RSA);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer_altered(&mut server, duplicate_key_share, &mut client);
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::PeerMisbehavedError("server sent more than one key share".into())));
+
+    let mut alert = Vec::new();
+    client.write_tls(&mut alert).unwrap();
+    assert_eq!(alert.last(), Some(&AlertDescription::IllegalParameter.get_u8()));
+}
+
+#[test]
+fn client_can_get_peer_ocsp_response() {
+    let kt = KeyType::RSA;
+    let mut server_config = make_server_config(kt);
+    server_config.set_single_cert_with_ocsp_and_sct(kt.get_chain(), kt.get_key(),
+                                                    b"ocsp-response".to_vec(), vec![])
+        .unwrap();
+    let server_config = Arc::new(server_config);
+
+    for client_config in AllClientVersions::new(make_client_config(kt)) {
+        let (mut client, mut server) = make_pair_for_arc_configs(&Arc::new(client_config),
+                                                                 &server_config);
+        assert_eq!(client.get_peer_ocsp_response(), None);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.get_peer_ocsp_response(), Some(&b"ocsp-response"[..]));
+    }
+}
+
+#[test]
+fn client_enforces_min_auth_kem_level() {
+    let kt = KeyType::Kyber512;
+    let mut client_config = make_client_config(kt);
+    client_config.min_auth_kem_level = rustls::KemSecurityLevel::Level3;
+    let (mut client, mut server) = make_pair_for_configs(client_config, make_server_config(kt));
+
+    let errs = do_handshake_until_both_error(&mut client, &mut server);
+    assert_eq!(errs,
+               Err(vec![
+                   TLSErrorFromPeer::Client(TLSError::PeerIncompatibleError(
+                       "server's KEM certificate is Some(Level1), below Level3".into())),
+                   TLSErrorFromPeer::Server(TLSError::AlertReceived(
+                       rustls::AlertDescription::InsufficientSecurity)),
+               ]));
+
+    let mut client_config = make_client_config(kt);
+    client_config.min_auth_kem_level = rustls::KemSecurityLevel::Level1;
+    let (mut client, mut server) = make_pair_for_configs(client_config, make_server_config(kt));
+    do_handshake(&mut client, &mut server);
+}
+
+#[test]
+fn client_rejects_ocsp_staple_on_kem_certificate() {
+    let kt = KeyType::Kyber512;
+    let mut server_config = make_server_config(kt);
+    server_config.set_single_cert_with_ocsp_and_sct(kt.get_chain(), kt.get_key(),
+                                                    b"ocsp-response".to_vec(), vec![])
+        .unwrap();
+    let (mut client, mut server) = make_pair_for_configs(make_client_config(kt), server_config);
+
+    let errs = do_handshake_until_both_error(&mut client, &mut server);
+    assert_eq!(errs,
+               Err(vec![
+                   TLSErrorFromPeer::Client(TLSError::PeerMisbehavedError(
+                       "server sent StatusRequest extension for KEM certificate".into())),
+                   TLSErrorFromPeer::Server(TLSError::AlertReceived(
+                       rustls::AlertDescription::UnsupportedExtension)),
+               ]));
+    assert_eq!(client.get_peer_ocsp_response(), None);
+}
+
+#[test]
+fn peer_cert_chain_parsed_splits_kem_chain() {
+    let kt = KeyType::Kyber512;
+    let (mut client, mut server) = make_pair(kt);
+    assert_eq!(client.peer_cert_chain_parsed().err(),
+               Some(TLSError::NoCertificatesPresented));
+
+    do_handshake(&mut client, &mut server);
+
+    let chain = client.peer_cert_chain_parsed().unwrap();
+    assert!(chain.end_entity.is_kem_cert());
+    assert_eq!(chain.intermediates, &kt.get_chain()[1..]);
+
+    // no client auth, so the server has nothing to parse
+    assert_eq!(server.peer_cert_chain_parsed().err(),
+               Some(TLSError::NoCertificatesPresented));
+}
+
+#[test]
+fn client_peer_ocsp_response_is_none_without_staple() {
+    for client_config in AllClientVersions::new(make_client_config(KeyType::RSA)) {
+        let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                             make_server_config(KeyType::RSA));
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.get_peer_ocsp_response(), None);
+    }
+}
+
+#[test]
+fn client_sends_distinct_cert_signature_schemes() {
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.set_signature_schemes(&[SignatureScheme::RSA_PSS_SHA256]).unwrap();
+    client_config.cert_signature_schemes = vec![ SignatureScheme::RSA_PKCS1_SHA256 ];
+
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let hello = client_hello_from(&mut client);
+    assert_eq!(hello.get_sigalgs_extension(),
+               Some(&vec![ SignatureScheme::RSA_PSS_SHA256 ]));
+    assert_eq!(hello.get_sigalgs_cert_extension(),
+               Some(&vec![ SignatureScheme::RSA_PKCS1_SHA256 ]));
+
+    let client_config = make_client_config(KeyType::RSA);
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    assert_eq!(client_hello_from(&mut client).get_sigalgs_cert_extension(), None);
+}
+
+#[test]
+fn cert_signature_schemes_apply_to_chain_not_transcript() {
+    // the test chain is signed with PKCS#1, which can't be used for
+    // CertificateVerify in TLS1.3.
+    for version in &[ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3] {
+        let mut client_config = make_client_config(KeyType::RSA);
+        client_config.versions = vec![*version];
+        client_config.set_signature_schemes(&[SignatureScheme::RSA_PSS_SHA256]).unwrap();
+        client_config.cert_signature_schemes = vec![ SignatureScheme::RSA_PKCS1_SHA256 ];
+
+        let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                             make_server_config(KeyType::RSA));
+        do_handshake(&mut client, &mut server);
+    }
+}
+
+#[test]
+fn cert_signature_schemes_reject_other_chains() {
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.cert_signature_schemes = vec![ SignatureScheme::ECDSA_NISTP256_SHA256 ];
+
+    let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                         make_server_config(KeyType::RSA));
+    match do_handshake_until_error(&mut client, &mut server) {
+        Err(TLSErrorFromPeer::Client(TLSError::WebPKIError(_))) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+/// A CT log whose key can't verify anything, for SCT tests.
+static TEST_CT_LOG: sct::Log = sct::Log {
+    description: "test log",
+    url: "ct.example.com",
+    operated_by: "nobody",
+    key: &[],
+    id: [0u8; 32],
+    max_merge_delay: 86400,
+};
+
+/// An SCT claiming to be from the log with `log_id`, with a junk
+/// ECDSA signature.
+fn sct_from_log(log_id: [u8; 32]) -> Vec<u8> {
+    let mut sct = vec![ 0x00 ];
+    sct.extend_from_slice(&log_id);
+    sct.extend_from_slice(&[ 0u8; 8 ]); // timestamp
+    sct.extend_from_slice(&[ 0x00, 0x00 ]); // extensions
+    sct.extend_from_slice(&[ 0x04, 0x03 ]); // ecdsa_secp256r1_sha256
+    sct.extend_from_slice(&[ 0x00, 0x01, 0x00 ]); // signature
+    sct
+}
+
+/// Handshake with a server presenting the single SCT `sct`, and check
+/// the client fails with `err` and sends `alert`.
+fn check_sct_alert(sct: &[u8], logs: &'static [&'static sct::Log],
+                   err: sct::Error, alert: rustls::AlertDescription) {
+    let mut scts = Vec::new();
+    scts.extend_from_slice(&(sct.len() as u16 + 2).to_be_bytes());
+    scts.extend_from_slice(&(sct.len() as u16).to_be_bytes());
+    scts.extend_from_slice(sct);
+
+    let kt = KeyType::RSA;
+    let mut server_config = make_server_config(kt);
+    server_config.set_single_cert_with_ocsp_and_sct(kt.get_chain(), kt.get_key(),
+                                                    vec![], scts)
+        .unwrap();
+    let server_config = Arc::new(server_config);
+
+    let mut client_config = make_client_config(kt);
+    client_config.ct_logs = Some(logs);
+
+    for client_config in AllClientVersions::new(client_config) {
+        let (mut client, mut server) = make_pair_for_arc_configs(&Arc::new(client_config),
+                                                                 &server_config);
+        let errs = do_handshake_until_both_error(&mut client, &mut server);
+        assert_eq!(errs,
+                   Err(vec![
+                       TLSErrorFromPeer::Client(TLSError::InvalidSCT(err)),
+                       TLSErrorFromPeer::Server(TLSError::AlertReceived(alert)),
+                   ]));
+    }
+}
+
+#[test]
+fn client_sends_alert_for_invalid_sct() {
+    static NO_LOGS: &[&sct::Log] = &[];
+
+    // one SCT, which is truncated after its version
+    check_sct_alert(&[ 0x00, 0x01, 0x02 ], NO_LOGS,
+                    sct::Error::MalformedSCT, rustls::AlertDescription::DecodeError);
+}
+
+#[test]
+fn client_sends_alert_for_sct_with_unsupported_version() {
+    static LOGS: &[&sct::Log] = &[ &TEST_CT_LOG ];
+
+    check_sct_alert(&[ 0x01 ], LOGS,
+                    sct::Error::UnsupportedSCTVersion,
+                    rustls::AlertDescription::UnsupportedCertificate);
+}
+
+#[test]
+fn client_sends_alert_for_sct_from_unknown_log() {
+    static LOGS: &[&sct::Log] = &[ &TEST_CT_LOG ];
+
+    check_sct_alert(&sct_from_log([ 0x11; 32 ]), LOGS,
+                    sct::Error::UnknownLog, rustls::AlertDescription::UnknownCA);
+}
+
+#[test]
+fn client_sends_alert_for_sct_with_bad_signature() {
+    static LOGS: &[&sct::Log] = &[ &TEST_CT_LOG ];
+
+    check_sct_alert(&sct_from_log(TEST_CT_LOG.id), LOGS,
+                    sct::Error::InvalidSignature, rustls::AlertDescription::DecryptError);
+}
+
+#[test]
+fn client_extra_extensions_survive_hello_retry_request() {
+    use rustls::internal::msgs::codec::Codec;
+    use rustls::internal::msgs::enums::NamedGroup;
+    use rustls::internal::msgs::handshake::{ClientExtension, ClientHelloPayload};
+
+    fn extra_region(hello: &ClientHelloPayload) -> Vec<u8> {
+        let mut region = Vec::new();
+        for ext in hello.extensions.iter() {
+            if let ClientExtension::Unknown(_) = ext {
+                ext.encode(&mut region);
+            }
+        }
+        region
+    }
+
+    let extras = vec![
+        (0x0a0a, vec![]),
+        (0xfe00, b"custom".to_vec()),
+    ];
+
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.versions = vec![ ProtocolVersion::TLSv1_3 ];
+    let mut client = ClientSession::new_with_extensions(&Arc::new(client_config),
+                                                        dns_name("localhost"),
+                                                        extras)
+        .unwrap();
+
+    let first = client_hello_from(&mut client);
+    client.read_tls(&mut &hrr_message(first.session_id, NamedGroup::Kyber512)[..]).unwrap();
+    client.process_new_packets().unwrap();
+    let second = client_hello_from(&mut client);
+
+    let region = extra_region(&first);
+    assert_eq!(region, vec![ 0x0a, 0x0a, 0x00, 0x00,
+                             0xfe, 0x00, 0x00, 0x06, b'c', b'u', b's', b't', b'o', b'm' ]);
+    assert_eq!(region, extra_region(&second));
+}
+
+#[test]
+fn client_rejects_unsuitable_extra_extensions() {
+    let client_config = Arc::new(make_client_config(KeyType::RSA));
+
+    // server_name is sent by rustls
+    assert!(ClientSession::new_with_extensions(&client_config, dns_name("localhost"),
+                                               vec![ (0x0000, vec![]) ])
+            .is_err());
+    assert!(ClientSession::new_with_extensions(&client_config, dns_name("localhost"),
+                                               vec![ (0x0a0a, vec![]), (0x0a0a, vec![]) ])
+            .is_err());
+}
+
+#[test]
+fn client_can_skip_server_name_check_without_sni() {
+    for client_config in AllClientVersions::new(make_client_config(KeyType::RSA)) {
+        let mut client_config = client_config;
+        client_config.enable_sni = false;
+        client_config.verify_server_name = false;
+
+        // eg. a server addressed by IP, with no name for us to check
+        let mut client = ClientSession::new(&Arc::new(client_config),
+                                            dns_name("no-name.invalid"));
+        let mut server = ServerSession::new(&Arc::new(make_server_config(KeyType::RSA)));
+        do_handshake(&mut client, &mut server);
+        assert!(client.get_peer_certificates().is_some());
+    }
+}
+
+#[test]
+fn client_skips_server_name_check_with_sni_enabled() {
+    let server_config = Arc::new(make_server_config(KeyType::RSA));
+
+    let client_config = Arc::new(make_client_config(KeyType::RSA));
+    let mut client = ClientSession::new(&client_config, dns_name("no-name.invalid"));
+    let mut server = ServerSession::new(&server_config);
+    assert_eq!(do_handshake_until_error(&mut client, &mut server),
+               Err(TLSErrorFromPeer::Client(
+                   TLSError::WebPKIError(webpki::Error::CertNotValidForName))));
+
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.verify_server_name = false;
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("no-name.invalid"));
+    let mut server = ServerSession::new(&server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.get_sni_hostname(), Some("no-name.invalid"));
+}
+
+#[test]
+fn kemtls_client_connects_without_server_name() {
+    let mut client_config = make_client_config(KeyType::Kyber512);
+    client_config.enable_sni = false;
+    client_config.verify_server_name = false;
+
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("no-name.invalid"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config(KeyType::Kyber512)));
+    do_handshake(&mut client, &mut server);
+    assert!(client.get_peer_certificates().is_some());
+}
+
+#[test]
+fn kemtls_client_checks_kem_spki_pins() {
+    let kt = KeyType::Kyber512;
+    let pin = rustls::kem_spki_pin(&kt.get_chain()[0]).unwrap();
+    assert_eq!(rustls::kem_spki_pin(&KeyType::RSA.get_chain()[0]), None);
+    let server_config = Arc::new(make_server_config(kt));
+
+    let pinned_client = |pins| {
+        let mut client_config = make_client_config(kt);
+        client_config.enable_sni = false;
+        client_config.verify_server_name = false;
+        client_config.kem_spki_pins = pins;
+        ClientSession::new(&Arc::new(client_config), dns_name("no-name.invalid"))
+    };
+
+    let mut client = pinned_client(vec![ [0u8; 32], pin ]);
+    let mut server = ServerSession::new(&server_config);
+    do_handshake(&mut client, &mut server);
+
+    let mut client = pinned_client(vec![ [0u8; 32] ]);
+    let mut server = ServerSession::new(&server_config);
+    assert_eq!(do_handshake_until_error(&mut client, &mut server),
+               Err(TLSErrorFromPeer::Client(
+                   TLSError::General("server certificate key is not pinned".to_string()))));
+}
+
+#[test]
+fn client_did_resume_is_false_when_server_declines() {
+    for kt in &[KeyType::RSA, KeyType::Kyber512] {
+        let store = Arc::new(MemorySessionStore::new());
+        let client_config = make_client_config_with_store(*kt, &store);
+        let server_config = Arc::new(make_server_config(*kt));
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert!(!client.did_resume());
+        assert_eq!(client.selected_psk_index(), None);
+
+        // the client offers its ticket, but this server has never seen it
+        let mut server_config = make_server_config(*kt);
+        server_config.ticketer = rustls::Ticketer::new();
+        let server_config = Arc::new(server_config);
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert!(!client.did_resume());
+        assert_eq!(client.selected_psk_index(), None);
+        assert!(client.get_peer_certificates().is_some());
+    }
+}
+
+#[test]
+fn client_rejects_cert_chain_over_size_limit() {
+    let kt = KeyType::RSA;
+    let chain_bytes: usize = kt.get_chain()
+        .iter()
+        .map(|cert| cert.0.len())
+        .sum();
+
+    for client_config in AllClientVersions::new(make_client_config(kt)) {
+        let mut client_config = client_config;
+        client_config.max_peer_cert_chain_bytes = chain_bytes;
+        let (mut client, mut server) = make_pair_for_configs(client_config.clone(),
+                                                             make_server_config(kt));
+        do_handshake(&mut client, &mut server);
+
+        client_config.max_peer_cert_chain_bytes = chain_bytes - 1;
+        let (mut client, mut server) = make_pair_for_configs(client_config,
+                                                             make_server_config(kt));
+        assert_eq!(do_handshake_until_both_error(&mut client, &mut server),
+                   Err(vec![
+                       TLSErrorFromPeer::Client(TLSError::PeerMisbehavedError(
+                           "server certificate chain too large".into())),
+                       TLSErrorFromPeer::Server(TLSError::AlertReceived(
+                           rustls::AlertDescription::DecodeError)),
+                   ]));
+    }
+}
+
+#[test]
+fn client_hellos_never_share_pooled_key_pairs() {
+    use rustls::EphemeralKeyPool;
+    use rustls::internal::msgs::enums::NamedGroup;
+
+    let group = NamedGroup::X25519;
+    let pool = EphemeralKeyPool::new(&[group], 2);
+    let mut client_config = make_client_config(KeyType::RSA);
+    client_config.ephemeral_key_pool = Some(pool.clone());
+    let client_config = Arc::new(client_config);
+
+    let mut shares = Vec::new();
+    for _ in 0..4 {
+        while pool.available(group) == 0 {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        let mut client = ClientSession::new(&client_config, dns_name("localhost"));
+        let hello = client_hello_from(&mut client);
+        let share = hello.get_keyshare_extension().unwrap()[0].payload.0.clone();
+        assert!(!shares.contains(&share));
+        shares.push(share);
+    }
+
+    let server_config = Arc::new(make_server_config(KeyType::RSA));
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
 }
@@ -196,6 +196,74 @@ pub fn transfer_altered<F>(left: &mut dyn Session, filter: F, right: &mut dyn Se
     total
 }
 
+/// Like `transfer`, but also appends each TLS record moved to `records`.
+pub fn transfer_recorded(left: &mut dyn Session, right: &mut dyn Session,
+                         records: &mut Vec<Vec<u8>>) -> usize {
+    let mut buf = [0u8; 262144];
+    let mut total = 0;
+
+    while left.wants_write() {
+        let sz = {
+            let into_buf: &mut dyn io::Write = &mut &mut buf[..];
+            left.write_tls(into_buf).unwrap()
+        };
+        total += sz;
+        if sz == 0 {
+            return total;
+        }
+
+        let mut reader = Reader::init(&buf[..sz]);
+        while reader.any_left() {
+            let record = Message::read(&mut reader)
+                .unwrap()
+                .get_encoding();
+            let len = right.read_tls(&mut &record[..]).unwrap();
+            assert_eq!(len, record.len());
+            records.push(record);
+        }
+    }
+
+    total
+}
+
+/// Handshake fixtures (see tests/replay) hold the TLS records a
+/// server sent, one per line in hex.  Blank lines and lines starting
+/// with '#' are ignored.
+pub fn encode_fixture(records: &[Vec<u8>]) -> String {
+    records.iter()
+        .map(|record| {
+            record.iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>() + "\n"
+        })
+        .collect()
+}
+
+pub fn decode_fixture(text: &str) -> Vec<Vec<u8>> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            (0..line.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&line[i..i + 2], 16).unwrap())
+                .collect()
+        })
+        .collect()
+}
+
+/// Feed `records` to `client` one at a time, as if a server had sent
+/// them, stopping at the first error.
+pub fn replay(client: &mut ClientSession, records: &[Vec<u8>]) -> Result<(), TLSError> {
+    for record in records {
+        let len = client.read_tls(&mut &record[..]).unwrap();
+        assert_eq!(len, record.len());
+        client.process_new_packets()?;
+    }
+
+    Ok(())
+}
+
 #[derive(Clone, Debug, Copy, PartialEq)]
 pub enum KeyType {
     RSA,
@@ -213,7 +281,7 @@ pub static ALL_KEY_TYPES: [KeyType; 3] = [
 ];
 
 impl KeyType {
-    fn bytes_for(&self, part: &str) -> &'static [u8] {
+    pub fn bytes_for(&self, part: &str) -> &'static [u8] {
         match self {
             KeyType::RSA => bytes_for("rsa", part),
             KeyType::ECDSA => bytes_for("ecdsa", part),
@@ -234,12 +302,12 @@ impl KeyType {
             .clone()
     }
 
-    fn get_client_chain(&self) -> Vec<Certificate> {
+    pub fn get_client_chain(&self) -> Vec<Certificate> {
         pemfile::certs(&mut io::BufReader::new(self.bytes_for("client.fullchain")))
             .unwrap()
     }
 
-    fn get_client_key(&self) -> PrivateKey {
+    pub fn get_client_key(&self) -> PrivateKey {
         pemfile::pkcs8_private_keys(&mut io::BufReader::new(self.bytes_for("client.key")))
                 .unwrap()[0]
             .clone()
@@ -288,6 +356,13 @@ pub fn make_client_config_with_auth(kt: KeyType) -> ClientConfig {
     cfg
 }
 
+pub fn make_client_config_with_kem_auth(kt: KeyType) -> ClientConfig {
+    let mut cfg = make_client_config(kt);
+    cfg.set_single_client_kem_cert(kt.get_client_chain(), kt.get_client_key())
+        .unwrap();
+    cfg
+}
+
 pub fn make_pair(kt: KeyType) -> (ClientSession, ServerSession) {
     make_pair_for_configs(make_client_config(kt),
                           make_server_config(kt))
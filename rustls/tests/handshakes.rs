@@ -0,0 +1,38 @@
+//! Full in-memory handshakes, timed with `rustls::bench`.  Build with
+//! `--features bench`.
+
+#![cfg(feature = "bench")]
+
+use criterion::Criterion;
+use criterion::criterion_group;
+use criterion::criterion_main;
+
+#[allow(dead_code)]
+mod common;
+use crate::common::*;
+
+use rustls::bench::HandshakeBench;
+
+use std::sync::Arc;
+
+fn bench_handshake(c: &mut Criterion, name: &str, client_config: rustls::ClientConfig,
+                   server_config: rustls::ServerConfig) {
+    let bench = HandshakeBench::new(&Arc::new(client_config),
+                                    &Arc::new(server_config));
+    c.bench_function(name,
+                     move |b| b.iter(|| bench.run(dns_name("localhost")).unwrap()));
+}
+
+fn bench_handshakes(c: &mut Criterion) {
+    for kt in &[KeyType::RSA, KeyType::ECDSA, KeyType::ED25519] {
+        bench_handshake(c, &format!("full handshake {:?}", kt),
+                        make_client_config(*kt), make_server_config(*kt));
+    }
+
+    bench_handshake(c, "KEMTLS handshake Kyber512",
+                    make_client_config(KeyType::Kyber512),
+                    make_server_config(KeyType::Kyber512));
+}
+
+criterion_group!(benches, bench_handshakes);
+criterion_main!(benches);